@@ -0,0 +1,429 @@
+use futures::io::{AsyncRead, AsyncWrite};
+use libp2p::core::muxing::{StreamMuxer, StreamMuxerEvent};
+use libp2p::PeerId;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+/// Substream bytes seen before multistream-select settles are buffered up to this size
+/// while looking for the negotiated protocol name.
+const SNIFF_LIMIT: usize = 256;
+
+#[derive(Clone, Copy, Default, Serialize, Debug)]
+pub struct Traffic {
+    pub inbound: u64,
+    pub outbound: u64,
+}
+
+impl Traffic {
+    fn add(&mut self, direction: Direction, bytes: u64) {
+        match direction {
+            Direction::Inbound => self.inbound += bytes,
+            Direction::Outbound => self.outbound += bytes,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum Direction {
+    Inbound,
+    Outbound,
+}
+
+/// Cumulative byte counts together with the rate measured over the last sampling window.
+#[derive(Clone, Copy, Default, Serialize, Debug)]
+pub struct TrafficReport {
+    pub inbound: u64,
+    pub outbound: u64,
+    pub inbound_rate: f64,
+    pub outbound_rate: f64,
+}
+
+#[derive(Clone, Serialize, Debug)]
+pub struct BandwidthReport {
+    pub total: TrafficReport,
+    pub by_peer: HashMap<String, TrafficReport>,
+    pub by_protocol: HashMap<String, TrafficReport>,
+    pub by_topic: HashMap<String, TrafficReport>,
+    /// Per-peer caps in bytes per second (inbound + outbound).
+    pub caps: HashMap<String, u64>,
+    pub default_cap: Option<u64>,
+}
+
+/// Token bucket holding one second worth of the peer's allowance.
+struct Bucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl Bucket {
+    fn new(rate: u64) -> Self {
+        Self { tokens: rate as f64, last_refill: Instant::now() }
+    }
+
+    fn refill(&mut self, rate: u64) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate as f64).min(rate as f64);
+        self.last_refill = now;
+    }
+}
+
+#[derive(Default)]
+struct Counters {
+    total: Traffic,
+    by_peer: HashMap<PeerId, Traffic>,
+    by_protocol: HashMap<String, Traffic>,
+    by_topic: HashMap<String, Traffic>,
+    caps: HashMap<PeerId, u64>,
+    default_cap: Option<u64>,
+    buckets: HashMap<PeerId, Bucket>,
+    previous: Option<Sample>,
+    latest: Option<Sample>,
+}
+
+#[derive(Clone)]
+struct Sample {
+    at: Instant,
+    total: Traffic,
+    by_peer: HashMap<PeerId, Traffic>,
+    by_protocol: HashMap<String, Traffic>,
+    by_topic: HashMap<String, Traffic>,
+}
+
+/// Byte counters shared between the metered transport and the rest of the node.
+///
+/// Peer and protocol counters are fed by [`MeteredMuxer`] at the substream level, so they
+/// exclude noise and yamux framing. Topic counters are fed by the gossipsub handlers and
+/// count message payloads only.
+#[derive(Clone, Default)]
+pub struct Bandwidth {
+    inner: Arc<Mutex<Counters>>,
+}
+
+impl Bandwidth {
+    fn record_stream(&self, peer: &PeerId, protocol: &str, direction: Direction, bytes: usize) {
+        let bytes = bytes as u64;
+        let mut counters = self.inner.lock().unwrap();
+        counters.total.add(direction, bytes);
+        counters.by_peer.entry(*peer).or_default().add(direction, bytes);
+        counters.by_protocol.entry(protocol.to_string()).or_default().add(direction, bytes);
+    }
+
+    pub fn record_topic(&self, topic: &str, direction: Direction, bytes: usize) {
+        let mut counters = self.inner.lock().unwrap();
+        counters.by_topic.entry(topic.to_string()).or_default().add(direction, bytes as u64);
+    }
+
+    pub fn set_default_cap(&self, cap: Option<u64>) {
+        let mut counters = self.inner.lock().unwrap();
+        counters.default_cap = cap;
+        counters.buckets.clear();
+    }
+
+    pub fn set_cap(&self, peer: PeerId, cap: Option<u64>) {
+        let mut counters = self.inner.lock().unwrap();
+        match cap {
+            Some(cap) => {
+                counters.caps.insert(peer, cap);
+            }
+            None => {
+                counters.caps.remove(&peer);
+            }
+        }
+        counters.buckets.remove(&peer);
+    }
+
+    /// How many bytes `peer` may transfer right now, or how long to wait if its cap is exhausted.
+    fn allowance(&self, peer: &PeerId, wanted: usize) -> Result<usize, Duration> {
+        let mut counters = self.inner.lock().unwrap();
+        let Some(rate) = counters.caps.get(peer).copied().or(counters.default_cap) else {
+            return Ok(wanted);
+        };
+        let rate = rate.max(1);
+
+        let bucket = counters.buckets.entry(*peer).or_insert_with(|| Bucket::new(rate));
+        bucket.refill(rate);
+        if bucket.tokens >= 1.0 {
+            Ok(wanted.min(bucket.tokens as usize))
+        } else {
+            let missing = 1.0 - bucket.tokens;
+            Err(Duration::from_secs_f64(missing / rate as f64).max(Duration::from_millis(10)))
+        }
+    }
+
+    fn consume(&self, peer: &PeerId, bytes: usize) {
+        let mut counters = self.inner.lock().unwrap();
+        if let Some(bucket) = counters.buckets.get_mut(peer) {
+            bucket.tokens -= bytes as f64;
+        }
+    }
+
+    /// Snapshots the counters so that `report` can compute rates over the elapsed window.
+    pub fn sample(&self) {
+        let mut counters = self.inner.lock().unwrap();
+        let sample = Sample {
+            at: Instant::now(),
+            total: counters.total,
+            by_peer: counters.by_peer.clone(),
+            by_protocol: counters.by_protocol.clone(),
+            by_topic: counters.by_topic.clone(),
+        };
+        counters.previous = counters.latest.replace(sample);
+    }
+
+    pub fn report(&self) -> BandwidthReport {
+        let counters = self.inner.lock().unwrap();
+        // Rates are measured between the last two samples; totals are live.
+        let window = match (&counters.previous, &counters.latest) {
+            (Some(prev), Some(latest)) => Some((prev, latest)),
+            _ => None,
+        };
+
+        let by_peer = counters
+            .by_peer
+            .iter()
+            .map(|(peer, live)| {
+                let window = window.map(|(p, l)| (p.by_peer.get(peer).copied(), l.by_peer.get(peer).copied(), l.at.duration_since(p.at)));
+                (peer.to_string(), traffic_report(*live, window))
+            })
+            .collect();
+        let by_protocol = counters
+            .by_protocol
+            .iter()
+            .map(|(name, live)| {
+                let window = window.map(|(p, l)| (p.by_protocol.get(name).copied(), l.by_protocol.get(name).copied(), l.at.duration_since(p.at)));
+                (name.clone(), traffic_report(*live, window))
+            })
+            .collect();
+        let by_topic = counters
+            .by_topic
+            .iter()
+            .map(|(name, live)| {
+                let window = window.map(|(p, l)| (p.by_topic.get(name).copied(), l.by_topic.get(name).copied(), l.at.duration_since(p.at)));
+                (name.clone(), traffic_report(*live, window))
+            })
+            .collect();
+
+        BandwidthReport {
+            total: traffic_report(counters.total, window.map(|(p, l)| (Some(p.total), Some(l.total), l.at.duration_since(p.at)))),
+            by_peer,
+            by_protocol,
+            by_topic,
+            caps: counters.caps.iter().map(|(p, c)| (p.to_string(), *c)).collect(),
+            default_cap: counters.default_cap,
+        }
+    }
+}
+
+/// Combines live totals with the rate between two samples `(previous, latest, elapsed)`.
+fn traffic_report(live: Traffic, window: Option<(Option<Traffic>, Option<Traffic>, Duration)>) -> TrafficReport {
+    let (inbound_rate, outbound_rate) = match window {
+        Some((prev, Some(latest), elapsed)) if !elapsed.is_zero() => {
+            let prev = prev.unwrap_or_default();
+            let secs = elapsed.as_secs_f64();
+            (
+                latest.inbound.saturating_sub(prev.inbound) as f64 / secs,
+                latest.outbound.saturating_sub(prev.outbound) as f64 / secs,
+            )
+        }
+        _ => (0.0, 0.0),
+    };
+
+    TrafficReport { inbound: live.inbound, outbound: live.outbound, inbound_rate, outbound_rate }
+}
+
+/// Maps a negotiated libp2p protocol name to the label used in reports.
+fn protocol_label(name: &str) -> String {
+    if name.starts_with("/meshsub/") {
+        "gossipsub".to_string()
+    } else if name.starts_with("/ipfs/id/") {
+        "identify".to_string()
+    } else if name.starts_with("/ipfs/ping/") {
+        "ping".to_string()
+    } else {
+        name.to_string()
+    }
+}
+
+/// Scans multistream-select messages (`<uvarint len><name>\n`) for the first protocol
+/// name other than the multistream header itself.
+fn sniff_protocol(buf: &[u8]) -> Option<String> {
+    let mut rest = buf;
+    while !rest.is_empty() {
+        let len = rest[0] as usize;
+        if len >= 0x80 || rest.len() < 1 + len {
+            return None;
+        }
+        let msg = &rest[1..1 + len];
+        rest = &rest[1 + len..];
+        if let Some(name) = msg.strip_suffix(b"\n") {
+            if name.starts_with(b"/") && name != b"/multistream/1.0.0" {
+                return Some(String::from_utf8_lossy(name).to_string());
+            }
+        }
+    }
+    None
+}
+
+/// Wraps a connection's muxer so every substream is metered and rate limited for `peer`.
+pub struct MeteredMuxer<M> {
+    inner: M,
+    peer: PeerId,
+    bandwidth: Bandwidth,
+}
+
+impl<M> MeteredMuxer<M> {
+    pub fn new(inner: M, peer: PeerId, bandwidth: Bandwidth) -> Self {
+        Self { inner, peer, bandwidth }
+    }
+
+    fn wrap<S>(&self, inner: S, sniff: Direction) -> MeteredStream<S> {
+        MeteredStream {
+            inner,
+            peer: self.peer,
+            bandwidth: self.bandwidth.clone(),
+            protocol: None,
+            sniff,
+            sniff_buf: Vec::new(),
+            pending: Traffic::default(),
+            delay: None,
+        }
+    }
+}
+
+impl<M> StreamMuxer for MeteredMuxer<M>
+where
+    M: StreamMuxer + Unpin,
+    M::Substream: Unpin,
+{
+    type Substream = MeteredStream<M::Substream>;
+    type Error = M::Error;
+
+    fn poll_inbound(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<Self::Substream, Self::Error>> {
+        let inner = futures::ready!(Pin::new(&mut self.inner).poll_inbound(cx))?;
+        // The remote dialed this substream, so our side echoes the accepted protocol.
+        Poll::Ready(Ok(self.wrap(inner, Direction::Outbound)))
+    }
+
+    fn poll_outbound(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<Self::Substream, Self::Error>> {
+        let inner = futures::ready!(Pin::new(&mut self.inner).poll_outbound(cx))?;
+        Poll::Ready(Ok(self.wrap(inner, Direction::Inbound)))
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<StreamMuxerEvent, Self::Error>> {
+        Pin::new(&mut self.inner).poll(cx)
+    }
+}
+
+pub struct MeteredStream<S> {
+    inner: S,
+    peer: PeerId,
+    bandwidth: Bandwidth,
+    protocol: Option<String>,
+    /// Direction in which the listener's protocol confirmation travels.
+    sniff: Direction,
+    sniff_buf: Vec<u8>,
+    /// Bytes transferred before the protocol was known.
+    pending: Traffic,
+    delay: Option<Pin<Box<tokio::time::Sleep>>>,
+}
+
+impl<S> MeteredStream<S> {
+    fn account(&mut self, direction: Direction, data: &[u8]) {
+        self.bandwidth.consume(&self.peer, data.len());
+
+        if let Some(protocol) = &self.protocol {
+            self.bandwidth.record_stream(&self.peer, protocol, direction, data.len());
+            return;
+        }
+
+        self.pending.add(direction, data.len() as u64);
+        if matches!((direction, self.sniff), (Direction::Inbound, Direction::Inbound) | (Direction::Outbound, Direction::Outbound)) {
+            let take = data.len().min(SNIFF_LIMIT.saturating_sub(self.sniff_buf.len()));
+            self.sniff_buf.extend_from_slice(&data[..take]);
+        }
+
+        let protocol = match sniff_protocol(&self.sniff_buf) {
+            Some(name) => protocol_label(&name),
+            None if self.sniff_buf.len() >= SNIFF_LIMIT => "other".to_string(),
+            None => return,
+        };
+        self.flush_pending(&protocol);
+        self.protocol = Some(protocol);
+        self.sniff_buf = Vec::new();
+    }
+
+    fn flush_pending(&mut self, protocol: &str) {
+        let pending = std::mem::take(&mut self.pending);
+        if pending.inbound > 0 {
+            self.bandwidth.record_stream(&self.peer, protocol, Direction::Inbound, pending.inbound as usize);
+        }
+        if pending.outbound > 0 {
+            self.bandwidth.record_stream(&self.peer, protocol, Direction::Outbound, pending.outbound as usize);
+        }
+    }
+
+    /// Applies the peer's bandwidth cap, returning how many bytes may be moved now.
+    fn poll_allowance(&mut self, cx: &mut Context<'_>, wanted: usize) -> Poll<usize> {
+        loop {
+            if let Some(delay) = self.delay.as_mut() {
+                futures::ready!(delay.as_mut().poll(cx));
+                self.delay = None;
+            }
+            match self.bandwidth.allowance(&self.peer, wanted) {
+                Ok(allowed) => return Poll::Ready(allowed),
+                Err(wait) => self.delay = Some(Box::pin(tokio::time::sleep(wait))),
+            }
+        }
+    }
+}
+
+impl<S> Drop for MeteredStream<S> {
+    fn drop(&mut self) {
+        if self.protocol.is_none() {
+            self.flush_pending("other");
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for MeteredStream<S> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        if buf.is_empty() {
+            return Pin::new(&mut self.inner).poll_read(cx, buf);
+        }
+        let allowed = futures::ready!(self.poll_allowance(cx, buf.len()));
+        let n = futures::ready!(Pin::new(&mut self.inner).poll_read(cx, &mut buf[..allowed]))?;
+        self.account(Direction::Inbound, &buf[..n]);
+        Poll::Ready(Ok(n))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for MeteredStream<S> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        if buf.is_empty() {
+            return Pin::new(&mut self.inner).poll_write(cx, buf);
+        }
+        let allowed = futures::ready!(self.poll_allowance(cx, buf.len()));
+        let n = futures::ready!(Pin::new(&mut self.inner).poll_write(cx, &buf[..allowed]))?;
+        self.account(Direction::Outbound, &buf[..n]);
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+}
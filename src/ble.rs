@@ -21,7 +21,7 @@ pub async fn run_ble_service() -> Result<(), Box<dyn Error>> {
         return Ok(());
     }
 
-    let adapter = adapters.into_iter().nth(0).unwrap();
+    let adapter = adapters.into_iter().next().unwrap();
     info!("Using Bluetooth Adapter: {:?}", adapter.adapter_info().await?);
 
     // Start Scanning
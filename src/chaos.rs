@@ -0,0 +1,173 @@
+use futures::io::{AsyncRead, AsyncWrite};
+use libp2p::core::muxing::{StreamMuxer, StreamMuxerEvent};
+use libp2p::PeerId;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, RwLock};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+/// Faults to inject, for rehearsing outages locally. The default injects nothing.
+#[derive(Clone, Default, Serialize, Deserialize, Debug, PartialEq)]
+#[serde(default)]
+pub struct ChaosConfig {
+    /// Percentage (0-100) of inbound gossipsub messages discarded before the node sees them.
+    pub drop_percent: f64,
+    /// Delay added to every substream write, i.e. extra one-way latency towards each peer.
+    pub delay_ms: u64,
+    /// Groups of peer IDs that cannot reach each other. Peers in different groups are
+    /// disconnected and refused; peers missing from every group are unaffected.
+    pub partitions: Vec<Vec<String>>,
+}
+
+impl ChaosConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if !(0.0..=100.0).contains(&self.drop_percent) {
+            return Err(format!("drop_percent must be between 0 and 100, got {}", self.drop_percent));
+        }
+        for peer in self.partitions.iter().flatten() {
+            if peer.parse::<PeerId>().is_err() {
+                return Err(format!("invalid peer ID '{}' in partitions", peer));
+            }
+        }
+        Ok(())
+    }
+
+    fn group_of(&self, peer: &PeerId) -> Option<usize> {
+        let peer = peer.to_string();
+        self.partitions.iter().position(|group| group.contains(&peer))
+    }
+}
+
+/// Shared, runtime-adjustable fault injection settings, consulted by [`ChaosMuxer`] for
+/// every connection and by the swarm loop for every inbound message.
+#[derive(Clone, Default)]
+pub struct Chaos {
+    inner: Arc<RwLock<ChaosConfig>>,
+}
+
+impl Chaos {
+    pub fn config(&self) -> ChaosConfig {
+        self.inner.read().unwrap().clone()
+    }
+
+    pub fn set(&self, config: ChaosConfig) {
+        *self.inner.write().unwrap() = config;
+    }
+
+    pub fn should_drop(&self) -> bool {
+        let percent = self.inner.read().unwrap().drop_percent;
+        percent > 0.0 && rand::thread_rng().gen_range(0.0..100.0) < percent
+    }
+
+    fn delay(&self) -> Duration {
+        Duration::from_millis(self.inner.read().unwrap().delay_ms)
+    }
+
+    pub fn is_partitioned(&self, local: &PeerId, remote: &PeerId) -> bool {
+        let config = self.inner.read().unwrap();
+        match (config.group_of(local), config.group_of(remote)) {
+            (Some(a), Some(b)) => a != b,
+            _ => false,
+        }
+    }
+}
+
+/// Wraps a connection's muxer to delay writes and to fail the connection while `peer` is
+/// partitioned away from `local`.
+pub struct ChaosMuxer<M> {
+    inner: M,
+    local: PeerId,
+    peer: PeerId,
+    chaos: Chaos,
+}
+
+impl<M> ChaosMuxer<M> {
+    pub fn new(inner: M, local: PeerId, peer: PeerId, chaos: Chaos) -> Self {
+        Self { inner, local, peer, chaos }
+    }
+
+    fn check_partition(&self) -> io::Result<()> {
+        if self.chaos.is_partitioned(&self.local, &self.peer) {
+            return Err(io::Error::new(io::ErrorKind::ConnectionRefused, "peer is partitioned by chaos config"));
+        }
+        Ok(())
+    }
+
+    fn wrap<S>(&self, inner: S) -> ChaosStream<S> {
+        ChaosStream { inner, chaos: self.chaos.clone(), delay: None, delayed: false }
+    }
+}
+
+impl<M> StreamMuxer for ChaosMuxer<M>
+where
+    M: StreamMuxer + Unpin,
+    M::Substream: Unpin,
+    M::Error: std::error::Error + Send + Sync + 'static,
+{
+    type Substream = ChaosStream<M::Substream>;
+    type Error = io::Error;
+
+    fn poll_inbound(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<Self::Substream, Self::Error>> {
+        self.check_partition()?;
+        let inner = futures::ready!(Pin::new(&mut self.inner).poll_inbound(cx)).map_err(io::Error::other)?;
+        Poll::Ready(Ok(self.wrap(inner)))
+    }
+
+    fn poll_outbound(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<Self::Substream, Self::Error>> {
+        self.check_partition()?;
+        let inner = futures::ready!(Pin::new(&mut self.inner).poll_outbound(cx)).map_err(io::Error::other)?;
+        Poll::Ready(Ok(self.wrap(inner)))
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.inner).poll_close(cx).map_err(io::Error::other)
+    }
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<StreamMuxerEvent, Self::Error>> {
+        self.check_partition()?;
+        Pin::new(&mut self.inner).poll(cx).map_err(io::Error::other)
+    }
+}
+
+pub struct ChaosStream<S> {
+    inner: S,
+    chaos: Chaos,
+    delay: Option<Pin<Box<tokio::time::Sleep>>>,
+    /// The configured delay already elapsed for the write in progress.
+    delayed: bool,
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for ChaosStream<S> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for ChaosStream<S> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        if !buf.is_empty() && !self.delayed {
+            let wait = self.chaos.delay();
+            if !wait.is_zero() {
+                let delay = self.delay.get_or_insert_with(|| Box::pin(tokio::time::sleep(wait)));
+                futures::ready!(delay.as_mut().poll(cx));
+                self.delay = None;
+            }
+            self.delayed = true;
+        }
+        let n = futures::ready!(Pin::new(&mut self.inner).poll_write(cx, buf))?;
+        self.delayed = false;
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+}
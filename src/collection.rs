@@ -0,0 +1,391 @@
+use crate::counter;
+use crate::document::{DocOp, Document};
+use crate::hlc::{self, Hlc};
+use crate::kv::{KvOp, Register, RegisterMap};
+use crate::replica::{self, Gap, Replica, ReplicaMessage};
+use crate::retention::CollectionRetention;
+use anyhow::{anyhow, Result};
+use crdts::{orswot, pncounter, CmRDT, CvRDT, GSet, LWWReg, Orswot, PNCounter};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use tracing::error;
+
+/// CRDT type of a named collection.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum CollectionKind {
+    /// Grow-only set of strings.
+    Set,
+    /// Observed-remove set of strings: a remove only cancels the adds it saw.
+    OrSet,
+    /// Last-writer-wins registers keyed by string.
+    Map,
+    /// Counter that can go up and down.
+    Counter,
+    /// A single last-writer-wins JSON value.
+    Register,
+    /// JSON object whose fields, at any depth, are edited concurrently.
+    Document,
+}
+
+impl CollectionKind {
+    pub fn parse(s: &str) -> Option<Self> {
+        serde_json::from_value(Value::String(s.to_string())).ok()
+    }
+
+    /// Whether collections of this kind take size limits, see `CollectionRetention`.
+    pub fn has_retention(&self) -> bool {
+        !matches!(self, CollectionKind::Counter | CollectionKind::Register)
+    }
+}
+
+/// A local change to a collection, as requested through the CLI or HTTP.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum CollectionUpdate {
+    /// Adds a member to a set or OR-set.
+    Add { member: String },
+    /// Removes a member from an OR-set.
+    Remove { member: String },
+    /// Sets a key of a map.
+    Put { key: String, value: Value },
+    /// Deletes a key of a map.
+    Delete { key: String },
+    /// Adds `by` (negative to decrement) to a counter.
+    Increment { by: i64 },
+    /// Sets a register.
+    Set { value: Value },
+    /// Applies a JSON Patch (RFC 6902) to a document.
+    JsonPatch { patch: Value },
+    /// Applies a JSON Merge Patch (RFC 7396) to a document.
+    MergePatch { patch: Value },
+}
+
+impl CollectionUpdate {
+    /// Whether this update applies to a collection of `kind`.
+    pub fn fits(&self, kind: CollectionKind) -> bool {
+        matches!(
+            (self, kind),
+            (CollectionUpdate::Add { .. }, CollectionKind::Set | CollectionKind::OrSet)
+                | (CollectionUpdate::Remove { .. }, CollectionKind::OrSet)
+                | (CollectionUpdate::Put { .. } | CollectionUpdate::Delete { .. }, CollectionKind::Map)
+                | (CollectionUpdate::Increment { .. }, CollectionKind::Counter)
+                | (CollectionUpdate::Set { .. }, CollectionKind::Register)
+                | (CollectionUpdate::JsonPatch { .. } | CollectionUpdate::MergePatch { .. }, CollectionKind::Document)
+        )
+    }
+}
+
+/// Operation on a collection, as published on its topic.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "kind", content = "op", rename_all = "kebab-case")]
+pub enum CollectionOp {
+    Set(String),
+    OrSet(orswot::Op<String, String>),
+    Map(KvOp),
+    Counter(pncounter::Op<String>),
+    Register(Register),
+    /// The writes of one patch.
+    Document(Vec<DocOp>),
+}
+
+/// Messages on a collection's topic.
+pub type CollectionMessage = ReplicaMessage<Collection>;
+
+/// A named collection's state: one CRDT with actors identified by peer ID.
+///
+/// Collections are opened per node: a node replicates the collections it created, on
+/// topic `ghostmesh-col-<name>`, with every other node that created one of the same name.
+/// Ops and states of another kind than the local collection's are ignored.
+///
+/// Like the KV store, OR-set, map and document ops must be applied in causal order, and
+/// an op after a gap is refused so the caller fetches the full state instead.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(tag = "kind", content = "state", rename_all = "kebab-case")]
+pub enum Collection {
+    Set(GSet<String>),
+    OrSet(Orswot<String, String>),
+    Map(RegisterMap),
+    Counter(PNCounter<String>),
+    Register(Register),
+    Document(Document),
+}
+
+/// Required by `Replica`; collections are always created with a kind.
+impl Default for Collection {
+    fn default() -> Self {
+        Collection::Set(GSet::new())
+    }
+}
+
+impl Collection {
+    pub fn new(kind: CollectionKind) -> Self {
+        match kind {
+            CollectionKind::Set => Collection::Set(GSet::new()),
+            CollectionKind::OrSet => Collection::OrSet(Orswot::new()),
+            CollectionKind::Map => Collection::Map(RegisterMap::new()),
+            CollectionKind::Counter => Collection::Counter(PNCounter::new()),
+            CollectionKind::Register => Collection::Register(Register::default()),
+            CollectionKind::Document => Collection::Document(Document::new()),
+        }
+    }
+
+    pub fn kind(&self) -> CollectionKind {
+        match self {
+            Collection::Set(_) => CollectionKind::Set,
+            Collection::OrSet(_) => CollectionKind::OrSet,
+            Collection::Map(_) => CollectionKind::Map,
+            Collection::Counter(_) => CollectionKind::Counter,
+            Collection::Register(_) => CollectionKind::Register,
+            Collection::Document(_) => CollectionKind::Document,
+        }
+    }
+
+    /// Current value as JSON: sorted members for sets, an object for maps, a number for
+    /// counters (saturating at the bounds of `i64`), the value of a register and the
+    /// object of a document.
+    pub fn value(&self) -> Value {
+        match self {
+            Collection::Set(set) => json!(set.read()),
+            Collection::OrSet(set) => {
+                let mut members: Vec<String> = set.read().val.into_iter().collect();
+                members.sort();
+                json!(members)
+            }
+            Collection::Map(map) => json!(map.values()),
+            Collection::Counter(counter) => json!(counter::value_of(counter)),
+            Collection::Register(register) => register.0.val.clone(),
+            Collection::Document(document) => document.value(),
+        }
+    }
+
+    /// Applies a local update, returning the op to publish, or `None` when nothing
+    /// changed. Fails when the update is for another kind of collection.
+    pub fn update(&mut self, actor: &str, update: CollectionUpdate, at: Hlc) -> Result<Option<CollectionOp>> {
+        let kind = self.kind();
+        let actor = actor.to_string();
+        let op = match (self, update) {
+            (Collection::Set(set), CollectionUpdate::Add { member }) => {
+                if set.contains(&member) {
+                    return Ok(None);
+                }
+                set.insert(member.clone());
+                CollectionOp::Set(member)
+            }
+            (Collection::OrSet(set), CollectionUpdate::Add { member }) => {
+                let op = set.add(member, set.read_ctx().derive_add_ctx(actor));
+                set.apply(op.clone());
+                CollectionOp::OrSet(op)
+            }
+            (Collection::OrSet(set), CollectionUpdate::Remove { member }) => {
+                let read = set.contains(&member);
+                if !read.val {
+                    return Ok(None);
+                }
+                let op = set.rm(member, read.derive_rm_ctx());
+                set.apply(op.clone());
+                CollectionOp::OrSet(op)
+            }
+            (Collection::Map(map), CollectionUpdate::Put { key, value }) => CollectionOp::Map(map.put(&actor, &key, value, at)),
+            (Collection::Map(map), CollectionUpdate::Delete { key }) => match map.delete(&key) {
+                Some(op) => CollectionOp::Map(op),
+                None => return Ok(None),
+            },
+            (Collection::Counter(counter), CollectionUpdate::Increment { by }) => {
+                let op = match by {
+                    0 => return Ok(None),
+                    by if by > 0 => counter.inc_many(actor, by.unsigned_abs()),
+                    by => counter.dec_many(actor, by.unsigned_abs()),
+                };
+                counter.apply(op.clone());
+                CollectionOp::Counter(op)
+            }
+            (Collection::Register(register), CollectionUpdate::Set { value }) => {
+                let op = Register(LWWReg { val: value, marker: (at, actor) });
+                register.apply(op.clone());
+                CollectionOp::Register(op)
+            }
+            (Collection::Document(document), CollectionUpdate::JsonPatch { patch }) => {
+                CollectionOp::Document(document.json_patch(&actor, &patch, at)?)
+            }
+            (Collection::Document(document), CollectionUpdate::MergePatch { patch }) => {
+                CollectionOp::Document(document.merge_patch(&actor, &patch, at)?)
+            }
+            _ => return Err(anyhow!("update does not apply to a {:?} collection", kind)),
+        };
+        Ok(Some(op))
+    }
+
+    /// Checks a local update against `retention`: adding a new member to a full set or
+    /// OR-set is refused. Other updates are let through, and `trim` brings maps and
+    /// documents back within the limits afterwards.
+    pub fn admits(&self, update: &CollectionUpdate, retention: &CollectionRetention) -> Result<()> {
+        let CollectionUpdate::Add { member } = update else {
+            return Ok(());
+        };
+        let members = match self {
+            Collection::Set(set) if !set.contains(member) => set.read().len(),
+            Collection::OrSet(set) if !set.contains(member).val => set.read().val.len(),
+            _ => return Ok(()),
+        };
+        // The member's JSON string, and a comma unless the set was empty
+        let bytes = entry_bytes(&self.value()) + entry_bytes(&json!(member)) + usize::from(members > 0);
+        if !retention.allows(members + 1, bytes) {
+            return Err(anyhow!("collection is full: {:?}", retention));
+        }
+        Ok(())
+    }
+
+    /// Brings a map or document within `retention` by deleting its least recently written
+    /// keys or top-level fields, returning the ops to publish. Ops of a document are stamped
+    /// from `at` on like those of `Document::json_patch`.
+    pub fn trim(&mut self, actor: &str, retention: &CollectionRetention, at: Hlc) -> Result<Vec<CollectionOp>> {
+        let value = self.value();
+        let Value::Object(entries) = &value else {
+            return Ok(Vec::new());
+        };
+        let oldest = match self {
+            Collection::Map(map) => map.keys_by_age(),
+            Collection::Document(document) => document.fields_by_age(),
+            _ => return Ok(Vec::new()),
+        };
+        let (mut count, mut bytes) = (entries.len(), entry_bytes(&value));
+        let mut dropped = Vec::new();
+        for key in oldest {
+            if retention.allows(count, bytes) {
+                break;
+            }
+            // `"key":value`, and a comma unless it is the last one
+            let entry = entry_bytes(&json!(key)) + 1 + entries.get(&key).map_or(0, entry_bytes) + usize::from(count > 1);
+            count -= 1;
+            bytes = bytes.saturating_sub(entry);
+            dropped.push(key);
+        }
+        if dropped.is_empty() {
+            return Ok(Vec::new());
+        }
+        Ok(match self {
+            Collection::Map(map) => dropped.iter().filter_map(|key| map.delete(key)).map(CollectionOp::Map).collect(),
+            Collection::Document(document) => {
+                let patch: Vec<Value> = dropped
+                    .iter()
+                    .map(|name| json!({"op": "remove", "path": format!("/{}", name.replace('~', "~0").replace('/', "~1"))}))
+                    .collect();
+                vec![CollectionOp::Document(document.json_patch(actor, &Value::Array(patch), at)?)]
+            }
+            _ => Vec::new(),
+        })
+    }
+
+    /// Applies a remote op, returning whether the value changed.
+    pub fn apply(&mut self, op: CollectionOp) -> Result<bool, Gap> {
+        let before = self.value();
+        match (&mut *self, op) {
+            (Collection::Set(set), CollectionOp::Set(member)) => set.insert(member),
+            (Collection::OrSet(set), CollectionOp::OrSet(op)) => {
+                let seen = set.clock();
+                let in_order = match &op {
+                    orswot::Op::Add { dot, .. } => dot.counter <= seen.get(&dot.actor) + 1,
+                    orswot::Op::Rm { clock, .. } => clock <= &seen,
+                };
+                if !in_order {
+                    return Err(Gap);
+                }
+                set.apply(op);
+            }
+            (Collection::Map(map), CollectionOp::Map(op)) => {
+                map.apply(op)?;
+            }
+            (Collection::Counter(counter), CollectionOp::Counter(op)) => counter.apply(op),
+            // Like log entries, writes stamped too far in the future are dropped
+            (Collection::Register(_), CollectionOp::Register(op)) if hlc::beyond_drift(op.0.marker.0) => {
+                error!("Rejected register write of {}: stamped {:?}, too far in the future", op.0.marker.1, op.0.marker.0);
+            }
+            (Collection::Register(register), CollectionOp::Register(op)) => register.apply(op),
+            (Collection::Document(document), CollectionOp::Document(ops)) => {
+                for op in ops {
+                    document.apply(op)?;
+                }
+            }
+            _ => {}
+        }
+        Ok(self.value() != before)
+    }
+
+    /// Merges another replica of the collection, returning whether the value changed.
+    pub fn merge(&mut self, other: Collection) -> bool {
+        let before = self.value();
+        match (&mut *self, other) {
+            (Collection::Set(set), Collection::Set(other)) => set.merge(other),
+            (Collection::OrSet(set), Collection::OrSet(other)) => set.merge(other),
+            (Collection::Map(map), Collection::Map(other)) => {
+                map.merge(other);
+            }
+            (Collection::Counter(counter), Collection::Counter(other)) => counter.merge(other),
+            (Collection::Register(_), Collection::Register(other)) if hlc::beyond_drift(other.0.marker.0) => {
+                error!("Rejected register written by {}: stamped {:?}, too far in the future", other.0.marker.1, other.0.marker.0);
+            }
+            (Collection::Register(register), Collection::Register(other)) => register.merge(other),
+            (Collection::Document(document), Collection::Document(other)) => {
+                document.merge(other);
+            }
+            _ => {}
+        }
+        self.value() != before
+    }
+
+    /// Highest HLC timestamp written to a map, register or document, to advance the local clock past.
+    pub fn latest(&self) -> Option<Hlc> {
+        match self {
+            Collection::Map(map) => map.latest(),
+            Collection::Register(register) => Some(register.0.marker.0),
+            Collection::Document(document) => document.latest(),
+            _ => None,
+        }
+    }
+
+    /// SHA-256 over the kind and state. Equal digests mean equal collections.
+    pub fn digest(&self) -> String {
+        match self {
+            // Members are kept in a hash map, so hash them in order
+            Collection::OrSet(set) => {
+                let entries: BTreeMap<&String, _> = set.iter().map(|ctx| (ctx.val, ctx.rm_clock)).collect();
+                replica::hash_json(&(self.kind(), set.clock(), entries))
+            }
+            Collection::Map(map) => replica::hash_json(&(self.kind(), map.digest())),
+            _ => replica::hash_json(self),
+        }
+    }
+}
+
+impl Replica for Collection {
+    type Op = CollectionOp;
+    /// The collection's new value.
+    type Change = Value;
+
+    fn apply(&mut self, op: CollectionOp) -> Result<Vec<Value>, Gap> {
+        Ok(Collection::apply(self, op)?.then(|| self.value()).into_iter().collect())
+    }
+
+    fn merge(&mut self, other: Collection) -> Vec<Value> {
+        Collection::merge(self, other).then(|| self.value()).into_iter().collect()
+    }
+
+    fn digest(&self) -> String {
+        Collection::digest(self)
+    }
+}
+
+/// Size of a value's JSON encoding.
+fn entry_bytes(value: &Value) -> usize {
+    serde_json::to_vec(value).map_or(0, |json| json.len())
+}
+
+/// Checks a collection name, which is also a file name: 1 to 64 ASCII letters, digits,
+/// `-`, `_` or `.`, not starting with `.`.
+pub fn valid_name(name: &str) -> bool {
+    (1..=64).contains(&name.len())
+        && !name.starts_with('.')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+}
@@ -0,0 +1,121 @@
+use crate::replica::{self, Gap, Replica, ReplicaMessage};
+use crdts::pncounter::{self, PNCounter};
+use crdts::{CmRDT, CvRDT};
+use num_bigint::{BigInt, Sign};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Operation on one named counter, as published on the counters topic.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct CounterOp {
+    pub name: String,
+    pub op: pncounter::Op<String>,
+}
+
+/// Messages on the counters topic.
+pub type CounterMessage = ReplicaMessage<Counters>;
+
+/// A counter whose value changed.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CounterChange {
+    pub name: String,
+    pub value: i64,
+}
+
+/// Replicated named counters: `crdts::PNCounter`s with actors identified by peer ID.
+///
+/// An op carries the writer's running total in one direction rather than a step, so
+/// ops may arrive twice or out of order and never leave a gap. A counter exists once
+/// it was first incremented or decremented.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct Counters {
+    counters: BTreeMap<String, PNCounter<String>>,
+}
+
+/// Reads a counter, saturating at the bounds of `i64`.
+pub fn value_of(counter: &PNCounter<String>) -> i64 {
+    let value: BigInt = counter.read();
+    i64::try_from(&value).unwrap_or(if value.sign() == Sign::Minus { i64::MIN } else { i64::MAX })
+}
+
+impl Counters {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self, name: &str) -> Option<i64> {
+        self.counters.get(name).map(value_of)
+    }
+
+    /// Every counter with its value.
+    pub fn all(&self) -> BTreeMap<String, i64> {
+        self.counters.iter().map(|(name, counter)| (name.clone(), value_of(counter))).collect()
+    }
+
+    /// Adds `amount` (negative to decrement) to a counter, returning the op to publish,
+    /// or `None` when `amount` is zero.
+    pub fn add(&mut self, actor: &str, name: &str, amount: i64) -> Option<CounterOp> {
+        if amount == 0 {
+            return None;
+        }
+        let counter = self.counters.entry(name.to_string()).or_default();
+        let steps = amount.unsigned_abs();
+        let op = if amount > 0 {
+            counter.inc_many(actor.to_string(), steps)
+        } else {
+            counter.dec_many(actor.to_string(), steps)
+        };
+        counter.apply(op.clone());
+        Some(CounterOp { name: name.to_string(), op })
+    }
+
+    /// Applies a remote op, returning the counter if its value changed.
+    pub fn apply(&mut self, op: CounterOp) -> Vec<CounterChange> {
+        let counter = self.counters.entry(op.name.clone()).or_default();
+        let before = value_of(counter);
+        counter.apply(op.op);
+        let value = value_of(counter);
+        if value == before {
+            return Vec::new();
+        }
+        vec![CounterChange { name: op.name, value }]
+    }
+
+    /// Merges another replica's counters, returning those whose value changed.
+    pub fn merge(&mut self, other: Counters) -> Vec<CounterChange> {
+        let mut changes = Vec::new();
+        for (name, theirs) in other.counters {
+            let counter = self.counters.entry(name.clone()).or_default();
+            let before = value_of(counter);
+            counter.merge(theirs);
+            let value = value_of(counter);
+            if value != before {
+                changes.push(CounterChange { name, value });
+            }
+        }
+        changes
+    }
+
+    /// SHA-256 over the counters and their per-peer totals. Equal digests mean equal
+    /// counters.
+    pub fn digest(&self) -> String {
+        replica::hash_json(&self.counters)
+    }
+}
+
+impl Replica for Counters {
+    type Op = CounterOp;
+    type Change = CounterChange;
+
+    fn apply(&mut self, op: CounterOp) -> Result<Vec<CounterChange>, Gap> {
+        Ok(Counters::apply(self, op))
+    }
+
+    fn merge(&mut self, other: Counters) -> Vec<CounterChange> {
+        Counters::merge(self, other)
+    }
+
+    fn digest(&self) -> String {
+        Counters::digest(self)
+    }
+}
@@ -0,0 +1,443 @@
+use crate::hlc::{self, Hlc};
+use crate::kv::Marker;
+use crate::replica::Gap;
+use anyhow::{anyhow, Result};
+use crdts::identifier::Identifier;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+use tracing::error;
+
+/// Position of a list element: a dense identifier, so an element can always be inserted
+/// between two others, ending with the marker of the insert to keep it unique.
+pub type Position = Identifier<Marker>;
+
+/// Where a field sits in its parent: a key of an object, or a position in a list.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Key {
+    Field(String),
+    Item(Position),
+}
+
+/// A slot of the document holding a value, written last at `marker`.
+///
+/// The marker is also the slot's identity: edits inside an object or list keep its
+/// marker, so concurrent edits of different fields merge, while assigning the slot again
+/// replaces the whole subtree with a new marker, and the higher marker wins.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Field {
+    marker: Marker,
+    content: Content,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(tag = "type", content = "value", rename_all = "kebab-case")]
+enum Content {
+    /// A removed field, kept so the removal wins over older writes arriving later.
+    Deleted,
+    Scalar(Value),
+    Object(BTreeMap<String, Field>),
+    /// Elements sorted by position, removed ones included.
+    List(Vec<(Position, Field)>),
+}
+
+/// Containers from the root to a field, each with the marker it had when written to.
+type Parents = Vec<(Key, Marker)>;
+
+/// One write to a document: `field` is merged at `key` of the container reached through
+/// `parents`, each given with the marker it had when the write was made.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct DocOp {
+    parents: Parents,
+    key: Key,
+    field: Field,
+}
+
+impl DocOp {
+    /// Timestamp the write was stamped with.
+    pub fn marker(&self) -> Hlc {
+        self.field.marker.0
+    }
+}
+
+/// Replicated JSON document: an object whose fields, at any depth, are last-writer-wins
+/// slots ordered by HLC timestamp and peer ID, with lists of positioned elements.
+///
+/// Concurrent writes to different fields (or list elements) all survive, concurrent
+/// inserts into a list keep both elements, and concurrent writes to the same field keep
+/// the latest. Merging is deterministic, so every replica converges to the same document.
+/// Removed fields stay as tombstones.
+///
+/// Like the KV store, ops must be applied in causal order: an op inside an object or list
+/// this replica has not seen yet is refused, and the caller fetches the full state.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct Document {
+    root: BTreeMap<String, Field>,
+}
+
+/// Children of an object or list, for walking down a path.
+enum Children<'a> {
+    Object(&'a mut BTreeMap<String, Field>),
+    List(&'a mut Vec<(Position, Field)>),
+}
+
+impl<'a> Children<'a> {
+    fn of(field: &'a mut Field) -> Option<Self> {
+        match &mut field.content {
+            Content::Object(fields) => Some(Children::Object(fields)),
+            Content::List(items) => Some(Children::List(items)),
+            _ => None,
+        }
+    }
+
+    fn get(self, key: &Key) -> Option<&'a mut Field> {
+        match (self, key) {
+            (Children::Object(fields), Key::Field(name)) => fields.get_mut(name),
+            (Children::List(items), Key::Item(position)) => {
+                let i = items.binary_search_by(|(p, _)| p.cmp(position)).ok()?;
+                Some(&mut items[i].1)
+            }
+            _ => None,
+        }
+    }
+
+    /// Merges `incoming` into the slot at `key`, returning whether it changed.
+    fn merge(self, key: Key, incoming: Field) -> bool {
+        match (self, key) {
+            (Children::Object(fields), Key::Field(name)) => match fields.get_mut(&name) {
+                Some(field) => field.merge(incoming),
+                None => {
+                    fields.insert(name, incoming);
+                    true
+                }
+            },
+            (Children::List(items), Key::Item(position)) => match items.binary_search_by(|(p, _)| p.cmp(&position)) {
+                Ok(i) => items[i].1.merge(incoming),
+                Err(i) => {
+                    items.insert(i, (position, incoming));
+                    true
+                }
+            },
+            _ => false,
+        }
+    }
+
+    /// Resolves a JSON Pointer token to the key of a live child.
+    fn key_of(&self, token: &str) -> Option<Key> {
+        match self {
+            Children::Object(fields) => fields.get(token).filter(|f| f.is_live()).map(|_| Key::Field(token.to_string())),
+            Children::List(items) => {
+                let index: usize = token.parse().ok()?;
+                live(items).nth(index).map(|(position, _)| Key::Item(position.clone()))
+            }
+        }
+    }
+}
+
+fn live(items: &[(Position, Field)]) -> impl Iterator<Item = &(Position, Field)> {
+    items.iter().filter(|(_, field)| field.is_live())
+}
+
+impl Field {
+    fn new(value: Value, marker: &Marker) -> Self {
+        let content = match value {
+            Value::Object(fields) => {
+                Content::Object(fields.into_iter().map(|(name, value)| (name, Field::new(value, marker))).collect())
+            }
+            Value::Array(values) => {
+                let mut items: Vec<(Position, Field)> = Vec::new();
+                for value in values {
+                    let position = Identifier::between(items.last().map(|(p, _)| p), None, marker.clone());
+                    items.push((position, Field::new(value, marker)));
+                }
+                Content::List(items)
+            }
+            value => Content::Scalar(value),
+        };
+        Field { marker: marker.clone(), content }
+    }
+
+    fn is_live(&self) -> bool {
+        !matches!(self.content, Content::Deleted)
+    }
+
+    fn value(&self) -> Value {
+        match &self.content {
+            Content::Deleted => Value::Null,
+            Content::Scalar(value) => value.clone(),
+            Content::Object(fields) => Value::Object(object_value(fields)),
+            Content::List(items) => Value::Array(live(items).map(|(_, field)| field.value()).collect()),
+        }
+    }
+
+    /// Keeps the field with the higher marker, or merges the children of the same
+    /// object or list. Returns whether this field changed.
+    fn merge(&mut self, other: Field) -> bool {
+        if other.marker > self.marker {
+            *self = other;
+            return true;
+        }
+        if other.marker < self.marker {
+            return false;
+        }
+        match (&mut self.content, other.content) {
+            (Content::Object(fields), Content::Object(others)) => {
+                let mut changed = false;
+                for (name, field) in others {
+                    changed |= Children::Object(fields).merge(Key::Field(name), field);
+                }
+                changed
+            }
+            (Content::List(items), Content::List(others)) => {
+                let mut changed = false;
+                for (position, field) in others {
+                    changed |= Children::List(items).merge(Key::Item(position), field);
+                }
+                changed
+            }
+            _ => false,
+        }
+    }
+
+    fn latest(&self) -> Hlc {
+        let children = match &self.content {
+            Content::Object(fields) => fields.values().map(Field::latest).max(),
+            Content::List(items) => items.iter().map(|(_, field)| field.latest()).max(),
+            _ => None,
+        };
+        children.unwrap_or_default().max(self.marker.0)
+    }
+}
+
+fn object_value(fields: &BTreeMap<String, Field>) -> Map<String, Value> {
+    fields.iter().filter(|(_, field)| field.is_live()).map(|(name, field)| (name.clone(), field.value())).collect()
+}
+
+/// Splits a JSON Pointer (RFC 6901) into unescaped tokens. The root is `""`.
+fn tokens(pointer: &str) -> Result<Vec<String>> {
+    if pointer.is_empty() {
+        return Ok(Vec::new());
+    }
+    let rest = pointer.strip_prefix('/').ok_or_else(|| anyhow!("invalid JSON pointer {:?}", pointer))?;
+    Ok(rest.split('/').map(|token| token.replace("~1", "/").replace("~0", "~")).collect())
+}
+
+/// Builds local ops, each stamped with its own marker so later writes in a patch win: the
+/// first at `next`, the others a tick after the previous one.
+struct Writer<'a> {
+    actor: &'a str,
+    next: Hlc,
+    ops: Vec<DocOp>,
+}
+
+impl Writer<'_> {
+    fn next_marker(&mut self) -> Marker {
+        let marker = (self.next, self.actor.to_string());
+        self.next = hlc::tick(self.next);
+        marker
+    }
+}
+
+impl Document {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The document as a JSON object.
+    pub fn value(&self) -> Value {
+        Value::Object(object_value(&self.root))
+    }
+
+    /// Looks up a JSON Pointer.
+    pub fn get(&self, pointer: &str) -> Option<Value> {
+        self.get_tokens(&tokens(pointer).ok()?)
+    }
+
+    /// Walks down to the container holding the last token, returning its parents with
+    /// their markers and the key the last token resolves to, `None` if it is not a live
+    /// child (or `-`, the end of a list).
+    fn locate(&mut self, tokens: &[String]) -> Result<(Parents, Children<'_>, Option<Key>)> {
+        let (last, path) = tokens.split_last().ok_or_else(|| anyhow!("the document root cannot be replaced"))?;
+        let mut parents = Vec::new();
+        let mut children = Children::Object(&mut self.root);
+        for token in path {
+            let key = children.key_of(token).ok_or_else(|| anyhow!("path not found: {}", token))?;
+            let field = children.get(&key).expect("live child");
+            parents.push((key, field.marker.clone()));
+            children = Children::of(field).ok_or_else(|| anyhow!("not an object or list: {}", token))?;
+        }
+        let key = children.key_of(last);
+        Ok((parents, children, key))
+    }
+
+    /// Writes `value` at `pointer`: adds or replaces an object field, or with `insert`
+    /// inserts a list element before the one at the index (or at the end for `-`), or
+    /// otherwise replaces it.
+    fn write(&mut self, writer: &mut Writer, pointer: &[String], value: Option<Value>, insert: bool) -> Result<()> {
+        let marker = writer.next_marker();
+        let (parents, children, key) = self.locate(pointer)?;
+        let last = pointer.last().expect("not the root");
+        let key = match (&children, key) {
+            (Children::List(items), _) if insert => {
+                let positions: Vec<&Position> = live(items).map(|(position, _)| position).collect();
+                let index = match last.as_str() {
+                    "-" => positions.len(),
+                    index => index.parse::<usize>().map_err(|_| anyhow!("invalid list index {:?}", index))?,
+                };
+                if index > positions.len() {
+                    return Err(anyhow!("list index out of bounds: {}", index));
+                }
+                let before = index.checked_sub(1).map(|i| positions[i]);
+                Key::Item(Identifier::between(before, positions.get(index).copied(), marker.clone()))
+            }
+            (_, Some(key)) => key,
+            (Children::Object(_), None) if value.is_some() => Key::Field(last.clone()),
+            _ => return Err(anyhow!("path not found: {}", last)),
+        };
+        let field = match value {
+            Some(value) => Field::new(value, &marker),
+            None => Field { marker, content: Content::Deleted },
+        };
+        children.merge(key.clone(), field.clone());
+        writer.ops.push(DocOp { parents, key, field });
+        Ok(())
+    }
+
+    /// Applies a JSON Patch (RFC 6902), all or nothing, returning the ops to publish. Ops
+    /// are stamped from `at` on, one tick apart: the clock must be moved past the last one
+    /// (see `DocOp::marker`) before stamping anything else.
+    pub fn json_patch(&mut self, actor: &str, patch: &Value, at: Hlc) -> Result<Vec<DocOp>> {
+        let steps = patch.as_array().ok_or_else(|| anyhow!("a JSON Patch is an array of operations"))?;
+        let mut doc = self.clone();
+        let mut writer = Writer { actor, next: at, ops: Vec::new() };
+        for step in steps {
+            let member = |name: &str| step.get(name).ok_or_else(|| anyhow!("missing {:?} in {}", name, step));
+            let string = |name: &str| member(name)?.as_str().ok_or_else(|| anyhow!("{:?} must be a string in {}", name, step));
+            let path = tokens(string("path")?)?;
+            match string("op")? {
+                "add" => doc.write(&mut writer, &path, Some(member("value")?.clone()), true)?,
+                "remove" => doc.write(&mut writer, &path, None, false)?,
+                "replace" => {
+                    doc.get_tokens(&path).ok_or_else(|| anyhow!("path not found: {}", string("path").unwrap_or_default()))?;
+                    doc.write(&mut writer, &path, Some(member("value")?.clone()), false)?
+                }
+                op @ ("move" | "copy") => {
+                    let from = tokens(string("from")?)?;
+                    let value = doc.get_tokens(&from).ok_or_else(|| anyhow!("path not found: {}", string("from").unwrap_or_default()))?;
+                    if op == "move" {
+                        doc.write(&mut writer, &from, None, false)?;
+                    }
+                    doc.write(&mut writer, &path, Some(value), true)?
+                }
+                "test" => {
+                    if doc.get_tokens(&path).as_ref() != Some(member("value")?) {
+                        return Err(anyhow!("test failed at {}", string("path")?));
+                    }
+                }
+                op => return Err(anyhow!("unknown JSON Patch operation {:?}", op)),
+            }
+        }
+        *self = doc;
+        Ok(writer.ops)
+    }
+
+    /// Applies a JSON Merge Patch (RFC 7396), returning the ops to publish. Objects in the
+    /// patch are merged into existing objects field by field; `null` removes a field. Ops
+    /// are stamped like those of `json_patch`.
+    pub fn merge_patch(&mut self, actor: &str, patch: &Value, at: Hlc) -> Result<Vec<DocOp>> {
+        let patch = patch.as_object().ok_or_else(|| anyhow!("documents are JSON objects"))?;
+        let mut writer = Writer { actor, next: at, ops: Vec::new() };
+        let mut doc = self.clone();
+        doc.merge_object(&mut writer, &mut Vec::new(), patch)?;
+        *self = doc;
+        Ok(writer.ops)
+    }
+
+    fn merge_object(&mut self, writer: &mut Writer, path: &mut Vec<String>, patch: &Map<String, Value>) -> Result<()> {
+        for (name, value) in patch {
+            path.push(name.clone());
+            let existing = self.get_tokens(path);
+            match (value, existing) {
+                (Value::Null, None) => {}
+                (Value::Null, Some(_)) => self.write(writer, path, None, false)?,
+                (Value::Object(fields), Some(Value::Object(_))) => self.merge_object(writer, path, fields)?,
+                // A new object is merged into `{}`, which drops the nulls it contains
+                (Value::Object(fields), _) => {
+                    self.write(writer, path, Some(Value::Object(Map::new())), false)?;
+                    self.merge_object(writer, path, fields)?
+                }
+                (value, _) => self.write(writer, path, Some(value.clone()), false)?,
+            }
+            path.pop();
+        }
+        Ok(())
+    }
+
+    fn get_tokens(&self, tokens: &[String]) -> Option<Value> {
+        let mut value = self.value();
+        for token in tokens {
+            value = match value {
+                Value::Object(mut fields) => fields.remove(token)?,
+                Value::Array(mut items) => {
+                    let index: usize = token.parse().ok()?;
+                    (index < items.len()).then(|| items.swap_remove(index))?
+                }
+                _ => return None,
+            };
+        }
+        Some(value)
+    }
+
+    /// Applies a remote op, returning whether the document changed. An op stamped beyond
+    /// `hlc::MAX_DRIFT_MS` ahead of the local clock is dropped.
+    pub fn apply(&mut self, op: DocOp) -> Result<bool, Gap> {
+        if hlc::beyond_drift(op.field.latest()) {
+            error!("Rejected document op of {}: stamped {:?}, too far in the future", op.field.marker.1, op.field.latest());
+            return Ok(false);
+        }
+        let mut children = Children::Object(&mut self.root);
+        for (key, marker) in &op.parents {
+            let field = children.get(key).ok_or(Gap)?;
+            if field.marker < *marker {
+                return Err(Gap);
+            }
+            if field.marker > *marker {
+                // Written inside a value since replaced
+                return Ok(false);
+            }
+            children = match Children::of(field) {
+                Some(children) => children,
+                None => return Ok(false),
+            };
+        }
+        Ok(children.merge(op.key, op.field))
+    }
+
+    /// Merges another replica, returning whether the document changed. A replica with a
+    /// write stamped beyond `hlc::MAX_DRIFT_MS` ahead of the local clock is dropped whole.
+    pub fn merge(&mut self, other: Document) -> bool {
+        if let Some(latest) = other.latest().filter(|latest| hlc::beyond_drift(*latest)) {
+            error!("Rejected document with a write stamped {:?}, too far in the future", latest);
+            return false;
+        }
+        let mut changed = false;
+        for (name, field) in other.root {
+            changed |= Children::Object(&mut self.root).merge(Key::Field(name), field);
+        }
+        changed
+    }
+
+    /// Highest HLC timestamp written, to advance the local clock past.
+    pub fn latest(&self) -> Option<Hlc> {
+        self.root.values().map(Field::latest).max()
+    }
+
+    /// Live top-level fields, the least recently written first (anywhere inside them).
+    pub fn fields_by_age(&self) -> Vec<String> {
+        let mut fields: Vec<(Hlc, &String)> =
+            self.root.iter().filter(|(_, field)| field.is_live()).map(|(name, field)| (field.latest(), name)).collect();
+        fields.sort();
+        fields.into_iter().map(|(_, name)| name.clone()).collect()
+    }
+}
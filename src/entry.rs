@@ -0,0 +1,291 @@
+use crate::hlc::Hlc;
+use base64::{engine::general_purpose::STANDARD as BASE64_STANDARD, Engine as _};
+use libp2p::identity::{Keypair, PublicKey};
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::cmp::Ordering;
+use std::collections::HashSet;
+use std::sync::OnceLock;
+
+/// Prefix of the ids of entries migrated from the unsigned log of earlier versions.
+pub const LEGACY_ID_PREFIX: &str = "legacy-";
+
+/// An entry of the shared log, signed by its author's identity key.
+///
+/// Entries are ordered by timestamp, then author and id, so every node lists the log in
+/// the same order. Two entries with the same text remain distinct through their ids.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct LogEntry {
+    pub id: String,
+    /// Peer ID of the author. Its Ed25519 public key is embedded in the ID.
+    pub author: String,
+    pub timestamp: Hlc,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    pub body: Value,
+    /// Base64 signature over the other fields, see `signed_bytes`.
+    pub signature: String,
+}
+
+/// The signed part of an entry. Object keys in `body` serialize sorted, so the encoding is
+/// the same on every node.
+#[derive(Serialize)]
+struct Unsigned<'a> {
+    id: &'a str,
+    author: &'a str,
+    timestamp: Hlc,
+    tags: &'a [String],
+    body: &'a Value,
+}
+
+impl LogEntry {
+    /// Creates an entry authored and signed by `keypair`.
+    pub fn new(keypair: &Keypair, timestamp: Hlc, tags: Vec<String>, body: Value) -> Self {
+        Self::with_id(keypair, uuid::Uuid::new_v4().to_string(), timestamp, tags, body)
+    }
+
+    pub fn with_id(keypair: &Keypair, id: String, timestamp: Hlc, tags: Vec<String>, body: Value) -> Self {
+        let mut entry = Self {
+            id,
+            author: keypair.public().to_peer_id().to_string(),
+            timestamp,
+            tags,
+            body,
+            signature: String::new(),
+        };
+        entry.signature = sign(keypair, &entry.signed_bytes());
+        entry
+    }
+
+    pub fn key(&self) -> EntryKey {
+        EntryKey { id: self.id.clone(), author: self.author.clone() }
+    }
+
+    /// A line of the unsigned log of earlier versions, as an entry tagged `migrated` and
+    /// signed by the migrating node.
+    ///
+    /// The id and timestamp derive from the text (stamped at time zero, before every signed
+    /// entry, as the old log kept no times), so nodes migrating the same line write entries
+    /// with the same id, which `LogSet` keeps once.
+    pub fn legacy(keypair: &Keypair, text: &str) -> Self {
+        let (id, timestamp) = legacy_identity(text);
+        Self::with_id(keypair, id, timestamp, vec!["migrated".to_string()], Value::String(text.to_string()))
+    }
+
+    /// Whether it was migrated from the unsigned log, see `legacy`.
+    pub fn is_migrated(&self) -> bool {
+        self.id.starts_with(LEGACY_ID_PREFIX)
+    }
+
+    /// A plain-text entry, as written with `/log` or the dashboard.
+    pub fn text(keypair: &Keypair, timestamp: Hlc, text: &str) -> Self {
+        Self::new(keypair, timestamp, Vec::new(), Value::String(text.to_string()))
+    }
+
+    fn signed_bytes(&self) -> Vec<u8> {
+        let unsigned = Unsigned {
+            id: &self.id,
+            author: &self.author,
+            timestamp: self.timestamp,
+            tags: &self.tags,
+            body: &self.body,
+        };
+        serde_json::to_vec(&unsigned).unwrap_or_default()
+    }
+
+    /// Checks the signature against the public key embedded in the author's peer ID. A
+    /// migrated entry must also carry the id, timestamp and tags `legacy` derives from its
+    /// text, and entries signed with the public legacy key are refused.
+    pub fn verify(&self) -> Result<(), String> {
+        if self.author == legacy_author() {
+            return Err(format!("entry {}: signed with the legacy migration key", self.id));
+        }
+        verify(&self.author, &self.signed_bytes(), &self.signature)
+            .map_err(|reason| format!("entry {}: {}", self.id, reason))?;
+        if self.is_migrated() {
+            let canonical = self.body.as_str().map(legacy_identity);
+            if canonical != Some((self.id.clone(), self.timestamp)) || self.tags != ["migrated"] {
+                return Err(format!("entry {}: not a migrated line in its canonical form", self.id));
+            }
+        }
+        Ok(())
+    }
+
+    /// The body as display text: strings as they are, anything else as JSON.
+    pub fn text_body(&self) -> String {
+        match &self.body {
+            Value::String(text) => text.clone(),
+            other => other.to_string(),
+        }
+    }
+}
+
+impl Ord for LogEntry {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.timestamp, &self.author, &self.id)
+            .cmp(&(other.timestamp, &other.author, &other.id))
+            .then_with(|| (&self.tags, &self.signature).cmp(&(&other.tags, &other.signature)))
+            .then_with(|| self.body.to_string().cmp(&other.body.to_string()))
+    }
+}
+
+impl PartialOrd for LogEntry {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Identity of a log entry. Ids are chosen by their authors, so only the id together with
+/// the author names one entry: a peer reusing another's id writes a different entry.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct EntryKey {
+    pub id: String,
+    pub author: String,
+}
+
+/// Removal of a log entry, signed by the peer that removed it. Only the entry's author and
+/// the configured log admins may remove an entry; see `permitted`.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Tombstone {
+    pub entry_id: String,
+    /// Author of the removed entry, so permission can be checked before the entry is seen.
+    pub entry_author: String,
+    pub removed_by: String,
+    pub timestamp: Hlc,
+    pub signature: String,
+}
+
+#[derive(Serialize)]
+struct UnsignedTombstone<'a> {
+    entry_id: &'a str,
+    entry_author: &'a str,
+    removed_by: &'a str,
+    timestamp: Hlc,
+}
+
+impl Tombstone {
+    pub fn new(keypair: &Keypair, entry: &LogEntry, timestamp: Hlc) -> Self {
+        let mut tombstone = Self {
+            entry_id: entry.id.clone(),
+            entry_author: entry.author.clone(),
+            removed_by: keypair.public().to_peer_id().to_string(),
+            timestamp,
+            signature: String::new(),
+        };
+        tombstone.signature = sign(keypair, &tombstone.signed_bytes());
+        tombstone
+    }
+
+    /// The entry it removes.
+    pub fn key(&self) -> EntryKey {
+        EntryKey { id: self.entry_id.clone(), author: self.entry_author.clone() }
+    }
+
+    /// Whether it removes `entry`.
+    pub fn removes(&self, entry: &LogEntry) -> bool {
+        self.entry_id == entry.id && self.entry_author == entry.author
+    }
+
+    fn signed_bytes(&self) -> Vec<u8> {
+        let unsigned = UnsignedTombstone {
+            entry_id: &self.entry_id,
+            entry_author: &self.entry_author,
+            removed_by: &self.removed_by,
+            timestamp: self.timestamp,
+        };
+        serde_json::to_vec(&unsigned).unwrap_or_default()
+    }
+
+    pub fn verify(&self) -> Result<(), String> {
+        verify(&self.removed_by, &self.signed_bytes(), &self.signature)
+            .map_err(|reason| format!("removal of {}: {}", self.entry_id, reason))
+    }
+
+    pub fn permitted(&self, admins: &HashSet<String>) -> bool {
+        may_remove(&self.removed_by, &self.entry_author, admins)
+    }
+}
+
+/// A log admin's signature of a compaction horizon. Peers adopt a signed horizon whatever
+/// their own retention policy; see `RetentionPolicy::admits`.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct HorizonSignature {
+    pub signer: String,
+    pub signature: String,
+}
+
+#[derive(Serialize)]
+struct UnsignedHorizon<'a> {
+    horizon: Hlc,
+    signer: &'a str,
+}
+
+impl HorizonSignature {
+    pub fn new(keypair: &Keypair, horizon: Hlc) -> Self {
+        let signer = keypair.public().to_peer_id().to_string();
+        let signature = sign(keypair, &Self::signed_bytes(horizon, &signer));
+        Self { signer, signature }
+    }
+
+    fn signed_bytes(horizon: Hlc, signer: &str) -> Vec<u8> {
+        serde_json::to_vec(&UnsignedHorizon { horizon, signer }).unwrap_or_default()
+    }
+
+    pub fn verify(&self, horizon: Hlc) -> Result<(), String> {
+        verify(&self.signer, &Self::signed_bytes(horizon, &self.signer), &self.signature)
+            .map_err(|reason| format!("horizon {:?}: {}", horizon, reason))
+    }
+}
+
+/// Whether `remover` may remove an entry by `author`: it wrote it, or it is a log admin.
+pub fn may_remove(remover: &str, author: &str, admins: &HashSet<String>) -> bool {
+    remover == author || admins.contains(remover)
+}
+
+/// Id and timestamp of the entry migrated from a line of the unsigned log.
+fn legacy_identity(text: &str) -> (String, Hlc) {
+    let digest = Sha256::digest(text.as_bytes());
+    let id = format!("{}{}", LEGACY_ID_PREFIX, digest[..16].iter().map(|b| format!("{:02x}", b)).collect::<String>());
+    let counter = u32::from_be_bytes([digest[16], digest[17], digest[18], digest[19]]);
+    (id, Hlc::new(0, counter))
+}
+
+/// Key that signed the migrated entries of earlier versions. Its secret derives from a
+/// fixed seed, so anyone can sign with it: entries it signed are refused, see `verify`.
+pub fn legacy_keypair() -> Keypair {
+    let seed: [u8; 32] = Sha256::digest(b"ghostmesh legacy log").into();
+    Keypair::ed25519_from_bytes(seed).expect("an Ed25519 secret key is any 32 bytes")
+}
+
+/// Peer ID of `legacy_keypair`.
+pub fn legacy_author() -> &'static str {
+    static AUTHOR: OnceLock<String> = OnceLock::new();
+    AUTHOR.get_or_init(|| legacy_keypair().public().to_peer_id().to_string())
+}
+
+fn sign(keypair: &Keypair, bytes: &[u8]) -> String {
+    // Ed25519 signing cannot fail
+    BASE64_STANDARD.encode(keypair.sign(bytes).unwrap_or_default())
+}
+
+fn verify(signer: &str, bytes: &[u8], signature: &str) -> Result<(), String> {
+    let signer: PeerId = signer.parse().map_err(|_| format!("invalid peer ID '{}'", signer))?;
+    let public_key = public_key_of(&signer).ok_or_else(|| format!("no public key in peer ID {}", signer))?;
+    let signature = BASE64_STANDARD.decode(signature).map_err(|_| "signature is not base64".to_string())?;
+    if public_key.verify(bytes, &signature) {
+        Ok(())
+    } else {
+        Err(format!("bad signature by {}", signer))
+    }
+}
+
+/// Public key of a peer whose ID inlines it (identity multihash), as for Ed25519 keys.
+pub(crate) fn public_key_of(peer: &PeerId) -> Option<PublicKey> {
+    let multihash = peer.as_ref();
+    if multihash.code() != 0 {
+        return None;
+    }
+    PublicKey::try_decode_protobuf(multihash.digest()).ok()
+}
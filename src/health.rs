@@ -0,0 +1,90 @@
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant};
+
+/// Consecutive ping failures after which a peer is considered suspect. More than one, so a
+/// single lost ping on a busy link does not move the peer to another transport.
+pub const SUSPECT_AFTER_FAILURES: u32 = 3;
+
+/// A peer with no successful ping for this long is suspect, even without explicit failures.
+pub const SUSPECT_AFTER_SILENCE: Duration = Duration::from_secs(150);
+
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub enum PeerStatus {
+    Alive,
+    Suspect,
+}
+
+/// RTT statistics and liveness for a single connected peer, fed by `ping::Event`s.
+#[derive(Clone, Serialize, Debug)]
+pub struct PeerHealth {
+    pub last_rtt_ms: Option<f64>,
+    pub min_rtt_ms: Option<f64>,
+    pub avg_rtt_ms: Option<f64>,
+    /// Smoothed RTT variation (RFC 3550 interarrival jitter estimator).
+    pub jitter_ms: f64,
+    pub samples: u64,
+    pub failures: u64,
+    pub consecutive_failures: u32,
+    pub status: PeerStatus,
+    #[serde(skip)]
+    last_success: Instant,
+}
+
+impl PeerHealth {
+    pub fn new() -> Self {
+        Self {
+            last_rtt_ms: None,
+            min_rtt_ms: None,
+            avg_rtt_ms: None,
+            jitter_ms: 0.0,
+            samples: 0,
+            failures: 0,
+            consecutive_failures: 0,
+            status: PeerStatus::Alive,
+            last_success: Instant::now(),
+        }
+    }
+
+    pub fn record_rtt(&mut self, rtt: Duration) {
+        let rtt_ms = rtt.as_secs_f64() * 1000.0;
+
+        if let Some(last) = self.last_rtt_ms {
+            let delta = (rtt_ms - last).abs();
+            self.jitter_ms += (delta - self.jitter_ms) / 16.0;
+        }
+
+        self.samples += 1;
+        let avg = self.avg_rtt_ms.unwrap_or(0.0);
+        self.avg_rtt_ms = Some(avg + (rtt_ms - avg) / self.samples as f64);
+        self.min_rtt_ms = Some(self.min_rtt_ms.map_or(rtt_ms, |m| m.min(rtt_ms)));
+        self.last_rtt_ms = Some(rtt_ms);
+
+        self.consecutive_failures = 0;
+        self.last_success = Instant::now();
+        self.status = PeerStatus::Alive;
+    }
+
+    pub fn record_failure(&mut self) {
+        self.failures += 1;
+        self.consecutive_failures += 1;
+        if self.consecutive_failures >= SUSPECT_AFTER_FAILURES {
+            self.status = PeerStatus::Suspect;
+        }
+    }
+
+    /// Re-evaluates liveness based on how long the peer has been silent.
+    /// Returns `true` if the status changed.
+    pub fn check_silence(&mut self) -> bool {
+        if self.status == PeerStatus::Alive && self.last_success.elapsed() > SUSPECT_AFTER_SILENCE {
+            self.status = PeerStatus::Suspect;
+            return true;
+        }
+        false
+    }
+}
+
+impl Default for PeerHealth {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,79 @@
+use serde::{Deserialize, Serialize};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Hybrid logical clock timestamp: wall-clock milliseconds plus a counter that orders
+/// events within the same millisecond, or stamped after a peer whose clock runs ahead.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Hlc {
+    pub wall_ms: u64,
+    pub counter: u32,
+}
+
+impl Hlc {
+    pub fn new(wall_ms: u64, counter: u32) -> Self {
+        Self { wall_ms, counter }
+    }
+}
+
+/// How far ahead of the local wall clock a timestamp from a peer may be, for clock skew.
+pub const MAX_DRIFT_MS: u64 = 60_000;
+
+fn wall_clock_ms() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0)
+}
+
+/// Whether `timestamp` is more than `MAX_DRIFT_MS` ahead of the local wall clock.
+pub fn beyond_drift(timestamp: Hlc) -> bool {
+    timestamp.wall_ms > wall_clock_ms().saturating_add(MAX_DRIFT_MS)
+}
+
+/// The timestamp right after `timestamp`: the next counter, or the next millisecond once
+/// the counter is exhausted.
+pub fn tick(timestamp: Hlc) -> Hlc {
+    match timestamp.counter.checked_add(1) {
+        Some(counter) => Hlc::new(timestamp.wall_ms, counter),
+        None => Hlc::new(timestamp.wall_ms.saturating_add(1), 0),
+    }
+}
+
+/// Issues timestamps that never go backwards and that follow every timestamp observed
+/// from peers, so an entry is always stamped after the entries its author had seen.
+#[derive(Default, Debug)]
+pub struct HybridClock {
+    last: Hlc,
+}
+
+impl HybridClock {
+    /// Timestamp for a local event.
+    pub fn now(&mut self) -> Hlc {
+        let wall = wall_clock_ms();
+        self.last = if wall > self.last.wall_ms {
+            Hlc::new(wall, 0)
+        } else {
+            tick(self.last)
+        };
+        self.last
+    }
+
+    /// Advances the clock past a timestamp received from a peer. A timestamp beyond
+    /// `MAX_DRIFT_MS` of the wall clock is ignored, so one peer cannot drag every clock
+    /// into the future; returns whether it was observed.
+    pub fn observe(&mut self, remote: Hlc) -> bool {
+        let wall = wall_clock_ms();
+        if remote.wall_ms > wall.saturating_add(MAX_DRIFT_MS) {
+            return false;
+        }
+        let last = self.last;
+        let wall_ms = wall.max(last.wall_ms).max(remote.wall_ms);
+        self.last = if wall_ms == last.wall_ms && wall_ms == remote.wall_ms {
+            tick(last.max(remote))
+        } else if wall_ms == last.wall_ms {
+            tick(last)
+        } else if wall_ms == remote.wall_ms {
+            tick(remote)
+        } else {
+            Hlc::new(wall_ms, 0)
+        };
+        true
+    }
+}
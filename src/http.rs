@@ -1,42 +1,586 @@
 use warp::Filter;
 use crate::state::AppState;
 use crate::p2p::NodeCommand;
+use crate::chaos::ChaosConfig;
+use crate::retention::{CollectionRetention, RetentionPolicy};
+use crate::snapshot::Snapshot;
+use crate::query::LogQuery;
+use crate::entry::LogEntry;
+use crate::kv;
+use crate::collection::{self, Collection, CollectionKind, CollectionUpdate};
+use crate::hlc::Hlc;
 use tokio::sync::mpsc;
 use std::net::SocketAddr;
 use warp::ws::{Message, WebSocket};
 use futures::{StreamExt, SinkExt};
+use serde::Deserialize;
+
+/// Body of a JSON `POST /api/log`.
+#[derive(Deserialize)]
+struct LogRequest {
+    body: serde_json::Value,
+    #[serde(default)]
+    tags: Vec<String>,
+}
 
 pub async fn start_server(
     port: u16, 
     state: AppState, 
-    log_tx: mpsc::UnboundedSender<NodeCommand>
+    log_tx: mpsc::UnboundedSender<NodeCommand>,
+    shutdown: impl std::future::Future<Output = ()> + Send + 'static,
 ) {
     let state_filter = warp::any().map(move || state.clone());
     let log_tx_filter = warp::any().map(move || log_tx.clone());
 
-    // GET /api/state
+    // GET /api/state?log=false
+    // `log=false` leaves out the log, which `GET /api/log` reads in pages
+    #[derive(serde::Deserialize)]
+    struct StateQuery {
+        log: Option<bool>,
+    }
+
     let state_route = warp::path!("api" / "state")
+        .and(warp::get())
+        .and(warp::query::<StateQuery>())
+        .and(state_filter.clone())
+        .map(|query: StateQuery, state: AppState| {
+            warp::reply::json(&state.snapshot_with(query.log.unwrap_or(true)))
+        });
+
+    // GET /api/peers
+    // Connected peers with their replication lag, the most behind first
+    let peers_route = warp::path!("api" / "peers")
+        .and(warp::get())
+        .and(state_filter.clone())
+        .map(|state: AppState| {
+            warp::reply::json(&state.peer_report())
+        });
+
+    // GET /api/versions
+    let versions_route = warp::path!("api" / "versions")
+        .and(warp::get())
+        .and(state_filter.clone())
+        .map(|state: AppState| {
+            warp::reply::json(&state.version_report())
+        });
+
+    // GET /api/transports
+    let transports_route = warp::path!("api" / "transports")
+        .and(warp::get())
+        .and(state_filter.clone())
+        .map(|state: AppState| {
+            warp::reply::json(&state.transport_report())
+        });
+
+    // GET /api/bandwidth
+    let bandwidth_route = warp::path!("api" / "bandwidth")
+        .and(warp::get())
+        .and(state_filter.clone())
+        .map(|state: AppState| {
+            warp::reply::json(&state.bandwidth.report())
+        });
+
+    // PUT /api/bandwidth/caps/:peer_id (body: bytes per second), DELETE to remove the cap
+    let bandwidth_cap_route = warp::path!("api" / "bandwidth" / "caps" / String)
+        .and(warp::put().map(|| true).or(warp::delete().map(|| false)).unify())
+        .and(warp::body::content_length_limit(64))
+        .and(warp::body::bytes())
+        .and(state_filter.clone())
+        .map(|peer: String, set: bool, bytes: bytes::Bytes, state: AppState| {
+            let Ok(peer_id) = peer.parse::<libp2p::PeerId>() else {
+                return warp::reply::with_status("Invalid Peer ID", warp::http::StatusCode::BAD_REQUEST);
+            };
+            let cap = if set {
+                match String::from_utf8_lossy(&bytes).trim().parse::<u64>() {
+                    Ok(cap) => Some(cap),
+                    Err(_) => return warp::reply::with_status("Invalid cap", warp::http::StatusCode::BAD_REQUEST),
+                }
+            } else {
+                None
+            };
+            state.bandwidth.set_cap(peer_id, cap);
+            warp::reply::with_status("Updated", warp::http::StatusCode::OK)
+        });
+
+    // GET /api/chaos
+    let chaos_route = warp::path!("api" / "chaos")
+        .and(warp::get())
+        .and(state_filter.clone())
+        .map(|state: AppState| {
+            warp::reply::json(&state.chaos.config())
+        });
+
+    // PUT /api/chaos (body: ChaosConfig JSON)
+    let chaos_set_route = warp::path!("api" / "chaos")
+        .and(warp::put())
+        .and(warp::body::content_length_limit(1024 * 64))
+        .and(warp::body::json())
+        .and(log_tx_filter.clone())
+        .map(|config: ChaosConfig, tx: mpsc::UnboundedSender<NodeCommand>| {
+            if let Err(e) = config.validate() {
+                return warp::reply::with_status(e, warp::http::StatusCode::BAD_REQUEST);
+            }
+            if let Err(e) = tx.send(NodeCommand::SetChaos(config)) {
+                eprintln!("Failed to send chaos config to P2P loop: {}", e);
+                return warp::reply::with_status("Internal Error".to_string(), warp::http::StatusCode::INTERNAL_SERVER_ERROR);
+            }
+            warp::reply::with_status("Updated".to_string(), warp::http::StatusCode::OK)
+        });
+
+    // GET /api/retention
+    let retention_route = warp::path!("api" / "retention")
+        .and(warp::get())
+        .and(state_filter.clone())
+        .map(|state: AppState| {
+            let policy = *state.retention.read().unwrap();
+            let horizon = state.log.read().unwrap().horizon();
+            warp::reply::json(&serde_json::json!({ "policy": policy, "horizon": horizon }))
+        });
+
+    // PUT /api/retention (body: RetentionPolicy JSON)
+    let retention_set_route = warp::path!("api" / "retention")
+        .and(warp::put())
+        .and(warp::body::content_length_limit(1024 * 16))
+        .and(warp::body::json())
+        .and(log_tx_filter.clone())
+        .map(|policy: RetentionPolicy, tx: mpsc::UnboundedSender<NodeCommand>| {
+            if let Err(e) = policy.validate() {
+                return warp::reply::with_status(e, warp::http::StatusCode::BAD_REQUEST);
+            }
+            if let Err(e) = tx.send(NodeCommand::SetRetention(policy)) {
+                eprintln!("Failed to send retention policy to P2P loop: {}", e);
+                return warp::reply::with_status("Internal Error".to_string(), warp::http::StatusCode::INTERNAL_SERVER_ERROR);
+            }
+            warp::reply::with_status("Updated".to_string(), warp::http::StatusCode::OK)
+        });
+
+    // GET /api/snapshot
+    // The node's full state as a snapshot archive, see `snapshot::Snapshot`
+    let snapshot_get_route = warp::path!("api" / "snapshot")
         .and(warp::get())
         .and(state_filter.clone())
         .map(|state: AppState| {
-            warp::reply::json(&state.snapshot())
+            let disposition = format!("attachment; filename=\"ghostmesh-{}.snapshot.json\"", state.namespace.name);
+            let reply = match Snapshot::capture(&state).encode() {
+                Ok(bytes) => warp::reply::with_status(bytes, warp::http::StatusCode::OK),
+                Err(e) => warp::reply::with_status(e.to_string().into_bytes(), warp::http::StatusCode::INTERNAL_SERVER_ERROR),
+            };
+            let reply = warp::reply::with_header(reply, "Content-Type", "application/json");
+            warp::reply::with_header(reply, "Content-Disposition", disposition)
+        });
+
+    // POST /api/snapshot (body: snapshot archive)
+    // Checked here, then merged into the local state by the P2P loop
+    let snapshot_post_route = warp::path!("api" / "snapshot")
+        .and(warp::post())
+        .and(warp::body::content_length_limit(1024 * 1024 * 64))
+        .and(warp::body::bytes())
+        .and(state_filter.clone())
+        .and(log_tx_filter.clone())
+        .map(|bytes: bytes::Bytes, state: AppState, tx: mpsc::UnboundedSender<NodeCommand>| {
+            let snapshot = match Snapshot::decode_for(&bytes, &state.namespace.name) {
+                Ok(snapshot) => snapshot,
+                Err(e) => return warp::reply::with_status(e.to_string(), warp::http::StatusCode::BAD_REQUEST),
+            };
+            if let Err(e) = tx.send(NodeCommand::ImportSnapshot(Box::new(snapshot))) {
+                eprintln!("Failed to send snapshot to P2P loop: {}", e);
+                return warp::reply::with_status("Internal Error".to_string(), warp::http::StatusCode::INTERNAL_SERVER_ERROR);
+            }
+            warp::reply::with_status("Importing".to_string(), warp::http::StatusCode::ACCEPTED)
+        });
+
+    // POST /api/chaos/reset and /api/chaos/reset/:peer_id
+    let chaos_reset_route = warp::path!("api" / "chaos" / "reset")
+        .map(|| None)
+        .or(warp::path!("api" / "chaos" / "reset" / String).map(Some))
+        .unify()
+        .and(warp::post())
+        .and(log_tx_filter.clone())
+        .map(|peer: Option<String>, tx: mpsc::UnboundedSender<NodeCommand>| {
+            let peer = match peer.map(|p| p.parse::<libp2p::PeerId>()) {
+                Some(Ok(peer_id)) => Some(peer_id),
+                Some(Err(_)) => return warp::reply::with_status("Invalid Peer ID", warp::http::StatusCode::BAD_REQUEST),
+                None => None,
+            };
+            if let Err(e) = tx.send(NodeCommand::ResetConnections(peer)) {
+                eprintln!("Failed to send reset to P2P loop: {}", e);
+                return warp::reply::with_status("Internal Error", warp::http::StatusCode::INTERNAL_SERVER_ERROR);
+            }
+            warp::reply::with_status("Reset", warp::http::StatusCode::OK)
         });
 
     // POST /api/log
+    // Plain text, or with a JSON content type `{"body": <any JSON>, "tags": ["..."]}`
     let log_route = warp::path!("api" / "log")
         .and(warp::post())
+        .and(warp::header::optional::<String>("content-type"))
         .and(warp::body::content_length_limit(1024 * 16))
         .and(warp::body::bytes())
         .and(log_tx_filter.clone())
-        .map(|bytes: bytes::Bytes, tx: mpsc::UnboundedSender<NodeCommand>| {
-            let msg = String::from_utf8_lossy(&bytes).to_string();
-            if let Err(e) = tx.send(NodeCommand::Log(msg)) {
+        .map(|content_type: Option<String>, bytes: bytes::Bytes, tx: mpsc::UnboundedSender<NodeCommand>| {
+            let cmd = if content_type.is_some_and(|c| c.starts_with("application/json")) {
+                match serde_json::from_slice::<LogRequest>(&bytes) {
+                    Ok(req) => NodeCommand::LogJson { body: req.body, tags: req.tags },
+                    Err(_) => return warp::reply::with_status("Invalid log entry", warp::http::StatusCode::BAD_REQUEST),
+                }
+            } else {
+                NodeCommand::Log(String::from_utf8_lossy(&bytes).to_string())
+            };
+            if let Err(e) = tx.send(cmd) {
                 eprintln!("Failed to send log to P2P loop: {}", e);
                 return warp::reply::with_status("Internal Error", warp::http::StatusCode::INTERNAL_SERVER_ERROR);
             }
             warp::reply::with_status("Logged", warp::http::StatusCode::OK)
         });
 
+    // GET /api/log?from=<ms>&to=<ms>&author=<peer>&tag=<tag>&q=<text>&limit=<n>&before=<cursor>&since=<cursor>
+    // A page of the newest matching entries, or with `since` what changed after it
+    let log_get_route = warp::path!("api" / "log")
+        .and(warp::get())
+        .and(warp::query::<LogQuery>())
+        .and(state_filter.clone())
+        .map(|query: LogQuery, state: AppState| {
+            match query.run(&state.log.read().unwrap()) {
+                Ok(page) => warp::reply::with_status(warp::reply::json(&page), warp::http::StatusCode::OK),
+                Err(reason) => warp::reply::with_status(warp::reply::json(&reason), warp::http::StatusCode::BAD_REQUEST),
+            }
+        });
+
+    // DELETE /api/log/:id?author=<peer id>
+    // `author` may be left out while a single entry has the id
+    #[derive(serde::Deserialize)]
+    struct RemoveQuery {
+        author: Option<String>,
+    }
+
+    let log_remove_route = warp::path!("api" / "log" / String)
+        .and(warp::delete())
+        .and(warp::query::<RemoveQuery>())
+        .and(state_filter.clone())
+        .and(log_tx_filter.clone())
+        .map(|id: String, query: RemoveQuery, state: AppState, tx: mpsc::UnboundedSender<NodeCommand>| {
+            let matching: Vec<LogEntry> = {
+                let log = state.log.read().unwrap();
+                match &query.author {
+                    Some(author) => log.get(&id, author).cloned().into_iter().collect(),
+                    None => log.with_id(&id).cloned().collect(),
+                }
+            };
+            let entry = match matching.as_slice() {
+                [] => return warp::reply::with_status("Unknown log entry", warp::http::StatusCode::NOT_FOUND),
+                [entry] => entry.clone(),
+                _ => return warp::reply::with_status("Several entries have this id, pass ?author=", warp::http::StatusCode::CONFLICT),
+            };
+            if !state.may_remove(&entry) {
+                return warp::reply::with_status("Not allowed to remove this entry", warp::http::StatusCode::FORBIDDEN);
+            }
+            if let Err(e) = tx.send(NodeCommand::RemoveLog { id, author: entry.author }) {
+                eprintln!("Failed to send removal to P2P loop: {}", e);
+                return warp::reply::with_status("Internal Error", warp::http::StatusCode::INTERNAL_SERVER_ERROR);
+            }
+            warp::reply::with_status("Removed", warp::http::StatusCode::OK)
+        });
+
+    // GET /api/kv, /api/kv/:device and /api/kv/:device/:attribute
+    let kv_get_route = warp::path!("api" / "kv")
+        .map(|| (None, None))
+        .or(warp::path!("api" / "kv" / String).map(|device| (Some(device), None)))
+        .unify()
+        .or(warp::path!("api" / "kv" / String / String).map(|device, attribute| (Some(device), Some(attribute))))
+        .unify()
+        .and(warp::get())
+        .and(state_filter.clone())
+        .map(|(device, attribute): (Option<String>, Option<String>), state: AppState| {
+            let kv = state.kv.read().unwrap();
+            let found = match (device, attribute) {
+                (None, _) => Some(serde_json::json!(kv.all())),
+                (Some(device), None) => Some(kv.device(&device)).filter(|attrs| !attrs.is_empty()).map(|attrs| serde_json::json!(attrs)),
+                (Some(device), Some(attribute)) => kv.get(&device, &attribute),
+            };
+            match found {
+                Some(value) => warp::reply::with_status(warp::reply::json(&value), warp::http::StatusCode::OK),
+                None => warp::reply::with_status(warp::reply::json(&"Not found"), warp::http::StatusCode::NOT_FOUND),
+            }
+        });
+
+    // PUT /api/kv/:device/:attribute
+    // Plain text is stored as a string; with a JSON content type, any JSON value
+    let kv_put_route = warp::path!("api" / "kv" / String / String)
+        .and(warp::put())
+        .and(warp::header::optional::<String>("content-type"))
+        .and(warp::body::content_length_limit(1024 * 16))
+        .and(warp::body::bytes())
+        .and(log_tx_filter.clone())
+        .map(|device: String, attribute: String, content_type: Option<String>, bytes: bytes::Bytes, tx: mpsc::UnboundedSender<NodeCommand>| {
+            if !kv::valid_name(&device) || !kv::valid_name(&attribute) {
+                return warp::reply::with_status("Invalid device or attribute", warp::http::StatusCode::BAD_REQUEST);
+            }
+            let value = if content_type.is_some_and(|c| c.starts_with("application/json")) {
+                match serde_json::from_slice(&bytes) {
+                    Ok(value) => value,
+                    Err(_) => return warp::reply::with_status("Invalid JSON value", warp::http::StatusCode::BAD_REQUEST),
+                }
+            } else {
+                serde_json::Value::String(String::from_utf8_lossy(&bytes).trim().to_string())
+            };
+            if let Err(e) = tx.send(NodeCommand::KvPut { device, attribute, value }) {
+                eprintln!("Failed to send KV put to P2P loop: {}", e);
+                return warp::reply::with_status("Internal Error", warp::http::StatusCode::INTERNAL_SERVER_ERROR);
+            }
+            warp::reply::with_status("Updated", warp::http::StatusCode::OK)
+        });
+
+    // DELETE /api/kv/:device/:attribute
+    let kv_delete_route = warp::path!("api" / "kv" / String / String)
+        .and(warp::delete())
+        .and(state_filter.clone())
+        .and(log_tx_filter.clone())
+        .map(|device: String, attribute: String, state: AppState, tx: mpsc::UnboundedSender<NodeCommand>| {
+            if state.kv.read().unwrap().get(&device, &attribute).is_none() {
+                return warp::reply::with_status("Not found", warp::http::StatusCode::NOT_FOUND);
+            }
+            if let Err(e) = tx.send(NodeCommand::KvDelete { device, attribute }) {
+                eprintln!("Failed to send KV delete to P2P loop: {}", e);
+                return warp::reply::with_status("Internal Error", warp::http::StatusCode::INTERNAL_SERVER_ERROR);
+            }
+            warp::reply::with_status("Deleted", warp::http::StatusCode::OK)
+        });
+
+    // GET /api/counters and /api/counters/:name
+    let counters_get_route = warp::path!("api" / "counters")
+        .map(|| None)
+        .or(warp::path!("api" / "counters" / String).map(Some))
+        .unify()
+        .and(warp::get())
+        .and(state_filter.clone())
+        .map(|name: Option<String>, state: AppState| {
+            let counters = state.counters.read().unwrap();
+            let found = match name {
+                None => Some(serde_json::json!(counters.all())),
+                Some(name) => counters.get(&name).map(|value| serde_json::json!(value)),
+            };
+            match found {
+                Some(value) => warp::reply::with_status(warp::reply::json(&value), warp::http::StatusCode::OK),
+                None => warp::reply::with_status(warp::reply::json(&"Not found"), warp::http::StatusCode::NOT_FOUND),
+            }
+        });
+
+    // POST /api/counters/:name/inc?by=<n> and /api/counters/:name/dec?by=<n>
+    // Steps by 1 unless `by` is given; an unknown counter starts at zero
+    #[derive(serde::Deserialize)]
+    struct CounterQuery {
+        by: Option<i64>,
+    }
+
+    let counters_post_route = warp::path!("api" / "counters" / String / String)
+        .and(warp::post())
+        .and(warp::query::<CounterQuery>())
+        .and(log_tx_filter.clone())
+        .map(|name: String, dir: String, query: CounterQuery, tx: mpsc::UnboundedSender<NodeCommand>| {
+            let by = query.by.unwrap_or(1);
+            let amount = match dir.as_str() {
+                "inc" => by,
+                "dec" => -by,
+                _ => return warp::reply::with_status("Unknown operation", warp::http::StatusCode::NOT_FOUND),
+            };
+            if !kv::valid_name(&name) {
+                return warp::reply::with_status("Invalid counter name", warp::http::StatusCode::BAD_REQUEST);
+            }
+            if by <= 0 {
+                return warp::reply::with_status("Amount must be positive", warp::http::StatusCode::BAD_REQUEST);
+            }
+            if let Err(e) = tx.send(NodeCommand::CounterAdd { name, amount }) {
+                eprintln!("Failed to send counter update to P2P loop: {}", e);
+                return warp::reply::with_status("Internal Error", warp::http::StatusCode::INTERNAL_SERVER_ERROR);
+            }
+            warp::reply::with_status("Updated", warp::http::StatusCode::OK)
+        });
+
+    // GET /api/collections and /api/collections/:name
+    // Each collection as `{"kind": "or-set", "value": [...]}`, with its `"retention"` if it has limits
+    let collections_get_route = warp::path!("api" / "collections")
+        .map(|| None)
+        .or(warp::path!("api" / "collections" / String).map(Some))
+        .unify()
+        .and(warp::get())
+        .and(state_filter.clone())
+        .map(|name: Option<String>, state: AppState| {
+            let collections = state.collections.read().unwrap();
+            let limits = state.collection_retention.read().unwrap();
+            let describe = |name: &str, collection: &std::sync::RwLock<Collection>| {
+                let collection = collection.read().unwrap();
+                let mut described = serde_json::json!({ "kind": collection.kind(), "value": collection.value() });
+                if let Some(retention) = limits.get(name) {
+                    described["retention"] = serde_json::json!(retention);
+                }
+                described
+            };
+            let found = match name {
+                None => Some(serde_json::json!(collections
+                    .iter()
+                    .map(|(name, collection)| (name.clone(), describe(name, collection)))
+                    .collect::<std::collections::BTreeMap<_, _>>())),
+                Some(name) => collections.get(&name).map(|collection| describe(&name, collection)),
+            };
+            match found {
+                Some(value) => warp::reply::with_status(warp::reply::json(&value), warp::http::StatusCode::OK),
+                None => warp::reply::with_status(warp::reply::json(&"Not found"), warp::http::StatusCode::NOT_FOUND),
+            }
+        });
+
+    // PUT /api/collections/:name with `{"kind": "set" | "or-set" | "map" | "counter" | "register" | "document"}`
+    // and optionally `"retention": {"max_entries": 100, "max_bytes": 65536}`, replacing the limits
+    #[derive(serde::Deserialize)]
+    struct CollectionRequest {
+        kind: CollectionKind,
+        #[serde(default)]
+        retention: Option<CollectionRetention>,
+    }
+
+    let collections_put_route = warp::path!("api" / "collections" / String)
+        .and(warp::put())
+        .and(warp::body::content_length_limit(1024 * 16))
+        .and(warp::body::bytes())
+        .and(state_filter.clone())
+        .and(log_tx_filter.clone())
+        .map(|name: String, bytes: bytes::Bytes, state: AppState, tx: mpsc::UnboundedSender<NodeCommand>| {
+            if !collection::valid_name(&name) {
+                return warp::reply::with_status("Invalid collection name", warp::http::StatusCode::BAD_REQUEST);
+            }
+            let Ok(CollectionRequest { kind, retention }) = serde_json::from_slice(&bytes) else {
+                return warp::reply::with_status("Invalid collection kind", warp::http::StatusCode::BAD_REQUEST);
+            };
+            if let Some(retention) = retention {
+                if retention.validate().is_err() {
+                    return warp::reply::with_status("Invalid retention", warp::http::StatusCode::BAD_REQUEST);
+                }
+                if !kind.has_retention() && retention != CollectionRetention::default() {
+                    return warp::reply::with_status("Counters and registers take no retention", warp::http::StatusCode::BAD_REQUEST);
+                }
+            }
+            if let Some(existing) = state.collections.read().unwrap().get(&name) {
+                if existing.read().unwrap().kind() != kind {
+                    return warp::reply::with_status("Collection exists with another kind", warp::http::StatusCode::CONFLICT);
+                }
+            }
+            let retention = retention.map(|retention| NodeCommand::SetCollectionRetention { name: name.clone(), retention });
+            let sent = tx.send(NodeCommand::CreateCollection { name, kind });
+            if let Err(e) = sent.and_then(|_| retention.map_or(Ok(()), |command| tx.send(command))) {
+                eprintln!("Failed to send collection creation to P2P loop: {}", e);
+                return warp::reply::with_status("Internal Error", warp::http::StatusCode::INTERNAL_SERVER_ERROR);
+            }
+            warp::reply::with_status("Created", warp::http::StatusCode::OK)
+        });
+
+    // POST /api/collections/:name with an update, e.g. `{"op": "add", "member": "sala"}`
+    let collections_post_route = warp::path!("api" / "collections" / String)
+        .and(warp::post())
+        .and(warp::body::content_length_limit(1024 * 16))
+        .and(warp::body::bytes())
+        .and(state_filter.clone())
+        .and(log_tx_filter.clone())
+        .map(|name: String, bytes: bytes::Bytes, state: AppState, tx: mpsc::UnboundedSender<NodeCommand>| {
+            let Some(kind) = state.collections.read().unwrap().get(&name).map(|c| c.read().unwrap().kind()) else {
+                return warp::reply::with_status("Not found", warp::http::StatusCode::NOT_FOUND);
+            };
+            let update = match serde_json::from_slice::<CollectionUpdate>(&bytes) {
+                Ok(update) if update.fits(kind) => update,
+                Ok(_) => return warp::reply::with_status("Update does not apply to this kind of collection", warp::http::StatusCode::BAD_REQUEST),
+                Err(_) => return warp::reply::with_status("Invalid update", warp::http::StatusCode::BAD_REQUEST),
+            };
+            if let Err(e) = tx.send(NodeCommand::UpdateCollection { name, update }) {
+                eprintln!("Failed to send collection update to P2P loop: {}", e);
+                return warp::reply::with_status("Internal Error", warp::http::StatusCode::INTERNAL_SERVER_ERROR);
+            }
+            warp::reply::with_status("Updated", warp::http::StatusCode::OK)
+        });
+
+    // PATCH /api/collections/:name
+    // Patches a document: a JSON Patch with `application/json-patch+json`, otherwise a
+    // merge patch. A patch that does not apply (failed test, missing path) is refused
+    // with 422 and changes nothing
+    let collections_patch_route = warp::path!("api" / "collections" / String)
+        .and(warp::patch())
+        .and(warp::header::optional::<String>("content-type"))
+        .and(warp::body::content_length_limit(1024 * 64))
+        .and(warp::body::bytes())
+        .and(state_filter.clone())
+        .and(log_tx_filter.clone())
+        .map(|name: String, content_type: Option<String>, bytes: bytes::Bytes, state: AppState, tx: mpsc::UnboundedSender<NodeCommand>| {
+            let Some(mut probe) = state.collections.read().unwrap().get(&name).map(|c| c.read().unwrap().clone()) else {
+                return warp::reply::with_status("Not found".to_string(), warp::http::StatusCode::NOT_FOUND);
+            };
+            if probe.kind() != CollectionKind::Document {
+                return warp::reply::with_status("Not a document".to_string(), warp::http::StatusCode::BAD_REQUEST);
+            }
+            let Ok(patch) = serde_json::from_slice(&bytes) else {
+                return warp::reply::with_status("Invalid JSON".to_string(), warp::http::StatusCode::BAD_REQUEST);
+            };
+            let update = if content_type.is_some_and(|c| c.starts_with("application/json-patch+json")) {
+                CollectionUpdate::JsonPatch { patch }
+            } else {
+                CollectionUpdate::MergePatch { patch }
+            };
+            if let Err(e) = probe.update(&state.local_peer_id, update.clone(), Hlc::default()) {
+                return warp::reply::with_status(e.to_string(), warp::http::StatusCode::UNPROCESSABLE_ENTITY);
+            }
+            if let Err(e) = tx.send(NodeCommand::UpdateCollection { name, update }) {
+                eprintln!("Failed to send collection update to P2P loop: {}", e);
+                return warp::reply::with_status("Internal Error".to_string(), warp::http::StatusCode::INTERNAL_SERVER_ERROR);
+            }
+            warp::reply::with_status("Updated".to_string(), warp::http::StatusCode::OK)
+        });
+
+    // DELETE /api/collections/:name
+    let collections_delete_route = warp::path!("api" / "collections" / String)
+        .and(warp::delete())
+        .and(state_filter.clone())
+        .and(log_tx_filter.clone())
+        .map(|name: String, state: AppState, tx: mpsc::UnboundedSender<NodeCommand>| {
+            if !state.collections.read().unwrap().contains_key(&name) {
+                return warp::reply::with_status("Not found", warp::http::StatusCode::NOT_FOUND);
+            }
+            if let Err(e) = tx.send(NodeCommand::DropCollection(name)) {
+                eprintln!("Failed to send collection drop to P2P loop: {}", e);
+                return warp::reply::with_status("Internal Error", warp::http::StatusCode::INTERNAL_SERVER_ERROR);
+            }
+            warp::reply::with_status("Dropped", warp::http::StatusCode::OK)
+        });
+
+    // POST /api/chat
+    let chat_post_route = warp::path!("api" / "chat")
+        .and(warp::post())
+        .and(warp::body::content_length_limit(1024 * 16))
+        .and(warp::body::bytes())
+        .and(log_tx_filter.clone())
+        .map(|bytes: bytes::Bytes, tx: mpsc::UnboundedSender<NodeCommand>| {
+            let msg = String::from_utf8_lossy(&bytes).trim().to_string();
+            if msg.is_empty() {
+                return warp::reply::with_status("Empty message", warp::http::StatusCode::BAD_REQUEST);
+            }
+            if let Err(e) = tx.send(NodeCommand::Chat(msg)) {
+                eprintln!("Failed to send chat to P2P loop: {}", e);
+                return warp::reply::with_status("Internal Error", warp::http::StatusCode::INTERNAL_SERVER_ERROR);
+            }
+            warp::reply::with_status("Sent", warp::http::StatusCode::OK)
+        });
+
+    // GET /api/chat?before=<cursor>&limit=<n>
+    #[derive(serde::Deserialize)]
+    struct ChatQuery {
+        before: Option<usize>,
+        limit: Option<usize>,
+    }
+
+    let chat_get_route = warp::path!("api" / "chat")
+        .and(warp::get())
+        .and(warp::query::<ChatQuery>())
+        .and(state_filter.clone())
+        .map(|query: ChatQuery, state: AppState| {
+            let limit = query.limit.unwrap_or(50).clamp(1, 500);
+            warp::reply::json(&state.chat_page(query.before, limit))
+        });
+
     // GET / -> serve static files from ./web
     let static_files = warp::fs::dir("web");
     let index = warp::get()
@@ -62,6 +606,41 @@ pub async fn start_server(
             warp::reply::with_status("Sent", warp::http::StatusCode::OK)
         });
 
+    // GET /api/watch?prefix=<key prefix>&resume=<seq>
+    // Server-sent events of the changes to keys under `prefix`; a reconnecting client's
+    // `Last-Event-ID` resumes after the last change it received
+    #[derive(serde::Deserialize)]
+    struct WatchQuery {
+        #[serde(default)]
+        prefix: String,
+        resume: Option<u64>,
+    }
+
+    let watch_sse_route = warp::path!("api" / "watch")
+        .and(warp::get())
+        .and(warp::query::<WatchQuery>())
+        .and(warp::header::optional::<u64>("last-event-id"))
+        .and(state_filter.clone())
+        .map(|query: WatchQuery, last_event_id: Option<u64>, state: AppState| {
+            let subscription = state.watch.subscribe(&query.prefix, query.resume.or(last_event_id));
+            let events = futures::stream::unfold(subscription, |mut subscription| async move {
+                let item = subscription.next().await?;
+                let event = warp::sse::Event::default().id(item.seq().to_string()).event(item.kind()).json_data(&item);
+                Some((event, subscription))
+            });
+            warp::sse::reply(warp::sse::keep_alive().stream(events))
+        });
+
+    // WebSocket /ws/watch?prefix=<key prefix>&resume=<seq>
+    // The same changes as `GET /api/watch`, one JSON message each
+    let watch_ws_route = warp::path!("ws" / "watch")
+        .and(warp::ws())
+        .and(warp::query::<WatchQuery>())
+        .and(state_filter.clone())
+        .map(|ws: warp::ws::Ws, query: WatchQuery, state: AppState| {
+            ws.on_upgrade(move |socket| handle_watch_connection(socket, state, query.prefix, query.resume))
+        });
+
     // WebSocket /ws
     let ws_route = warp::path("ws")
         .and(warp::ws())
@@ -71,8 +650,36 @@ pub async fn start_server(
         });
 
     let routes = state_route
+        .or(peers_route)
+        .or(versions_route)
+        .or(transports_route)
+        .or(bandwidth_route)
+        .or(bandwidth_cap_route)
+        .or(chaos_route)
+        .or(chaos_set_route)
+        .or(chaos_reset_route)
+        .or(retention_route)
+        .or(retention_set_route)
+        .or(snapshot_get_route)
+        .or(snapshot_post_route)
         .or(log_route)
+        .or(log_get_route)
+        .or(log_remove_route)
+        .or(kv_get_route)
+        .or(kv_put_route)
+        .or(kv_delete_route)
+        .or(counters_get_route)
+        .or(counters_post_route)
+        .or(collections_get_route)
+        .or(collections_put_route)
+        .or(collections_post_route)
+        .or(collections_patch_route)
+        .or(collections_delete_route)
         .or(dm_route)
+        .or(chat_post_route)
+        .or(chat_get_route)
+        .or(watch_sse_route)
+        .or(watch_ws_route)
         .or(ws_route)
         .or(index)
         .or(static_files);
@@ -80,7 +687,8 @@ pub async fn start_server(
     let addr = SocketAddr::from(([0, 0, 0, 0], port));
     println!("Web Dashboard running at http://0.0.0.0:{}", port);
 
-    warp::serve(routes).run(addr).await;
+    let (_, server) = warp::serve(routes).bind_with_graceful_shutdown(addr, shutdown);
+    server.await;
 }
 
 async fn handle_ws_connection(ws: WebSocket, state: AppState) {
@@ -97,3 +705,27 @@ async fn handle_ws_connection(ws: WebSocket, state: AppState) {
         }
     }
 }
+
+async fn handle_watch_connection(ws: WebSocket, state: AppState, prefix: String, resume: Option<u64>) {
+    let (mut user_ws_tx, mut user_ws_rx) = ws.split();
+    let mut subscription = state.watch.subscribe(&prefix, resume);
+
+    loop {
+        tokio::select! {
+            item = subscription.next() => {
+                let Some(item) = item else { break };
+                let Ok(json) = serde_json::to_string(&item) else { continue };
+                if let Err(e) = user_ws_tx.send(Message::text(json)).await {
+                    eprintln!("WebSocket send error: {}", e);
+                    break;
+                }
+            }
+            // Stop watching once the client goes away
+            message = user_ws_rx.next() => {
+                if !matches!(message, Some(Ok(_))) {
+                    break;
+                }
+            }
+        }
+    }
+}
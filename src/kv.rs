@@ -0,0 +1,274 @@
+use crate::hlc::{self, Hlc};
+use crate::replica::{self, Gap, Replica, ReplicaMessage};
+use crdts::{map, CmRDT, CvRDT, LWWReg, Map, ResetRemove, VClock};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use tracing::error;
+
+/// Orders concurrent writes of a register: the writer's HLC timestamp, then its peer ID.
+pub type Marker = (Hlc, String);
+
+/// Last-writer-wins register holding one attribute of a device.
+///
+/// Wraps `LWWReg` so it can be nested in a `crdts::Map`, which needs its values to
+/// support reset-remove. A register is a single value, so there is nothing to reset.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Register(pub LWWReg<Value, Marker>);
+
+impl Default for Register {
+    fn default() -> Self {
+        Register(LWWReg { val: Value::Null, marker: Marker::default() })
+    }
+}
+
+impl ResetRemove<String> for Register {
+    fn reset_remove(&mut self, _clock: &VClock<String>) {}
+}
+
+impl CmRDT for Register {
+    type Op = Register;
+    type Validation = crdts::lwwreg::Validation;
+
+    fn validate_op(&self, op: &Self::Op) -> Result<(), Self::Validation> {
+        self.0.validate_op(&op.0)
+    }
+
+    fn apply(&mut self, op: Self::Op) {
+        self.0.apply(op.0)
+    }
+}
+
+impl CvRDT for Register {
+    type Validation = crdts::lwwreg::Validation;
+
+    fn validate_merge(&self, other: &Self) -> Result<(), Self::Validation> {
+        self.0.validate_merge(&other.0)
+    }
+
+    fn merge(&mut self, other: Self) {
+        self.0.merge(other.0)
+    }
+}
+
+/// Operation on the device store, as published on the KV topic.
+pub type KvOp = map::Op<String, Register, String>;
+
+/// Messages on the KV topic.
+pub type KvMessage = ReplicaMessage<DeviceStore>;
+
+/// A key whose value changed, `None` once deleted.
+#[derive(Clone, Debug, PartialEq)]
+pub struct KvChange {
+    pub device: String,
+    pub attribute: String,
+    pub value: Option<Value>,
+}
+
+/// Last-writer-wins registers keyed by string: a `crdts::Map` with actors identified by
+/// peer ID.
+///
+/// Ops must be applied in causal order per writer. `apply` refuses an op that skips
+/// ahead of what this replica has seen, and the caller fetches the writer's full state
+/// instead. That keeps deferred removals (which don't serialize) out of the map.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(transparent)]
+pub struct RegisterMap {
+    map: Map<String, Register, String>,
+}
+
+impl RegisterMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self, key: &str) -> Option<Value> {
+        self.map.get(&key.to_string()).val.map(|reg| reg.0.val)
+    }
+
+    /// Every key with its value.
+    pub fn values(&self) -> BTreeMap<String, Value> {
+        self.map.iter().map(|ctx| (ctx.val.0.clone(), ctx.val.1 .0.val.clone())).collect()
+    }
+
+    /// Sets a key, returning the op to publish.
+    pub fn put(&mut self, actor: &str, key: &str, value: Value, at: Hlc) -> KvOp {
+        let ctx = self.map.read_ctx().derive_add_ctx(actor.to_string());
+        let marker = (at, actor.to_string());
+        let op = self.map.update(key.to_string(), ctx, |_, _| Register(LWWReg { val: value, marker }));
+        self.map.apply(op.clone());
+        op
+    }
+
+    /// Deletes a key, returning the op to publish, or `None` if it is not set.
+    /// A concurrent put on another node survives the delete.
+    pub fn delete(&mut self, key: &str) -> Option<KvOp> {
+        let key = key.to_string();
+        let read = self.map.get(&key);
+        read.val.as_ref()?;
+        let op = self.map.rm(key, read.derive_rm_ctx());
+        self.map.apply(op.clone());
+        Some(op)
+    }
+
+    /// Applies a remote op, returning the keys it changed, `None` for deleted ones. A write
+    /// stamped beyond `hlc::MAX_DRIFT_MS` ahead of the local clock is dropped, like log entries.
+    pub fn apply(&mut self, op: KvOp) -> Result<Vec<(String, Option<Value>)>, Gap> {
+        if let map::Op::Up { key, op: register, .. } = &op {
+            if hlc::beyond_drift(register.0.marker.0) {
+                error!("Rejected write of {} by {}: stamped {:?}, too far in the future", key, register.0.marker.1, register.0.marker.0);
+                return Ok(Vec::new());
+            }
+        }
+        let seen = self.map.read_ctx().add_clock;
+        let in_order = match &op {
+            map::Op::Up { dot, .. } => dot.counter <= seen.get(&dot.actor) + 1,
+            map::Op::Rm { clock, .. } => clock <= &seen,
+        };
+        if !in_order {
+            return Err(Gap);
+        }
+        let before = self.values();
+        self.map.apply(op);
+        Ok(self.changes_since(before))
+    }
+
+    /// Merges another replica's registers, returning the keys that changed. A replica with a
+    /// write stamped beyond `hlc::MAX_DRIFT_MS` ahead of the local clock is dropped whole.
+    pub fn merge(&mut self, other: RegisterMap) -> Vec<(String, Option<Value>)> {
+        if let Some(latest) = other.latest().filter(|latest| hlc::beyond_drift(*latest)) {
+            error!("Rejected registers with a write stamped {:?}, too far in the future", latest);
+            return Vec::new();
+        }
+        let before = self.values();
+        self.map.merge(other.map);
+        self.changes_since(before)
+    }
+
+    fn changes_since(&self, mut before: BTreeMap<String, Value>) -> Vec<(String, Option<Value>)> {
+        let mut changes = Vec::new();
+        for (key, value) in self.values() {
+            if before.remove(&key).as_ref() != Some(&value) {
+                changes.push((key, Some(value)));
+            }
+        }
+        changes.extend(before.into_keys().map(|key| (key, None)));
+        changes
+    }
+
+    /// Highest HLC timestamp among the registers, to advance the local clock past.
+    pub fn latest(&self) -> Option<Hlc> {
+        self.map.values().map(|ctx| ctx.val.0.marker.0).max()
+    }
+
+    /// Keys, the least recently written first.
+    pub fn keys_by_age(&self) -> Vec<String> {
+        let mut keys: Vec<(Marker, String)> = self.map.iter().map(|ctx| (ctx.val.1 .0.marker.clone(), ctx.val.0.clone())).collect();
+        keys.sort();
+        keys.into_iter().map(|(_, key)| key).collect()
+    }
+
+    /// SHA-256 over the keys and registers. Equal digests mean equal values.
+    pub fn digest(&self) -> String {
+        let registers: BTreeMap<String, Register> =
+            self.map.iter().map(|ctx| (ctx.val.0.clone(), ctx.val.1.clone())).collect();
+        replica::hash_json(&registers)
+    }
+}
+
+/// Replicated device state: a `RegisterMap` keyed by `<device>/<attribute>`.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct DeviceStore {
+    map: RegisterMap,
+}
+
+fn key_of(device: &str, attribute: &str) -> String {
+    format!("{}/{}", device, attribute)
+}
+
+fn kv_changes(changes: Vec<(String, Option<Value>)>) -> Vec<KvChange> {
+    changes
+        .into_iter()
+        .filter_map(|(key, value)| {
+            let (device, attribute) = key.split_once('/')?;
+            Some(KvChange { device: device.to_string(), attribute: attribute.to_string(), value })
+        })
+        .collect()
+}
+
+impl DeviceStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self, device: &str, attribute: &str) -> Option<Value> {
+        self.map.get(&key_of(device, attribute))
+    }
+
+    /// Attributes of one device.
+    pub fn device(&self, device: &str) -> BTreeMap<String, Value> {
+        self.all().remove(device).unwrap_or_default()
+    }
+
+    /// Every device with its attributes.
+    pub fn all(&self) -> BTreeMap<String, BTreeMap<String, Value>> {
+        let mut devices: BTreeMap<String, BTreeMap<String, Value>> = BTreeMap::new();
+        for (key, value) in self.map.values() {
+            if let Some((device, attribute)) = key.split_once('/') {
+                devices.entry(device.to_string()).or_default().insert(attribute.to_string(), value);
+            }
+        }
+        devices
+    }
+
+    /// Sets an attribute, returning the op to publish.
+    pub fn put(&mut self, actor: &str, device: &str, attribute: &str, value: Value, at: Hlc) -> KvOp {
+        self.map.put(actor, &key_of(device, attribute), value, at)
+    }
+
+    /// Deletes an attribute, returning the op to publish, or `None` if it is not set.
+    pub fn delete(&mut self, device: &str, attribute: &str) -> Option<KvOp> {
+        self.map.delete(&key_of(device, attribute))
+    }
+
+    /// Applies a remote op, returning the keys it changed.
+    pub fn apply(&mut self, op: KvOp) -> Result<Vec<KvChange>, Gap> {
+        self.map.apply(op).map(kv_changes)
+    }
+
+    /// Merges another replica's full store, returning the keys that changed.
+    pub fn merge(&mut self, other: DeviceStore) -> Vec<KvChange> {
+        kv_changes(self.map.merge(other.map))
+    }
+
+    /// Highest HLC timestamp among the registers, to advance the local clock past.
+    pub fn latest(&self) -> Option<Hlc> {
+        self.map.latest()
+    }
+
+    pub fn digest(&self) -> String {
+        self.map.digest()
+    }
+}
+
+impl Replica for DeviceStore {
+    type Op = KvOp;
+    type Change = KvChange;
+
+    fn apply(&mut self, op: KvOp) -> Result<Vec<KvChange>, Gap> {
+        DeviceStore::apply(self, op)
+    }
+
+    fn merge(&mut self, other: DeviceStore) -> Vec<KvChange> {
+        DeviceStore::merge(self, other)
+    }
+
+    fn digest(&self) -> String {
+        DeviceStore::digest(self)
+    }
+}
+
+/// Checks a device or attribute name: not empty, without `/` or whitespace.
+pub fn valid_name(name: &str) -> bool {
+    !name.is_empty() && !name.contains('/') && !name.contains(char::is_whitespace)
+}
@@ -0,0 +1,30 @@
+// The filter chain of the routes in `http::start_server` nests deeper than the default
+#![recursion_limit = "256"]
+
+pub mod p2p;
+pub mod state;
+pub mod http;
+pub mod ble;
+pub mod storage;
+pub mod telemetry;
+pub mod health;
+pub mod version;
+pub mod bandwidth;
+pub mod namespace;
+pub mod chaos;
+pub mod transport;
+pub mod sync;
+pub mod hlc;
+pub mod entry;
+pub mod logset;
+pub mod kv;
+pub mod replica;
+pub mod counter;
+pub mod collection;
+pub mod document;
+pub mod retention;
+pub mod snapshot;
+pub mod query;
+pub mod replication;
+pub mod watch;
+pub mod wal;
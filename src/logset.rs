@@ -0,0 +1,380 @@
+use crate::entry::{EntryKey, HorizonSignature, LogEntry, Tombstone};
+use crate::hlc::Hlc;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Removals remembered for `changes_since`; older cursors get the whole log again.
+const MAX_JOURNALED_REMOVALS: usize = 10_000;
+
+/// The shared log: an observed-remove set of `LogEntry`s.
+///
+/// Every entry carries a unique id, so a removal observes exactly one add and re-adding
+/// the same text creates a new entry. Ids are picked by authors, so entries, tombstones
+/// and acks are all keyed by id and author (`EntryKey`): an entry forged with another
+/// author's id is a separate entry, and removing it leaves the original in place. Of two
+/// entries an author signed with the same id, the lowest is kept, so every node keeps the
+/// same one. Entries and tombstones are indexed by key. Removals are kept as signed `Tombstone`s until
+/// every known peer acknowledged them (`ack`, `collect_garbage`); until then a removed
+/// entry arriving from a lagging peer is not resurrected. A tombstone may arrive before
+/// its entry, in which case the entry is never added.
+///
+/// Retention compacts the log up to a horizon (`compact`): entries and tombstones stamped
+/// before it are dropped, and entries below it are refused from then on, so a peer that
+/// still holds them cannot sync them back.
+///
+/// Each change is also numbered in a local sequence (`seq`, `changes_since`), so readers
+/// such as `GET /api/log?since=` can fetch only what changed since their last read.
+///
+/// Live entries serialize under `value`, like the `GSet` this replaced, so older files
+/// and peers read the live entries and ignore the tombstones.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(from = "StoredLogSet")]
+pub struct LogSet {
+    #[serde(rename = "value")]
+    entries: BTreeSet<LogEntry>,
+    #[serde(skip_serializing_if = "BTreeSet::is_empty")]
+    tombstones: BTreeSet<Tombstone>,
+    /// Entries stamped before this were compacted; see `retention::RetentionPolicy`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    horizon: Option<Hlc>,
+    /// A log admin's signature of `horizon`, passed on so peers adopt it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    horizon_signature: Option<HorizonSignature>,
+    /// Timestamps of the live entries, by key. Ordered by id first, for `with_id`.
+    #[serde(skip)]
+    index: BTreeMap<EntryKey, Hlc>,
+    /// Keys of the entries in `tombstones`.
+    #[serde(skip)]
+    removed: HashSet<EntryKey>,
+    /// Peers known to hold each tombstone, by entry. Rebuilt from acks after a restart.
+    #[serde(skip)]
+    acks: HashMap<EntryKey, HashSet<String>>,
+    #[serde(skip)]
+    changes: Changes,
+}
+
+/// The serialized fields of a `LogSet`, which is indexed once loaded.
+#[derive(Deserialize)]
+struct StoredLogSet {
+    #[serde(rename = "value")]
+    entries: BTreeSet<LogEntry>,
+    #[serde(default)]
+    tombstones: BTreeSet<Tombstone>,
+    #[serde(default)]
+    horizon: Option<Hlc>,
+    #[serde(default)]
+    horizon_signature: Option<HorizonSignature>,
+}
+
+impl From<StoredLogSet> for LogSet {
+    fn from(stored: StoredLogSet) -> Self {
+        let mut log = LogSet { horizon: stored.horizon, horizon_signature: stored.horizon_signature, ..Default::default() };
+        for tombstone in stored.tombstones {
+            log.removed.insert(tombstone.key());
+            log.tombstones.insert(tombstone);
+        }
+        // Through `insert`, so files holding several entries of one key load like peers merge them
+        for entry in stored.entries {
+            log.insert(entry);
+        }
+        log.changes = Changes::default();
+        log
+    }
+}
+
+/// Local sequence numbers of the changes to a `LogSet`. Not replicated nor persisted.
+///
+/// The sequence starts at the wall clock in microseconds when the set is created (or
+/// loaded), so cursors handed out by an earlier run of the node are below any of this run
+/// and are answered with the whole log. Microseconds keep cursors exact as JavaScript
+/// numbers. Entries without an arrival number were there from the start.
+#[derive(Clone, Debug)]
+struct Changes {
+    /// Cursors below this are too old to answer with changes.
+    floor: u64,
+    seq: u64,
+    arrivals: HashMap<EntryKey, u64>,
+    removals: VecDeque<(u64, EntryKey)>,
+}
+
+impl Default for Changes {
+    fn default() -> Self {
+        let start = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_micros() as u64);
+        Self { floor: start, seq: start, arrivals: HashMap::new(), removals: VecDeque::new() }
+    }
+}
+
+impl Changes {
+    fn arrived(&mut self, key: EntryKey) {
+        self.seq += 1;
+        self.arrivals.insert(key, self.seq);
+    }
+
+    fn removed(&mut self, key: EntryKey) {
+        self.seq += 1;
+        self.arrivals.remove(&key);
+        self.removals.push_back((self.seq, key));
+        if self.removals.len() > MAX_JOURNALED_REMOVALS {
+            if let Some((seq, _)) = self.removals.pop_front() {
+                self.floor = seq;
+            }
+        }
+    }
+}
+
+/// What changed in a `LogSet` after a cursor, see `LogSet::changes_since`.
+pub struct LogChanges<'a> {
+    /// Entries added after the cursor, with their sequence numbers, in arrival order.
+    pub added: Vec<(u64, &'a LogEntry)>,
+    /// Entries removed after the cursor, with their sequence numbers, in order.
+    pub removed: Vec<(u64, &'a EntryKey)>,
+}
+
+impl LogSet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Live entries, in timestamp order.
+    pub fn read(&self) -> BTreeSet<LogEntry> {
+        self.entries.clone()
+    }
+
+    /// Live entries, in timestamp order, without copying them.
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = &LogEntry> {
+        self.entries.iter()
+    }
+
+    /// Live entries stamped at or after `from` and before `to`, in wall-clock milliseconds,
+    /// in timestamp order.
+    pub fn range(&self, from: Option<u64>, to: Option<u64>) -> Vec<LogEntry> {
+        self.entries
+            .iter()
+            .skip_while(|entry| from.is_some_and(|from| entry.timestamp.wall_ms < from))
+            .take_while(|entry| to.is_none_or(|to| entry.timestamp.wall_ms < to))
+            .cloned()
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn get(&self, id: &str, author: &str) -> Option<&LogEntry> {
+        self.entry(&EntryKey { id: id.to_string(), author: author.to_string() })
+    }
+
+    /// Live entries with this id, whoever wrote them.
+    pub fn with_id<'a>(&'a self, id: &'a str) -> impl Iterator<Item = &'a LogEntry> {
+        let from = EntryKey { id: id.to_string(), author: String::new() };
+        self.index
+            .range(from..)
+            .take_while(move |(key, _)| key.id == id)
+            .filter_map(|(key, _)| self.entry(key))
+    }
+
+    pub fn contains(&self, entry: &LogEntry) -> bool {
+        self.entries.contains(entry)
+    }
+
+    pub fn is_removed(&self, id: &str, author: &str) -> bool {
+        self.removed.contains(&EntryKey { id: id.to_string(), author: author.to_string() })
+    }
+
+    /// The live entry with this key.
+    fn entry(&self, key: &EntryKey) -> Option<&LogEntry> {
+        let timestamp = *self.index.get(key)?;
+        // Entries sort by timestamp first, so the entry is among the few stamped alike
+        let from = LogEntry { id: String::new(), author: String::new(), timestamp, tags: Vec::new(), body: Value::Null, signature: String::new() };
+        self.entries
+            .range(from..)
+            .take_while(|entry| entry.timestamp == timestamp)
+            .find(|entry| entry.id == key.id && entry.author == key.author)
+    }
+
+    pub fn tombstones(&self) -> &BTreeSet<Tombstone> {
+        &self.tombstones
+    }
+
+    pub fn horizon(&self) -> Option<Hlc> {
+        self.horizon
+    }
+
+    pub fn horizon_signature(&self) -> Option<&HorizonSignature> {
+        self.horizon_signature.as_ref()
+    }
+
+    /// Sequence number of the latest change, the cursor to pass to `changes_since` next.
+    pub fn seq(&self) -> u64 {
+        self.changes.seq
+    }
+
+    /// Entries added and removed after the `since` cursor, or `None` when the cursor is
+    /// from an earlier run or older than the remembered removals. Entries dropped by
+    /// compaction are not listed: they are the ones stamped before `horizon`.
+    pub fn changes_since(&self, since: u64) -> Option<LogChanges<'_>> {
+        let changes = &self.changes;
+        if since < changes.floor || since > changes.seq {
+            return None;
+        }
+        let mut added: Vec<_> = self
+            .entries
+            .iter()
+            .filter_map(|entry| changes.arrivals.get(&entry.key()).filter(|seq| **seq > since).map(|seq| (*seq, entry)))
+            .collect();
+        added.sort_by_key(|(seq, _)| *seq);
+        let removed = changes.removals.iter().filter(|(seq, _)| *seq > since).map(|(seq, key)| (*seq, key)).collect();
+        Some(LogChanges { added, removed })
+    }
+
+    fn is_compacted(&self, timestamp: Hlc) -> bool {
+        self.horizon.is_some_and(|horizon| timestamp < horizon)
+    }
+
+    /// Adds an entry unless it was removed or compacted. Returns whether the log changed.
+    ///
+    /// Nodes migrating the same line of the unsigned log each sign their own copy (see
+    /// `LogEntry::legacy`); only the copy of the lowest author is kept, so every node ends
+    /// up with the same one.
+    pub fn insert(&mut self, entry: LogEntry) -> bool {
+        if self.is_compacted(entry.timestamp) {
+            return false;
+        }
+        let key = entry.key();
+        if self.removed.contains(&key) {
+            return false;
+        }
+        let mut replaced: Vec<LogEntry> = Vec::new();
+        if let Some(existing) = self.entry(&key) {
+            if existing <= &entry {
+                return false;
+            }
+            replaced.push(existing.clone());
+        }
+        if entry.is_migrated() {
+            let copies: Vec<&LogEntry> = self.with_id(&entry.id).filter(|copy| copy.author != entry.author).collect();
+            if copies.iter().any(|copy| copy.author < entry.author) {
+                return false;
+            }
+            replaced.extend(copies.into_iter().cloned());
+        }
+        for old in replaced {
+            self.entries.remove(&old);
+            self.index.remove(&old.key());
+            if old.key() != key {
+                self.changes.removed(old.key());
+            }
+        }
+        self.index.insert(key.clone(), entry.timestamp);
+        self.entries.insert(entry);
+        self.changes.arrived(key);
+        true
+    }
+
+    /// Applies a removal of the entry with the tombstone's id and author; entries of other
+    /// authors with the same id are left in place. One stamped before the horizon is not
+    /// needed: its entry was compacted too. Returns whether the tombstone is new.
+    pub fn remove(&mut self, tombstone: Tombstone) -> bool {
+        if self.is_compacted(tombstone.timestamp) {
+            return false;
+        }
+        let key = tombstone.key();
+        if !self.removed.insert(key.clone()) {
+            return false;
+        }
+        if let Some(entry) = self.entry(&key).cloned() {
+            self.entries.remove(&entry);
+            self.index.remove(&key);
+            self.changes.removed(key.clone());
+        }
+        self.acks.entry(key).or_default().insert(tombstone.removed_by.clone());
+        self.tombstones.insert(tombstone)
+    }
+
+    /// Raises the horizon, dropping the entries and tombstones stamped before it. Returns
+    /// the dropped entries, or `None` when `horizon` is not above the current one.
+    pub fn compact(&mut self, horizon: Hlc) -> Option<Vec<LogEntry>> {
+        self.compact_signed(horizon, None)
+    }
+
+    /// Like `compact`, keeping a log admin's signature of the horizon.
+    pub fn compact_signed(&mut self, horizon: Hlc, signature: Option<HorizonSignature>) -> Option<Vec<LogEntry>> {
+        if self.horizon.is_some_and(|current| horizon <= current) {
+            return None;
+        }
+        self.horizon = Some(horizon);
+        self.horizon_signature = signature;
+        let (dropped, kept): (BTreeSet<LogEntry>, _) = std::mem::take(&mut self.entries).into_iter().partition(|entry| entry.timestamp < horizon);
+        self.entries = kept;
+        for entry in &dropped {
+            self.index.remove(&entry.key());
+            self.changes.arrivals.remove(&entry.key());
+        }
+        let (acks, removed) = (&mut self.acks, &mut self.removed);
+        self.tombstones.retain(|t| {
+            let keep = t.timestamp >= horizon;
+            if !keep {
+                acks.remove(&t.key());
+                removed.remove(&t.key());
+            }
+            keep
+        });
+        Some(dropped.into_iter().collect())
+    }
+
+    /// Merges another replica: its horizon, its entries, then its removals.
+    pub fn merge(&mut self, other: LogSet) {
+        if let Some(horizon) = other.horizon {
+            self.compact_signed(horizon, other.horizon_signature);
+        }
+        for entry in other.entries {
+            self.insert(entry);
+        }
+        for tombstone in other.tombstones {
+            self.remove(tombstone);
+        }
+    }
+
+    /// Records that `peer` holds the tombstones of these entries.
+    pub fn ack(&mut self, peer: &str, keys: &[EntryKey]) {
+        for key in keys {
+            if let Some(acked) = self.acks.get_mut(key) {
+                acked.insert(peer.to_string());
+            }
+        }
+    }
+
+    /// Drops the tombstones every peer in `known` acknowledged, returning their entries.
+    /// Nothing is dropped while no peer is known.
+    pub fn collect_garbage(&mut self, known: &HashSet<String>) -> Vec<EntryKey> {
+        if known.is_empty() {
+            return Vec::new();
+        }
+        let done: HashSet<EntryKey> = self
+            .tombstones
+            .iter()
+            .map(Tombstone::key)
+            .filter(|key| self.acks.get(key).is_some_and(|acked| known.is_subset(acked)))
+            .collect();
+        self.tombstones.retain(|t| !done.contains(&t.key()));
+        for key in &done {
+            self.acks.remove(key);
+            self.removed.remove(key);
+        }
+        let mut done: Vec<EntryKey> = done.into_iter().collect();
+        done.sort();
+        done
+    }
+}
+
+impl PartialEq for LogSet {
+    fn eq(&self, other: &Self) -> bool {
+        self.entries == other.entries && self.tombstones == other.tombstones
+    }
+}
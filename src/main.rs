@@ -1,14 +1,13 @@
-mod p2p;
-mod state;
-mod http;
-mod ble;
-mod storage;
-mod telemetry;
-
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use tracing_subscriber::EnvFilter;
 use libp2p::identity;
-use tracing::info;
+use tokio::io::{self, AsyncBufReadExt};
+use tokio::sync::{mpsc, watch};
+use tracing::{info, error};
+use ghostmesh::{ble, p2p, state::AppState, namespace::Namespace, chaos::ChaosConfig, retention::RetentionPolicy};
+use ghostmesh::{snapshot::Snapshot, storage, wal::SyncPolicy};
+use std::path::PathBuf;
+use std::collections::HashMap;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
@@ -16,28 +15,293 @@ struct Args {
     /// Port to listen on
     #[arg(short, long, default_value_t = 0)]
     port: u16,
+
+    /// Default per-peer bandwidth cap in bytes per second (inbound + outbound)
+    #[arg(long)]
+    peer_bandwidth_cap: Option<u64>,
+
+    /// Namespace to join, as `name` or `name=<network key file>`. Repeat to join several;
+    /// the N-th namespace listens on `port + 2*N`. Defaults to the `default` namespace.
+    #[arg(short, long = "namespace")]
+    namespaces: Vec<String>,
+
+    /// Chaos: percentage (0-100) of inbound gossipsub messages to drop
+    #[arg(long, default_value_t = 0.0)]
+    chaos_drop: f64,
+
+    /// Chaos: delay in milliseconds added to every write towards a peer
+    #[arg(long, default_value_t = 0)]
+    chaos_delay_ms: u64,
+
+    /// Chaos: comma-separated peer IDs forming one side of a partition. Repeat for each
+    /// group; peers in different groups cannot reach each other.
+    #[arg(long = "chaos-partition")]
+    chaos_partitions: Vec<String>,
+
+    /// Peer ID allowed to remove any log entry, not only its own. Repeat for each admin;
+    /// every node of the mesh needs the same list.
+    #[arg(long = "log-admin")]
+    log_admins: Vec<String>,
+
+    /// Log retention, as comma-separated `max-age=<n>[s|m|h|d]`, `max-entries=<n>`,
+    /// `max-bytes=<n>[k|M|G]` and `archive`. Prefix with `<namespace>:` to set it for one
+    /// namespace; without a prefix it applies to the namespaces not given their own. Only
+    /// the namespace's log is compacted, not its named collections.
+    #[arg(long = "retention")]
+    retentions: Vec<String>,
+
+    /// When the log's write-ahead log is synced to disk: `always` (after every change),
+    /// `never` (left to the OS) or an interval such as `200ms` or `1s`
+    #[arg(long = "wal-sync", default_value = "always")]
+    wal_sync: String,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+/// Offline operations on the data of a stopped node, picked by `--port` and `--namespace`.
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Writes the node's saved state to a snapshot archive.
+    Export { file: PathBuf },
+    /// Checks a snapshot archive and queues it; the node merges it on its next start.
+    Import { file: PathBuf },
 }
 
+/// Process exit codes.
+const EXIT_OK: i32 = 0;
+const EXIT_ERROR: i32 = 1;
+/// A second SIGINT/SIGTERM during graceful shutdown aborts it.
+const EXIT_FORCED: i32 = 130;
+
 #[tokio::main]
-async fn main() -> anyhow::Result<()> {
+async fn main() {
+    let code = match run().await {
+        Ok(()) => EXIT_OK,
+        Err(e) => {
+            error!("Fatal error: {:?}", e);
+            EXIT_ERROR
+        }
+    };
+    // Exit explicitly: the blocking stdin reader would otherwise keep the runtime alive
+    std::process::exit(code);
+}
+
+async fn run() -> anyhow::Result<()> {
     tracing_subscriber::fmt()
         .with_env_filter(EnvFilter::from_default_env().add_directive(tracing::Level::INFO.into()))
         .init();
 
     let args = Args::parse();
-    // let identity_file = format!("identity_{}.key", args.port); // Removed
-    let id_keys = load_or_generate_keypair(args.port)?; // Updated call site
 
-    p2p::run_node(args.port, id_keys).await
+    let mut namespaces = args
+        .namespaces
+        .iter()
+        .map(|spec| Namespace::parse(spec))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    if namespaces.is_empty() {
+        namespaces.push(Namespace::default());
+    }
+    for (i, ns) in namespaces.iter().enumerate() {
+        if namespaces[..i].iter().any(|other| other.name == ns.name) {
+            anyhow::bail!("namespace '{}' given more than once", ns.name);
+        }
+    }
+
+    if let Some(command) = args.command {
+        let [namespace] = &namespaces[..] else {
+            anyhow::bail!("export and import work on one namespace at a time");
+        };
+        return run_offline(command, args.port, namespace);
+    }
+
+    // Spawn BLE Service (shared by all namespaces)
+    let ble_service = tokio::spawn(async move {
+        if let Err(e) = ble::run_ble_service().await {
+            error!("BLE Service error: {:?}", e);
+        }
+    });
+
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    tokio::spawn(async move {
+        wait_for_signal().await;
+        info!("Shutdown requested, stopping nodes... (repeat to force)");
+        let _ = shutdown_tx.send(true);
+        wait_for_signal().await;
+        error!("Forced shutdown");
+        std::process::exit(EXIT_FORCED);
+    });
+
+    let chaos = ChaosConfig {
+        drop_percent: args.chaos_drop,
+        delay_ms: args.chaos_delay_ms,
+        partitions: args
+            .chaos_partitions
+            .iter()
+            .map(|group| group.split(',').map(|p| p.trim().to_string()).filter(|p| !p.is_empty()).collect())
+            .collect(),
+    };
+    chaos.validate().map_err(anyhow::Error::msg)?;
+    if chaos != ChaosConfig::default() {
+        info!("Chaos mode enabled: {:?}", chaos);
+    }
+
+    for admin in &args.log_admins {
+        admin.parse::<libp2p::PeerId>().map_err(|_| anyhow::anyhow!("invalid log admin peer ID '{}'", admin))?;
+    }
+
+    let mut default_retention = RetentionPolicy::default();
+    let mut retentions = HashMap::new();
+    for spec in &args.retentions {
+        match spec.split_once(':') {
+            Some((name, policy)) => {
+                if !namespaces.iter().any(|ns| ns.name == name) {
+                    anyhow::bail!("retention given for namespace '{}', which is not joined", name);
+                }
+                retentions.insert(name.to_string(), RetentionPolicy::parse(policy)?);
+            }
+            None => default_retention = RetentionPolicy::parse(spec)?,
+        }
+    }
+    let wal_sync = SyncPolicy::parse(&args.wal_sync)?;
+
+    let mut nodes = Vec::new();
+    let mut line_txs = Vec::new();
+    for (i, namespace) in namespaces.into_iter().enumerate() {
+        let port = match args.port {
+            0 => 0,
+            port => u16::try_from(2 * i)
+                .ok()
+                .and_then(|offset| port.checked_add(offset))
+                .filter(|port| *port < u16::MAX)
+                .ok_or_else(|| anyhow::anyhow!("namespace '{}' would listen past port {}", namespace.name, u16::MAX - 1))?,
+        };
+        let id_keys = load_or_generate_keypair(port, &namespace)?;
+        let (line_tx, line_rx) = mpsc::unbounded_channel();
+        line_txs.push((namespace.name.clone(), line_tx));
+
+        let app_state = AppState::new(id_keys.public().to_peer_id().to_string(), namespace.clone());
+        let retention = retentions.get(&namespace.name).copied().unwrap_or(default_retention);
+        let options = p2p::NodeOptions {
+            peer_bandwidth_cap: args.peer_bandwidth_cap,
+            namespace,
+            transport: p2p::TransportKind::Tcp,
+            chaos: chaos.clone(),
+            // BLE scanning runs separately; there is no GATT data transport to fall back on yet
+            fallback_link: None,
+            // An ephemeral node port leaves no port to put the web server next to
+            web_port: if port == 0 { None } else { Some(port + 1) },
+            log_admins: args.log_admins.iter().cloned().collect(),
+            retention,
+            wal_sync,
+        };
+        let io = p2p::NodeIo::new(line_rx, shutdown_rx.clone());
+        nodes.push(p2p::run_node(port, id_keys, options, app_state, io));
+    }
+
+    tokio::spawn(route_stdin(line_txs));
+
+    futures::future::try_join_all(nodes).await?;
+    ble_service.abort();
+    info!("GhostMesh stopped cleanly");
+    Ok(())
+}
+
+fn run_offline(command: Command, port: u16, namespace: &Namespace) -> anyhow::Result<()> {
+    match command {
+        Command::Export { file } => {
+            let id_keys = load_or_generate_keypair(port, namespace)?;
+            let app_state = AppState::new(id_keys.public().to_peer_id().to_string(), namespace.clone());
+            storage::restore_state(&app_state, port, SyncPolicy::default(), &id_keys);
+            std::fs::write(&file, Snapshot::capture(&app_state).encode()?)?;
+            info!("Exported namespace '{}' of port {} to {:?}", namespace.name, port, file);
+        }
+        Command::Import { file } => {
+            let bytes = std::fs::read(&file)?;
+            let snapshot = Snapshot::decode_for(&bytes, &namespace.name)?;
+            let pending = storage::get_pending_import_path(namespace, port);
+            if std::path::Path::new(&pending).exists() {
+                anyhow::bail!("a snapshot is already queued in {}", pending);
+            }
+            storage::ensure_data_dir(namespace)?;
+            storage::write_atomic(&pending, &bytes)?;
+            info!(
+                "Queued snapshot by {} ({} log entries) in {}; it is merged when the node starts",
+                snapshot.exported_by,
+                snapshot.data.log.len(),
+                pending
+            );
+        }
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+async fn wait_for_signal() {
+    use tokio::signal::unix::{signal, SignalKind};
+    match signal(SignalKind::terminate()) {
+        Ok(mut sigterm) => {
+            tokio::select! {
+                _ = tokio::signal::ctrl_c() => {}
+                _ = sigterm.recv() => {}
+            }
+        }
+        Err(_) => {
+            let _ = tokio::signal::ctrl_c().await;
+        }
+    }
+}
+
+#[cfg(not(unix))]
+async fn wait_for_signal() {
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+/// Forwards stdin lines to the node of the current namespace. `/ns <name>` switches the
+/// current namespace and `@<name> <line>` sends a single line to another one.
+async fn route_stdin(nodes: Vec<(String, mpsc::UnboundedSender<String>)>) {
+    let mut stdin = io::BufReader::new(io::stdin()).lines();
+    let mut current = 0;
+
+    while let Ok(Some(line)) = stdin.next_line().await {
+        let (target, line) = if let Some(name) = line.strip_prefix("/ns ") {
+            match nodes.iter().position(|(n, _)| n == name.trim()) {
+                Some(i) => {
+                    current = i;
+                    info!("Switched to namespace '{}'", nodes[i].0);
+                }
+                None => info!("Unknown namespace '{}'", name.trim()),
+            }
+            continue;
+        } else if let Some(rest) = line.strip_prefix('@') {
+            let (name, rest) = rest.split_once(' ').unwrap_or((rest, ""));
+            match nodes.iter().position(|(n, _)| n == name) {
+                Some(i) => (i, rest.to_string()),
+                None => {
+                    info!("Unknown namespace '{}'", name);
+                    continue;
+                }
+            }
+        } else {
+            (current, line)
+        };
+
+        let _ = nodes[target].1.send(line);
+    }
 }
 
-fn load_or_generate_keypair(port: u16) -> anyhow::Result<identity::Keypair> {
+fn load_or_generate_keypair(port: u16, namespace: &Namespace) -> anyhow::Result<identity::Keypair> {
     let dir = std::path::Path::new(".key");
     if !dir.exists() {
         std::fs::create_dir(dir)?;
     }
     
-    let file_path = dir.join(format!("identity_{}.key", port));
+    // Each namespace needs its own PeerId, as its swarm is a separate node on the network
+    let file_path = if namespace.is_default() {
+        dir.join(format!("identity_{}.key", port))
+    } else {
+        dir.join(format!("identity_{}_{}.key", namespace.name, port))
+    };
     
     if file_path.exists() {
         info!("Loading identity from {:?}", file_path);
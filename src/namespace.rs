@@ -0,0 +1,137 @@
+use anyhow::{anyhow, Result};
+use base64::{Engine as _, engine::general_purpose::STANDARD as BASE64_STANDARD};
+use chacha20poly1305::aead::Aead;
+use chacha20poly1305::{ChaCha20Poly1305, Key, KeyInit, Nonce};
+use libp2p::gossipsub;
+use rand::RngCore;
+use std::path::Path;
+use tracing::info;
+
+pub const DEFAULT_NAMESPACE: &str = "default";
+
+/// An independent mesh sharing the process with others.
+///
+/// The default namespace keeps the historical topic names and storage paths, so existing
+/// deployments are unaffected. Any other namespace prefixes its topics with `<name>/` and
+/// stores its files under `data/<name>/`. With a network key, every gossipsub payload is
+/// sealed with it, so nodes without the key cannot read or inject messages.
+#[derive(Clone)]
+pub struct Namespace {
+    pub name: String,
+    network_key: Option<[u8; 32]>,
+    /// Directory holding the storage files of every namespace, `data` by default.
+    data_root: String,
+}
+
+impl std::fmt::Debug for Namespace {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Namespace")
+            .field("name", &self.name)
+            .field("network_key", &self.network_key.map(|_| "<redacted>"))
+            .finish()
+    }
+}
+
+impl Default for Namespace {
+    fn default() -> Self {
+        Self { name: DEFAULT_NAMESPACE.to_string(), network_key: None, data_root: "data".to_string() }
+    }
+}
+
+impl Namespace {
+    pub fn new(name: &str, network_key: Option<[u8; 32]>) -> Result<Self> {
+        let valid = !name.is_empty()
+            && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+        if !valid {
+            return Err(anyhow!("invalid namespace name '{}': use letters, digits, '-' or '_'", name));
+        }
+        Ok(Self { name: name.to_string(), network_key, data_root: "data".to_string() })
+    }
+
+    pub fn with_data_root(mut self, data_root: impl Into<String>) -> Self {
+        self.data_root = data_root.into();
+        self
+    }
+
+    /// Parses a `--namespace` argument: `name` or `name=<key file>`. A missing key file is
+    /// created with a fresh random key, to be copied to the other nodes of the namespace.
+    pub fn parse(spec: &str) -> Result<Self> {
+        match spec.split_once('=') {
+            Some((name, key_path)) => Self::new(name, Some(load_or_generate_network_key(Path::new(key_path))?)),
+            None => Self::new(spec, None),
+        }
+    }
+
+    pub fn is_default(&self) -> bool {
+        self.name == DEFAULT_NAMESPACE
+    }
+
+    pub fn topic(&self, base: &str) -> gossipsub::IdentTopic {
+        if self.is_default() {
+            gossipsub::IdentTopic::new(base)
+        } else {
+            gossipsub::IdentTopic::new(format!("{}/{}", self.name, base))
+        }
+    }
+
+    pub fn data_dir(&self) -> String {
+        if self.is_default() {
+            self.data_root.clone()
+        } else {
+            format!("{}/{}", self.data_root, self.name)
+        }
+    }
+
+    /// Encrypts an outgoing payload with the network key (nonce prepended), if there is one.
+    pub fn seal(&self, data: Vec<u8>) -> Vec<u8> {
+        let Some(key) = &self.network_key else {
+            return data;
+        };
+        let cipher = ChaCha20Poly1305::new(Key::from_slice(key));
+        let mut nonce = [0u8; 12];
+        rand::thread_rng().fill_bytes(&mut nonce);
+
+        match cipher.encrypt(Nonce::from_slice(&nonce), data.as_slice()) {
+            Ok(ciphertext) => {
+                let mut sealed = nonce.to_vec();
+                sealed.extend(ciphertext);
+                sealed
+            }
+            Err(_) => Vec::new(),
+        }
+    }
+
+    /// Reverses `seal`. Returns `None` for payloads not sealed with this namespace's key.
+    pub fn open(&self, data: &[u8]) -> Option<Vec<u8>> {
+        let Some(key) = &self.network_key else {
+            return Some(data.to_vec());
+        };
+        if data.len() < 12 {
+            return None;
+        }
+        let cipher = ChaCha20Poly1305::new(Key::from_slice(key));
+        let (nonce, ciphertext) = data.split_at(12);
+        cipher.decrypt(Nonce::from_slice(nonce), ciphertext).ok()
+    }
+}
+
+fn load_or_generate_network_key(path: &Path) -> Result<[u8; 32]> {
+    if path.exists() {
+        let encoded = std::fs::read_to_string(path)?;
+        let bytes = BASE64_STANDARD.decode(encoded.trim())?;
+        return bytes
+            .try_into()
+            .map_err(|_| anyhow!("network key in {:?} must be 32 bytes (base64)", path));
+    }
+
+    info!("Generating new network key and saving to {:?}", path);
+    let mut key = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut key);
+    if let Some(parent) = path.parent() {
+        if !parent.as_os_str().is_empty() {
+            std::fs::create_dir_all(parent)?;
+        }
+    }
+    std::fs::write(path, BASE64_STANDARD.encode(key))?;
+    Ok(key)
+}
@@ -1,22 +1,46 @@
-use crdts::{GSet, CvRDT};
 use libp2p::{
-    gossipsub, mdns, noise, ping, swarm::NetworkBehaviour, swarm::SwarmEvent, tcp, yamux, PeerId, Swarm,
+    core::{muxing::StreamMuxerBox, transport::{Boxed, MemoryTransport}, upgrade},
+    gossipsub, mdns, noise, ping, request_response,
+    swarm::{behaviour::toggle::Toggle, NetworkBehaviour, SwarmEvent},
+    tcp, yamux, Multiaddr, PeerId, Swarm, Transport,
 };
 use libp2p::futures::StreamExt;
 use std::collections::hash_map::DefaultHasher;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::hash::{Hash, Hasher};
-use std::time::Duration;
-use tokio::io::{self, AsyncBufReadExt};
+use std::time::{Duration, Instant};
 use tracing::{info, error};
-use anyhow::Result;
-use crate::state::{AppState, DmEntry};
+use anyhow::{anyhow, Result};
+use crate::state::{AppState, ChatMessage, DmEntry, MAX_CHAT_HISTORY};
 use crate::telemetry::NetworkEvent;
 use crate::http;
-use crate::ble;
 use crate::storage;
+use crate::namespace::Namespace;
+use crate::bandwidth::{Bandwidth, Direction, MeteredMuxer};
+use crate::chaos::{Chaos, ChaosConfig, ChaosMuxer};
+use crate::entry::{self, EntryKey, HorizonSignature, LogEntry, Tombstone};
+use crate::hlc::{self, Hlc};
+use crate::retention::{CollectionRetention, RetentionPolicy};
+use crate::snapshot::Snapshot;
+use crate::kv::{self, KvChange, KvMessage};
+use crate::counter::{CounterChange, CounterMessage};
+use crate::collection::{self, Collection, CollectionKind, CollectionMessage, CollectionOp, CollectionUpdate};
+use crate::document::DocOp;
+use crate::replica::{Replica, ReplicaMessage};
+use crate::replication::VersionVector;
+use crate::watch::{collection_view, View};
+use crate::wal::{LogStore, SyncPolicy, WalRecord};
+use crate::logset::LogSet;
+use crate::sync::{
+    AntiEntropy, ChunkedTransfers, CrdtMessage, LogDigest, SyncCodec, SyncMessage, SyncProgress, CHUNK_RETRY_INTERVAL, CHUNK_SIZE,
+    SESSION_TIMEOUT, SYNC_PROTOCOL,
+};
+use crate::transport::{ConnectivityScore, FallbackLink, LinkFrame, Reassembler, Route, TransportManager};
+use std::ops::Deref;
+use std::sync::{Arc, RwLock, RwLockWriteGuard};
+use crate::version::{self, PeerVersion};
 use std::time::{SystemTime, UNIX_EPOCH};
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, watch};
 use libp2p::identify;
 use base64::{Engine as _, engine::general_purpose::STANDARD as BASE64_STANDARD};
 use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce, KeyInit};
@@ -30,86 +54,384 @@ struct PrivateMessage {
     nonce: String, // Base64 encoded
 }
 
+/// Which transport the swarm runs on.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TransportKind {
+    /// TCP on all interfaces, with mDNS discovery.
+    #[default]
+    Tcp,
+    /// In-process libp2p memory transport without mDNS; peers are found through
+    /// `NodeCommand::Dial`. Used by the integration tests.
+    Memory,
+}
+
+/// Startup options that are not tied to the listen port or identity.
+#[derive(Clone, Debug, Default)]
+pub struct NodeOptions {
+    /// Default per-peer bandwidth cap in bytes per second.
+    pub peer_bandwidth_cap: Option<u64>,
+    /// Mesh this node takes part in; see `Namespace`.
+    pub namespace: Namespace,
+    pub transport: TransportKind,
+    /// Faults injected from startup; adjustable later through `NodeCommand::SetChaos`.
+    pub chaos: ChaosConfig,
+    /// Low-bandwidth link for peers whose connectivity score drops below the switch threshold.
+    pub fallback_link: Option<Arc<dyn FallbackLink>>,
+    /// Port of the web dashboard and HTTP API, `None` to run without it.
+    pub web_port: Option<u16>,
+    /// Peer IDs allowed to remove any log entry; see `entry::Tombstone::permitted`.
+    pub log_admins: HashSet<String>,
+    /// Retention of the log; adjustable later through `NodeCommand::SetRetention`.
+    pub retention: RetentionPolicy,
+    /// When the log's WAL is synced to disk.
+    pub wal_sync: SyncPolicy,
+}
+
+/// Channels through which a node is driven from outside the swarm loop.
+pub struct NodeIo {
+    /// Sender side of `commands`, handed to the web server.
+    pub command_tx: mpsc::UnboundedSender<NodeCommand>,
+    pub commands: mpsc::UnboundedReceiver<NodeCommand>,
+    /// CLI lines (normally stdin, routed by `main`).
+    pub lines: mpsc::UnboundedReceiver<String>,
+    /// Graceful shutdown starts once this flips to `true`.
+    pub shutdown: watch::Receiver<bool>,
+}
+
+impl NodeIo {
+    pub fn new(lines: mpsc::UnboundedReceiver<String>, shutdown: watch::Receiver<bool>) -> Self {
+        let (command_tx, commands) = mpsc::unbounded_channel();
+        Self { command_tx, commands, lines, shutdown }
+    }
+}
+
+/// Node-level announcements on the control topic.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "type")]
+enum ControlMessage {
+    /// Sent once during graceful shutdown so peers drop us without waiting for timeouts.
+    /// The leaving peer is the message's signed source, so no peer can evict another.
+    Goodbye { reason: String },
+}
+
+/// How often a node publishes its log digest.
+const DIGEST_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How often the retention policy is applied to the log.
+const RETENTION_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Minimum time between full-state requests to the same peer.
+const STATE_REQUEST_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How long mDNS discoveries of a peer rejected on version are ignored before it is dialed
+/// again, in case it upgraded.
+const INCOMPATIBLE_RETRY: Duration = Duration::from_secs(600);
+
+/// How long to keep driving the swarm after publishing the goodbye, so it gets flushed.
+const GOODBYE_GRACE: Duration = Duration::from_millis(500);
+
+/// Upper bound for closing all connections during shutdown.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Largest gossipsub message. CRDT messages above `sync::CHUNK_SIZE` are chunked to fit.
+const MAX_TRANSMIT_SIZE: usize = 64 * 1024;
+
 #[derive(Debug)]
 pub enum NodeCommand {
+    /// Appends a plain-text entry to the shared log.
     Log(String),
+    /// Appends an entry with a JSON body and tags to the shared log.
+    LogJson { body: serde_json::Value, tags: Vec<String> },
+    /// Removes the log entry with this id by `author`. Refused unless this node wrote it or
+    /// is a log admin.
+    RemoveLog { id: String, author: String },
+    /// Replaces the log's retention policy and compacts the log right away.
+    SetRetention(RetentionPolicy),
+    /// Merges a snapshot, already checked with `Snapshot::decode_for`, into the local state.
+    ImportSnapshot(Box<Snapshot>),
+    /// Sets an attribute of a device in the replicated KV store.
+    KvPut { device: String, attribute: String, value: serde_json::Value },
+    KvDelete { device: String, attribute: String },
+    /// Adds `amount` to a replicated counter, creating it at zero first if needed.
+    /// Negative amounts decrement.
+    CounterAdd { name: String, amount: i64 },
+    /// Opens a named collection on this node and joins its topic. Opening an existing
+    /// collection again with the same kind does nothing.
+    CreateCollection { name: String, kind: CollectionKind },
+    /// Leaves a collection's topic and deletes its local copy.
+    DropCollection(String),
+    UpdateCollection { name: String, update: CollectionUpdate },
+    /// Replaces the limits of an open collection and trims it right away. The default
+    /// removes them.
+    SetCollectionRetention { name: String, retention: CollectionRetention },
+    Chat(String),
     SendDm { to: String, content: String },
+    /// Dials an address, e.g. one found by an external discovery mechanism. A trailing
+    /// `/p2p/<peer id>` also adds the peer as an explicit gossipsub peer, like mDNS does.
+    Dial(Multiaddr),
+    /// Replaces the fault injection settings and cuts connections to newly partitioned peers.
+    SetChaos(ChaosConfig),
+    /// Closes the connections to one peer, or to every peer, to rehearse a link reset.
+    ResetConnections(Option<PeerId>),
 }
 
 // We create a custom network behaviour that combines Gossipsub and Mdns.
 #[derive(NetworkBehaviour)]
 pub struct MyBehaviour {
     pub gossipsub: gossipsub::Behaviour,
-    pub mdns: mdns::tokio::Behaviour,
+    pub mdns: Toggle<mdns::tokio::Behaviour>,
     pub ping: ping::Behaviour,
     pub identify: identify::Behaviour,
+    /// Anti-entropy sessions, exchanged only with the peer involved.
+    pub sync: request_response::Behaviour<SyncCodec>,
 }
 
-pub async fn run_node(port: u16, id_keys: libp2p::identity::Keypair) -> Result<()> {
-    let mut swarm = create_swarm(port, id_keys).await?;
+/// Runs one node of a namespace on `app_state`, which must have been created for `id_keys`.
+/// Returns after a graceful shutdown once `io.shutdown` flips to `true`.
+pub async fn run_node(
+    port: u16,
+    id_keys: libp2p::identity::Keypair,
+    options: NodeOptions,
+    app_state: AppState,
+    io: NodeIo,
+) -> Result<()> {
+    let NodeIo { command_tx: log_tx, commands: mut log_rx, mut lines, mut shutdown } = io;
+    let namespace = options.namespace.clone();
+    app_state.bandwidth.set_default_cap(options.peer_bandwidth_cap);
+    app_state.chaos.set(options.chaos.clone());
+    *app_state.log_admins.write().unwrap() = options.log_admins.clone();
+    *app_state.retention.write().unwrap() = options.retention;
+    *app_state.transports.write().unwrap() = TransportManager::new(options.fallback_link.clone()).with_identity(id_keys.clone());
+
+    // Signs the log entries written on this node
+    let keypair = id_keys.clone();
+
+    let mut swarm = create_swarm(
+        port,
+        id_keys,
+        app_state.bandwidth.clone(),
+        app_state.chaos.clone(),
+        &namespace,
+        options.transport,
+    )
+    .await?;
 
     // Subscribe to topics
-    let topic_global = gossipsub::IdentTopic::new("ghostmesh-global");
-    let topic_crdt = gossipsub::IdentTopic::new("ghostmesh-crdt");
-    let topic_private = gossipsub::IdentTopic::new("ghostmesh-private");
+    let topic_global = namespace.topic("ghostmesh-global");
+    let topic_crdt = namespace.topic("ghostmesh-crdt");
+    let topic_private = namespace.topic("ghostmesh-private");
+    let topic_control = namespace.topic("ghostmesh-control");
+    let mut kv = ReplicaTopic::new(namespace.topic("ghostmesh-kv"), &app_state.kv, "device store");
+    let mut counters = ReplicaTopic::new(namespace.topic("ghostmesh-counters"), &app_state.counters, "counters");
     
     swarm.behaviour_mut().gossipsub.subscribe(&topic_global)?;
     swarm.behaviour_mut().gossipsub.subscribe(&topic_crdt)?;
     swarm.behaviour_mut().gossipsub.subscribe(&topic_private)?;
+    swarm.behaviour_mut().gossipsub.subscribe(&topic_control)?;
+    swarm.behaviour_mut().gossipsub.subscribe(&kv.topic)?;
+    swarm.behaviour_mut().gossipsub.subscribe(&counters.topic)?;
 
-    // Initialize App State
-    let local_peer_id = swarm.local_peer_id().to_string();
-    let app_state = AppState::new(local_peer_id);
-    
-    // Load existing log
-    if let Ok(loaded_log) = storage::load_log(port) {
-        *app_state.log.write().unwrap() = loaded_log;
+    // Load everything persisted by earlier runs, then reopen the collections
+    storage::restore_state(&app_state, port, options.wal_sync, &keypair);
+    seed_watch(&app_state);
+    let mut collections: HashMap<String, ReplicaTopic<Collection>> = HashMap::new();
+    for (name, collection) in app_state.collections.read().unwrap().iter() {
+        let replica = ReplicaTopic::new(collection_topic(&namespace, name), collection, "collection");
+        swarm.behaviour_mut().gossipsub.subscribe(&replica.topic)?;
+        collections.insert(name.clone(), replica);
     }
 
+    // A snapshot queued by `ghostmesh import` while the node was stopped
+    let pending_import = storage::get_pending_import_path(&namespace, port);
+    if std::path::Path::new(&pending_import).exists() {
+        let imported = std::fs::read(&pending_import)
+            .map_err(anyhow::Error::from)
+            .and_then(|bytes| Snapshot::decode_for(&bytes, &namespace.name))
+            .and_then(|snapshot| import_snapshot(&mut swarm, &app_state, port, &mut collections, snapshot));
+        if let Err(e) = imported {
+            error!("Failed to import queued snapshot {}: {:?}", pending_import, e);
+        }
+        if let Err(e) = std::fs::remove_file(&pending_import) {
+            error!("Failed to remove queued snapshot {}: {:?}", pending_import, e);
+        }
+    }
+
+    // Frames from the fallback link, reassembled into whole messages
+    let local_peer_id = *swarm.local_peer_id();
+    let mut fallback = options
+        .fallback_link
+        .as_ref()
+        .map(|link| (link.attach(local_peer_id), Reassembler::default()));
+
     // Track pending dials to prevent storms
     let mut pending_dials: HashSet<PeerId> = HashSet::new();
-
-    // Channel for Web -> P2P communication
-    let (log_tx, mut log_rx) = mpsc::unbounded_channel::<NodeCommand>();
+    // When each peer was last rejected on version
+    let mut rejected: HashMap<PeerId, Instant> = HashMap::new();
 
     // Spawn Web Server
-    let web_state = app_state.clone();
-    let web_tx = log_tx.clone();
-    let web_port = port + 1;
-    tokio::spawn(async move {
-        http::start_server(web_port, web_state, web_tx).await;
+    let web_server = options.web_port.map(|web_port| {
+        let web_state = app_state.clone();
+        let web_tx = log_tx.clone();
+        let mut web_shutdown = shutdown.clone();
+        tokio::spawn(async move {
+            let stop = async move {
+                let _ = web_shutdown.wait_for(|stop| *stop).await;
+            };
+            http::start_server(web_port, web_state, web_tx, stop).await;
+        })
     });
+    drop(log_tx);
 
-    // Spawn BLE Service
-    tokio::spawn(async move {
-        if let Err(e) = ble::run_ble_service().await {
-            error!("BLE Service error: {:?}", e);
-        }
+    // Periodic liveness sweep for peers that stopped answering pings
+    let mut health_tick = tokio::time::interval(Duration::from_secs(30));
+
+    // Bandwidth rate sampling
+    let mut bandwidth_tick = tokio::time::interval(Duration::from_secs(10));
+
+    // Log digests for gap detection
+    let mut digest_tick = tokio::time::interval(DIGEST_INTERVAL);
+    let mut state_requests: HashMap<PeerId, Instant> = HashMap::new();
+
+    // Retention, also applied once the loaded log is in place
+    let mut retention_tick = tokio::time::interval(RETENTION_INTERVAL);
+
+    // Anti-entropy sessions run with each peer once it is connected, identified and
+    // subscribed to the CRDT topic, so it has the log's horizon first
+    let mut anti_entropy = AntiEntropy::default();
+
+    // CRDT messages too large for one gossipsub message, and re-requests of lost chunks
+    let mut transfers = ChunkedTransfers::default();
+    let mut chunk_tick = tokio::time::interval(CHUNK_RETRY_INTERVAL);
+
+    // Syncs the log's WAL when it is synced on an interval
+    let mut wal_tick = tokio::time::interval(match options.wal_sync {
+        SyncPolicy::Interval(interval) => interval,
+        _ => Duration::from_secs(1),
     });
 
-    // Read from stdin
-    let mut stdin = io::BufReader::new(io::stdin()).lines();
+    // Connectivity scoring for transport switching
+    let mut transport_tick = tokio::time::interval(Duration::from_secs(10));
 
-    info!("GhostMesh Node Started on port {}. Web Dashboard: http://localhost:{}", port, web_port);
+    match options.web_port {
+        Some(web_port) => info!(
+            "GhostMesh Node Started on port {} (namespace '{}'). Web Dashboard: http://localhost:{}",
+            port, namespace.name, web_port
+        ),
+        None => info!("GhostMesh Node Started on port {} (namespace '{}')", port, namespace.name),
+    }
 
     loop {
         tokio::select! {
+            _ = shutdown.wait_for(|stop| *stop) => break,
             // Handle Web Input (Log)
             Some(cmd) = log_rx.recv() => {
                 match cmd {
                     NodeCommand::Log(msg) => {
-                        app_state.log.write().unwrap().insert(msg.clone());
                         info!("Web Logged: {}", msg);
-                        if let Err(e) = storage::save_log(port, &*app_state.log.read().unwrap()) {
-                            error!("Failed to save log: {:?}", e);
+                        let entry = LogEntry::text(&keypair, app_state.clock.write().unwrap().now(), &msg);
+                        if let Err(e) = append_log(&mut swarm, &app_state, &mut transfers, port, &topic_crdt, &keypair, entry) {
+                            error!("Failed to publish log entry: {:?}", e);
                         }
-                        
-                        // Broadcast new state
-                        let state_bytes = serde_json::to_vec(&*app_state.log.read().unwrap())?;
-                        if let Err(e) = swarm.behaviour_mut().gossipsub.publish(topic_crdt.clone(), state_bytes) {
-                            error!("Publish error: {:?}", e);
+                    }
+                    NodeCommand::LogJson { body, tags } => {
+                        info!("Web Logged: {} {:?}", body, tags);
+                        let entry = LogEntry::new(&keypair, app_state.clock.write().unwrap().now(), tags, body);
+                        if let Err(e) = append_log(&mut swarm, &app_state, &mut transfers, port, &topic_crdt, &keypair, entry) {
+                            error!("Failed to publish log entry: {:?}", e);
+                        }
+                    }
+                    NodeCommand::RemoveLog { id, author } => {
+                        if let Err(e) = remove_log(&mut swarm, &app_state, &mut transfers, &topic_crdt, &keypair, &id, Some(&author)) {
+                            error!("Failed to remove log entry {}: {:?}", id, e);
+                        }
+                    }
+                    NodeCommand::SetRetention(policy) => {
+                        info!("Retention policy set to {:?}", policy);
+                        *app_state.retention.write().unwrap() = policy;
+                        enforce_retention(&mut swarm, &app_state, &mut transfers, port, &topic_crdt, &keypair);
+                    }
+                    NodeCommand::ImportSnapshot(snapshot) => {
+                        if let Err(e) = import_snapshot(&mut swarm, &app_state, port, &mut collections, *snapshot) {
+                            error!("Failed to import snapshot: {:?}", e);
+                        }
+                    }
+                    NodeCommand::KvPut { device, attribute, value } => {
+                        if let Err(e) = put_kv(&mut swarm, &app_state, port, &kv.topic, &device, &attribute, value) {
+                            error!("Failed to publish {}/{}: {:?}", device, attribute, e);
+                        }
+                    }
+                    NodeCommand::KvDelete { device, attribute } => {
+                        if let Err(e) = delete_kv(&mut swarm, &app_state, port, &kv.topic, &device, &attribute) {
+                            error!("Failed to publish deletion of {}/{}: {:?}", device, attribute, e);
+                        }
+                    }
+                    NodeCommand::CounterAdd { name, amount } => {
+                        if let Err(e) = add_counter(&mut swarm, &app_state, port, &counters.topic, &name, amount) {
+                            error!("Failed to update counter {}: {:?}", name, e);
+                        }
+                    }
+                    NodeCommand::CreateCollection { name, kind } => {
+                        if let Err(e) = create_collection(&mut swarm, &app_state, port, &mut collections, &name, kind) {
+                            error!("Failed to create collection {}: {:?}", name, e);
+                        }
+                    }
+                    NodeCommand::DropCollection(name) => {
+                        if let Err(e) = drop_collection(&mut swarm, &app_state, port, &mut collections, &name) {
+                            error!("Failed to drop collection {}: {:?}", name, e);
+                        }
+                    }
+                    NodeCommand::UpdateCollection { name, update } => {
+                        if let Err(e) = update_collection(&mut swarm, &app_state, port, &collections, &name, update) {
+                            error!("Failed to update collection {}: {:?}", name, e);
                         }
                     }
+                    NodeCommand::SetCollectionRetention { name, retention } => {
+                        if let Err(e) = set_collection_retention(&mut swarm, &app_state, port, &collections, &name, retention) {
+                            error!("Failed to set retention of collection {}: {:?}", name, e);
+                        }
+                    }
+                    NodeCommand::Dial(addr) => {
+                        if let Some(libp2p::multiaddr::Protocol::P2p(peer_id)) = addr.iter().last() {
+                            swarm.behaviour_mut().gossipsub.add_explicit_peer(&peer_id);
+                        }
+                        if let Err(e) = swarm.dial(addr.clone()) {
+                            error!("Dial error for {}: {:?}", addr, e);
+                        }
+                    }
+                    NodeCommand::SetChaos(config) => {
+                        info!("Chaos config set to {:?}", config);
+                        app_state.chaos.set(config);
+                        let local = *swarm.local_peer_id();
+                        let partitioned: Vec<PeerId> = swarm
+                            .connected_peers()
+                            .filter(|peer| app_state.chaos.is_partitioned(&local, peer))
+                            .copied()
+                            .collect();
+                        for peer_id in partitioned {
+                            let _ = app_state.telemetry_tx.send(NetworkEvent::FaultInjected {
+                                kind: "partition".to_string(),
+                                peer_id: Some(peer_id.to_string()),
+                            });
+                            let _ = swarm.disconnect_peer_id(peer_id);
+                        }
+                    }
+                    NodeCommand::ResetConnections(peer) => {
+                        let targets: Vec<PeerId> = match peer {
+                            Some(peer_id) => vec![peer_id],
+                            None => swarm.connected_peers().copied().collect(),
+                        };
+                        for peer_id in targets {
+                            info!("Chaos: resetting connections to {}", peer_id);
+                            let _ = app_state.telemetry_tx.send(NetworkEvent::FaultInjected {
+                                kind: "reset".to_string(),
+                                peer_id: Some(peer_id.to_string()),
+                            });
+                            let _ = swarm.disconnect_peer_id(peer_id);
+                        }
+                    }
+                    NodeCommand::Chat(content) => {
+                        publish_chat(&mut swarm, &app_state, port, &topic_global, content);
+                    }
                     NodeCommand::SendDm { to, content } => {
                         // Logic reused from /dm command
                          if let Ok(target_peer_id) = to.parse::<PeerId>() {
@@ -127,7 +449,7 @@ pub async fn run_node(port: u16, id_keys: libp2p::identity::Keypair) -> Result<(
                                     };
                                     
                                     if let Ok(json) = serde_json::to_vec(&payload) {
-                                        if let Err(e) = swarm.behaviour_mut().gossipsub.publish(topic_private.clone(), json) {
+                                        if let Err(e) = publish(&mut swarm, &app_state, &topic_private, json) {
                                             error!("Publish error: {:?}", e);
                                         } else {
                                             info!("Web Sent encrypted DM to {}", to);
@@ -143,9 +465,9 @@ pub async fn run_node(port: u16, id_keys: libp2p::identity::Keypair) -> Result<(
                     }
                 }
             }
-            // Handle Stdin Input
-            line = stdin.next_line() => {
-                if let Ok(Some(line)) = line {
+            // Handle CLI Input
+            line = lines.recv() => {
+                if let Some(line) = line {
                     if line.starts_with("/") {
                         let parts: Vec<&str> = line.split_whitespace().collect();
                         match parts[0] {
@@ -154,25 +476,171 @@ pub async fn run_node(port: u16, id_keys: libp2p::identity::Keypair) -> Result<(
                                 info!("Connected Peers: {} - {:?}", peers.len(), peers);
                                 let gossip_peers: Vec<_> = swarm.behaviour().gossipsub.all_peers().collect();
                                 info!("Gossipsub Peers: {} - {:?}", gossip_peers.len(), gossip_peers);
+                                for peer in app_state.peer_report() {
+                                    match peer.replication {
+                                        Some(_) if peer.in_sync => info!("{}: in sync", peer.peer_id),
+                                        Some(status) => info!(
+                                            "{}: {} entries behind, lagging {} ms{}{}",
+                                            peer.peer_id,
+                                            status.missing,
+                                            peer.lag_ms,
+                                            if status.unknown > 0 { format!(", {} entries unknown", status.unknown) } else { String::new() },
+                                            if status.ahead { ", has entries this node lacks" } else { "" }
+                                        ),
+                                        None => info!("{}: replication status unknown", peer.peer_id),
+                                    }
+                                }
                             }
                             "/log" => {
                                 if parts.len() > 1 {
                                     let msg = parts[1..].join(" ");
-                                    app_state.log.write().unwrap().insert(msg.clone());
                                     info!("Logged: {}", msg);
-                                    if let Err(e) = storage::save_log(port, &*app_state.log.read().unwrap()) {
-                                        error!("Failed to save log: {:?}", e);
-                                    }
-                                    
-                                    // Broadcast new state
-                                    let state_bytes = serde_json::to_vec(&*app_state.log.read().unwrap())?;
-                                    if let Err(e) = swarm.behaviour_mut().gossipsub.publish(topic_crdt.clone(), state_bytes) {
-                                        error!("Publish error: {:?}", e);
+                                    let entry = LogEntry::text(&keypair, app_state.clock.write().unwrap().now(), &msg);
+                                    if let Err(e) = append_log(&mut swarm, &app_state, &mut transfers, port, &topic_crdt, &keypair, entry) {
+                                        error!("Failed to publish log entry: {:?}", e);
                                     }
                                 } else {
                                     info!("Usage: /log <message>");
                                 }
                             }
+                            "/rm" => {
+                                if parts.len() == 2 || parts.len() == 3 {
+                                    match remove_log(&mut swarm, &app_state, &mut transfers, &topic_crdt, &keypair, parts[1], parts.get(2).copied()) {
+                                        Ok(()) => info!("Removed log entry {}", parts[1]),
+                                        Err(e) => error!("Failed to remove log entry {}: {:?}", parts[1], e),
+                                    }
+                                } else {
+                                    info!("Usage: /rm <entry id> [author]");
+                                }
+                            }
+                            "/retain" => {
+                                let policy = match parts.get(1..).unwrap_or_default() {
+                                    [] => {
+                                        info!(
+                                            "Retention: {:?}, compacted up to {:?}",
+                                            app_state.retention.read().unwrap(),
+                                            app_state.log.read().unwrap().horizon()
+                                        );
+                                        None
+                                    }
+                                    ["off"] => Some(Ok(RetentionPolicy::default())),
+                                    [spec] => Some(RetentionPolicy::parse(spec)),
+                                    _ => {
+                                        info!("Usage: /retain [off | max-age=<n>[s|m|h|d],max-entries=<n>,max-bytes=<n>[k|M|G],archive]");
+                                        None
+                                    }
+                                };
+                                match policy {
+                                    Some(Ok(policy)) => {
+                                        info!("Retention policy set to {:?}", policy);
+                                        *app_state.retention.write().unwrap() = policy;
+                                        enforce_retention(&mut swarm, &app_state, &mut transfers, port, &topic_crdt, &keypair);
+                                    }
+                                    Some(Err(e)) => info!("{}", e),
+                                    None => {}
+                                }
+                            }
+                            "/kv" => match parts.get(1..).unwrap_or_default() {
+                                ["get", device] => {
+                                    info!("{}: {:?}", device, app_state.kv.read().unwrap().device(device));
+                                }
+                                ["get", device, attribute] => {
+                                    info!("{}/{}: {:?}", device, attribute, app_state.kv.read().unwrap().get(device, attribute));
+                                }
+                                ["put", device, attribute, value @ ..] if !value.is_empty() => {
+                                    // JSON when it parses (`50`, `true`), otherwise text (`ON`)
+                                    let value = value.join(" ");
+                                    let value = serde_json::from_str(&value).unwrap_or(serde_json::Value::String(value));
+                                    if let Err(e) = put_kv(&mut swarm, &app_state, port, &kv.topic, device, attribute, value) {
+                                        error!("Failed to publish {}/{}: {:?}", device, attribute, e);
+                                    }
+                                }
+                                ["del", device, attribute] => {
+                                    if let Err(e) = delete_kv(&mut swarm, &app_state, port, &kv.topic, device, attribute) {
+                                        error!("Failed to delete {}/{}: {:?}", device, attribute, e);
+                                    }
+                                }
+                                _ => info!("Usage: /kv get <device> [attribute] | /kv put <device> <attribute> <value> | /kv del <device> <attribute>"),
+                            },
+                            "/count" => match parts.get(1..).unwrap_or_default() {
+                                ["get"] => info!("Counters: {:?}", app_state.counters.read().unwrap().all()),
+                                ["get", name] => info!("{}: {:?}", name, app_state.counters.read().unwrap().get(name)),
+                                [dir @ ("inc" | "dec"), name, amount @ ..] if amount.len() <= 1 => {
+                                    match amount.first().map_or(Ok(1), |amount| amount.parse::<i64>()) {
+                                        Ok(amount) if amount > 0 => {
+                                            let amount = if *dir == "dec" { -amount } else { amount };
+                                            if let Err(e) = add_counter(&mut swarm, &app_state, port, &counters.topic, name, amount) {
+                                                error!("Failed to update counter {}: {:?}", name, e);
+                                            }
+                                        }
+                                        _ => info!("Amount must be a positive integer"),
+                                    }
+                                }
+                                _ => info!("Usage: /count get [name] | /count inc <name> [amount] | /count dec <name> [amount]"),
+                            },
+                            "/col" => {
+                                let args = parts.get(1..).unwrap_or_default();
+                                // JSON when it parses, otherwise text, like `/kv put`
+                                let value = |words: &[&str]| {
+                                    let value = words.join(" ");
+                                    serde_json::from_str(&value).unwrap_or(serde_json::Value::String(value))
+                                };
+                                let update = match args {
+                                    ["add", name, member] => Some((name, CollectionUpdate::Add { member: member.to_string() })),
+                                    ["rm", name, member] => Some((name, CollectionUpdate::Remove { member: member.to_string() })),
+                                    ["put", name, key, words @ ..] if !words.is_empty() => Some((name, CollectionUpdate::Put { key: key.to_string(), value: value(words) })),
+                                    ["del", name, key] => Some((name, CollectionUpdate::Delete { key: key.to_string() })),
+                                    ["inc", name, by] => by.parse().ok().map(|by| (name, CollectionUpdate::Increment { by })),
+                                    ["set", name, words @ ..] if !words.is_empty() => Some((name, CollectionUpdate::Set { value: value(words) })),
+                                    // A JSON Patch is an array, a merge patch an object
+                                    ["patch", name, words @ ..] => match value(words) {
+                                        patch @ serde_json::Value::Array(_) => Some((name, CollectionUpdate::JsonPatch { patch })),
+                                        patch @ serde_json::Value::Object(_) => Some((name, CollectionUpdate::MergePatch { patch })),
+                                        _ => None,
+                                    },
+                                    _ => None,
+                                };
+                                match (args, update) {
+                                    (["new", name, kind], _) => match CollectionKind::parse(kind) {
+                                        Some(kind) => {
+                                            if let Err(e) = create_collection(&mut swarm, &app_state, port, &mut collections, name, kind) {
+                                                error!("Failed to create collection {}: {:?}", name, e);
+                                            }
+                                        }
+                                        None => info!("Kinds: set, or-set, map, counter, register, document"),
+                                    },
+                                    (["drop", name], _) => {
+                                        if let Err(e) = drop_collection(&mut swarm, &app_state, port, &mut collections, name) {
+                                            error!("Failed to drop collection {}: {:?}", name, e);
+                                        }
+                                    }
+                                    (["retention", name, spec], _) => {
+                                        let retention = match *spec {
+                                            "off" => Ok(CollectionRetention::default()),
+                                            spec => CollectionRetention::parse(spec),
+                                        };
+                                        match retention {
+                                            Ok(retention) => {
+                                                if let Err(e) = set_collection_retention(&mut swarm, &app_state, port, &collections, name, retention) {
+                                                    error!("Failed to set retention of collection {}: {:?}", name, e);
+                                                }
+                                            }
+                                            Err(e) => info!("{}", e),
+                                        }
+                                    }
+                                    (["get"], _) => info!("Collections: {:?}", app_state.snapshot().collections),
+                                    (["get", name], _) => match app_state.collections.read().unwrap().get(*name) {
+                                        Some(collection) => info!("{}: {}", name, collection.read().unwrap().value()),
+                                        None => info!("No collection {}", name),
+                                    },
+                                    (_, Some((name, update))) => {
+                                        if let Err(e) = update_collection(&mut swarm, &app_state, port, &collections, name, update) {
+                                            error!("Failed to update collection {}: {:?}", name, e);
+                                        }
+                                    }
+                                    _ => info!("Usage: /col new <name> <kind> | /col drop <name> | /col get [name] | /col add|rm <name> <member> | /col put <name> <key> <value> | /col del <name> <key> | /col inc <name> <n> | /col set <name> <value> | /col patch <name> <JSON Patch or merge patch> | /col retention <name> <max-entries=<n>,max-bytes=<n>[k|M|G]|off>"),
+                                }
+                            }
                             "/dm" => {
                                 if parts.len() > 2 {
                                     let target_peer_str = parts[1];
@@ -202,7 +670,7 @@ pub async fn run_node(port: u16, id_keys: libp2p::identity::Keypair) -> Result<(
                                                 };
                                                 
                                                 let json = serde_json::to_vec(&payload)?;
-                                                if let Err(e) = swarm.behaviour_mut().gossipsub.publish(topic_private.clone(), json) {
+                                                if let Err(e) = publish(&mut swarm, &app_state, &topic_private, json) {
                                                     error!("Publish error: {:?}", e);
                                                 } else {
                                                     info!("Sent encrypted DM to {}", target_peer_str);
@@ -226,24 +694,153 @@ pub async fn run_node(port: u16, id_keys: libp2p::identity::Keypair) -> Result<(
                                     info!("Usage: /dm <peer_id> <message>");
                                 }
                             }
+                            "/export" => {
+                                if parts.len() == 2 {
+                                    match Snapshot::capture(&app_state).encode().and_then(|bytes| Ok(std::fs::write(parts[1], bytes)?)) {
+                                        Ok(()) => info!("Exported snapshot to {}", parts[1]),
+                                        Err(e) => error!("Failed to export snapshot: {:?}", e),
+                                    }
+                                } else {
+                                    info!("Usage: /export <file>");
+                                }
+                            }
+                            "/import" => {
+                                if parts.len() == 2 {
+                                    let imported = std::fs::read(parts[1])
+                                        .map_err(anyhow::Error::from)
+                                        .and_then(|bytes| Snapshot::decode_for(&bytes, &namespace.name))
+                                        .and_then(|snapshot| import_snapshot(&mut swarm, &app_state, port, &mut collections, snapshot));
+                                    if let Err(e) = imported {
+                                        error!("Failed to import snapshot {}: {:?}", parts[1], e);
+                                    }
+                                } else {
+                                    info!("Usage: /import <file>");
+                                }
+                            }
+                            "/sync" => {
+                                info!("Requesting full state from all peers");
+                                let peers: Vec<PeerId> = swarm.connected_peers().copied().collect();
+                                for peer in peers {
+                                    if sync_with(&mut swarm, &app_state, &mut anti_entropy, peer) {
+                                        continue;
+                                    }
+                                    let request = CrdtMessage::StateRequest { to: Some(peer.to_string()) };
+                                    if let Err(e) = publish_crdt(&mut swarm, &app_state, &mut transfers, &topic_crdt, &request) {
+                                        error!("Failed to request state from {}: {:?}", peer, e);
+                                    }
+                                }
+                            }
                             "/show" => {
-                                info!("Current Log: {:?}", app_state.log.read().unwrap().read());
+                                let entries: Vec<String> = app_state
+                                    .log
+                                    .read()
+                                    .unwrap()
+                                    .read()
+                                    .iter()
+                                    .map(|entry| format!("{} {}", entry.id, entry.text_body()))
+                                    .collect();
+                                info!("Current Log: {:?}", entries);
                             }
-                            _ => info!("Unknown command. Try /peers, /log, or /show"),
+                            _ => info!("Unknown command. Try /peers, /log, /rm, /retain, /kv, /count, /col, /export, /import, /sync or /show"),
                         }
                     } else {
-                        if let Err(e) = swarm.behaviour_mut().gossipsub.publish(topic_global.clone(), line.as_bytes()) {
-                            error!("Publish error: {:?}", e);
-                        }
+                        publish_chat(&mut swarm, &app_state, port, &topic_global, line);
+                    }
+                }
+            }
+            _ = health_tick.tick() => {
+                let mut health = app_state.health.write().unwrap();
+                for (peer_id, peer_health) in health.iter_mut() {
+                    if peer_health.check_silence() {
+                        info!("Peer {} marked suspect: no ping response", peer_id);
+                        let _ = app_state.telemetry_tx.send(NetworkEvent::peer_health(peer_id.to_string(), peer_health));
+                    }
+                }
+            }
+            _ = bandwidth_tick.tick() => {
+                app_state.bandwidth.sample();
+                let total = app_state.bandwidth.report().total;
+                let _ = app_state.telemetry_tx.send(NetworkEvent::Bandwidth {
+                    inbound: total.inbound,
+                    outbound: total.outbound,
+                    inbound_rate: total.inbound_rate,
+                    outbound_rate: total.outbound_rate,
+                });
+            }
+            _ = digest_tick.tick() => {
+                state_requests.retain(|_, at| at.elapsed() < STATE_REQUEST_INTERVAL);
+                if !app_state.peers.read().unwrap().is_empty() && app_state.mesh_supports(version::FEATURE_CRDT_DELTA) {
+                    let (digest, vector) = {
+                        let log = app_state.log.read().unwrap();
+                        (LogDigest::of(&log), VersionVector::of(&log))
+                    };
+                    let digest = CrdtMessage::Digest { digest, vector: Some(vector) };
+                    if let Err(e) = publish_crdt(&mut swarm, &app_state, &mut transfers, &topic_crdt, &digest) {
+                        error!("Failed to publish log digest: {:?}", e);
                     }
+                    report_replication(&app_state, None);
                 }
+                kv.state_requests.retain(|_, at| at.elapsed() < STATE_REQUEST_INTERVAL);
+                counters.state_requests.retain(|_, at| at.elapsed() < STATE_REQUEST_INTERVAL);
+                if !app_state.peers.read().unwrap().is_empty() {
+                    publish_replica_digest(&mut swarm, &app_state, &kv);
+                    publish_replica_digest(&mut swarm, &app_state, &counters);
+                    for replica in collections.values_mut() {
+                        replica.state_requests.retain(|_, at| at.elapsed() < STATE_REQUEST_INTERVAL);
+                        publish_replica_digest(&mut swarm, &app_state, replica);
+                    }
+                }
+                // Repeat the horizon for peers that were away when the log was compacted
+                publish_compact(&mut swarm, &app_state, &mut transfers, &topic_crdt);
+                // Repeat acks for the tombstones held, in case earlier ones were lost
+                if !app_state.peers.read().unwrap().is_empty() && app_state.mesh_supports(version::FEATURE_LOG_REMOVE) {
+                    let entries: Vec<EntryKey> = app_state.log.read().unwrap().tombstones().iter().map(Tombstone::key).collect();
+                    publish_ack(&mut swarm, &app_state, &mut transfers, &topic_crdt, entries);
+                }
+            }
+            _ = wal_tick.tick() => {
+                if let Some(store) = app_state.log_store.lock().unwrap().as_mut() {
+                    if let Err(e) = store.sync() {
+                        error!("Failed to sync log: {:?}", e);
+                    }
+                }
+            }
+            _ = retention_tick.tick() => {
+                enforce_retention(&mut swarm, &app_state, &mut transfers, port, &topic_crdt, &keypair);
             }
-            event = swarm.select_next_some() => match event {
+            _ = chunk_tick.tick() => {
+                for request in transfers.stalled() {
+                    if let CrdtMessage::ChunkRequest { to, transfer, missing } = &request {
+                        info!("Requesting {} missing chunks of transfer {:016x} from {}", missing.len(), transfer, to);
+                    }
+                    if let Err(e) = publish_crdt(&mut swarm, &app_state, &mut transfers, &topic_crdt, &request) {
+                        error!("Failed to request missing chunks: {:?}", e);
+                    }
+                }
+            }
+            _ = transport_tick.tick() => {
+                let peers: HashSet<PeerId> = swarm
+                    .connected_peers()
+                    .copied()
+                    .chain(app_state.transports.read().unwrap().known_peers())
+                    .collect();
+                evaluate_transports(&swarm, &app_state, peers, true);
+            }
+            event = next_event(&mut swarm, &app_state, &mut fallback) => match event {
                 SwarmEvent::NewListenAddr { address, .. } => {
                     info!("Listening on {:?}", address);
+                    app_state.listen_addrs.write().unwrap().push(address.to_string());
+                }
+                SwarmEvent::ExpiredListenAddr { address, .. } => {
+                    info!("No longer listening on {:?}", address);
+                    app_state.listen_addrs.write().unwrap().retain(|a| *a != address.to_string());
                 }
                 SwarmEvent::Behaviour(MyBehaviourEvent::Mdns(mdns::Event::Discovered(list))) => {
                     for (peer_id, multiaddr) in list {
+                        // Don't keep redialing peers we rejected on version, but retry once in a while
+                        if rejected.get(&peer_id).is_some_and(|at| at.elapsed() < INCOMPATIBLE_RETRY) {
+                            continue;
+                        }
                         swarm.behaviour_mut().gossipsub.add_explicit_peer(&peer_id);
                         
                         // Only dial if not already connected and not currently dialing
@@ -266,16 +863,24 @@ pub async fn run_node(port: u16, id_keys: libp2p::identity::Keypair) -> Result<(
                 SwarmEvent::ConnectionEstablished { peer_id, .. } => {
                     info!("Connection established with peer: {peer_id}");
                     app_state.peers.write().unwrap().insert(peer_id);
+                    app_state.health.write().unwrap().entry(peer_id).or_default();
                     pending_dials.remove(&peer_id);
                     
                     let _ = app_state.telemetry_tx.send(NetworkEvent::PeerConnected { peer_id: peer_id.to_string() });
+                    evaluate_transports(&swarm, &app_state, [peer_id], false);
                 }
                 SwarmEvent::ConnectionClosed { peer_id, cause, .. } => {
                     info!("Connection closed with peer: {peer_id}. Cause: {cause:?}");
                     app_state.peers.write().unwrap().remove(&peer_id);
+                    app_state.health.write().unwrap().remove(&peer_id);
+                    app_state.replication.write().unwrap().remove(&peer_id);
                     pending_dials.remove(&peer_id);
 
                     let _ = app_state.telemetry_tx.send(NetworkEvent::PeerDisconnected { peer_id: peer_id.to_string() });
+                    evaluate_transports(&swarm, &app_state, [peer_id], false);
+                    if !swarm.is_connected(&peer_id) {
+                        anti_entropy.disconnected(&peer_id);
+                    }
                 }
                 SwarmEvent::OutgoingConnectionError { peer_id, error, .. } => {
                     info!("Outgoing connection error with peer {:?}: {error:?}", peer_id);
@@ -287,34 +892,300 @@ pub async fn run_node(port: u16, id_keys: libp2p::identity::Keypair) -> Result<(
                 SwarmEvent::IncomingConnectionError { error, .. } => {
                     info!("Incoming connection error: {error:?}");
                 }
+                SwarmEvent::Behaviour(MyBehaviourEvent::Ping(ping::Event { peer, result, .. })) => {
+                    let mut health = app_state.health.write().unwrap();
+                    let peer_health = health.entry(peer).or_default();
+                    match result {
+                        Ok(rtt) => peer_health.record_rtt(rtt),
+                        Err(e) => {
+                            info!("Ping to {} failed: {:?}", peer, e);
+                            peer_health.record_failure();
+                        }
+                    }
+                    let _ = app_state.telemetry_tx.send(NetworkEvent::peer_health(peer.to_string(), peer_health));
+                    drop(health);
+                    evaluate_transports(&swarm, &app_state, [peer], false);
+                }
                 SwarmEvent::Behaviour(MyBehaviourEvent::Identify(identify::Event::Received { peer_id, info })) => {
-                    info!("Received Identify from {}: {:?}", peer_id, info.protocol_version);
-                    app_state.public_keys.write().unwrap().insert(peer_id, info.public_key.encode_protobuf());
+                    info!("Received Identify from {}: {:?} ({})", peer_id, info.protocol_version, info.agent_version);
+                    let peer_version = PeerVersion::from_identify(&info.protocol_version, &info.agent_version, &namespace.name);
+                    let compatible = peer_version.compatible;
+                    let reason = peer_version.reason.clone();
+                    app_state.versions.write().unwrap().insert(peer_id, peer_version);
+
+                    if compatible {
+                        rejected.remove(&peer_id);
+                        app_state.public_keys.write().unwrap().insert(peer_id, info.public_key.encode_protobuf());
+                        let addrs = info.listen_addrs.iter().map(|addr| addr.to_string()).collect();
+                        app_state.address_book.write().unwrap().insert(peer_id.to_string(), addrs);
+                        maybe_start_sync(&mut swarm, &app_state, &mut anti_entropy, &topic_crdt, peer_id);
+                    } else {
+                        let reason = reason.unwrap_or_default();
+                        info!("Disconnecting {}: {}", peer_id, reason);
+                        rejected.insert(peer_id, Instant::now());
+                        let _ = app_state.telemetry_tx.send(NetworkEvent::PeerIncompatible {
+                            peer_id: peer_id.to_string(),
+                            protocol_version: info.protocol_version.clone(),
+                            reason,
+                        });
+                        swarm.behaviour_mut().gossipsub.remove_explicit_peer(&peer_id);
+                        app_state.transports.write().unwrap().forget(&peer_id);
+                        let _ = swarm.disconnect_peer_id(peer_id);
+                    }
                 }
                 SwarmEvent::Behaviour(MyBehaviourEvent::Gossipsub(gossipsub::Event::Subscribed { peer_id, topic })) => {
                     info!("Peer {} subscribed to topic {:?}", peer_id, topic);
+                    if topic == topic_crdt.hash() {
+                        // The horizon first, so the peer drops compacted entries before syncing
+                        publish_compact(&mut swarm, &app_state, &mut transfers, &topic_crdt);
+                        maybe_start_sync(&mut swarm, &app_state, &mut anti_entropy, &topic_crdt, peer_id);
+                    } else if topic == kv.topic.hash() {
+                        // Lets the new peer find out what it is missing
+                        publish_replica_digest(&mut swarm, &app_state, &kv);
+                    } else if topic == counters.topic.hash() {
+                        publish_replica_digest(&mut swarm, &app_state, &counters);
+                    } else if let Some(replica) = collections.values().find(|replica| replica.topic.hash() == topic) {
+                        publish_replica_digest(&mut swarm, &app_state, replica);
+                    }
                 }
                 SwarmEvent::Behaviour(MyBehaviourEvent::Gossipsub(gossipsub::Event::Unsubscribed { peer_id, topic })) => {
                     info!("Peer {} unsubscribed from topic {:?}", peer_id, topic);
                 }
+                SwarmEvent::Behaviour(MyBehaviourEvent::Sync(request_response::Event::Message { peer, message })) => {
+                    let (msg, channel) = match message {
+                        request_response::Message::Request { request, channel, .. } => (request, Some(channel)),
+                        request_response::Message::Response { response, .. } => (response, None),
+                    };
+                    // Replica states go to the requester only, outside of anti-entropy sessions
+                    let msg = match msg {
+                        SyncMessage::StateRequest { topic } => {
+                            let state = if kv.is_on(&topic) {
+                                kv.state()
+                            } else if counters.is_on(&topic) {
+                                counters.state()
+                            } else {
+                                collections.values().find(|replica| replica.is_on(&topic)).and_then(ReplicaTopic::state)
+                            };
+                            if let Some(channel) = channel {
+                                if swarm.behaviour_mut().sync.send_response(channel, SyncMessage::State { topic, state }).is_err() {
+                                    error!("Failed to answer state request of {}: stream closed", peer);
+                                }
+                            }
+                            continue;
+                        }
+                        SyncMessage::State { topic, state: Some(state) } => {
+                            if kv.is_on(&topic) {
+                                let changes = kv.merge_state(peer, state);
+                                record_kv(&app_state, port, changes);
+                            } else if counters.is_on(&topic) {
+                                let changes = counters.merge_state(peer, state);
+                                record_counters(&app_state, port, changes);
+                            } else if let Some((name, replica)) = collections.iter_mut().find(|(_, replica)| replica.is_on(&topic)) {
+                                let changes = replica.merge_state(peer, state);
+                                record_collection(&app_state, port, name, &replica.store, changes);
+                                if let Err(e) = trim_collection(&mut swarm, &app_state, port, name, replica) {
+                                    error!("Failed to trim collection {}: {:?}", name, e);
+                                }
+                            }
+                            continue;
+                        }
+                        SyncMessage::State { state: None, .. } => continue,
+                        msg => msg,
+                    };
+                    let vector = match &msg {
+                        SyncMessage::Offer { vector, .. } | SyncMessage::Diff { vector, .. } => Some(vector.clone()),
+                        _ => None,
+                    };
+                    // The horizon first, so entries it compacted are not taken back
+                    if let SyncMessage::Offer { horizon: Some(horizon), signature, .. } | SyncMessage::Diff { horizon: Some(horizon), signature, .. } = &msg {
+                        let newer = app_state.log.read().unwrap().horizon().is_none_or(|local| *horizon > local);
+                        if newer && adopt_horizon(&app_state, port, *horizon, signature.clone(), &peer.to_string()) {
+                            info!("Compacted log to the horizon of {}", peer);
+                        }
+                    }
+                    let step = anti_entropy.handle(peer, msg, &app_state.log.read().unwrap());
+                    let sent = step.merge.clone();
+                    if !step.merge.is_empty() {
+                        let added = merge_log(&app_state, step.merge);
+                        info!("Anti-entropy with {}: {} new entries", peer, added);
+                    }
+                    observe_peer_log(&app_state, peer, |known| match vector {
+                        Some(vector) => *known = vector,
+                        None => known.observe(&sent),
+                    });
+                    if !step.remove.is_empty() {
+                        let removed = merge_removals(&app_state, step.remove);
+                        info!("Anti-entropy with {}: {} entries removed", peer, removed.len());
+                        publish_ack(&mut swarm, &app_state, &mut transfers, &topic_crdt, removed);
+                    }
+                    // A request is answered on its channel; a response may call for the next request
+                    match (step.reply, channel) {
+                        (Some(reply), Some(channel)) => {
+                            if swarm.behaviour_mut().sync.send_response(channel, reply).is_err() {
+                                error!("Failed to answer anti-entropy request of {}: stream closed", peer);
+                            }
+                        }
+                        (Some(reply), None) => {
+                            swarm.behaviour_mut().sync.send_request(&peer, reply);
+                        }
+                        (None, _) => {}
+                    }
+                    if let Some(progress) = step.progress {
+                        report_sync(&app_state, progress);
+                    }
+                }
+                SwarmEvent::Behaviour(MyBehaviourEvent::Sync(request_response::Event::OutboundFailure { peer, error, .. })) => {
+                    error!("Anti-entropy request to {} failed: {}", peer, error);
+                }
+                SwarmEvent::Behaviour(MyBehaviourEvent::Sync(request_response::Event::InboundFailure { peer, error, .. })) => {
+                    error!("Anti-entropy request from {} failed: {}", peer, error);
+                }
                 SwarmEvent::Behaviour(MyBehaviourEvent::Gossipsub(gossipsub::Event::Message {
                     propagation_source: peer_id,
                     message_id: _id,
                     message,
                 })) => {
+                    app_state.bandwidth.record_topic(message.topic.as_str(), Direction::Inbound, message.data.len());
+                    if app_state.chaos.should_drop() {
+                        let _ = app_state.telemetry_tx.send(NetworkEvent::FaultInjected {
+                            kind: "drop".to_string(),
+                            peer_id: Some(peer_id.to_string()),
+                        });
+                        continue;
+                    }
+                    let Some(data) = namespace.open(&message.data) else {
+                        info!("Dropping message from {} not sealed with the namespace key", peer_id);
+                        continue;
+                    };
+                    // Gossipsub validates strictly, so a source is signed; acks, chunks and
+                    // replica states are credited to it
+                    let Some(author) = message.source else {
+                        info!("Dropping unsigned message from {}", peer_id);
+                        continue;
+                    };
                     if message.topic == topic_crdt.hash() {
-                        match serde_json::from_slice::<GSet<String>>(&message.data) {
-                            Ok(remote_state) => {
-                                app_state.log.write().unwrap().merge(remote_state);
-                                if let Err(e) = storage::save_log(port, &*app_state.log.read().unwrap()) {
-                                    error!("Failed to save log: {:?}", e);
+                        let msg = match CrdtMessage::decode(&data) {
+                            Some(CrdtMessage::Chunk { transfer, index, total, hash, data, .. }) => {
+                                match transfers.receive(author, transfer, index, total, &hash, &data) {
+                                    Ok(Some(msg)) => {
+                                        info!("Reassembled transfer {:016x} from {} ({} chunks)", transfer, author, total);
+                                        Some(msg)
+                                    }
+                                    Ok(None) => continue,
+                                    Err(e) => {
+                                        error!("Rejected chunk from {}: {:?}", author, e);
+                                        continue;
+                                    }
                                 }
-                                info!("Synced CRDT state. Current Log: {:?}", app_state.log.read().unwrap().read());
                             }
-                            Err(e) => error!("Failed to deserialize CRDT state: {:?}", e),
+                            other => other,
+                        };
+                        match msg {
+                            Some(CrdtMessage::Delta { entries }) => {
+                                let sent = entries.clone();
+                                let added = merge_log(&app_state, entries);
+                                info!("Merged delta from {}: {} new entries", author, added);
+                                observe_peer_log(&app_state, author, |known| known.observe(&sent));
+                            }
+                            Some(CrdtMessage::State { state }) => {
+                                let vector = VersionVector::of(&state);
+                                if let Some(horizon) = state.horizon() {
+                                    adopt_horizon(&app_state, port, horizon, state.horizon_signature().cloned(), &author.to_string());
+                                }
+                                let added = merge_log(&app_state, state.read());
+                                let removed = merge_removals(&app_state, state.tombstones().clone());
+                                info!("Merged full state from {}: {} new entries, {} removed", author, added, removed.len());
+                                observe_peer_log(&app_state, author, |known| *known = vector);
+                                publish_ack(&mut swarm, &app_state, &mut transfers, &topic_crdt, removed);
+                            }
+                            Some(CrdtMessage::Remove { tombstones }) => {
+                                let removed = merge_removals(&app_state, tombstones);
+                                info!("Merged removals from {}: {} entries removed", author, removed.len());
+                                publish_ack(&mut swarm, &app_state, &mut transfers, &topic_crdt, removed);
+                            }
+                            Some(CrdtMessage::Ack { ids, entries }) => {
+                                {
+                                    let mut log = app_state.log.write().unwrap();
+                                    // Older nodes ack by id alone, standing for every tombstone of that id
+                                    let entries = if entries.is_empty() {
+                                        log.tombstones().iter().filter(|t| ids.contains(&t.entry_id)).map(Tombstone::key).collect()
+                                    } else {
+                                        entries
+                                    };
+                                    log.ack(&author.to_string(), &entries);
+                                }
+                                collect_garbage(&app_state);
+                            }
+                            Some(CrdtMessage::Compact { horizon, signature }) => {
+                                if adopt_horizon(&app_state, port, horizon, signature, &author.to_string()) {
+                                    info!("Compacted log to the horizon of {}", author);
+                                }
+                            }
+                            Some(CrdtMessage::Digest { digest, vector }) => {
+                                if let Some(vector) = vector {
+                                    observe_peer_log(&app_state, author, |known| *known = vector);
+                                }
+                                let local = LogDigest::of(&app_state.log.read().unwrap());
+                                let recently_asked = state_requests
+                                    .get(&author)
+                                    .is_some_and(|at: &Instant| at.elapsed() < STATE_REQUEST_INTERVAL);
+                                if digest != local && !recently_asked {
+                                    info!(
+                                        "Log gap with {} ({} entries there, {} here), requesting its state",
+                                        author, digest.count, local.count
+                                    );
+                                    state_requests.insert(author, Instant::now());
+                                    if !sync_with(&mut swarm, &app_state, &mut anti_entropy, author) {
+                                        let request = CrdtMessage::StateRequest { to: Some(author.to_string()) };
+                                        if let Err(e) = publish_crdt(&mut swarm, &app_state, &mut transfers, &topic_crdt, &request) {
+                                            error!("Failed to request state from {}: {:?}", author, e);
+                                        }
+                                    }
+                                }
+                            }
+                            Some(CrdtMessage::StateRequest { to }) => {
+                                if to.is_none() || to.as_deref() == Some(&swarm.local_peer_id().to_string()) {
+                                    // A session involves the requester only; older nodes get the whole log on the topic
+                                    if sync_with(&mut swarm, &app_state, &mut anti_entropy, author) {
+                                        continue;
+                                    }
+                                    let state = Box::new(app_state.log.read().unwrap().clone());
+                                    if let Err(e) = publish_crdt(&mut swarm, &app_state, &mut transfers, &topic_crdt, &CrdtMessage::State { state }) {
+                                        error!("Failed to publish state: {:?}", e);
+                                    }
+                                }
+                            }
+                            Some(CrdtMessage::ChunkRequest { to, transfer, missing }) => {
+                                if to != swarm.local_peer_id().to_string() {
+                                    continue;
+                                }
+                                let chunks = transfers.resend(transfer, &missing);
+                                info!("Resending {} chunks of transfer {:016x} to {}", chunks.len(), transfer, author);
+                                if let Err(e) = publish_chunks(&mut swarm, &app_state, &topic_crdt, chunks) {
+                                    error!("Failed to resend chunks: {:?}", e);
+                                }
+                            }
+                            // Reassembled transfers never contain chunks
+                            Some(CrdtMessage::Chunk { .. }) => {}
+                            None => error!("Failed to deserialize CRDT message from {}", author),
+                        }
+                    } else if message.topic == kv.topic.hash() {
+                        let changes = handle_replica_message(&mut swarm, &app_state, &mut kv, author, &data);
+                        record_kv(&app_state, port, changes);
+                    } else if message.topic == counters.topic.hash() {
+                        let changes = handle_replica_message(&mut swarm, &app_state, &mut counters, author, &data);
+                        record_counters(&app_state, port, changes);
+                    } else if let Some((name, replica)) = collections.iter_mut().find(|(_, replica)| replica.topic.hash() == message.topic) {
+                        let changes = handle_replica_message(&mut swarm, &app_state, replica, author, &data);
+                        let changed = !changes.is_empty();
+                        record_collection(&app_state, port, name, &replica.store, changes);
+                        if changed {
+                            if let Err(e) = trim_collection(&mut swarm, &app_state, port, name, replica) {
+                                error!("Failed to trim collection {}: {:?}", name, e);
+                            }
                         }
                     } else if message.topic == topic_private.hash() {
-                        if let Ok(pm) = serde_json::from_slice::<PrivateMessage>(&message.data) {
+                        if let Ok(pm) = serde_json::from_slice::<PrivateMessage>(&data) {
                             let local_id = swarm.local_peer_id().to_string();
                             if pm.to == local_id {
                                 // Decrypt
@@ -334,7 +1205,12 @@ pub async fn run_node(port: u16, id_keys: libp2p::identity::Keypair) -> Result<(
                                                 content,
                                                 timestamp,
                                             };
-                                            app_state.dms.write().unwrap().push(entry);
+                                            let mut dms = app_state.dms.write().unwrap();
+                                            dms.push(entry);
+                                            if let Err(e) = storage::save_dms(&namespace, port, &dms) {
+                                                error!("Failed to save direct messages: {:?}", e);
+                                            }
+                                            drop(dms);
                                             
                                             println!("DEBUG: Emitting MessageReceived event for {}", peer_id);
                                             if let Err(e) = app_state.telemetry_tx.send(NetworkEvent::MessageReceived { 
@@ -351,10 +1227,41 @@ pub async fn run_node(port: u16, id_keys: libp2p::identity::Keypair) -> Result<(
                                 }
                             }
                         }
+                    } else if message.topic == topic_control.hash() {
+                        match serde_json::from_slice::<ControlMessage>(&data) {
+                            Ok(ControlMessage::Goodbye { reason }) => {
+                                info!("Peer {} is leaving: {}", author, reason);
+                                app_state.peers.write().unwrap().remove(&author);
+                                app_state.health.write().unwrap().remove(&author);
+                                app_state.replication.write().unwrap().remove(&author);
+                                pending_dials.remove(&author);
+                                app_state.transports.write().unwrap().forget(&author);
+                                let _ = app_state.telemetry_tx.send(NetworkEvent::PeerLeaving { peer_id: author.to_string(), reason });
+                            }
+                            Err(e) => error!("Failed to deserialize control message: {:?}", e),
+                        }
+                    } else if message.topic == topic_global.hash() {
+                        // Older nodes publish the bare line, newer ones a `ChatMessage`.
+                        let author = author.to_string();
+                        let chat_msg = match serde_json::from_slice::<ChatMessage>(&data) {
+                            Ok(mut m) => {
+                                m.from = author;
+                                m.topic = topic_global.to_string();
+                                m
+                            }
+                            Err(_) => ChatMessage {
+                                from: author,
+                                content: String::from_utf8_lossy(&data).to_string(),
+                                timestamp: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs(),
+                                topic: topic_global.to_string(),
+                            },
+                        };
+                        info!("Got message: '{}' from peer: {}", chat_msg.content, chat_msg.from);
+                        record_chat(&app_state, port, chat_msg);
                     } else {
                         info!(
                             "Got message: '{}' from peer: {:?}",
-                            String::from_utf8_lossy(&message.data),
+                            String::from_utf8_lossy(&data),
                             peer_id
                         );
                     }
@@ -363,32 +1270,1261 @@ pub async fn run_node(port: u16, id_keys: libp2p::identity::Keypair) -> Result<(
             }
         }
     }
+
+    info!("Shutting down node on port {} (namespace '{}')", port, namespace.name);
+
+    // Stop accepting commands from the dashboard and the CLI
+    log_rx.close();
+    lines.close();
+
+    // Flush state, leaving an empty WAL for a quick start
+    checkpoint_log(&app_state, app_state.log.read().unwrap());
+    if let Err(e) = storage::save_chat(&namespace, port, &app_state.chat.read().unwrap()) {
+        error!("Failed to save chat history: {:?}", e);
+    }
+    if let Err(e) = storage::save_dms(&namespace, port, &app_state.dms.read().unwrap()) {
+        error!("Failed to save direct messages: {:?}", e);
+    }
+    if let Err(e) = storage::save_address_book(&namespace, port, &app_state.address_book.read().unwrap()) {
+        error!("Failed to save address book: {:?}", e);
+    }
+    if let Err(e) = storage::save_kv(&namespace, port, &app_state.kv.read().unwrap()) {
+        error!("Failed to save device store: {:?}", e);
+    }
+    if let Err(e) = storage::save_counters(&namespace, port, &app_state.counters.read().unwrap()) {
+        error!("Failed to save counters: {:?}", e);
+    }
+    for (name, collection) in app_state.collections.read().unwrap().iter() {
+        if let Err(e) = storage::save_collection(&namespace, port, name, &collection.read().unwrap()) {
+            error!("Failed to save collection {}: {:?}", name, e);
+        }
+    }
+
+    // Tell peers we're leaving, then give gossipsub a moment to send it
+    let goodbye = ControlMessage::Goodbye { reason: "shutdown".to_string() };
+    if let Err(e) = publish(&mut swarm, &app_state, &topic_control, serde_json::to_vec(&goodbye)?) {
+        info!("Goodbye not published: {:?}", e);
+    }
+    let _ = tokio::time::timeout(GOODBYE_GRACE, async {
+        loop {
+            swarm.select_next_some().await;
+        }
+    })
+    .await;
+
+    // Close connections and wait for them to be torn down
+    let connected: Vec<PeerId> = swarm.connected_peers().copied().collect();
+    for peer_id in connected {
+        let _ = swarm.disconnect_peer_id(peer_id);
+    }
+    let _ = tokio::time::timeout(CLOSE_TIMEOUT, async {
+        while swarm.connected_peers().next().is_some() {
+            swarm.select_next_some().await;
+        }
+    })
+    .await;
+
+    if let Some(web_server) = web_server {
+        let _ = web_server.await;
+    }
+    info!("Node on port {} stopped", port);
+    Ok(())
+}
+
+/// Publishes on a gossipsub topic, accounting the payload to the topic's outbound traffic.
+/// Peers routed to the fallback link also get the message over it; the publish succeeds
+/// if either transport carried it.
+fn publish(
+    swarm: &mut Swarm<MyBehaviour>,
+    app_state: &AppState,
+    topic: &gossipsub::IdentTopic,
+    data: impl Into<Vec<u8>>,
+) -> Result<gossipsub::MessageId, gossipsub::PublishError> {
+    let data = data.into();
+    let len = data.len();
+    let sealed = app_state.namespace.seal(data);
+    let published = swarm.behaviour_mut().gossipsub.publish(topic.clone(), sealed.clone());
+    // Without peers the message just stays local; any other failure is reported
+    if let Err(e) = &published {
+        if !matches!(e, gossipsub::PublishError::InsufficientPeers) {
+            let _ = app_state.telemetry_tx.send(NetworkEvent::PublishFailed {
+                topic: topic.to_string(),
+                bytes: len,
+                reason: e.to_string(),
+            });
+        }
+    }
+
+    let mut transports = app_state.transports.write().unwrap();
+    let id = if transports.link().is_some() {
+        transports.first_seen(payload_hash(&sealed));
+        let ble_peers = transports.fallback_peers();
+        let sent = transports.send_fallback(&ble_peers, topic.hash().as_str(), &sealed);
+        let tcp_peers = swarm
+            .connected_peers()
+            .filter(|peer| transports.route(peer) != Some(Route::Ble))
+            .map(|peer| peer.to_string())
+            .collect();
+        let _ = app_state.telemetry_tx.send(NetworkEvent::RouteDecision {
+            topic: topic.to_string(),
+            tcp_peers,
+            ble_peers: sent.iter().map(|peer| peer.to_string()).collect(),
+        });
+        match published {
+            Err(gossipsub::PublishError::InsufficientPeers) if !sent.is_empty() => {
+                gossipsub::MessageId::from(payload_hash(&sealed).to_string())
+            }
+            other => other?,
+        }
+    } else {
+        published?
+    };
+    app_state.bandwidth.record_topic(&topic.to_string(), Direction::Outbound, len);
+    Ok(id)
+}
+
+/// Hash of a sealed payload, used as gossipsub message ID and to recognise copies of a
+/// message arriving over both TCP and the fallback link.
+fn payload_hash(data: &[u8]) -> u64 {
+    let mut s = DefaultHasher::new();
+    data.hash(&mut s);
+    s.finish()
+}
+
+/// Next swarm event. Whole messages from the fallback link are surfaced as gossipsub
+/// messages so they go through the same handlers, and flooded on to the other peers routed
+/// over the link. Copies of a message already received over the other transport are skipped.
+async fn next_event(
+    swarm: &mut Swarm<MyBehaviour>,
+    app_state: &AppState,
+    fallback: &mut Option<(mpsc::UnboundedReceiver<LinkFrame>, Reassembler)>,
+) -> SwarmEvent<MyBehaviourEvent> {
+    let Some((frames, reassembler)) = fallback else {
+        return swarm.select_next_some().await;
+    };
+
+    loop {
+        tokio::select! {
+            event = swarm.select_next_some() => {
+                if let SwarmEvent::Behaviour(MyBehaviourEvent::Gossipsub(gossipsub::Event::Message { message, .. })) = &event {
+                    if !app_state.transports.write().unwrap().first_seen(payload_hash(&message.data)) {
+                        continue;
+                    }
+                }
+                return event;
+            }
+            Some(frame) = frames.recv() => {
+                let from = frame.from;
+                let Some(message) = reassembler.push(frame) else { continue };
+                let hash = payload_hash(&message.data);
+                let mut transports = app_state.transports.write().unwrap();
+                if !transports.first_seen(hash) {
+                    continue;
+                }
+                let others: Vec<PeerId> = transports
+                    .fallback_peers()
+                    .into_iter()
+                    .filter(|peer| *peer != from && *peer != message.source)
+                    .collect();
+                transports.relay_fallback(&others, &message);
+
+                return SwarmEvent::Behaviour(MyBehaviourEvent::Gossipsub(gossipsub::Event::Message {
+                    propagation_source: from,
+                    message_id: gossipsub::MessageId::from(hash.to_string()),
+                    message: gossipsub::Message {
+                        source: Some(message.source),
+                        data: message.data,
+                        sequence_number: None,
+                        topic: gossipsub::TopicHash::from_raw(message.topic),
+                    },
+                }));
+            }
+        }
+    }
+}
+
+/// Re-scores `peers`, switching their routes as needed. Route changes are always reported;
+/// scores only when `report_scores` is set, i.e. on the periodic sweep.
+fn evaluate_transports(
+    swarm: &Swarm<MyBehaviour>,
+    app_state: &AppState,
+    peers: impl IntoIterator<Item = PeerId>,
+    report_scores: bool,
+) {
+    // The high-bandwidth interface counts as up while the node listens on a non-loopback address
+    let interface_up = app_state
+        .listen_addrs
+        .read()
+        .unwrap()
+        .iter()
+        .any(|addr| !addr.starts_with("/ip4/127.") && !addr.starts_with("/ip6/::1/"));
+    let health = app_state.health.read().unwrap();
+    let versions = app_state.versions.read().unwrap();
+    let mut transports = app_state.transports.write().unwrap();
+
+    for peer_id in peers {
+        if versions.get(&peer_id).is_some_and(|v| !v.compatible) {
+            transports.forget(&peer_id);
+            continue;
+        }
+        let connected = swarm.is_connected(&peer_id);
+        let score = ConnectivityScore::compute(health.get(&peer_id), connected, interface_up);
+        let transition = transports.update(peer_id, score, connected);
+
+        if report_scores {
+            let _ = app_state.telemetry_tx.send(NetworkEvent::TransportScore {
+                peer_id: peer_id.to_string(),
+                score: score.total,
+                latency: score.latency,
+                loss: score.loss,
+                interface: score.interface,
+                route: transports.route(&peer_id).map(|r| r.to_string()),
+            });
+        }
+        if let Some(t) = transition {
+            info!("Transport for {}: {:?} -> {:?} (score {:.0})", peer_id, t.from, t.to, t.score);
+            let _ = app_state.telemetry_tx.send(NetworkEvent::TransportSwitched {
+                peer_id: peer_id.to_string(),
+                from: t.from.map(|r| r.to_string()),
+                to: t.to.map(|r| r.to_string()),
+                score: t.score,
+            });
+        }
+    }
+}
+
+/// Inserts a log entry, persists the log, publishes the entry as a delta and applies the
+/// retention policy. Peers without delta support get the whole state, as before. The entry is kept locally even when the
+/// publish fails; anti-entropy delivers it once peers are reachable.
+fn append_log(
+    swarm: &mut Swarm<MyBehaviour>,
+    app_state: &AppState,
+    transfers: &mut ChunkedTransfers,
+    port: u16,
+    topic: &gossipsub::IdentTopic,
+    keypair: &libp2p::identity::Keypair,
+    entry: LogEntry,
+) -> Result<()> {
+    {
+        let mut log = app_state.log.write().unwrap();
+        log.insert(entry.clone());
+        app_state.watch.log_inserted([&entry]);
+        persist_log(app_state, log, vec![WalRecord::Insert { entry: entry.clone() }]);
+    }
+
+    let published = if app_state.mesh_supports(version::FEATURE_CRDT_DELTA) {
+        publish_crdt(swarm, app_state, transfers, topic, &CrdtMessage::Delta { entries: vec![entry] })
+    } else {
+        let state = app_state.log.read().unwrap().clone();
+        publish(swarm, app_state, topic, serde_json::to_vec(&state)?).map(|_| ()).map_err(anyhow::Error::from)
+    };
+    // A count or size limit may be exceeded now
+    enforce_retention(swarm, app_state, transfers, port, topic, keypair);
+    published
+}
+
+/// Removes a log entry with a tombstone signed by this node, persists the log and publishes
+/// the tombstone. Without `author`, the id must name a single entry. Fails if the entry is
+/// unknown or this node may not remove it.
+fn remove_log(
+    swarm: &mut Swarm<MyBehaviour>,
+    app_state: &AppState,
+    transfers: &mut ChunkedTransfers,
+    topic: &gossipsub::IdentTopic,
+    keypair: &libp2p::identity::Keypair,
+    id: &str,
+    author: Option<&str>,
+) -> Result<()> {
+    let entry = {
+        let log = app_state.log.read().unwrap();
+        match author {
+            Some(author) => log.get(id, author).cloned(),
+            None => {
+                let mut matching = log.with_id(id);
+                let entry = matching.next().cloned();
+                if matching.next().is_some() {
+                    return Err(anyhow!("several authors wrote an entry with this id, name the author"));
+                }
+                entry
+            }
+        }
+    };
+    let entry = entry.ok_or_else(|| anyhow!("unknown log entry"))?;
+    if !app_state.may_remove(&entry) {
+        return Err(anyhow!("only the author {} or a log admin may remove it", entry.author));
+    }
+    let tombstone = Tombstone::new(keypair, &entry, app_state.clock.write().unwrap().now());
+    {
+        let mut log = app_state.log.write().unwrap();
+        if log.remove(tombstone.clone()) {
+            app_state.watch.log_removed([tombstone.key()]);
+            persist_log(app_state, log, vec![WalRecord::Remove { tombstone: tombstone.clone() }]);
+        }
+    }
+    let _ = app_state.telemetry_tx.send(NetworkEvent::LogEntryRemoved {
+        id: tombstone.entry_id.clone(),
+        removed_by: tombstone.removed_by.clone(),
+    });
+    collect_garbage(app_state);
+    publish_crdt(swarm, app_state, transfers, topic, &CrdtMessage::Remove { tombstones: vec![tombstone] })
+}
+
+/// Compacts the log to the horizon the local retention policy proposes, and tells peers
+/// so they drop the same entries.
+fn enforce_retention(
+    swarm: &mut Swarm<MyBehaviour>,
+    app_state: &AppState,
+    transfers: &mut ChunkedTransfers,
+    port: u16,
+    topic: &gossipsub::IdentTopic,
+    keypair: &libp2p::identity::Keypair,
+) {
+    let now_ms = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0);
+    let proposed = app_state.retention.read().unwrap().horizon(&app_state.log.read().unwrap(), now_ms);
+    if let Some(horizon) = proposed {
+        // Peers adopt a log admin's horizon whatever their own policy
+        let signature = app_state.is_log_admin().then(|| HorizonSignature::new(keypair, horizon));
+        if compact_log(app_state, port, horizon, signature) {
+            publish_compact(swarm, app_state, transfers, topic);
+        }
+    }
+}
+
+/// Compacts the log to a horizon received from `source`, a peer or a snapshot, if the
+/// retention policy admits it. Returns whether the horizon advanced.
+fn adopt_horizon(app_state: &AppState, port: u16, horizon: Hlc, signature: Option<HorizonSignature>, source: &str) -> bool {
+    let now_ms = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0);
+    let admitted = app_state.retention.read().unwrap().admits(horizon, signature.as_ref(), &app_state.log_admins.read().unwrap(), now_ms);
+    if let Err(reason) = admitted {
+        error!("Refused log horizon from {}: {}", source, reason);
+        return false;
+    }
+    compact_log(app_state, port, horizon, signature)
+}
+
+/// Raises the log's horizon, archiving the dropped entries if the retention policy asks,
+/// and persists the log. Returns whether the horizon advanced.
+fn compact_log(app_state: &AppState, port: u16, horizon: Hlc, signature: Option<HorizonSignature>) -> bool {
+    let mut log = app_state.log.write().unwrap();
+    let Some(dropped) = log.compact_signed(horizon, signature.clone()) else {
+        return false;
+    };
+    let mut archived = false;
+    if app_state.retention.read().unwrap().archive && !dropped.is_empty() {
+        match storage::archive_entries(&app_state.namespace, port, &dropped) {
+            Ok(()) => archived = true,
+            Err(e) => error!("Failed to archive compacted entries: {:?}", e),
+        }
+    }
+    app_state.watch.log_removed(dropped.iter().map(LogEntry::key));
+    persist_log(app_state, log, vec![WalRecord::Compact { horizon, signature }]);
+    info!("Compacted log up to {:?}: {} entries dropped", horizon, dropped.len());
+    let _ = app_state.telemetry_tx.send(NetworkEvent::LogCompacted { horizon, removed: dropped.len(), archived });
+    true
+}
+
+/// Tells peers the log's horizon, with its signature if a log admin set it. Does nothing
+/// before the log was compacted.
+fn publish_compact(swarm: &mut Swarm<MyBehaviour>, app_state: &AppState, transfers: &mut ChunkedTransfers, topic: &gossipsub::IdentTopic) {
+    if app_state.peers.read().unwrap().is_empty() || !app_state.mesh_supports(version::FEATURE_RETENTION) {
+        return;
+    }
+    let (horizon, signature) = {
+        let log = app_state.log.read().unwrap();
+        (log.horizon(), log.horizon_signature().cloned())
+    };
+    let Some(horizon) = horizon else {
+        return;
+    };
+    if let Err(e) = publish_crdt(swarm, app_state, transfers, topic, &CrdtMessage::Compact { horizon, signature }) {
+        error!("Failed to publish log horizon: {:?}", e);
+    }
+}
+
+/// Tells peers this node holds the tombstones of `entries`. Does nothing for an empty list.
+fn publish_ack(
+    swarm: &mut Swarm<MyBehaviour>,
+    app_state: &AppState,
+    transfers: &mut ChunkedTransfers,
+    topic: &gossipsub::IdentTopic,
+    entries: Vec<EntryKey>,
+) {
+    if entries.is_empty() {
+        return;
+    }
+    let ids = entries.iter().map(|key| key.id.clone()).collect();
+    if let Err(e) = publish_crdt(swarm, app_state, transfers, topic, &CrdtMessage::Ack { ids, entries }) {
+        error!("Failed to acknowledge removals: {:?}", e);
+    }
+}
+
+/// Publishes a CRDT message, split into chunks when its encoding exceeds `CHUNK_SIZE`.
+fn publish_crdt(
+    swarm: &mut Swarm<MyBehaviour>,
+    app_state: &AppState,
+    transfers: &mut ChunkedTransfers,
+    topic: &gossipsub::IdentTopic,
+    msg: &CrdtMessage,
+) -> Result<()> {
+    let bytes = serde_json::to_vec(msg)?;
+    if bytes.len() <= CHUNK_SIZE {
+        publish(swarm, app_state, topic, bytes)?;
+        return Ok(());
+    }
+
+    let len = bytes.len();
+    let chunks = transfers.split(bytes);
+    info!("Publishing {} bytes as {} chunks", len, chunks.len());
+    publish_chunks(swarm, app_state, topic, chunks)
+}
+
+/// Publishes `Chunk`s as they are: their encoding is over `CHUNK_SIZE` but fits a message.
+fn publish_chunks(
+    swarm: &mut Swarm<MyBehaviour>,
+    app_state: &AppState,
+    topic: &gossipsub::IdentTopic,
+    chunks: Vec<CrdtMessage>,
+) -> Result<()> {
+    for chunk in chunks {
+        publish(swarm, app_state, topic, serde_json::to_vec(&chunk)?)?;
+    }
+    Ok(())
+}
+
+/// Opens an anti-entropy session with `peer` once it is identified as supporting it and
+/// subscribed to the CRDT topic, so the log's horizon reached it first. Runs once per
+/// connection.
+fn maybe_start_sync(
+    swarm: &mut Swarm<MyBehaviour>,
+    app_state: &AppState,
+    anti_entropy: &mut AntiEntropy,
+    topic: &gossipsub::IdentTopic,
+    peer: PeerId,
+) {
+    let supported = app_state.peer_supports(&peer, version::FEATURE_SYNC_PROTOCOL);
+    let subscribed = swarm
+        .behaviour()
+        .gossipsub
+        .all_peers()
+        .any(|(p, topics)| *p == peer && topics.contains(&&topic.hash()));
+    if !supported || !subscribed || !anti_entropy.should_initiate(swarm.local_peer_id(), peer) {
+        return;
+    }
+    start_sync(swarm, app_state, anti_entropy, peer);
+}
+
+/// Opens an anti-entropy session with `peer` to catch up with its log, if it supports the
+/// sync protocol. Unlike a `CrdtMessage::StateRequest`, which is answered on the CRDT topic,
+/// only the two nodes exchange anything. Returns whether the session was opened.
+fn sync_with(swarm: &mut Swarm<MyBehaviour>, app_state: &AppState, anti_entropy: &mut AntiEntropy, peer: PeerId) -> bool {
+    if !app_state.peer_supports(&peer, version::FEATURE_SYNC_PROTOCOL) {
+        return false;
+    }
+    start_sync(swarm, app_state, anti_entropy, peer);
+    true
+}
+
+fn start_sync(swarm: &mut Swarm<MyBehaviour>, app_state: &AppState, anti_entropy: &mut AntiEntropy, peer: PeerId) {
+    let (offer, progress) = anti_entropy.start(peer, &app_state.log.read().unwrap());
+    info!("Starting anti-entropy with {}", peer);
+    swarm.behaviour_mut().sync.send_request(&peer, offer);
+    report_sync(app_state, progress);
+}
+
+fn report_sync(app_state: &AppState, progress: SyncProgress) {
+    let _ = app_state.telemetry_tx.send(NetworkEvent::SyncProgress {
+        peer_id: progress.peer_id.to_string(),
+        session: format!("{:016x}", progress.session),
+        stage: progress.stage.to_string(),
+        entries_sent: progress.entries_sent,
+        entries_received: progress.entries_received,
+    });
+}
+
+/// Updates what is known of `peer`'s log, then reports its replication status.
+fn observe_peer_log(app_state: &AppState, peer: PeerId, observe: impl FnOnce(&mut VersionVector)) {
+    {
+        let mut replication = app_state.replication.write().unwrap();
+        let known = replication.entry(peer).or_default();
+        observe(&mut known.vector);
+        known.updated_ms = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0);
+    }
+    report_replication(app_state, Some(peer));
+}
+
+/// Emits `ReplicationLag` for the peers (every known one when `only` is `None`) whose
+/// replication status changed since last reported.
+fn report_replication(app_state: &AppState, only: Option<PeerId>) {
+    let now_ms = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0);
+    let log = app_state.log.read().unwrap();
+    let mut replication = app_state.replication.write().unwrap();
+    for (peer, known) in replication.iter_mut().filter(|(peer, _)| only.is_none_or(|only| **peer == only)) {
+        let status = known.status(&log);
+        let changed = known.reported.as_ref().is_none_or(|reported| {
+            (reported.missing, reported.unknown, reported.oldest_missing_ms, reported.ahead)
+                != (status.missing, status.unknown, status.oldest_missing_ms, status.ahead)
+        });
+        if changed {
+            let _ = app_state.telemetry_tx.send(NetworkEvent::ReplicationLag {
+                peer_id: peer.to_string(),
+                missing: status.missing,
+                unknown: status.unknown,
+                lag_ms: status.lag_ms(now_ms),
+                ahead: status.ahead,
+            });
+            known.reported = Some(status);
+        }
+    }
+}
+
+/// Merges remote entries into the log, persisting it if anything was new. Entries whose
+/// signature does not verify, or stamped beyond `hlc::MAX_DRIFT_MS` ahead of the local
+/// clock, are rejected. Returns the number of new entries.
+fn merge_log(app_state: &AppState, entries: impl IntoIterator<Item = LogEntry>) -> usize {
+    // Verify outside the lock, only the entries not merged yet
+    let new: Vec<LogEntry> = {
+        let log = app_state.log.read().unwrap();
+        entries.into_iter().filter(|entry| !log.contains(entry)).collect()
+    };
+    let verified: Vec<LogEntry> = new
+        .into_iter()
+        .filter(|entry| {
+            if hlc::beyond_drift(entry.timestamp) {
+                error!("Rejected log entry {} of {}: stamped {:?}, too far in the future", entry.id, entry.author, entry.timestamp);
+                return false;
+            }
+            if entry.author == entry::legacy_author() {
+                error!("Rejected log entry {}: signed with the legacy migration key", entry.id);
+                return false;
+            }
+            match entry.verify() {
+                Ok(()) => true,
+                Err(reason) => {
+                    error!("Rejected log entry: {}", reason);
+                    false
+                }
+            }
+        })
+        .collect();
+
+    let mut log = app_state.log.write().unwrap();
+    let mut records = Vec::new();
+    for entry in verified {
+        app_state.clock.write().unwrap().observe(entry.timestamp);
+        if log.insert(entry.clone()) {
+            app_state.watch.log_inserted([&entry]);
+            records.push(WalRecord::Insert { entry });
+        }
+    }
+    let added = records.len();
+    persist_log(app_state, log, records);
+    added
+}
+
+/// Applies remote removals, persisting the log if any was new. Tombstones whose signature
+/// does not verify, or by a peer not allowed to remove the entry, are rejected. Returns the
+/// newly removed entries.
+fn merge_removals(app_state: &AppState, tombstones: impl IntoIterator<Item = Tombstone>) -> Vec<EntryKey> {
+    let new: Vec<Tombstone> = {
+        let log = app_state.log.read().unwrap();
+        tombstones.into_iter().filter(|t| !log.tombstones().contains(t)).collect()
+    };
+    let admins = app_state.log_admins.read().unwrap().clone();
+    let verified: Vec<Tombstone> = new
+        .into_iter()
+        .filter(|tombstone| match tombstone.verify() {
+            Ok(()) if tombstone.permitted(&admins) => true,
+            Ok(()) => {
+                error!("Rejected removal of {}: {} may not remove it", tombstone.entry_id, tombstone.removed_by);
+                false
+            }
+            Err(reason) => {
+                error!("Rejected log removal: {}", reason);
+                false
+            }
+        })
+        .collect();
+
+    let mut removed = Vec::new();
+    {
+        let mut log = app_state.log.write().unwrap();
+        let mut records = Vec::new();
+        for tombstone in verified {
+            app_state.clock.write().unwrap().observe(tombstone.timestamp);
+            let (key, removed_by) = (tombstone.key(), tombstone.removed_by.clone());
+            if log.remove(tombstone.clone()) {
+                let _ = app_state.telemetry_tx.send(NetworkEvent::LogEntryRemoved { id: key.id.clone(), removed_by });
+                removed.push(key);
+                records.push(WalRecord::Remove { tombstone });
+            }
+        }
+        app_state.watch.log_removed(removed.iter().cloned());
+        persist_log(app_state, log, records);
+    }
+    collect_garbage(app_state);
+    removed
+}
+
+/// Drops the tombstones acknowledged by every compatible peer identified since startup,
+/// connected or not, so a peer that is away keeps its removals from being forgotten.
+fn collect_garbage(app_state: &AppState) {
+    let known: HashSet<String> = app_state
+        .versions
+        .read()
+        .unwrap()
+        .iter()
+        .filter(|(_, v)| v.compatible)
+        .map(|(peer_id, _)| peer_id.to_string())
+        .collect();
+    let mut log = app_state.log.write().unwrap();
+    let collected = log.collect_garbage(&known);
+    if !collected.is_empty() {
+        info!("Collected {} tombstones acknowledged by all {} known peers", collected.len(), known.len());
+        // Dropped tombstones cannot be written to the WAL, so the snapshot is rewritten
+        checkpoint_log(app_state, log);
+    }
+}
+
+/// Appends changes already applied to `log` to its WAL, see `wal::LogStore`, and releases
+/// the log. Only the encoding happens under the log's lock; the write and fsync follow
+/// once it is released, so readers of the log don't wait on the disk. The store is locked
+/// first and throughout, so changes still reach the WAL in the order they were made.
+fn persist_log(app_state: &AppState, log: RwLockWriteGuard<'_, LogSet>, records: Vec<WalRecord>) {
+    if records.is_empty() {
+        return;
+    }
+    let mut store = app_state.log_store.lock().unwrap();
+    let Some(store) = store.as_mut() else {
+        error!("Log storage failed to open; {} changes not saved", records.len());
+        return;
+    };
+    let pending = store.prepare(&records, &log);
+    drop(log);
+    if let Err(e) = pending.and_then(|pending| store.write(pending)) {
+        error!("Failed to save log: {:?}", e);
+    }
+}
+
+/// Writes `log` as the new snapshot and empties its WAL, releasing the log once encoded.
+fn checkpoint_log(app_state: &AppState, log: impl Deref<Target = LogSet>) {
+    let mut store = app_state.log_store.lock().unwrap();
+    let Some(store) = store.as_mut() else {
+        return;
+    };
+    let snapshot = LogStore::snapshot(&log);
+    drop(log);
+    if let Err(e) = snapshot.and_then(|snapshot| store.write(snapshot)) {
+        error!("Failed to save log: {:?}", e);
+    }
+}
+
+/// Sets a device attribute, persists the store and publishes the op.
+fn put_kv(
+    swarm: &mut Swarm<MyBehaviour>,
+    app_state: &AppState,
+    port: u16,
+    topic: &gossipsub::IdentTopic,
+    device: &str,
+    attribute: &str,
+    value: serde_json::Value,
+) -> Result<()> {
+    if !kv::valid_name(device) || !kv::valid_name(attribute) {
+        return Err(anyhow!("device and attribute names must be non-empty, without '/' or spaces"));
+    }
+    let at = app_state.clock.write().unwrap().now();
+    let op = app_state.kv.write().unwrap().put(&app_state.local_peer_id, device, attribute, value.clone(), at);
+    let change = KvChange { device: device.to_string(), attribute: attribute.to_string(), value: Some(value) };
+    record_kv(app_state, port, vec![change]);
+    publish_replica_message(swarm, app_state, topic, &KvMessage::Op { op })
+}
+
+/// Deletes a device attribute, persists the store and publishes the op.
+fn delete_kv(
+    swarm: &mut Swarm<MyBehaviour>,
+    app_state: &AppState,
+    port: u16,
+    topic: &gossipsub::IdentTopic,
+    device: &str,
+    attribute: &str,
+) -> Result<()> {
+    let op = app_state.kv.write().unwrap().delete(device, attribute).ok_or_else(|| anyhow!("not set"))?;
+    let change = KvChange { device: device.to_string(), attribute: attribute.to_string(), value: None };
+    record_kv(app_state, port, vec![change]);
+    publish_replica_message(swarm, app_state, topic, &KvMessage::Op { op })
+}
+
+/// Persists the device store after `changes` and reports each changed key. Also advances
+/// the clock past remote writes, so a later local put wins over what was seen.
+fn record_kv(app_state: &AppState, port: u16, changes: Vec<KvChange>) {
+    if changes.is_empty() {
+        return;
+    }
+    let kv = app_state.kv.read().unwrap();
+    if let Some(latest) = kv.latest() {
+        app_state.clock.write().unwrap().observe(latest);
+    }
+    if let Err(e) = storage::save_kv(&app_state.namespace, port, &kv) {
+        error!("Failed to save device store: {:?}", e);
+    }
+    for KvChange { device, attribute, value } in changes {
+        app_state.watch.update_key("kv", &format!("{}/{}", device, attribute), value.clone());
+        let _ = app_state.telemetry_tx.send(NetworkEvent::KvChanged { device, attribute, value });
+    }
+}
+
+/// Adds `amount` to a counter, persists the counters and publishes the op.
+fn add_counter(
+    swarm: &mut Swarm<MyBehaviour>,
+    app_state: &AppState,
+    port: u16,
+    topic: &gossipsub::IdentTopic,
+    name: &str,
+    amount: i64,
+) -> Result<()> {
+    if !kv::valid_name(name) {
+        return Err(anyhow!("counter names must be non-empty, without '/' or spaces"));
+    }
+    let op = app_state.counters.write().unwrap().add(&app_state.local_peer_id, name, amount);
+    let Some(op) = op else {
+        return Ok(());
+    };
+    let value = app_state.counters.read().unwrap().get(name).unwrap_or_default();
+    record_counters(app_state, port, vec![CounterChange { name: name.to_string(), value }]);
+    publish_replica_message(swarm, app_state, topic, &CounterMessage::Op { op })
 }
 
-async fn create_swarm(port: u16, id_keys: libp2p::identity::Keypair) -> Result<Swarm<MyBehaviour>> {
+/// Persists the counters after `changes` and reports each changed counter.
+fn record_counters(app_state: &AppState, port: u16, changes: Vec<CounterChange>) {
+    if changes.is_empty() {
+        return;
+    }
+    if let Err(e) = storage::save_counters(&app_state.namespace, port, &app_state.counters.read().unwrap()) {
+        error!("Failed to save counters: {:?}", e);
+    }
+    for CounterChange { name, value } in changes {
+        app_state.watch.update_key("counters", &name, Some(value.into()));
+        let _ = app_state.telemetry_tx.send(NetworkEvent::CounterChanged { name, value });
+    }
+}
+
+fn collection_topic(namespace: &Namespace, name: &str) -> gossipsub::IdentTopic {
+    namespace.topic(&format!("ghostmesh-col-{}", name))
+}
+
+/// Opens a collection, persists it and joins its topic, where the digest exchange brings
+/// in what other nodes already hold.
+fn create_collection(
+    swarm: &mut Swarm<MyBehaviour>,
+    app_state: &AppState,
+    port: u16,
+    collections: &mut HashMap<String, ReplicaTopic<Collection>>,
+    name: &str,
+    kind: CollectionKind,
+) -> Result<()> {
+    if !collection::valid_name(name) {
+        return Err(anyhow!("collection names must be 1 to 64 letters, digits, '-', '_' or '.'"));
+    }
+    if let Some(replica) = collections.get(name) {
+        let existing = replica.store.read().unwrap().kind();
+        if existing != kind {
+            return Err(anyhow!("collection {} already exists as a {:?}", name, existing));
+        }
+        return Ok(());
+    }
+    let collection = Arc::new(RwLock::new(Collection::new(kind)));
+    let replica = ReplicaTopic::new(collection_topic(&app_state.namespace, name), &collection, "collection");
+    swarm.behaviour_mut().gossipsub.subscribe(&replica.topic)?;
+    app_state.collections.write().unwrap().insert(name.to_string(), collection.clone());
+    collections.insert(name.to_string(), replica);
+    storage::save_collection(&app_state.namespace, port, name, &collection.read().unwrap())?;
+    let value = collection.read().unwrap().value();
+    app_state.watch.update_view(&format!("collections/{}", name), collection_view(kind, &value));
+    let _ = app_state.telemetry_tx.send(NetworkEvent::CollectionChanged { name: name.to_string(), kind, value: Some(value) });
+    info!("Created {:?} collection {}", kind, name);
+    Ok(())
+}
+
+/// Leaves a collection's topic and deletes the local copy. Other nodes keep theirs.
+fn drop_collection(
+    swarm: &mut Swarm<MyBehaviour>,
+    app_state: &AppState,
+    port: u16,
+    collections: &mut HashMap<String, ReplicaTopic<Collection>>,
+    name: &str,
+) -> Result<()> {
+    let replica = collections.remove(name).ok_or_else(|| anyhow!("no collection {}", name))?;
+    swarm.behaviour_mut().gossipsub.unsubscribe(&replica.topic)?;
+    app_state.collections.write().unwrap().remove(name);
+    storage::delete_collection(&app_state.namespace, port, name)?;
+    let mut limits = app_state.collection_retention.write().unwrap();
+    if limits.remove(name).is_some() {
+        storage::save_collection_retention(&app_state.namespace, port, &limits)?;
+    }
+    drop(limits);
+    let kind = replica.store.read().unwrap().kind();
+    app_state.watch.update_view(&format!("collections/{}", name), View::new());
+    let _ = app_state.telemetry_tx.send(NetworkEvent::CollectionChanged { name: name.to_string(), kind, value: None });
+    info!("Dropped collection {}", name);
+    Ok(())
+}
+
+/// Applies a local update to a collection, persists it and publishes the op.
+fn update_collection(
+    swarm: &mut Swarm<MyBehaviour>,
+    app_state: &AppState,
+    port: u16,
+    collections: &HashMap<String, ReplicaTopic<Collection>>,
+    name: &str,
+    update: CollectionUpdate,
+) -> Result<()> {
+    let replica = collections.get(name).ok_or_else(|| anyhow!("no collection {}", name))?;
+    let retention = app_state.collection_retention.read().unwrap().get(name).copied().unwrap_or_default();
+    replica.store.read().unwrap().admits(&update, &retention)?;
+    let at = app_state.clock.write().unwrap().now();
+    let op = replica.store.write().unwrap().update(&app_state.local_peer_id, update, at)?;
+    let Some(op) = op else {
+        return Ok(());
+    };
+    // The ops of a document patch take one tick each from `at` on
+    if let CollectionOp::Document(ops) = &op {
+        if let Some(last) = ops.iter().map(DocOp::marker).max() {
+            app_state.clock.write().unwrap().observe(last);
+        }
+    }
+    let value = replica.store.read().unwrap().value();
+    record_collection(app_state, port, name, &replica.store, vec![value]);
+    publish_replica_message(swarm, app_state, &replica.topic, &CollectionMessage::Op { op })?;
+    trim_collection(swarm, app_state, port, name, replica)
+}
+
+/// Replaces the limits of a collection, persists them and trims the collection to them.
+fn set_collection_retention(
+    swarm: &mut Swarm<MyBehaviour>,
+    app_state: &AppState,
+    port: u16,
+    collections: &HashMap<String, ReplicaTopic<Collection>>,
+    name: &str,
+    retention: CollectionRetention,
+) -> Result<()> {
+    retention.validate().map_err(anyhow::Error::msg)?;
+    let replica = collections.get(name).ok_or_else(|| anyhow!("no collection {}", name))?;
+    let kind = replica.store.read().unwrap().kind();
+    if !kind.has_retention() && retention != CollectionRetention::default() {
+        return Err(anyhow!("{:?} collections take no retention limits", kind));
+    }
+    let mut limits = app_state.collection_retention.write().unwrap();
+    if retention == CollectionRetention::default() {
+        limits.remove(name);
+    } else {
+        limits.insert(name.to_string(), retention);
+    }
+    storage::save_collection_retention(&app_state.namespace, port, &limits)?;
+    drop(limits);
+    info!("Retention of collection {} set to {:?}", name, retention);
+    trim_collection(swarm, app_state, port, name, replica)
+}
+
+/// Deletes what a map or document holds beyond its limits, if it has any, and publishes
+/// the deletions like local updates.
+fn trim_collection(
+    swarm: &mut Swarm<MyBehaviour>,
+    app_state: &AppState,
+    port: u16,
+    name: &str,
+    replica: &ReplicaTopic<Collection>,
+) -> Result<()> {
+    let Some(retention) = app_state.collection_retention.read().unwrap().get(name).copied() else {
+        return Ok(());
+    };
+    let at = app_state.clock.write().unwrap().now();
+    let ops = replica.store.write().unwrap().trim(&app_state.local_peer_id, &retention, at)?;
+    if ops.is_empty() {
+        return Ok(());
+    }
+    for op in &ops {
+        if let CollectionOp::Document(ops) = op {
+            if let Some(last) = ops.iter().map(DocOp::marker).max() {
+                app_state.clock.write().unwrap().observe(last);
+            }
+        }
+    }
+    info!("Trimmed collection {} to {:?}", name, retention);
+    let value = replica.store.read().unwrap().value();
+    record_collection(app_state, port, name, &replica.store, vec![value]);
+    for op in ops {
+        publish_replica_message(swarm, app_state, &replica.topic, &CollectionMessage::Op { op })?;
+    }
+    Ok(())
+}
+
+/// Persists a collection after `changes` (its new values) and reports the latest. Also
+/// advances the clock past remote writes to maps and registers.
+fn record_collection(app_state: &AppState, port: u16, name: &str, store: &RwLock<Collection>, changes: Vec<serde_json::Value>) {
+    let Some(value) = changes.into_iter().next_back() else {
+        return;
+    };
+    let collection = store.read().unwrap();
+    if let Some(latest) = collection.latest() {
+        app_state.clock.write().unwrap().observe(latest);
+    }
+    if let Err(e) = storage::save_collection(&app_state.namespace, port, name, &collection) {
+        error!("Failed to save collection {}: {:?}", name, e);
+    }
+    let kind = collection.kind();
+    app_state.watch.update_view(&format!("collections/{}", name), collection_view(kind, &value));
+    let _ = app_state.telemetry_tx.send(NetworkEvent::CollectionChanged { name: name.to_string(), kind, value: Some(value) });
+}
+
+/// Sets the watch views to the state restored at startup, so only later changes are
+/// reported.
+fn seed_watch(app_state: &AppState) {
+    let kv = app_state.kv.read().unwrap().all();
+    let kv = kv.into_iter().flat_map(|(device, attributes)| {
+        attributes.into_iter().map(move |(attribute, value)| (format!("{}/{}", device, attribute), value))
+    });
+    app_state.watch.seed("kv", kv.collect());
+    let counters = app_state.counters.read().unwrap().all();
+    app_state.watch.seed("counters", counters.into_iter().map(|(name, value)| (name, value.into())).collect());
+    for (name, collection) in app_state.collections.read().unwrap().iter() {
+        let collection = collection.read().unwrap();
+        app_state.watch.seed(&format!("collections/{}", name), collection_view(collection.kind(), &collection.value()));
+    }
+}
+
+/// Merges a snapshot as if its parts came from a peer: log entries and removals are
+/// verified, replicas and collections merged (opening the missing ones), DMs and
+/// addresses added, and peers not connected dialed. Digests are then published so peers
+/// pick up what the snapshot brought.
+fn import_snapshot(
+    swarm: &mut Swarm<MyBehaviour>,
+    app_state: &AppState,
+    port: u16,
+    collections: &mut HashMap<String, ReplicaTopic<Collection>>,
+    snapshot: Snapshot,
+) -> Result<()> {
+    let data = snapshot.data;
+    if let Some(horizon) = data.log.horizon() {
+        adopt_horizon(app_state, port, horizon, data.log.horizon_signature().cloned(), "the snapshot");
+    }
+    let entries = merge_log(app_state, data.log.read());
+    let removed = merge_removals(app_state, data.log.tombstones().clone()).len();
+
+    let changes = app_state.kv.write().unwrap().merge(data.kv);
+    record_kv(app_state, port, changes);
+    let changes = app_state.counters.write().unwrap().merge(data.counters);
+    record_counters(app_state, port, changes);
+    let mut merged_collections = 0;
+    for (name, collection) in data.collections {
+        if let Err(e) = create_collection(swarm, app_state, port, collections, &name, collection.kind()) {
+            error!("Skipping collection {} of the snapshot: {:?}", name, e);
+            continue;
+        }
+        let replica = &collections[&name];
+        let changes = Replica::merge(&mut *replica.store.write().unwrap(), collection);
+        record_collection(app_state, port, &name, &replica.store, changes);
+        merged_collections += 1;
+    }
+
+    let dms = {
+        let mut dms = app_state.dms.write().unwrap();
+        let before = dms.len();
+        for dm in data.dms {
+            if !dms.contains(&dm) {
+                dms.push(dm);
+            }
+        }
+        if let Err(e) = storage::save_dms(&app_state.namespace, port, &dms) {
+            error!("Failed to save direct messages: {:?}", e);
+        }
+        dms.len() - before
+    };
+
+    let local = swarm.local_peer_id().to_string();
+    let mut dials = Vec::new();
+    {
+        let mut book = app_state.address_book.write().unwrap();
+        for (peer, addrs) in data.address_book {
+            let Ok(peer_id) = peer.parse::<PeerId>() else {
+                continue;
+            };
+            if peer == local {
+                continue;
+            }
+            if !swarm.is_connected(&peer_id) {
+                dials.extend(addrs.iter().filter_map(|addr| addr.parse::<Multiaddr>().ok()).map(|addr| (peer_id, addr)));
+            }
+            book.entry(peer).or_default().extend(addrs);
+        }
+        if let Err(e) = storage::save_address_book(&app_state.namespace, port, &book) {
+            error!("Failed to save address book: {:?}", e);
+        }
+    }
+    let peers = dials.iter().map(|(peer_id, _)| *peer_id).collect::<HashSet<_>>().len();
+    for (peer_id, addr) in dials {
+        swarm.behaviour_mut().gossipsub.add_explicit_peer(&peer_id);
+        if let Err(e) = swarm.dial(addr.clone().with(libp2p::multiaddr::Protocol::P2p(peer_id))) {
+            error!("Dial error for {}: {:?}", addr, e);
+        }
+    }
+
+    info!(
+        "Imported snapshot by {}: {} new entries, {} removed, {} collections, {} DMs, dialing {} peers",
+        snapshot.exported_by, entries, removed, merged_collections, dms, peers
+    );
+    let _ = app_state.telemetry_tx.send(NetworkEvent::SnapshotImported {
+        exported_by: snapshot.exported_by,
+        entries,
+        removed,
+        collections: merged_collections,
+        dms,
+        peers,
+    });
+    Ok(())
+}
+
+/// A replica's topic, with the full-state requests recently sent on it.
+struct ReplicaTopic<R> {
+    topic: gossipsub::IdentTopic,
+    store: Arc<RwLock<R>>,
+    /// Names the replica in logs.
+    what: &'static str,
+    state_requests: HashMap<PeerId, Instant>,
+}
+
+impl<R: Replica> ReplicaTopic<R> {
+    fn new(topic: gossipsub::IdentTopic, store: &Arc<RwLock<R>>, what: &'static str) -> Self {
+        Self { topic, store: store.clone(), what, state_requests: HashMap::new() }
+    }
+
+    /// Whether it replicates on `topic`, as named in a `SyncMessage`.
+    fn is_on(&self, topic: &str) -> bool {
+        self.topic.hash().as_str() == topic
+    }
+
+    /// The full state, to answer a `SyncMessage::StateRequest`.
+    fn state(&self) -> Option<serde_json::Value> {
+        serde_json::to_value(&*self.store.read().unwrap()).ok()
+    }
+
+    /// Merges a full state received in a `SyncMessage::State`, returning what changed.
+    fn merge_state(&self, author: PeerId, state: serde_json::Value) -> Vec<R::Change> {
+        match serde_json::from_value::<R>(state) {
+            Ok(state) => self.merge(author, state),
+            Err(e) => {
+                error!("Failed to deserialize {} from {}: {:?}", self.what, author, e);
+                Vec::new()
+            }
+        }
+    }
+
+    fn merge(&self, author: PeerId, state: R) -> Vec<R::Change> {
+        let changes = self.store.write().unwrap().merge(state);
+        info!("Merged {} from {}: {} keys changed", self.what, author, changes.len());
+        changes
+    }
+}
+
+/// Handles a message on a replica topic: applies ops and merges states, returning what
+/// changed for the caller to record, and answers state requests. A digest that differs
+/// from ours, or an op after a gap, asks the author for its full state, at most once per
+/// `STATE_REQUEST_INTERVAL`.
+fn handle_replica_message<R: Replica>(
+    swarm: &mut Swarm<MyBehaviour>,
+    app_state: &AppState,
+    replica: &mut ReplicaTopic<R>,
+    author: PeerId,
+    data: &[u8],
+) -> Vec<R::Change> {
+    let what = replica.what;
+    let mut changes = Vec::new();
+    let request_state = match serde_json::from_slice::<ReplicaMessage<R>>(data) {
+        Ok(ReplicaMessage::Op { op }) => {
+            let applied = replica.store.write().unwrap().apply(op);
+            match applied {
+                Ok(applied) => {
+                    changes = applied;
+                    false
+                }
+                Err(_) => {
+                    info!("Missed earlier {} ops from {}", what, author);
+                    true
+                }
+            }
+        }
+        Ok(ReplicaMessage::Digest { digest }) => digest != replica.store.read().unwrap().digest(),
+        // Only nodes before `version::FEATURE_STATE_SYNC` ask on the topic
+        Ok(ReplicaMessage::StateRequest { to }) => {
+            let legacy = !app_state.peer_supports(&author, version::FEATURE_STATE_SYNC);
+            if legacy && (to.is_none() || to.as_deref() == Some(&swarm.local_peer_id().to_string())) {
+                let state = replica.store.read().unwrap().clone();
+                if let Err(e) = publish_replica_message(swarm, app_state, &replica.topic, &ReplicaMessage::State { state }) {
+                    error!("Failed to publish {}: {:?}", what, e);
+                }
+            }
+            false
+        }
+        Ok(ReplicaMessage::State { state }) => {
+            changes = replica.merge(author, state);
+            false
+        }
+        Err(e) => {
+            error!("Failed to deserialize {} message from {}: {:?}", what, author, e);
+            false
+        }
+    };
+    let recently_asked = replica
+        .state_requests
+        .get(&author)
+        .is_some_and(|at: &Instant| at.elapsed() < STATE_REQUEST_INTERVAL);
+    if request_state && !recently_asked {
+        replica.state_requests.insert(author, Instant::now());
+        if app_state.peer_supports(&author, version::FEATURE_STATE_SYNC) {
+            let request = SyncMessage::StateRequest { topic: replica.topic.hash().into_string() };
+            swarm.behaviour_mut().sync.send_request(&author, request);
+        } else {
+            let request = ReplicaMessage::<R>::StateRequest { to: Some(author.to_string()) };
+            if let Err(e) = publish_replica_message(swarm, app_state, &replica.topic, &request) {
+                error!("Failed to request {} from {}: {:?}", what, author, e);
+            }
+        }
+    }
+    changes
+}
+
+fn publish_replica_digest<R: Replica>(swarm: &mut Swarm<MyBehaviour>, app_state: &AppState, replica: &ReplicaTopic<R>) {
+    let digest = ReplicaMessage::<R>::Digest { digest: replica.store.read().unwrap().digest() };
+    if let Err(e) = publish_replica_message(swarm, app_state, &replica.topic, &digest) {
+        error!("Failed to publish {} digest: {:?}", replica.what, e);
+    }
+}
+
+/// Publishes on a replica topic. Having no peers yet is not an error: the digest
+/// exchange brings them up to date once they subscribe.
+fn publish_replica_message<R: Replica>(
+    swarm: &mut Swarm<MyBehaviour>,
+    app_state: &AppState,
+    topic: &gossipsub::IdentTopic,
+    msg: &ReplicaMessage<R>,
+) -> Result<()> {
+    match publish(swarm, app_state, topic, serde_json::to_vec(msg)?) {
+        Ok(_) | Err(gossipsub::PublishError::InsufficientPeers) => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Publishes a chat line on the global topic and records it in the local history.
+fn publish_chat(
+    swarm: &mut Swarm<MyBehaviour>,
+    app_state: &AppState,
+    port: u16,
+    topic: &gossipsub::IdentTopic,
+    content: String,
+) {
+    let chat_msg = ChatMessage {
+        from: swarm.local_peer_id().to_string(),
+        content,
+        timestamp: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs(),
+        topic: topic.to_string(),
+    };
+
+    // Peers from before 1.1.0 expect the bare line on the global topic.
+    let encoded = if app_state.mesh_supports(version::FEATURE_CHAT_JSON) {
+        serde_json::to_vec(&chat_msg)
+    } else {
+        Ok(chat_msg.content.as_bytes().to_vec())
+    };
+
+    match encoded {
+        Ok(bytes) => {
+            if let Err(e) = publish(swarm, app_state, topic, bytes) {
+                error!("Publish error: {:?}", e);
+            }
+        }
+        Err(e) => error!("Failed to serialize chat message: {:?}", e),
+    }
+
+    record_chat(app_state, port, chat_msg);
+}
+
+/// Appends a chat message to the history, persists it and notifies the dashboard.
+fn record_chat(app_state: &AppState, port: u16, chat_msg: ChatMessage) {
+    let _ = app_state.telemetry_tx.send(NetworkEvent::ChatMessage {
+        from: chat_msg.from.clone(),
+        content: chat_msg.content.clone(),
+        timestamp: chat_msg.timestamp,
+        topic: chat_msg.topic.clone(),
+    });
+
+    let mut chat = app_state.chat.write().unwrap();
+    chat.push(chat_msg);
+    // Trimmed back to `MAX_CHAT_HISTORY` once twice as long, rewriting the file
+    let saved = if chat.len() > 2 * MAX_CHAT_HISTORY {
+        let dropped = chat.len() - MAX_CHAT_HISTORY;
+        chat.drain(..dropped);
+        app_state.chat_dropped.fetch_add(dropped, std::sync::atomic::Ordering::SeqCst);
+        storage::save_chat(&app_state.namespace, port, &chat)
+    } else {
+        storage::append_chat(&app_state.namespace, port, &chat[chat.len() - 1])
+    };
+    if let Err(e) = saved {
+        error!("Failed to save chat history: {:?}", e);
+    }
+}
+
+fn instrument<M>(muxer: M, local: PeerId, peer: PeerId, bandwidth: Bandwidth, chaos: Chaos) -> StreamMuxerBox
+where
+    M: libp2p::core::muxing::StreamMuxer + Send + Unpin + 'static,
+    M::Substream: Send + Unpin + 'static,
+    M::Error: Send + Sync + 'static,
+{
+    StreamMuxerBox::new(ChaosMuxer::new(MeteredMuxer::new(muxer, peer, bandwidth), local, peer, chaos))
+}
+
+async fn create_swarm(
+    port: u16,
+    id_keys: libp2p::identity::Keypair,
+    bandwidth: Bandwidth,
+    chaos: Chaos,
+    namespace: &Namespace,
+    transport: TransportKind,
+) -> Result<Swarm<MyBehaviour>> {
     let peer_id = PeerId::from(id_keys.public());
     info!("Local Peer ID: {peer_id}");
 
     let mut swarm = libp2p::SwarmBuilder::with_existing_identity(id_keys)
         .with_tokio()
-        .with_tcp(
-            tcp::Config::default(),
-            noise::Config::new,
-            yamux::Config::default,
-        )?
+        .with_other_transport(|key| -> Result<_, Box<dyn std::error::Error + Send + Sync>> {
+            // TCP (or memory) + Noise + Yamux, with every connection metered for bandwidth
+            // accounting and passed through the fault injection layer
+            let local = key.public().to_peer_id();
+            let transport: Boxed<(PeerId, StreamMuxerBox)> = match transport {
+                TransportKind::Tcp => tcp::tokio::Transport::new(tcp::Config::default())
+                    .upgrade(upgrade::Version::V1Lazy)
+                    .authenticate(noise::Config::new(key)?)
+                    .multiplex(yamux::Config::default())
+                    .map(move |(peer, muxer), _| (peer, instrument(muxer, local, peer, bandwidth, chaos)))
+                    .boxed(),
+                TransportKind::Memory => MemoryTransport::default()
+                    .upgrade(upgrade::Version::V1Lazy)
+                    .authenticate(noise::Config::new(key)?)
+                    .multiplex(yamux::Config::default())
+                    .map(move |(peer, muxer), _| (peer, instrument(muxer, local, peer, bandwidth, chaos)))
+                    .boxed(),
+            };
+            Ok(transport)
+        })?
 
         .with_behaviour(|key| {
             // Gossipsub configuration
             let message_id_fn = |message: &gossipsub::Message| {
-                let mut s = DefaultHasher::new();
-                message.data.hash(&mut s);
-                gossipsub::MessageId::from(s.finish().to_string())
+                gossipsub::MessageId::from(payload_hash(&message.data).to_string())
             };
 
             let gossipsub_config = gossipsub::ConfigBuilder::default()
                 .heartbeat_interval(Duration::from_secs(10))
-                .validation_mode(gossipsub::ValidationMode::Permissive)
+                .validation_mode(gossipsub::ValidationMode::Strict)
                 .message_id_fn(message_id_fn)
+                .max_transmit_size(MAX_TRANSMIT_SIZE)
                 .build()
                 .map_err(|msg| anyhow::anyhow!(msg))?;
 
@@ -397,22 +2533,36 @@ async fn create_swarm(port: u16, id_keys: libp2p::identity::Keypair) -> Result<S
                 gossipsub_config,
             )?;
 
-            let mdns = mdns::tokio::Behaviour::new(mdns::Config::default(), key.public().to_peer_id())?;
+            let mdns = match transport {
+                TransportKind::Tcp => Some(mdns::tokio::Behaviour::new(mdns::Config::default(), key.public().to_peer_id())?),
+                TransportKind::Memory => None,
+            };
+            let mdns = Toggle::from(mdns);
             
             let ping = ping::Behaviour::new(ping::Config::new().with_interval(Duration::from_secs(60)).with_timeout(Duration::from_secs(30)));
             
-            let identify = identify::Behaviour::new(identify::Config::new(
-                "ghostmesh/1.0.0".to_string(),
-                key.public(),
-            ));
+            let identify = identify::Behaviour::new(
+                identify::Config::new(version::protocol_string(), key.public())
+                    .with_agent_version(version::agent_string(&namespace.name)),
+            );
+
+            let sync = request_response::Behaviour::with_codec(
+                SyncCodec::new(namespace.clone()),
+                [(SYNC_PROTOCOL, request_response::ProtocolSupport::Full)],
+                request_response::Config::default().with_request_timeout(SESSION_TIMEOUT),
+            );
 
-            Ok(MyBehaviour { gossipsub, mdns, ping, identify })
+            Ok(MyBehaviour { gossipsub, mdns, ping, identify, sync })
         })?
         .build();
 
     // Listen on all interfaces and the specified port
 
-    swarm.listen_on(format!("/ip4/0.0.0.0/tcp/{}", port).parse()?)?;
+    let listen_addr = match transport {
+        TransportKind::Tcp => format!("/ip4/0.0.0.0/tcp/{}", port),
+        TransportKind::Memory => format!("/memory/{}", port),
+    };
+    swarm.listen_on(listen_addr.parse()?)?;
 
     Ok(swarm)
 }
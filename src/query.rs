@@ -0,0 +1,160 @@
+use crate::entry::{EntryKey, LogEntry};
+use crate::hlc::Hlc;
+use crate::logset::LogSet;
+use serde::{Deserialize, Serialize};
+
+/// Entries per page when the query does not set `limit`.
+pub const DEFAULT_LIMIT: usize = 100;
+/// Most entries a page may hold.
+pub const MAX_LIMIT: usize = 1000;
+
+/// Query of `GET /api/log`. Every filter is optional, and they combine.
+///
+/// Without `since` the answer is a page of the newest matching entries, and `next_before`
+/// pages back through older ones. With `since` (the `cursor` of an earlier answer) it holds
+/// only the entries added and removed after it, so a client polling the log only
+/// transfers what changed.
+#[derive(Deserialize, Default, Clone, Debug)]
+#[serde(default)]
+pub struct LogQuery {
+    /// Wall-clock milliseconds, included.
+    pub from: Option<u64>,
+    /// Wall-clock milliseconds, excluded.
+    pub to: Option<u64>,
+    /// Peer ID of the author.
+    pub author: Option<String>,
+    pub tag: Option<String>,
+    /// Text searched, ignoring case, in the body and tags.
+    pub q: Option<String>,
+    /// Page cursor: only entries ordered before it.
+    pub before: Option<String>,
+    /// Change cursor: only changes after it.
+    pub since: Option<u64>,
+    pub limit: Option<usize>,
+}
+
+/// Answer to a `LogQuery`.
+#[derive(Serialize, Debug)]
+pub struct LogPage {
+    /// Matching entries, in timestamp order.
+    pub entries: Vec<LogEntry>,
+    /// Entries removed after `since`, by id and author.
+    pub removed: Vec<EntryKey>,
+    /// Matching entries over all pages or, for `since`, matching changes including those
+    /// left for the next poll.
+    pub total: usize,
+    /// Cursor for the next (older) page, `None` when the start of the log is reached.
+    pub next_before: Option<String>,
+    /// Change cursor to pass as `since` next.
+    pub cursor: u64,
+    /// `since` was too old to answer with changes, so this is a first page instead and
+    /// entries read before should be dropped.
+    pub reset: bool,
+    /// Entries stamped before this were compacted and should be dropped too.
+    pub horizon: Option<Hlc>,
+}
+
+impl LogQuery {
+    /// Runs the query against `log`. Fails on a malformed `before` cursor.
+    pub fn run(&self, log: &LogSet) -> Result<LogPage, String> {
+        let before = self.before.as_deref().map(parse_key).transpose()?;
+        let limit = self.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+        let needle = self.q.as_ref().map(|q| q.to_lowercase());
+        let matches = |entry: &LogEntry| {
+            self.from.is_none_or(|from| entry.timestamp.wall_ms >= from)
+                && self.to.is_none_or(|to| entry.timestamp.wall_ms < to)
+                && self.author.as_ref().is_none_or(|author| &entry.author == author)
+                && self.tag.as_ref().is_none_or(|tag| entry.tags.contains(tag))
+                && needle.as_ref().is_none_or(|needle| {
+                    entry.text_body().to_lowercase().contains(needle)
+                        || entry.tags.iter().any(|tag| tag.to_lowercase().contains(needle))
+                })
+        };
+
+        if let Some(changes) = self.since.and_then(|since| log.changes_since(since)) {
+            let mut added = changes.added.into_iter().filter(|(_, entry)| matches(entry)).peekable();
+            let mut removed = changes.removed.into_iter().peekable();
+            let mut page = LogPage::empty(log);
+            // Both lists are in sequence order; take the oldest changes first
+            while page.entries.len() + page.removed.len() < limit {
+                let take_added = match (added.peek(), removed.peek()) {
+                    (Some((a, _)), Some((r, _))) => a < r,
+                    (Some(_), None) => true,
+                    (None, Some(_)) => false,
+                    (None, None) => break,
+                };
+                if take_added {
+                    let (seq, entry) = added.next().unwrap();
+                    page.cursor = seq;
+                    page.entries.push(entry.clone());
+                } else {
+                    let (seq, key) = removed.next().unwrap();
+                    page.cursor = seq;
+                    page.removed.push(key.clone());
+                }
+            }
+            let left = added.count() + removed.count();
+            if left == 0 {
+                page.cursor = log.seq();
+            }
+            page.total = page.entries.len() + page.removed.len() + left;
+            page.entries.sort();
+            return Ok(page);
+        }
+
+        let mut page = LogPage::empty(log);
+        page.reset = self.since.is_some();
+        let mut more = false;
+        for entry in log.iter().rev().filter(|entry| matches(entry)) {
+            page.total += 1;
+            if before.as_ref().is_some_and(|before| order_key(entry) >= *before) {
+                continue;
+            }
+            if page.entries.len() < limit {
+                page.entries.push(entry.clone());
+            } else {
+                more = true;
+            }
+        }
+        page.entries.reverse();
+        if more {
+            page.next_before = page.entries.first().map(cursor_of);
+        }
+        Ok(page)
+    }
+}
+
+impl LogPage {
+    fn empty(log: &LogSet) -> Self {
+        Self {
+            entries: Vec::new(),
+            removed: Vec::new(),
+            total: 0,
+            next_before: None,
+            cursor: log.seq(),
+            reset: false,
+            horizon: log.horizon(),
+        }
+    }
+}
+
+/// The part of an entry's order that identifies it: timestamp, author and id.
+fn order_key(entry: &LogEntry) -> (Hlc, &str, &str) {
+    (entry.timestamp, &entry.author, &entry.id)
+}
+
+/// Page cursor of an entry, `<wall_ms>.<counter>.<author>.<id>`.
+fn cursor_of(entry: &LogEntry) -> String {
+    format!("{}.{}.{}.{}", entry.timestamp.wall_ms, entry.timestamp.counter, entry.author, entry.id)
+}
+
+fn parse_key(cursor: &str) -> Result<(Hlc, &str, &str), String> {
+    let invalid = || format!("invalid page cursor '{}'", cursor);
+    let mut parts = cursor.splitn(4, '.');
+    let mut next = || parts.next().ok_or_else(invalid);
+    let wall_ms = next()?.parse().map_err(|_| invalid())?;
+    let counter = next()?.parse().map_err(|_| invalid())?;
+    let author = next()?;
+    let id = next()?;
+    Ok((Hlc::new(wall_ms, counter), author, id))
+}
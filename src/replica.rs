@@ -0,0 +1,51 @@
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+/// Replicated state with its own gossipsub topic, such as `kv::DeviceStore` or
+/// `counter::Counters`.
+///
+/// Like the log, local changes are published as single ops, and a periodic digest lets
+/// peers detect what they missed and ask for the full state, which is merged. The state is
+/// asked for and sent over `sync::SYNC_PROTOCOL`, so it is not bounded by gossipsub's
+/// message size; nodes before `version::FEATURE_STATE_SYNC` use `StateRequest` and `State`
+/// on the topic instead.
+pub trait Replica: Clone + Default + Serialize + DeserializeOwned {
+    type Op: Clone + Serialize + DeserializeOwned;
+    /// A change reported on the WebSocket, one per key.
+    type Change;
+
+    /// Applies a remote op, returning what changed. Fails with `Gap` when the op depends
+    /// on earlier ops that were missed, so the full state is needed.
+    fn apply(&mut self, op: Self::Op) -> Result<Vec<Self::Change>, Gap>;
+
+    /// Merges another replica's full state, returning what changed.
+    fn merge(&mut self, other: Self) -> Vec<Self::Change>;
+
+    /// Hash of the state. Equal digests mean nothing to exchange.
+    fn digest(&self) -> String;
+}
+
+/// An op that cannot be applied before earlier ops of its writer, which were missed.
+#[derive(Debug)]
+pub struct Gap;
+
+/// Messages on a replica's topic.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", bound = "R: Replica")]
+pub enum ReplicaMessage<R: Replica> {
+    /// A local change.
+    Op { op: R::Op },
+    /// Periodic summary of the sender's state.
+    Digest { digest: String },
+    /// Asks one peer (or every peer when `to` is `None`) for its full state. Only sent to
+    /// and answered for nodes before `version::FEATURE_STATE_SYNC`.
+    StateRequest { to: Option<String> },
+    /// Full state, published in answer to a `StateRequest`.
+    State { state: R },
+}
+
+/// Hex SHA-256 of a value's JSON encoding, for `Replica::digest`.
+pub fn hash_json(value: &impl Serialize) -> String {
+    use sha2::{Digest as _, Sha256};
+    Sha256::digest(serde_json::to_vec(value).unwrap_or_default()).iter().map(|b| format!("{:02x}", b)).collect()
+}
@@ -0,0 +1,141 @@
+use crate::entry::LogEntry;
+use crate::hlc::Hlc;
+use crate::logset::LogSet;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+
+/// How many entries of each author a copy of the log holds, and the newest of them.
+///
+/// Gossip may drop an entry in the middle of an author's sequence, so holding an author's
+/// entry of `t` does not prove holding the earlier ones. A peer is taken to hold the local
+/// entries of an author up to its newest only when it holds as many of them as this node;
+/// when the counts differ, which ones it lacks is unknown. Peers send their vector with
+/// digests, full states and anti-entropy offers, and the entries they publish raise it in
+/// between.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+#[serde(transparent)]
+pub struct VersionVector(BTreeMap<String, AuthorVersion>);
+
+/// An author's entries in a `VersionVector`.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AuthorVersion {
+    pub newest: Hlc,
+    pub count: usize,
+}
+
+impl VersionVector {
+    pub fn of(log: &LogSet) -> Self {
+        let mut vector = Self::default();
+        for entry in log.iter() {
+            let version = vector.0.entry(entry.author.clone()).or_insert(AuthorVersion { newest: entry.timestamp, count: 0 });
+            version.newest = version.newest.max(entry.timestamp);
+            version.count += 1;
+        }
+        vector
+    }
+
+    /// Raises the vector with entries the peer showed it holds. Only entries newer than the
+    /// newest known of their author are counted, as older ones may be counted already.
+    pub fn observe<'a>(&mut self, entries: impl IntoIterator<Item = &'a LogEntry>) {
+        let mut entries: Vec<&LogEntry> = entries.into_iter().collect();
+        entries.sort_by_key(|entry| entry.timestamp);
+        for entry in entries {
+            match self.0.get_mut(&entry.author) {
+                Some(version) if entry.timestamp <= version.newest => {}
+                Some(version) => {
+                    version.newest = entry.timestamp;
+                    version.count += 1;
+                }
+                None => {
+                    self.0.insert(entry.author.clone(), AuthorVersion { newest: entry.timestamp, count: 1 });
+                }
+            }
+        }
+    }
+
+    pub fn get(&self, author: &str) -> Option<AuthorVersion> {
+        self.0.get(author).copied()
+    }
+}
+
+/// What this node knows of a peer's copy of the log.
+#[derive(Clone, Debug, Default)]
+pub struct PeerReplication {
+    pub vector: VersionVector,
+    /// Wall-clock milliseconds of the last exchange the vector was learned from.
+    pub updated_ms: u64,
+    /// Last status sent as telemetry, so only changes are.
+    pub reported: Option<ReplicationStatus>,
+}
+
+/// How far a peer's copy of the log is behind this node's.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ReplicationStatus {
+    /// Entries of the local log newer than any the peer holds of their author.
+    pub missing: usize,
+    /// Entries of the local log the peer may or may not hold: it holds another number of
+    /// their author's entries up to its newest than this node does.
+    pub unknown: usize,
+    /// Timestamp of the oldest of them, in wall-clock milliseconds.
+    pub oldest_missing_ms: Option<u64>,
+    /// The peer holds entries this node has not received yet.
+    pub ahead: bool,
+    /// Wall-clock milliseconds of the last exchange this is based on.
+    pub updated_ms: u64,
+}
+
+impl PeerReplication {
+    /// Compares the peer's vector against `log`.
+    pub fn status(&self, log: &LogSet) -> ReplicationStatus {
+        // Local entries of each author up to the peer's newest of that author
+        let mut held_here: HashMap<&str, usize> = HashMap::new();
+        for entry in log.iter() {
+            if self.vector.get(&entry.author).is_some_and(|version| entry.timestamp <= version.newest) {
+                *held_here.entry(&entry.author).or_default() += 1;
+            }
+        }
+        let counted_alike = |author: &str| self.vector.get(author).is_some_and(|version| version.count == held_here.get(author).copied().unwrap_or(0));
+
+        let (mut missing, mut unknown, mut oldest_missing_ms) = (0, 0, None);
+        for entry in log.iter() {
+            match self.vector.get(&entry.author) {
+                Some(version) if entry.timestamp <= version.newest => {
+                    if !counted_alike(&entry.author) {
+                        unknown += 1;
+                    }
+                }
+                _ => {
+                    missing += 1;
+                    oldest_missing_ms = oldest_missing_ms.or(Some(entry.timestamp.wall_ms));
+                }
+            }
+        }
+        let local = VersionVector::of(log);
+        let ahead = self.vector.0.iter().any(|(author, version)| {
+            version.count > held_here.get(author.as_str()).copied().unwrap_or(0) || local.get(author).is_none_or(|ours| version.newest > ours.newest)
+        });
+        ReplicationStatus { missing, unknown, oldest_missing_ms, ahead, updated_ms: self.updated_ms }
+    }
+}
+
+impl ReplicationStatus {
+    /// Age of the oldest missing entry at `now_ms`, 0 when the peer is not behind.
+    pub fn lag_ms(&self, now_ms: u64) -> u64 {
+        self.oldest_missing_ms.map_or(0, |oldest| now_ms.saturating_sub(oldest))
+    }
+
+    pub fn in_sync(&self) -> bool {
+        self.missing == 0 && self.unknown == 0 && !self.ahead
+    }
+}
+
+/// A connected peer in `GET /api/peers`.
+#[derive(Serialize, Clone, Debug)]
+pub struct PeerReport {
+    pub peer_id: String,
+    /// `None` until the peer sent its version vector or entries.
+    pub replication: Option<ReplicationStatus>,
+    /// See `ReplicationStatus::lag_ms`.
+    pub lag_ms: u64,
+    pub in_sync: bool,
+}
@@ -0,0 +1,165 @@
+use crate::entry::HorizonSignature;
+use crate::hlc::{Hlc, MAX_DRIFT_MS};
+use crate::logset::LogSet;
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+
+/// How long a namespace's log keeps its entries. The default keeps everything. Named
+/// collections have their own limits, see `CollectionRetention`.
+///
+/// Each limit proposes a compaction horizon, an HLC timestamp below which entries are
+/// dropped (see `LogSet::compact`), and the strictest limit wins. Nodes share the horizons
+/// they reach, and a replica adopts one a log admin signed whatever its own policy, so
+/// every replica drops the same entries and none hands compacted entries back. Horizons of
+/// other peers are adopted only as far as the local age limit reaches; see `admits`.
+#[derive(Clone, Copy, Default, Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[serde(default)]
+pub struct RetentionPolicy {
+    /// Entries stamped more than this many seconds ago are compacted.
+    pub max_age_secs: Option<u64>,
+    /// Only the newest entries are kept, up to this count.
+    pub max_entries: Option<usize>,
+    /// Only the newest entries are kept, up to this size of their JSON encoding.
+    pub max_bytes: Option<usize>,
+    /// Appends compacted entries to the archive file instead of only dropping them.
+    pub archive: bool,
+}
+
+impl RetentionPolicy {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.max_age_secs == Some(0) || self.max_entries == Some(0) || self.max_bytes == Some(0) {
+            return Err("retention limits must be positive".to_string());
+        }
+        Ok(())
+    }
+
+    /// Parses a `--retention` argument: comma-separated `max-age=<n>[s|m|h|d]`,
+    /// `max-entries=<n>`, `max-bytes=<n>[k|M|G]` and `archive`, e.g. `max-age=30d,archive`.
+    pub fn parse(spec: &str) -> Result<Self> {
+        let mut policy = Self::default();
+        for part in spec.split(',').map(str::trim).filter(|part| !part.is_empty()) {
+            match part.split_once('=') {
+                Some(("max-age", value)) => policy.max_age_secs = Some(with_unit(value, &[("s", 1), ("m", 60), ("h", 3600), ("d", 86400)])?),
+                Some(("max-entries", value)) => {
+                    policy.max_entries = Some(value.parse().map_err(|_| anyhow!("invalid entry count '{}'", value))?)
+                }
+                Some(("max-bytes", value)) => {
+                    policy.max_bytes = Some(with_unit(value, &[("k", 1 << 10), ("M", 1 << 20), ("G", 1 << 30)])? as usize)
+                }
+                None if part == "archive" => policy.archive = true,
+                _ => return Err(anyhow!("unknown retention setting '{}'", part)),
+            }
+        }
+        policy.validate().map_err(anyhow::Error::msg)?;
+        Ok(policy)
+    }
+
+    /// Horizon this policy proposes for `log` at wall-clock `now_ms`, or `None` while every
+    /// entry is within the limits. The newest entry is always kept.
+    pub fn horizon(&self, log: &LogSet, now_ms: u64) -> Option<Hlc> {
+        let by_age = self.max_age_secs.and_then(|secs| {
+            let cutoff = Hlc::new(now_ms.saturating_sub(secs.saturating_mul(1000)), 0);
+            log.iter().next().filter(|oldest| oldest.timestamp < cutoff).map(|_| cutoff)
+        });
+        let by_count = self
+            .max_entries
+            .filter(|max| log.len() > *max)
+            .and_then(|max| log.iter().nth(log.len() - max))
+            .map(|first_kept| first_kept.timestamp);
+        let by_size = self.max_bytes.and_then(|max| {
+            let mut total = 0;
+            let mut first_kept = None;
+            for entry in log.iter().rev() {
+                total += serde_json::to_vec(entry).map_or(0, |json| json.len());
+                if total > max {
+                    return Some(first_kept.unwrap_or(entry.timestamp));
+                }
+                first_kept = Some(entry.timestamp);
+            }
+            None
+        });
+        [by_age, by_count, by_size].into_iter().flatten().max()
+    }
+
+    /// Whether a horizon received from a peer or a snapshot may be adopted at wall-clock
+    /// `now_ms`. A horizon ahead of the clock (beyond `MAX_DRIFT_MS`) never is. One signed
+    /// by a log admin is; any other only up to this policy's own age limit, also with
+    /// `MAX_DRIFT_MS` of leeway, since another node's count and size limits cannot be checked.
+    pub fn admits(&self, horizon: Hlc, signature: Option<&HorizonSignature>, admins: &HashSet<String>, now_ms: u64) -> Result<(), String> {
+        if horizon.wall_ms > now_ms.saturating_add(MAX_DRIFT_MS) {
+            return Err(format!("horizon {:?} is ahead of the local clock", horizon));
+        }
+        if let Some(signature) = signature.filter(|signature| admins.contains(&signature.signer)) {
+            return signature.verify(horizon);
+        }
+        match self.max_age_secs {
+            Some(secs) if horizon.wall_ms <= now_ms.saturating_sub(secs.saturating_mul(1000)).saturating_add(MAX_DRIFT_MS) => Ok(()),
+            Some(_) => Err(format!("horizon {:?} is not signed by a log admin and beyond the local age limit", horizon)),
+            None => Err(format!("horizon {:?} is not signed by a log admin and no age limit is set here", horizon)),
+        }
+    }
+}
+
+/// Size limits of a named collection. The default has none.
+///
+/// Collections have no horizon to compact to, so limits are kept with ordinary ops that
+/// replicate like any other change (see `Collection::trim`): a map or document over its
+/// limits deletes its least recently written keys or top-level fields. A set only grows and
+/// an OR-set has no write times to pick the oldest members by, so once they are full local
+/// adds are refused instead (see `Collection::admits`). Counters and registers hold a
+/// single value and take no limits.
+#[derive(Clone, Copy, Default, Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[serde(default)]
+pub struct CollectionRetention {
+    /// Members of a set, keys of a map or top-level fields of a document kept.
+    pub max_entries: Option<usize>,
+    /// Largest JSON encoding of the collection's value.
+    pub max_bytes: Option<usize>,
+}
+
+impl CollectionRetention {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.max_entries == Some(0) || self.max_bytes == Some(0) {
+            return Err("retention limits must be positive".to_string());
+        }
+        Ok(())
+    }
+
+    /// Parses comma-separated `max-entries=<n>` and `max-bytes=<n>[k|M|G]`, as in
+    /// `/col retention`.
+    pub fn parse(spec: &str) -> Result<Self> {
+        let mut retention = Self::default();
+        for part in spec.split(',').map(str::trim).filter(|part| !part.is_empty()) {
+            match part.split_once('=') {
+                Some(("max-entries", value)) => {
+                    retention.max_entries = Some(value.parse().map_err(|_| anyhow!("invalid entry count '{}'", value))?)
+                }
+                Some(("max-bytes", value)) => {
+                    retention.max_bytes = Some(with_unit(value, &[("k", 1 << 10), ("M", 1 << 20), ("G", 1 << 30)])? as usize)
+                }
+                _ => return Err(anyhow!("unknown collection retention setting '{}'", part)),
+            }
+        }
+        retention.validate().map_err(anyhow::Error::msg)?;
+        Ok(retention)
+    }
+
+    /// Whether `entries` entries whose value encodes to `bytes` bytes are within the limits.
+    pub fn allows(&self, entries: usize, bytes: usize) -> bool {
+        self.max_entries.is_none_or(|max| entries <= max) && self.max_bytes.is_none_or(|max| bytes <= max)
+    }
+}
+
+/// Parses a number with an optional unit suffix, returning it times the unit's factor.
+fn with_unit(value: &str, units: &[(&str, u64)]) -> Result<u64> {
+    let (number, factor) = units
+        .iter()
+        .find_map(|(unit, factor)| value.strip_suffix(unit).map(|number| (number, *factor)))
+        .unwrap_or((value, 1));
+    number
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(factor))
+        .ok_or_else(|| anyhow!("invalid retention limit '{}'", value))
+}
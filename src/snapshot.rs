@@ -0,0 +1,135 @@
+use crate::collection::Collection;
+use crate::counter::Counters;
+use crate::kv::DeviceStore;
+use crate::logset::LogSet;
+use crate::replica;
+use crate::state::{AddressBook, AppState, DmEntry};
+use crate::version::{Version, PROTOCOL_VERSION};
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Layout version of snapshot archives. Archives of a newer format are refused.
+pub const SNAPSHOT_FORMAT: u32 = 1;
+
+/// State carried by a snapshot, as it sits in `AppState`.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct SnapshotData {
+    pub log: LogSet,
+    #[serde(default)]
+    pub dms: Vec<DmEntry>,
+    #[serde(default)]
+    pub address_book: AddressBook,
+    #[serde(default)]
+    pub kv: DeviceStore,
+    #[serde(default)]
+    pub counters: Counters,
+    #[serde(default)]
+    pub collections: BTreeMap<String, Collection>,
+}
+
+/// A node's full state with the header of its archive.
+///
+/// The archive is a JSON object whose `sha256` is taken over the canonical encoding of
+/// `data` (keys sorted), so a truncated or edited archive is refused on import. Importing
+/// merges it like state received from a peer: log entries are verified and merged, and
+/// so are the replicas, so it never removes anything written since the export.
+#[derive(Clone, Debug)]
+pub struct Snapshot {
+    pub format: u32,
+    /// Protocol version of the exporting node; only the major version must match.
+    pub protocol: String,
+    pub namespace: String,
+    pub exported_by: String,
+    /// Wall-clock milliseconds at export.
+    pub created_at: u64,
+    pub data: SnapshotData,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Archive {
+    format: u32,
+    protocol: String,
+    namespace: String,
+    exported_by: String,
+    created_at: u64,
+    sha256: String,
+    data: Value,
+}
+
+impl Snapshot {
+    /// Copies the state of a node.
+    pub fn capture(app_state: &AppState) -> Self {
+        let collections = app_state
+            .collections
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(name, collection)| (name.clone(), collection.read().unwrap().clone()))
+            .collect();
+        let data = SnapshotData {
+            log: app_state.log.read().unwrap().clone(),
+            dms: app_state.dms.read().unwrap().clone(),
+            address_book: app_state.address_book.read().unwrap().clone(),
+            kv: app_state.kv.read().unwrap().clone(),
+            counters: app_state.counters.read().unwrap().clone(),
+            collections,
+        };
+        Self {
+            format: SNAPSHOT_FORMAT,
+            protocol: PROTOCOL_VERSION.to_string(),
+            namespace: app_state.namespace.name.clone(),
+            exported_by: app_state.local_peer_id.clone(),
+            created_at: SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0),
+            data,
+        }
+    }
+
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        let data = serde_json::to_value(&self.data)?;
+        let archive = Archive {
+            format: self.format,
+            protocol: self.protocol.clone(),
+            namespace: self.namespace.clone(),
+            exported_by: self.exported_by.clone(),
+            created_at: self.created_at,
+            sha256: replica::hash_json(&data),
+            data,
+        };
+        Ok(serde_json::to_vec_pretty(&archive)?)
+    }
+
+    /// Reads an archive, checking its format, protocol version and checksum.
+    pub fn decode(bytes: &[u8]) -> Result<Self> {
+        let archive: Archive = serde_json::from_slice(bytes).map_err(|e| anyhow!("not a snapshot archive: {}", e))?;
+        if archive.format > SNAPSHOT_FORMAT {
+            return Err(anyhow!("snapshot format {} is newer than the supported {}", archive.format, SNAPSHOT_FORMAT));
+        }
+        let version = Version::parse(&archive.protocol).ok_or_else(|| anyhow!("invalid protocol version '{}'", archive.protocol))?;
+        if version.major != PROTOCOL_VERSION.major {
+            return Err(anyhow!("snapshot of protocol {} is incompatible with {}", version, PROTOCOL_VERSION));
+        }
+        if replica::hash_json(&archive.data) != archive.sha256 {
+            return Err(anyhow!("snapshot checksum mismatch"));
+        }
+        Ok(Self {
+            format: archive.format,
+            protocol: archive.protocol,
+            namespace: archive.namespace,
+            exported_by: archive.exported_by,
+            created_at: archive.created_at,
+            data: serde_json::from_value(archive.data).map_err(|e| anyhow!("invalid snapshot contents: {}", e))?,
+        })
+    }
+
+    /// Like `decode`, also refusing a snapshot of another namespace.
+    pub fn decode_for(bytes: &[u8], namespace: &str) -> Result<Self> {
+        let snapshot = Self::decode(bytes)?;
+        if snapshot.namespace != namespace {
+            return Err(anyhow!("snapshot of namespace '{}' cannot be imported into '{}'", snapshot.namespace, namespace));
+        }
+        Ok(snapshot)
+    }
+}
@@ -1,55 +1,249 @@
-use std::collections::HashSet;
-use std::sync::{Arc, RwLock};
-use crdts::GSet;
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
 use libp2p::PeerId;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use tokio::sync::broadcast;
 use crate::telemetry::NetworkEvent;
+use crate::health::PeerHealth;
+use crate::bandwidth::Bandwidth;
+use crate::chaos::Chaos;
+use crate::transport::{TransportManager, TransportReport};
+use crate::namespace::Namespace;
+use crate::version::{self, PeerVersion, VersionReport};
+use crate::entry::{self, LogEntry};
+use crate::logset::LogSet;
+use crate::kv::DeviceStore;
+use crate::counter::Counters;
+use crate::collection::{Collection, CollectionKind};
+use crate::hlc::HybridClock;
+use crate::retention::{CollectionRetention, RetentionPolicy};
+use crate::replication::{PeerReplication, PeerReport};
+use crate::watch::Watch;
+use crate::wal::LogStore;
+use std::time::{SystemTime, UNIX_EPOCH};
 
-#[derive(Clone, Serialize, Debug)]
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
 pub struct DmEntry {
     pub from: String,
     pub content: String,
     pub timestamp: u64,
 }
 
+/// Listen addresses of the peers identified so far, by peer ID.
+pub type AddressBook = BTreeMap<String, BTreeSet<String>>;
+
+/// Chat messages kept, in memory and on disk. The history grows to twice this before the
+/// oldest are dropped, so it is trimmed (and its file rewritten) once in a while only.
+pub const MAX_CHAT_HISTORY: usize = 1000;
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct ChatMessage {
+    pub from: String,
+    pub content: String,
+    pub timestamp: u64,
+    pub topic: String,
+}
+
+#[derive(Clone, Serialize)]
+pub struct ChatPage {
+    pub messages: Vec<ChatMessage>,
+    pub total: usize,
+    /// Cursor for the next (older) page, `None` when the start of history is reached.
+    pub next_before: Option<usize>,
+}
+
 #[derive(Clone, Serialize)]
 pub struct AppStateSnapshot {
     pub peers: Vec<String>,
-    pub log: Vec<String>,
+    /// Log entries in timestamp order, left out when asked for without them.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub log: Option<Vec<LogEntry>>,
     pub dms: Vec<DmEntry>,
+    pub health: std::collections::HashMap<String, PeerHealth>,
+    pub namespace: String,
+    pub listen_addrs: Vec<String>,
     pub local_peer_id: String,
+    /// Whether this node may remove entries written by others.
+    pub log_admin: bool,
+    /// Collections opened on this node, with their kind.
+    pub collections: BTreeMap<String, CollectionKind>,
 }
 
 #[derive(Clone)]
 pub struct AppState {
-    pub log: Arc<RwLock<GSet<String>>>,
+    pub log: Arc<RwLock<LogSet>>,
+    /// Where changes to `log` are persisted, `None` until it is loaded; see `wal::LogStore`.
+    /// Locked after `log` when both are.
+    pub log_store: Arc<Mutex<Option<LogStore>>>,
+    /// Stamps local log entries; advanced past the timestamps of merged remote entries.
+    pub clock: Arc<RwLock<HybridClock>>,
+    /// Peers allowed to remove any log entry, not only their own.
+    pub log_admins: Arc<RwLock<HashSet<String>>>,
+    /// Limits proposing the log's compaction horizon, see `retention::RetentionPolicy`.
+    pub retention: Arc<RwLock<RetentionPolicy>>,
+    /// Replicated device attributes, see `kv::DeviceStore`.
+    pub kv: Arc<RwLock<DeviceStore>>,
+    /// Replicated named counters, see `counter::Counters`.
+    pub counters: Arc<RwLock<Counters>>,
+    /// Named collections opened on this node, see `collection::Collection`.
+    pub collections: Arc<RwLock<BTreeMap<String, Arc<RwLock<Collection>>>>>,
+    /// Limits of the collections that have any, see `retention::CollectionRetention`.
+    pub collection_retention: Arc<RwLock<BTreeMap<String, CollectionRetention>>>,
     pub peers: Arc<RwLock<HashSet<PeerId>>>,
     pub public_keys: Arc<RwLock<std::collections::HashMap<PeerId, Vec<u8>>>>,
     pub dms: Arc<RwLock<Vec<DmEntry>>>,
+    pub chat: Arc<RwLock<Vec<ChatMessage>>>,
+    /// Messages trimmed off the front of `chat`, so page cursors stay valid across trims.
+    /// Changed with `chat` locked.
+    pub chat_dropped: Arc<AtomicUsize>,
+    pub health: Arc<RwLock<std::collections::HashMap<PeerId, PeerHealth>>>,
+    /// What is known of each connected peer's log, see `replication::VersionVector`.
+    pub replication: Arc<RwLock<std::collections::HashMap<PeerId, PeerReplication>>>,
+    pub versions: Arc<RwLock<std::collections::HashMap<PeerId, PeerVersion>>>,
+    pub bandwidth: Bandwidth,
+    pub chaos: Chaos,
+    pub transports: Arc<RwLock<TransportManager>>,
+    pub namespace: Namespace,
+    pub listen_addrs: Arc<RwLock<Vec<String>>>,
+    pub address_book: Arc<RwLock<AddressBook>>,
     pub local_peer_id: String,
     pub telemetry_tx: broadcast::Sender<NetworkEvent>,
+    /// Changes to the log, device store, counters and collections, see `watch::Watch`.
+    pub watch: Watch,
 }
 
 impl AppState {
-    pub fn new(local_peer_id: String) -> Self {
+    pub fn new(local_peer_id: String, namespace: Namespace) -> Self {
         let (tx, _rx) = broadcast::channel(100);
         Self {
-            log: Arc::new(RwLock::new(GSet::new())),
+            log: Arc::new(RwLock::new(LogSet::new())),
+            log_store: Arc::new(Mutex::new(None)),
+            clock: Arc::new(RwLock::new(HybridClock::default())),
+            log_admins: Arc::new(RwLock::new(HashSet::new())),
+            retention: Arc::new(RwLock::new(RetentionPolicy::default())),
+            kv: Arc::new(RwLock::new(DeviceStore::new())),
+            counters: Arc::new(RwLock::new(Counters::new())),
+            collections: Arc::new(RwLock::new(BTreeMap::new())),
+            collection_retention: Arc::new(RwLock::new(BTreeMap::new())),
             peers: Arc::new(RwLock::new(HashSet::new())),
             public_keys: Arc::new(RwLock::new(std::collections::HashMap::new())),
             dms: Arc::new(RwLock::new(Vec::new())),
+            chat: Arc::new(RwLock::new(Vec::new())),
+            chat_dropped: Arc::new(AtomicUsize::new(0)),
+            health: Arc::new(RwLock::new(std::collections::HashMap::new())),
+            replication: Arc::new(RwLock::new(std::collections::HashMap::new())),
+            versions: Arc::new(RwLock::new(std::collections::HashMap::new())),
+            bandwidth: Bandwidth::default(),
+            chaos: Chaos::default(),
+            transports: Arc::new(RwLock::new(TransportManager::default())),
+            namespace,
+            listen_addrs: Arc::new(RwLock::new(Vec::new())),
+            address_book: Arc::new(RwLock::new(BTreeMap::new())),
             local_peer_id,
             telemetry_tx: tx,
+            watch: Watch::default(),
         }
     }
 
     pub fn snapshot(&self) -> AppStateSnapshot {
+        self.snapshot_with(true)
+    }
+
+    /// Like `snapshot`, leaving out the log unless `with_log`; see `query::LogQuery` for
+    /// reading it in pages.
+    pub fn snapshot_with(&self, with_log: bool) -> AppStateSnapshot {
         let peers = self.peers.read().unwrap().iter().map(|p| p.to_string()).collect();
-        let log = self.log.read().unwrap().read().iter().cloned().collect();
+        let log = with_log.then(|| self.log.read().unwrap().iter().cloned().collect());
         let dms = self.dms.read().unwrap().clone();
+        let health = self.health.read().unwrap().iter().map(|(p, h)| (p.to_string(), h.clone())).collect();
+        let namespace = self.namespace.name.clone();
+        let listen_addrs = self.listen_addrs.read().unwrap().clone();
         let local_peer_id = self.local_peer_id.clone();
-        
-        AppStateSnapshot { peers, log, dms, local_peer_id }
+        let log_admin = self.log_admins.read().unwrap().contains(&local_peer_id);
+        let collections = self
+            .collections
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(name, collection)| (name.clone(), collection.read().unwrap().kind()))
+            .collect();
+
+        AppStateSnapshot { peers, log, dms, health, namespace, listen_addrs, local_peer_id, log_admin, collections }
+    }
+
+    pub fn transport_report(&self) -> TransportReport {
+        self.transports.read().unwrap().report()
+    }
+
+    pub fn version_report(&self) -> VersionReport {
+        version::skew_report(&self.versions.read().unwrap(), &self.peers.read().unwrap())
+    }
+
+    /// Connected peers with how far behind their log is, the most behind first.
+    pub fn peer_report(&self) -> Vec<PeerReport> {
+        let now_ms = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0);
+        let log = self.log.read().unwrap();
+        let replication = self.replication.read().unwrap();
+        let mut report: Vec<PeerReport> = self
+            .peers
+            .read()
+            .unwrap()
+            .iter()
+            .map(|peer| {
+                let status = replication.get(peer).map(|known| known.status(&log));
+                PeerReport {
+                    peer_id: peer.to_string(),
+                    lag_ms: status.as_ref().map_or(0, |status| status.lag_ms(now_ms)),
+                    in_sync: status.as_ref().is_some_and(|status| status.in_sync()),
+                    replication: status,
+                }
+            })
+            .collect();
+        report.sort_by(|a, b| {
+            let missing = |peer: &PeerReport| peer.replication.as_ref().map(|status| status.missing);
+            missing(b).cmp(&missing(a)).then_with(|| a.peer_id.cmp(&b.peer_id))
+        });
+        report
+    }
+
+    /// Whether this node may remove `entry`: it wrote it, or it is a log admin.
+    pub fn may_remove(&self, entry: &LogEntry) -> bool {
+        entry::may_remove(&self.local_peer_id, &entry.author, &self.log_admins.read().unwrap())
+    }
+
+    /// Whether this node is one of the configured log admins.
+    pub fn is_log_admin(&self) -> bool {
+        self.log_admins.read().unwrap().contains(&self.local_peer_id)
+    }
+
+    /// Whether every connected, identified peer advertises `feature`.
+    /// Peers that have not completed identify yet are assumed to be current.
+    pub fn mesh_supports(&self, feature: &str) -> bool {
+        let peers = self.peers.read().unwrap();
+        let versions = self.versions.read().unwrap();
+        peers
+            .iter()
+            .filter_map(|p| versions.get(p))
+            .all(|v| v.supports(feature))
+    }
+
+    /// Whether `peer` was identified as a compatible node supporting `feature`.
+    pub fn peer_supports(&self, peer: &PeerId, feature: &str) -> bool {
+        self.versions.read().unwrap().get(peer).is_some_and(|v| v.compatible && v.supports(feature))
+    }
+
+    /// Returns up to `limit` chat messages older than the `before` cursor (oldest first).
+    /// Cursors count every message since startup, including those trimmed since.
+    pub fn chat_page(&self, before: Option<usize>, limit: usize) -> ChatPage {
+        let chat = self.chat.read().unwrap();
+        let dropped = self.chat_dropped.load(Ordering::SeqCst);
+        let total = dropped + chat.len();
+        let end = before.unwrap_or(total).clamp(dropped, total);
+        let start = end.saturating_sub(limit).max(dropped);
+        let messages = chat[start - dropped..end - dropped].to_vec();
+        let next_before = if start > dropped { Some(start) } else { None };
+
+        ChatPage { messages, total, next_before }
     }
 }
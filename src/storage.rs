@@ -1,41 +1,380 @@
 use anyhow::Result;
 use crdts::GSet;
+use libp2p::identity::Keypair;
+use std::collections::BTreeMap;
 use std::fs;
+use std::io::Write;
 use std::path::Path;
-use tracing::info;
+use tracing::{error, info};
+use crate::entry::LogEntry;
+use crate::logset::LogSet;
+use crate::kv::DeviceStore;
+use crate::counter::Counters;
+use crate::collection::Collection;
+use crate::retention::CollectionRetention;
+use crate::state::{AddressBook, AppState, ChatMessage, DmEntry, MAX_CHAT_HISTORY};
+use crate::namespace::Namespace;
+use crate::wal::{LogStore, SyncPolicy};
 
-pub fn get_storage_path(port: u16) -> String {
-    format!("data/storage_{}.json", port)
+pub fn get_storage_path(ns: &Namespace, port: u16) -> String {
+    format!("{}/storage_{}.json", ns.data_dir(), port)
 }
 
-pub fn ensure_data_dir() -> Result<()> {
-    let path = Path::new("data");
+pub fn ensure_data_dir(ns: &Namespace) -> Result<()> {
+    let dir = ns.data_dir();
+    let path = Path::new(&dir);
     if !path.exists() {
-        fs::create_dir(path)?;
+        fs::create_dir_all(path)?;
     }
     Ok(())
 }
 
-pub fn save_log(port: u16, log: &GSet<String>) -> Result<()> {
-    ensure_data_dir()?;
-    let path = get_storage_path(port);
-    let json = serde_json::to_string_pretty(log)?;
-    fs::write(&path, json)?;
-    // info!("Saved state to {}", path); // Commented out to avoid spamming logs
+/// Writes `contents` to a sibling temp file, syncs it and renames it over `path`, so a
+/// crash or shutdown mid-write leaves either the old or the new file, never a torn one.
+pub fn write_atomic(path: &str, contents: &[u8]) -> Result<()> {
+    let tmp_path = format!("{}.tmp", path);
+    let mut file = fs::File::create(&tmp_path)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, path)?;
     Ok(())
 }
 
-pub fn load_log(port: u16) -> Result<GSet<String>> {
-    let path = get_storage_path(port);
-    let path = Path::new(&path);
+/// Snapshot of the log, see `wal::LogStore`.
+pub fn get_log_snapshot_path(ns: &Namespace, port: u16) -> String {
+    format!("{}/log_{}.snapshot.json", ns.data_dir(), port)
+}
+
+/// Write-ahead log of the changes since the snapshot, see `wal::LogStore`.
+pub fn get_wal_path(ns: &Namespace, port: u16) -> String {
+    format!("{}/log_{}.wal", ns.data_dir(), port)
+}
+
+/// Append-only JSON Lines file of the entries compacted by retention.
+pub fn get_archive_path(ns: &Namespace, port: u16) -> String {
+    format!("{}/archive_{}.jsonl", ns.data_dir(), port)
+}
+
+/// Appends compacted entries to the archive, one JSON entry per line.
+pub fn archive_entries(ns: &Namespace, port: u16, entries: &[LogEntry]) -> Result<()> {
+    ensure_data_dir(ns)?;
+    let mut lines = String::new();
+    for entry in entries {
+        lines.push_str(&serde_json::to_string(entry)?);
+        lines.push('\n');
+    }
+    let mut file = fs::OpenOptions::new().create(true).append(true).open(get_archive_path(ns, port))?;
+    file.write_all(lines.as_bytes())?;
+    file.sync_all()?;
+    Ok(())
+}
+
+/// Reads back the archived entries, oldest compaction first.
+pub fn load_archive(ns: &Namespace, port: u16) -> Result<Vec<LogEntry>> {
+    let path = get_archive_path(ns, port);
+    if !Path::new(&path).exists() {
+        return Ok(Vec::new());
+    }
+    let content = fs::read_to_string(path)?;
+    content.lines().filter(|line| !line.trim().is_empty()).map(|line| Ok(serde_json::from_str(line)?)).collect()
+}
+
+/// Loads the log from the JSON file `storage_<port>.json` that held it before the WAL,
+/// for `wal::LogStore` to migrate. A file from before signed entries (a set of bare
+/// strings) is converted too: a copy is kept as `storage_<port>.json.v1` and the lines
+/// become entries signed by `keypair`, see `migrate_legacy_log`.
+pub fn load_json_log(ns: &Namespace, port: u16, keypair: &Keypair) -> Result<LogSet> {
+    let path_str = get_storage_path(ns, port);
+    let path = Path::new(&path_str);
 
     if !path.exists() {
         info!("No existing storage found at {:?}. Starting fresh.", path);
-        return Ok(GSet::new());
+        return Ok(LogSet::new());
     }
 
     info!("Loading state from {:?}", path);
     let content = fs::read_to_string(path)?;
-    let log: GSet<String> = serde_json::from_str(&content)?;
+    if let Ok(log) = serde_json::from_str::<LogSet>(&content) {
+        return Ok(log);
+    }
+
+    let legacy: GSet<String> = serde_json::from_str(&content)?;
+    let log = migrate_legacy_log(&legacy, keypair);
+    info!(
+        "Migrated {} of {} legacy log entries in {:?}; original kept as {}.v1",
+        log.len(),
+        legacy.read().len(),
+        path,
+        path_str
+    );
+    fs::copy(path, format!("{}.v1", path_str))?;
     Ok(log)
 }
+
+/// Converts bare-string entries into text entries tagged `migrated`, signed by `keypair`,
+/// see `LogEntry::legacy`. Nodes migrating the same line write entries with the same id,
+/// so the merged log holds it once. Lines that are raw CLI commands (such as `/dm ...`),
+/// logged by mistake by older versions, are dropped.
+pub fn migrate_legacy_log(legacy: &GSet<String>, keypair: &Keypair) -> LogSet {
+    let mut log = LogSet::new();
+    for text in legacy.read().into_iter().filter(|text| !text.starts_with('/')) {
+        log.insert(LogEntry::legacy(keypair, &text));
+    }
+    log
+}
+
+/// JSON Lines file of the chat history, appended to as messages arrive.
+pub fn get_chat_path(ns: &Namespace, port: u16) -> String {
+    format!("{}/chat_{}.jsonl", ns.data_dir(), port)
+}
+
+/// JSON array holding the chat history of earlier versions, converted on load.
+fn get_legacy_chat_path(ns: &Namespace, port: u16) -> String {
+    format!("{}/chat_{}.json", ns.data_dir(), port)
+}
+
+/// Rewrites the whole chat history.
+pub fn save_chat(ns: &Namespace, port: u16, chat: &[ChatMessage]) -> Result<()> {
+    ensure_data_dir(ns)?;
+    let mut lines = String::new();
+    for message in chat {
+        lines.push_str(&serde_json::to_string(message)?);
+        lines.push('\n');
+    }
+    write_atomic(&get_chat_path(ns, port), lines.as_bytes())?;
+    Ok(())
+}
+
+/// Appends one message to the chat history.
+pub fn append_chat(ns: &Namespace, port: u16, message: &ChatMessage) -> Result<()> {
+    ensure_data_dir(ns)?;
+    let mut file = fs::OpenOptions::new().create(true).append(true).open(get_chat_path(ns, port))?;
+    file.write_all(format!("{}\n", serde_json::to_string(message)?).as_bytes())?;
+    Ok(())
+}
+
+/// Loads the last `MAX_CHAT_HISTORY` chat messages. A line torn by a crash is skipped.
+pub fn load_chat(ns: &Namespace, port: u16) -> Result<Vec<ChatMessage>> {
+    let path = get_chat_path(ns, port);
+    let legacy_path = get_legacy_chat_path(ns, port);
+    let mut chat: Vec<ChatMessage> = if Path::new(&path).exists() {
+        info!("Loading chat history from {:?}", path);
+        fs::read_to_string(&path)?.lines().filter_map(|line| serde_json::from_str(line).ok()).collect()
+    } else if Path::new(&legacy_path).exists() {
+        info!("Converting chat history {:?} to {:?}", legacy_path, path);
+        let chat: Vec<ChatMessage> = serde_json::from_str(&fs::read_to_string(&legacy_path)?)?;
+        save_chat(ns, port, &chat)?;
+        fs::remove_file(&legacy_path)?;
+        chat
+    } else {
+        return Ok(Vec::new());
+    };
+    chat.drain(..chat.len().saturating_sub(MAX_CHAT_HISTORY));
+    Ok(chat)
+}
+
+pub fn get_dms_path(ns: &Namespace, port: u16) -> String {
+    format!("{}/dms_{}.json", ns.data_dir(), port)
+}
+
+pub fn save_dms(ns: &Namespace, port: u16, dms: &[DmEntry]) -> Result<()> {
+    ensure_data_dir(ns)?;
+    let json = serde_json::to_string_pretty(dms)?;
+    write_atomic(&get_dms_path(ns, port), json.as_bytes())?;
+    Ok(())
+}
+
+pub fn load_dms(ns: &Namespace, port: u16) -> Result<Vec<DmEntry>> {
+    let path = get_dms_path(ns, port);
+    if !Path::new(&path).exists() {
+        return Ok(Vec::new());
+    }
+    let content = fs::read_to_string(path)?;
+    Ok(serde_json::from_str(&content)?)
+}
+
+pub fn get_address_book_path(ns: &Namespace, port: u16) -> String {
+    format!("{}/peers_{}.json", ns.data_dir(), port)
+}
+
+pub fn save_address_book(ns: &Namespace, port: u16, book: &AddressBook) -> Result<()> {
+    ensure_data_dir(ns)?;
+    let json = serde_json::to_string_pretty(book)?;
+    write_atomic(&get_address_book_path(ns, port), json.as_bytes())?;
+    Ok(())
+}
+
+pub fn load_address_book(ns: &Namespace, port: u16) -> Result<AddressBook> {
+    let path = get_address_book_path(ns, port);
+    if !Path::new(&path).exists() {
+        return Ok(BTreeMap::new());
+    }
+    let content = fs::read_to_string(path)?;
+    Ok(serde_json::from_str(&content)?)
+}
+
+pub fn get_kv_path(ns: &Namespace, port: u16) -> String {
+    format!("{}/kv_{}.json", ns.data_dir(), port)
+}
+
+pub fn save_kv(ns: &Namespace, port: u16, store: &DeviceStore) -> Result<()> {
+    ensure_data_dir(ns)?;
+    let path = get_kv_path(ns, port);
+    let json = serde_json::to_string_pretty(store)?;
+    write_atomic(&path, json.as_bytes())?;
+    Ok(())
+}
+
+pub fn load_kv(ns: &Namespace, port: u16) -> Result<DeviceStore> {
+    let path = get_kv_path(ns, port);
+    let path = Path::new(&path);
+
+    if !path.exists() {
+        return Ok(DeviceStore::new());
+    }
+
+    info!("Loading device store from {:?}", path);
+    let content = fs::read_to_string(path)?;
+    Ok(serde_json::from_str(&content)?)
+}
+
+pub fn get_counters_path(ns: &Namespace, port: u16) -> String {
+    format!("{}/counters_{}.json", ns.data_dir(), port)
+}
+
+pub fn save_counters(ns: &Namespace, port: u16, counters: &Counters) -> Result<()> {
+    ensure_data_dir(ns)?;
+    let path = get_counters_path(ns, port);
+    let json = serde_json::to_string_pretty(counters)?;
+    write_atomic(&path, json.as_bytes())?;
+    Ok(())
+}
+
+pub fn load_counters(ns: &Namespace, port: u16) -> Result<Counters> {
+    let path = get_counters_path(ns, port);
+    let path = Path::new(&path);
+
+    if !path.exists() {
+        return Ok(Counters::new());
+    }
+
+    info!("Loading counters from {:?}", path);
+    let content = fs::read_to_string(path)?;
+    Ok(serde_json::from_str(&content)?)
+}
+
+/// Directory holding one `<name>.json` file per collection.
+pub fn get_collections_dir(ns: &Namespace, port: u16) -> String {
+    format!("{}/collections_{}", ns.data_dir(), port)
+}
+
+pub fn save_collection(ns: &Namespace, port: u16, name: &str, collection: &Collection) -> Result<()> {
+    let dir = get_collections_dir(ns, port);
+    fs::create_dir_all(&dir)?;
+    let json = serde_json::to_string_pretty(collection)?;
+    write_atomic(&format!("{}/{}.json", dir, name), json.as_bytes())?;
+    Ok(())
+}
+
+pub fn delete_collection(ns: &Namespace, port: u16, name: &str) -> Result<()> {
+    let path = format!("{}/{}.json", get_collections_dir(ns, port), name);
+    if Path::new(&path).exists() {
+        fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+/// Loads every saved collection, by name.
+pub fn load_collections(ns: &Namespace, port: u16) -> Result<BTreeMap<String, Collection>> {
+    let dir = get_collections_dir(ns, port);
+    let mut collections = BTreeMap::new();
+    if !Path::new(&dir).exists() {
+        return Ok(collections);
+    }
+
+    for file in fs::read_dir(&dir)? {
+        let path = file?.path();
+        if path.extension().and_then(|ext| ext.to_str()) != Some("json") {
+            continue;
+        }
+        let Some(name) = path.file_stem().and_then(|stem| stem.to_str()) else {
+            continue;
+        };
+        info!("Loading collection {} from {:?}", name, path);
+        let content = fs::read_to_string(&path)?;
+        collections.insert(name.to_string(), serde_json::from_str(&content)?);
+    }
+    Ok(collections)
+}
+
+/// Limits of the collections that have any, kept next to the collections directory so
+/// `load_collections` does not mistake it for one.
+pub fn get_collection_retention_path(ns: &Namespace, port: u16) -> String {
+    format!("{}/collection_retention_{}.json", ns.data_dir(), port)
+}
+
+pub fn save_collection_retention(ns: &Namespace, port: u16, limits: &BTreeMap<String, CollectionRetention>) -> Result<()> {
+    ensure_data_dir(ns)?;
+    let json = serde_json::to_string_pretty(limits)?;
+    write_atomic(&get_collection_retention_path(ns, port), json.as_bytes())?;
+    Ok(())
+}
+
+pub fn load_collection_retention(ns: &Namespace, port: u16) -> Result<BTreeMap<String, CollectionRetention>> {
+    let path = get_collection_retention_path(ns, port);
+    if !Path::new(&path).exists() {
+        return Ok(BTreeMap::new());
+    }
+    let content = fs::read_to_string(path)?;
+    Ok(serde_json::from_str(&content)?)
+}
+
+/// Loads everything a node persists into `app_state`, logging what fails to load.
+pub fn restore_state(app_state: &AppState, port: u16, wal_sync: SyncPolicy, keypair: &Keypair) {
+    let ns = &app_state.namespace;
+    match LogStore::open(ns, port, wal_sync, keypair) {
+        Ok((store, log)) => {
+            *app_state.log.write().unwrap() = log;
+            *app_state.log_store.lock().unwrap() = Some(store);
+        }
+        Err(e) => error!("Failed to load log: {:?}", e),
+    }
+    match load_kv(ns, port) {
+        Ok(kv) => *app_state.kv.write().unwrap() = kv,
+        Err(e) => error!("Failed to load device store: {:?}", e),
+    }
+    match load_counters(ns, port) {
+        Ok(counters) => *app_state.counters.write().unwrap() = counters,
+        Err(e) => error!("Failed to load counters: {:?}", e),
+    }
+    match load_collections(ns, port) {
+        Ok(collections) => {
+            let mut open = app_state.collections.write().unwrap();
+            for (name, collection) in collections {
+                open.insert(name, std::sync::Arc::new(std::sync::RwLock::new(collection)));
+            }
+        }
+        Err(e) => error!("Failed to load collections: {:?}", e),
+    }
+    match load_collection_retention(ns, port) {
+        Ok(limits) => *app_state.collection_retention.write().unwrap() = limits,
+        Err(e) => error!("Failed to load collection retention: {:?}", e),
+    }
+    match load_chat(ns, port) {
+        Ok(chat) => *app_state.chat.write().unwrap() = chat,
+        Err(e) => error!("Failed to load chat history: {:?}", e),
+    }
+    match load_dms(ns, port) {
+        Ok(dms) => *app_state.dms.write().unwrap() = dms,
+        Err(e) => error!("Failed to load direct messages: {:?}", e),
+    }
+    match load_address_book(ns, port) {
+        Ok(book) => *app_state.address_book.write().unwrap() = book,
+        Err(e) => error!("Failed to load address book: {:?}", e),
+    }
+}
+
+/// Snapshot archive waiting to be imported by the node on its next start.
+pub fn get_pending_import_path(ns: &Namespace, port: u16) -> String {
+    format!("{}/import_{}.json", ns.data_dir(), port)
+}
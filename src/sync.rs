@@ -0,0 +1,640 @@
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use base64::{engine::general_purpose::STANDARD as BASE64_STANDARD, Engine as _};
+use crate::entry::{EntryKey, HorizonSignature, LogEntry, Tombstone};
+use crate::hlc::Hlc;
+use crate::logset::LogSet;
+use crate::namespace::Namespace;
+use crate::replication::VersionVector;
+use libp2p::futures::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use libp2p::{request_response, PeerId, StreamProtocol};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest as _, Sha256};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::io;
+use std::time::{Duration, Instant};
+
+/// Leaves of the anti-entropy hash tree; entries are bucketed by the first nibble of their hash.
+pub const SYNC_BUCKETS: usize = 16;
+
+/// Sessions that have not completed after this long are abandoned, as are their requests.
+pub const SESSION_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Request-response protocol anti-entropy sessions run on, directly between the two peers.
+pub const SYNC_PROTOCOL: StreamProtocol = StreamProtocol::new("/ghostmesh/sync/1.0.0");
+
+/// Largest encoded CRDT message published whole. Bigger ones are split into `Chunk`s of this
+/// many bytes, which stay under gossipsub's max transmit size once base64-encoded and sealed.
+pub const CHUNK_SIZE: usize = 32 * 1024;
+
+/// Upper bound on the chunks of one transfer (128 MiB), so a peer cannot make us buffer more.
+const MAX_CHUNKS: usize = 4096;
+
+/// Incoming transfers buffered at once per peer; chunks of further ones are rejected.
+pub const MAX_INCOMING_PER_PEER: usize = 4;
+
+/// Bytes buffered at once for the incoming transfers of one peer, one full-size transfer.
+const MAX_BUFFERED_PER_PEER: usize = MAX_CHUNKS * CHUNK_SIZE;
+
+/// Largest sealed `SyncMessage` read from a peer, the same bound as a chunked transfer.
+const MAX_SYNC_MESSAGE: usize = MAX_CHUNKS * CHUNK_SIZE;
+
+/// A receiver asks for the missing chunks once a transfer made no progress for this long.
+pub const CHUNK_RETRY_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Requests sent without progress before an incoming transfer is abandoned.
+const MAX_CHUNK_REQUESTS: u32 = 10;
+
+/// How long sent transfers are kept to answer `ChunkRequest`s.
+const TRANSFER_TTL: Duration = Duration::from_secs(120);
+
+/// Sent transfers kept at once; the oldest is forgotten first.
+const MAX_OUTGOING: usize = 8;
+
+/// Messages on the CRDT topic between peers supporting `version::FEATURE_CRDT_DELTA`.
+/// Older peers publish their whole log instead, which `decode` accepts as a full state.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type")]
+pub enum CrdtMessage {
+    /// Entries inserted since the sender's last publish.
+    Delta { entries: Vec<LogEntry> },
+    /// Periodic summary of the sender's log, letting peers detect entries they missed.
+    /// `vector` tells how far the sender's log goes, see `replication::VersionVector`.
+    Digest {
+        digest: LogDigest,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        vector: Option<VersionVector>,
+    },
+    /// Asks one peer (or every peer when `to` is `None`) for its full state.
+    StateRequest { to: Option<String> },
+    /// Full state, published in answer to a `StateRequest`.
+    State { state: Box<LogSet> },
+    /// One part of an encoded `CrdtMessage` larger than `CHUNK_SIZE`. `hash` is the SHA-256
+    /// of the whole message and `attempt` makes resent chunks distinct gossipsub messages.
+    Chunk { transfer: u64, index: usize, total: usize, hash: String, data: String, #[serde(default)] attempt: u32 },
+    /// Asks the sender of a transfer to publish the listed chunks again.
+    ChunkRequest { to: String, transfer: u64, missing: Vec<usize> },
+    /// Entries removed since the sender's last publish.
+    Remove { tombstones: Vec<Tombstone> },
+    /// The sender holds the tombstones of these entries; see `LogSet::collect_garbage`.
+    /// `ids` repeats their ids for nodes of 2.7 and earlier, which acked by id alone.
+    Ack {
+        #[serde(default)]
+        ids: Vec<String>,
+        #[serde(default)]
+        entries: Vec<EntryKey>,
+    },
+    /// The sender's log is compacted up to `horizon`, signed if a log admin set it; see
+    /// `LogSet::compact` and `RetentionPolicy::admits`.
+    Compact {
+        horizon: Hlc,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        signature: Option<HorizonSignature>,
+    },
+}
+
+impl CrdtMessage {
+    pub fn decode(data: &[u8]) -> Option<Self> {
+        serde_json::from_slice::<CrdtMessage>(data)
+            .ok()
+            .or_else(|| serde_json::from_slice::<LogSet>(data).ok().map(|state| CrdtMessage::State { state: Box::new(state) }))
+    }
+}
+
+/// Messages on `SYNC_PROTOCOL`. In an anti-entropy session the initiator's `Offer` is
+/// answered with a `Diff`, and its `Entries` with the responder's `Entries`. Outside of
+/// sessions, a `StateRequest` is answered with the `State` of a replica.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type")]
+pub enum SyncMessage {
+    /// Opens a session with the hash tree and version vector of the initiator's log.
+    /// `horizon` and `signature` are the log's compaction horizon, adopted before the
+    /// entries are compared, as a `CrdtMessage::Compact` would be.
+    Offer {
+        session: u64,
+        tree: LogTree,
+        vector: VersionVector,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        horizon: Option<Hlc>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        signature: Option<HorizonSignature>,
+    },
+    /// The responder's entry hashes for every bucket whose hash differs, keyed by the bucket's
+    /// hex nibble; empty when in sync. Carries the responder's version vector and horizon.
+    Diff {
+        session: u64,
+        buckets: BTreeMap<String, Vec<String>>,
+        vector: VersionVector,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        horizon: Option<Hlc>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        signature: Option<HorizonSignature>,
+    },
+    /// Entries the receiver lacks, and the hashes of entries the sender wants back.
+    Entries {
+        session: u64,
+        entries: Vec<LogEntry>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        tombstones: Vec<Tombstone>,
+        want: Vec<String>,
+    },
+    /// Asks for the full state of the replica on a topic, such as the device store on
+    /// `ghostmesh-kv`.
+    StateRequest { topic: String },
+    /// Full state of the replica on `topic`, in answer to a `StateRequest`; `None` when the
+    /// sender has no replica there.
+    State { topic: String, state: Option<serde_json::Value> },
+}
+
+/// Encodes `SyncMessage`s on a `SYNC_PROTOCOL` stream: one JSON message per stream
+/// direction, sealed with the namespace key like gossipsub payloads, up to
+/// `MAX_SYNC_MESSAGE` bytes.
+#[derive(Clone)]
+pub struct SyncCodec {
+    namespace: Namespace,
+}
+
+impl SyncCodec {
+    pub fn new(namespace: Namespace) -> Self {
+        Self { namespace }
+    }
+
+    async fn read<T: AsyncRead + Unpin + Send>(&self, io: &mut T) -> io::Result<SyncMessage> {
+        let mut sealed = Vec::new();
+        io.take(MAX_SYNC_MESSAGE as u64 + 1).read_to_end(&mut sealed).await?;
+        if sealed.len() > MAX_SYNC_MESSAGE {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "sync message exceeds the size limit"));
+        }
+        let data = self
+            .namespace
+            .open(&sealed)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "sync message not sealed with the namespace key"))?;
+        Ok(serde_json::from_slice(&data)?)
+    }
+
+    async fn write<T: AsyncWrite + Unpin + Send>(&self, io: &mut T, msg: SyncMessage) -> io::Result<()> {
+        io.write_all(&self.namespace.seal(serde_json::to_vec(&msg)?)).await?;
+        io.close().await
+    }
+}
+
+#[async_trait]
+impl request_response::Codec for SyncCodec {
+    type Protocol = StreamProtocol;
+    type Request = SyncMessage;
+    type Response = SyncMessage;
+
+    async fn read_request<T: AsyncRead + Unpin + Send>(&mut self, _: &StreamProtocol, io: &mut T) -> io::Result<SyncMessage> {
+        self.read(io).await
+    }
+
+    async fn read_response<T: AsyncRead + Unpin + Send>(&mut self, _: &StreamProtocol, io: &mut T) -> io::Result<SyncMessage> {
+        self.read(io).await
+    }
+
+    async fn write_request<T: AsyncWrite + Unpin + Send>(&mut self, _: &StreamProtocol, io: &mut T, msg: SyncMessage) -> io::Result<()> {
+        self.write(io, msg).await
+    }
+
+    async fn write_response<T: AsyncWrite + Unpin + Send>(&mut self, _: &StreamProtocol, io: &mut T, msg: SyncMessage) -> io::Result<()> {
+        self.write(io, msg).await
+    }
+}
+
+/// Entry count and SHA-256 over the sorted entries and tombstones. Equal digests mean
+/// equal logs.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct LogDigest {
+    pub count: usize,
+    pub hash: String,
+}
+
+impl LogDigest {
+    pub fn of(log: &LogSet) -> Self {
+        let mut hasher = Sha256::new();
+        for (hash, _) in items(log) {
+            hasher.update(hash.as_bytes());
+        }
+        Self { count: log.len(), hash: hex(&hasher.finalize()) }
+    }
+}
+
+/// Two-level hash tree over the log's entries and tombstones: one hash per bucket and a
+/// root over the buckets.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct LogTree {
+    pub root: String,
+    pub buckets: Vec<String>,
+}
+
+impl LogTree {
+    pub fn of(log: &LogSet) -> Self {
+        let mut leaves: Vec<BTreeSet<String>> = vec![BTreeSet::new(); SYNC_BUCKETS];
+        for (hash, _) in items(log) {
+            leaves[bucket_of(&hash)].insert(hash);
+        }
+
+        let buckets: Vec<String> = leaves
+            .iter()
+            .map(|hashes| {
+                let mut hasher = Sha256::new();
+                for hash in hashes {
+                    hasher.update(hash.as_bytes());
+                }
+                hex(&hasher.finalize())
+            })
+            .collect();
+        let mut root = Sha256::new();
+        for bucket in &buckets {
+            root.update(bucket.as_bytes());
+        }
+
+        Self { root: hex(&root.finalize()), buckets }
+    }
+}
+
+/// Hex SHA-256 of a log entry's encoding, as exchanged during anti-entropy.
+pub fn entry_hash(entry: &LogEntry) -> String {
+    hex(&Sha256::digest(serde_json::to_vec(entry).unwrap_or_default()))
+}
+
+/// Hex SHA-256 of a tombstone's encoding. Never equal to an entry hash.
+pub fn tombstone_hash(tombstone: &Tombstone) -> String {
+    hex(&Sha256::digest(serde_json::to_vec(tombstone).unwrap_or_default()))
+}
+
+fn bucket_of(hash: &str) -> usize {
+    usize::from_str_radix(&hash[..1], 16).unwrap_or(0) % SYNC_BUCKETS
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// An element of the log exchanged during anti-entropy.
+#[derive(Clone)]
+enum Item {
+    Entry(LogEntry),
+    Tombstone(Tombstone),
+}
+
+/// Entries and tombstones of the log with their hashes.
+fn items(log: &LogSet) -> impl Iterator<Item = (String, Item)> {
+    let entries = log.read().into_iter().map(|entry| (entry_hash(&entry), Item::Entry(entry)));
+    let tombstones = log.tombstones().clone().into_iter().map(|t| (tombstone_hash(&t), Item::Tombstone(t)));
+    entries.chain(tombstones)
+}
+
+/// Entries and tombstones in `buckets`, keyed by hash.
+fn items_in(log: &LogSet, buckets: &HashSet<usize>) -> HashMap<String, Item> {
+    items(log).filter(|(hash, _)| buckets.contains(&bucket_of(hash))).collect()
+}
+
+/// Splits items into the `entries` and `tombstones` of a `SyncMessage::Entries`.
+fn partition(items: impl IntoIterator<Item = Item>) -> (Vec<LogEntry>, Vec<Tombstone>) {
+    let mut entries = Vec::new();
+    let mut tombstones = Vec::new();
+    for item in items {
+        match item {
+            Item::Entry(entry) => entries.push(entry),
+            Item::Tombstone(tombstone) => tombstones.push(tombstone),
+        }
+    }
+    (entries, tombstones)
+}
+
+/// Progress of an anti-entropy session, reported as `NetworkEvent::SyncProgress`.
+#[derive(Clone, Debug)]
+pub struct SyncProgress {
+    pub peer_id: PeerId,
+    pub session: u64,
+    /// `offer`, `diff`, `entries` or `complete`.
+    pub stage: &'static str,
+    pub entries_sent: usize,
+    pub entries_received: usize,
+}
+
+/// What to do after handling an anti-entropy message.
+#[derive(Default)]
+pub struct SyncStep {
+    pub merge: Vec<LogEntry>,
+    pub remove: Vec<Tombstone>,
+    pub reply: Option<SyncMessage>,
+    pub progress: Option<SyncProgress>,
+}
+
+struct Session {
+    peer: PeerId,
+    /// Whether this node opened the session; the responder answers every request.
+    initiator: bool,
+    sent: usize,
+    received: usize,
+    started: Instant,
+}
+
+/// Anti-entropy sessions, run once per connection with each peer over `SYNC_PROTOCOL`:
+///
+/// 1. The initiator requests with an `Offer` of its `LogTree`.
+/// 2. The responder answers a `Diff` with its entry hashes for the differing buckets.
+/// 3. The initiator requests with `Entries` the responder lacks and the hashes it lacks
+///    itself, unless there are none either way.
+/// 4. The responder answers with the wanted entries in its own `Entries`.
+///
+/// Only the peer with the lower peer ID initiates, so a pair runs a single session.
+#[derive(Default)]
+pub struct AntiEntropy {
+    synced: HashSet<PeerId>,
+    sessions: HashMap<u64, Session>,
+}
+
+impl AntiEntropy {
+    /// Whether this node should open a session with `peer` now. Returns `true` once per
+    /// connection, until `disconnected` is called.
+    pub fn should_initiate(&mut self, local: &PeerId, peer: PeerId) -> bool {
+        local < &peer && self.synced.insert(peer)
+    }
+
+    pub fn disconnected(&mut self, peer: &PeerId) {
+        self.synced.remove(peer);
+        self.sessions.retain(|_, s| s.peer != *peer);
+    }
+
+    pub fn start(&mut self, peer: PeerId, log: &LogSet) -> (SyncMessage, SyncProgress) {
+        self.sessions.retain(|_, s| s.started.elapsed() < SESSION_TIMEOUT);
+        let session = rand::random();
+        self.sessions.insert(session, Session { peer, initiator: true, sent: 0, received: 0, started: Instant::now() });
+        let offer = SyncMessage::Offer {
+            session,
+            tree: LogTree::of(log),
+            vector: VersionVector::of(log),
+            horizon: log.horizon(),
+            signature: log.horizon_signature().cloned(),
+        };
+        (offer, SyncProgress { peer_id: peer, session, stage: "offer", entries_sent: 0, entries_received: 0 })
+    }
+
+    /// Handles a request or response of `from`. A request's reply is its response; a
+    /// response's reply is the next request.
+    pub fn handle(&mut self, from: PeerId, msg: SyncMessage, log: &LogSet) -> SyncStep {
+        match msg {
+            SyncMessage::Offer { session, tree, .. } => {
+                let local = LogTree::of(log);
+                let differing: HashSet<usize> = if local.root == tree.root {
+                    HashSet::new()
+                } else {
+                    (0..SYNC_BUCKETS).filter(|&i| tree.buckets.get(i) != local.buckets.get(i)).collect()
+                };
+                let mut buckets: BTreeMap<String, Vec<String>> =
+                    differing.iter().map(|&i| (format!("{:x}", i), Vec::new())).collect();
+                for hash in items_in(log, &differing).into_keys() {
+                    buckets.entry(format!("{:x}", bucket_of(&hash))).or_default().push(hash);
+                }
+
+                let stage = if buckets.is_empty() { "complete" } else { "diff" };
+                if !buckets.is_empty() {
+                    self.sessions.insert(session, Session { peer: from, initiator: false, sent: 0, received: 0, started: Instant::now() });
+                }
+                SyncStep {
+                    reply: Some(SyncMessage::Diff {
+                        session,
+                        buckets,
+                        vector: VersionVector::of(log),
+                        horizon: log.horizon(),
+                        signature: log.horizon_signature().cloned(),
+                    }),
+                    progress: Some(SyncProgress { peer_id: from, session, stage, entries_sent: 0, entries_received: 0 }),
+                    ..SyncStep::default()
+                }
+            }
+            SyncMessage::Diff { session, buckets, .. } => {
+                let Some(state) = self.sessions.get_mut(&session).filter(|s| s.peer == from && s.initiator) else {
+                    return SyncStep::default();
+                };
+                let differing: HashSet<usize> = buckets.keys().map(|key| bucket_of(key)).collect();
+                let ours = items_in(log, &differing);
+                let theirs: HashSet<&String> = buckets.values().flatten().collect();
+
+                let (entries, tombstones) = partition(
+                    ours.iter().filter(|(hash, _)| !theirs.contains(hash)).map(|(_, item)| item.clone()),
+                );
+                let want: Vec<String> = theirs.into_iter().filter(|hash| !ours.contains_key(*hash)).cloned().collect();
+                state.sent += entries.len() + tombstones.len();
+
+                let progress = SyncProgress {
+                    peer_id: from,
+                    session,
+                    stage: if want.is_empty() { "complete" } else { "entries" },
+                    entries_sent: state.sent,
+                    entries_received: state.received,
+                };
+                if want.is_empty() {
+                    self.sessions.remove(&session);
+                }
+                let reply = (!entries.is_empty() || !tombstones.is_empty() || !want.is_empty())
+                    .then_some(SyncMessage::Entries { session, entries, tombstones, want });
+                SyncStep { reply, progress: Some(progress), ..SyncStep::default() }
+            }
+            SyncMessage::Entries { session, entries, tombstones, want } => {
+                let Some(mut state) = self.sessions.remove(&session).filter(|s| s.peer == from) else {
+                    return SyncStep::default();
+                };
+                state.received += entries.len() + tombstones.len();
+
+                // The responder's answer, with whatever was wanted, ends the session
+                let reply = if state.initiator {
+                    None
+                } else {
+                    let wanted: HashSet<String> = want.into_iter().collect();
+                    let (found, found_tombstones) =
+                        partition(items(log).filter(|(hash, _)| wanted.contains(hash)).map(|(_, item)| item));
+                    state.sent += found.len() + found_tombstones.len();
+                    Some(SyncMessage::Entries {
+                        session,
+                        entries: found,
+                        tombstones: found_tombstones,
+                        want: Vec::new(),
+                    })
+                };
+
+                SyncStep {
+                    merge: entries,
+                    remove: tombstones,
+                    reply,
+                    progress: Some(SyncProgress {
+                        peer_id: from,
+                        session,
+                        stage: "complete",
+                        entries_sent: state.sent,
+                        entries_received: state.received,
+                    }),
+                }
+            }
+            // Replica states are not part of a session
+            SyncMessage::StateRequest { .. } | SyncMessage::State { .. } => SyncStep::default(),
+        }
+    }
+}
+
+struct Outgoing {
+    payload: Vec<u8>,
+    hash: String,
+    attempts: u32,
+    created: Instant,
+}
+
+struct Incoming {
+    total: usize,
+    hash: String,
+    chunks: BTreeMap<usize, Vec<u8>>,
+    /// Bytes in `chunks`.
+    buffered: usize,
+    last_progress: Instant,
+    requests: u32,
+}
+
+/// Chunked transfers of CRDT messages larger than `CHUNK_SIZE`, in both directions.
+///
+/// The sender splits the encoded message into `Chunk`s and keeps it for `TRANSFER_TTL`.
+/// Receivers buffer chunks per sender and transfer and, when one stalls for
+/// `CHUNK_RETRY_INTERVAL`, publish a `ChunkRequest` for the missing indices, so an
+/// interrupted transfer resumes where it stopped. A peer gets at most
+/// `MAX_INCOMING_PER_PEER` transfers and `MAX_BUFFERED_PER_PEER` bytes buffered at once.
+/// A reassembled message is only accepted if its SHA-256 matches.
+#[derive(Default)]
+pub struct ChunkedTransfers {
+    outgoing: HashMap<u64, Outgoing>,
+    incoming: HashMap<(PeerId, u64), Incoming>,
+    /// Transfers already reassembled, so resent chunks don't start them over.
+    completed: HashMap<(PeerId, u64), Instant>,
+}
+
+impl ChunkedTransfers {
+    /// Splits an encoded message into chunks, remembering it to answer later requests.
+    pub fn split(&mut self, payload: Vec<u8>) -> Vec<CrdtMessage> {
+        self.outgoing.retain(|_, t| t.created.elapsed() < TRANSFER_TTL);
+        if self.outgoing.len() >= MAX_OUTGOING {
+            if let Some(oldest) = self.outgoing.iter().min_by_key(|(_, t)| t.created).map(|(id, _)| *id) {
+                self.outgoing.remove(&oldest);
+            }
+        }
+        let transfer = rand::random();
+        let total = payload.len().div_ceil(CHUNK_SIZE);
+        let outgoing = Outgoing { hash: hex(&Sha256::digest(&payload)), payload, attempts: 0, created: Instant::now() };
+        let chunks = (0..total).map(|index| outgoing.chunk(transfer, index)).collect();
+        self.outgoing.insert(transfer, outgoing);
+        chunks
+    }
+
+    /// Chunks of a sent transfer to publish again. Empty once the transfer is forgotten.
+    pub fn resend(&mut self, transfer: u64, missing: &[usize]) -> Vec<CrdtMessage> {
+        let Some(outgoing) = self.outgoing.get_mut(&transfer).filter(|t| t.created.elapsed() < TRANSFER_TTL) else {
+            return Vec::new();
+        };
+        outgoing.attempts += 1;
+        let total = outgoing.payload.len().div_ceil(CHUNK_SIZE);
+        missing.iter().filter(|&&index| index < total).map(|&index| outgoing.chunk(transfer, index)).collect()
+    }
+
+    /// Buffers a received chunk. Returns the whole message once every chunk is in and the
+    /// hash matches; a mismatch discards the chunks so the next request fetches them all.
+    /// Chunks beyond the sender's caps are rejected.
+    pub fn receive(
+        &mut self,
+        from: PeerId,
+        transfer: u64,
+        index: usize,
+        total: usize,
+        hash: &str,
+        data: &str,
+    ) -> Result<Option<CrdtMessage>> {
+        let key = (from, transfer);
+        self.completed.retain(|_, at| at.elapsed() < TRANSFER_TTL);
+        if self.completed.contains_key(&key) {
+            return Ok(None);
+        }
+        if total == 0 || total > MAX_CHUNKS || index >= total {
+            return Err(anyhow!("chunk {} of {} is out of range", index, total));
+        }
+        let bytes = BASE64_STANDARD.decode(data)?;
+        if bytes.len() > CHUNK_SIZE {
+            return Err(anyhow!("chunk of {} bytes exceeds the chunk size", bytes.len()));
+        }
+
+        let (transfers, buffered) = self
+            .incoming
+            .iter()
+            .filter(|((peer, _), _)| *peer == from)
+            .fold((0, 0), |(transfers, buffered), (_, t)| (transfers + 1, buffered + t.buffered));
+        if !self.incoming.contains_key(&key) && transfers >= MAX_INCOMING_PER_PEER {
+            return Err(anyhow!("{} already has {} transfers in progress", from, transfers));
+        }
+        let incoming = self.incoming.entry(key).or_insert_with(|| Incoming {
+            total,
+            hash: hash.to_string(),
+            chunks: BTreeMap::new(),
+            buffered: 0,
+            last_progress: Instant::now(),
+            requests: 0,
+        });
+        if incoming.total != total || incoming.hash != hash {
+            return Err(anyhow!("chunk does not match transfer {:016x}", transfer));
+        }
+        if !incoming.chunks.contains_key(&index) {
+            if buffered + bytes.len() > MAX_BUFFERED_PER_PEER {
+                return Err(anyhow!("{} would have more than {} bytes buffered", from, MAX_BUFFERED_PER_PEER));
+            }
+            incoming.buffered += bytes.len();
+            incoming.chunks.insert(index, bytes);
+        }
+        incoming.last_progress = Instant::now();
+        incoming.requests = 0;
+        if incoming.chunks.len() < incoming.total {
+            return Ok(None);
+        }
+
+        let payload: Vec<u8> = std::mem::take(&mut incoming.chunks).into_values().flatten().collect();
+        incoming.buffered = 0;
+        if hex(&Sha256::digest(&payload)) != incoming.hash {
+            return Err(anyhow!("transfer {:016x} failed its integrity check", transfer));
+        }
+        self.incoming.remove(&key);
+        self.completed.insert(key, Instant::now());
+        match serde_json::from_slice(&payload)? {
+            CrdtMessage::Chunk { .. } => Err(anyhow!("transfer {:016x} contains a nested chunk", transfer)),
+            msg => Ok(Some(msg)),
+        }
+    }
+
+    /// `ChunkRequest`s for incoming transfers that stalled. Transfers still stalled after
+    /// `MAX_CHUNK_REQUESTS` requests are abandoned.
+    pub fn stalled(&mut self) -> Vec<CrdtMessage> {
+        self.incoming.retain(|_, t| t.requests < MAX_CHUNK_REQUESTS || t.last_progress.elapsed() < CHUNK_RETRY_INTERVAL);
+        self.incoming
+            .iter_mut()
+            .filter(|(_, t)| t.last_progress.elapsed() >= CHUNK_RETRY_INTERVAL)
+            .map(|(&(from, transfer), t)| {
+                t.requests += 1;
+                t.last_progress = Instant::now();
+                CrdtMessage::ChunkRequest {
+                    to: from.to_string(),
+                    transfer,
+                    missing: (0..t.total).filter(|i| !t.chunks.contains_key(i)).collect(),
+                }
+            })
+            .collect()
+    }
+}
+
+impl Outgoing {
+    fn chunk(&self, transfer: u64, index: usize) -> CrdtMessage {
+        let end = ((index + 1) * CHUNK_SIZE).min(self.payload.len());
+        CrdtMessage::Chunk {
+            transfer,
+            index,
+            total: self.payload.len().div_ceil(CHUNK_SIZE),
+            hash: self.hash.clone(),
+            data: BASE64_STANDARD.encode(&self.payload[index * CHUNK_SIZE..end]),
+            attempt: self.attempts,
+        }
+    }
+}
@@ -1,4 +1,7 @@
 use serde::{Serialize, Deserialize};
+use crate::health::{PeerHealth, PeerStatus};
+use crate::collection::CollectionKind;
+use crate::hlc::Hlc;
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 #[serde(tag = "type", content = "data")]
@@ -8,4 +11,61 @@ pub enum NetworkEvent {
     MessageSent { from: String, to: String, protocol: String },
     MessageReceived { from: String, to: String, protocol: String },
     LogEntry { from: String, content: String },
+    PeerHealth {
+        peer_id: String,
+        status: PeerStatus,
+        last_rtt_ms: Option<f64>,
+        min_rtt_ms: Option<f64>,
+        avg_rtt_ms: Option<f64>,
+        jitter_ms: f64,
+        failures: u64,
+    },
+    PeerLeaving { peer_id: String, reason: String },
+    PeerIncompatible { peer_id: String, protocol_version: String, reason: String },
+    Bandwidth { inbound: u64, outbound: u64, inbound_rate: f64, outbound_rate: f64 },
+    ChatMessage { from: String, content: String, timestamp: u64, topic: String },
+    /// A fault injected by the chaos layer: `drop`, `partition` or `reset`.
+    FaultInjected { kind: String, peer_id: Option<String> },
+    /// Periodic connectivity score of a peer and the transport it is routed over.
+    TransportScore { peer_id: String, score: f64, latency: f64, loss: f64, interface: f64, route: Option<String> },
+    /// A peer moved between TCP and the fallback link; `to` is `None` once it is unreachable.
+    TransportSwitched { peer_id: String, from: Option<String>, to: Option<String>, score: f64 },
+    /// Transports a published message was handed to, when a fallback link is configured.
+    RouteDecision { topic: String, tcp_peers: Vec<String>, ble_peers: Vec<String> },
+    /// Progress of an anti-entropy session with a peer; `stage` ends at `complete`.
+    SyncProgress { peer_id: String, session: String, stage: String, entries_sent: usize, entries_received: usize },
+    /// A gossipsub publish was rejected, e.g. a message over the max transmit size.
+    PublishFailed { topic: String, bytes: usize, reason: String },
+    /// A log entry was removed, locally or by a replicated tombstone.
+    LogEntryRemoved { id: String, removed_by: String },
+    /// A device attribute in the KV store changed; `value` is `None` once deleted.
+    KvChanged { device: String, attribute: String, value: Option<serde_json::Value> },
+    /// A replicated counter changed, locally or by a remote op.
+    CounterChanged { name: String, value: i64 },
+    /// A named collection was created, changed or dropped; `value` is `None` once dropped.
+    CollectionChanged { name: String, kind: CollectionKind, value: Option<serde_json::Value> },
+    /// The log was compacted up to `horizon`, by the local retention policy or a peer's,
+    /// dropping `removed` entries (appended to the archive when `archived`).
+    LogCompacted { horizon: Hlc, removed: usize, archived: bool },
+    /// A snapshot was merged: `entries` new log entries, `removed` entries removed by its
+    /// tombstones, `collections` collections merged, `dms` new DMs and `peers` peers dialed.
+    SnapshotImported { exported_by: String, entries: usize, removed: usize, collections: usize, dms: usize, peers: usize },
+    /// A peer's copy of the log fell behind or caught up: it lacks `missing` local entries,
+    /// the oldest written `lag_ms` ago, may or may not hold `unknown` others, and is `ahead`
+    /// when it holds entries this node lacks.
+    ReplicationLag { peer_id: String, missing: usize, unknown: usize, lag_ms: u64, ahead: bool },
+}
+
+impl NetworkEvent {
+    pub fn peer_health(peer_id: String, health: &PeerHealth) -> Self {
+        NetworkEvent::PeerHealth {
+            peer_id,
+            status: health.status,
+            last_rtt_ms: health.last_rtt_ms,
+            min_rtt_ms: health.min_rtt_ms,
+            avg_rtt_ms: health.avg_rtt_ms,
+            jitter_ms: health.jitter_ms,
+            failures: health.failures,
+        }
+    }
 }
@@ -0,0 +1,494 @@
+use anyhow::{anyhow, Result};
+use libp2p::identity::Keypair;
+use libp2p::PeerId;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+
+use crate::entry;
+use crate::health::{PeerHealth, PeerStatus};
+
+/// Peers scoring above this use TCP; at or below it they fall back to the low-bandwidth link.
+pub const SWITCH_THRESHOLD: f64 = 50.0;
+
+/// Average RTT up to which the latency factor keeps its full weight.
+const LATENCY_GOOD_MS: f64 = 500.0;
+/// Average RTT at which the latency factor drops to zero.
+const LATENCY_BAD_MS: f64 = 1500.0;
+
+/// Message IDs remembered to suppress copies arriving over both transports.
+const SEEN_CAPACITY: usize = 1024;
+
+/// Incomplete fragmented messages are discarded after this long.
+const REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Connectivity score (0-100) of a peer, as described in `doc/TRANSPORT_SWITCHING.md`.
+#[derive(Clone, Copy, Serialize, Debug, Default, PartialEq)]
+pub struct ConnectivityScore {
+    pub total: f64,
+    /// Ping latency, weighted 40%.
+    pub latency: f64,
+    /// Ping loss, weighted 30%.
+    pub loss: f64,
+    /// Whether the high-bandwidth interface is up, weighted 30%.
+    pub interface: f64,
+}
+
+impl ConnectivityScore {
+    /// Scores a peer from its ping statistics. A peer without a TCP connection scores 0.
+    pub fn compute(health: Option<&PeerHealth>, connected: bool, interface_up: bool) -> Self {
+        if !connected {
+            return Self::default();
+        }
+
+        let latency = match health.and_then(|h| h.avg_rtt_ms) {
+            Some(rtt) if rtt > LATENCY_GOOD_MS => {
+                40.0 * (1.0 - ((rtt - LATENCY_GOOD_MS) / (LATENCY_BAD_MS - LATENCY_GOOD_MS)).min(1.0))
+            }
+            _ => 40.0,
+        };
+
+        let loss = match health {
+            Some(h) if h.status == PeerStatus::Suspect => 0.0,
+            Some(h) if h.samples + h.failures > 0 => {
+                30.0 * (1.0 - h.failures as f64 / (h.samples + h.failures) as f64)
+            }
+            _ => 30.0,
+        };
+
+        let interface = if interface_up { 30.0 } else { 0.0 };
+
+        Self { total: latency + loss + interface, latency, loss, interface }
+    }
+}
+
+#[derive(Clone, Copy, Serialize, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Route {
+    Tcp,
+    Ble,
+}
+
+impl fmt::Display for Route {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Route::Tcp => write!(f, "tcp"),
+            Route::Ble => write!(f, "ble"),
+        }
+    }
+}
+
+/// A frame received on a fallback link.
+#[derive(Debug)]
+pub struct LinkFrame {
+    pub from: PeerId,
+    pub data: Vec<u8>,
+}
+
+/// A low-bandwidth transport used when TCP to a peer degrades, standing in for BLE GATT
+/// writes. Frames are at most `mtu()` bytes; larger messages are fragmented by the manager.
+pub trait FallbackLink: Send + Sync + fmt::Debug {
+    fn name(&self) -> &str;
+    fn mtu(&self) -> usize;
+    fn is_available(&self) -> bool;
+    fn can_reach(&self, peer: &PeerId) -> bool;
+    /// Registers the local node and returns the frames addressed to it.
+    fn attach(&self, local: PeerId) -> mpsc::UnboundedReceiver<LinkFrame>;
+    fn send(&self, to: &PeerId, frame: Vec<u8>) -> Result<()>;
+}
+
+#[derive(Clone, Serialize, Debug)]
+pub struct PeerTransport {
+    pub score: ConnectivityScore,
+    pub route: Route,
+    /// Seconds since the route last changed.
+    pub since_secs: u64,
+    #[serde(skip)]
+    since: Instant,
+}
+
+/// A route change for one peer. `to` is `None` when the peer became unreachable.
+#[derive(Clone, Debug)]
+pub struct Transition {
+    pub peer_id: PeerId,
+    pub from: Option<Route>,
+    pub to: Option<Route>,
+    pub score: f64,
+}
+
+#[derive(Clone, Serialize, Debug)]
+pub struct LinkReport {
+    pub name: String,
+    pub available: bool,
+    pub mtu: usize,
+}
+
+#[derive(Clone, Serialize, Debug)]
+pub struct TransportReport {
+    pub threshold: f64,
+    pub fallback: Option<LinkReport>,
+    pub peers: BTreeMap<String, PeerTransport>,
+}
+
+/// Keeps a connectivity score and route per peer and carries messages over the fallback
+/// link for peers routed to it. The swarm loop feeds it scores and consults it on publish.
+///
+/// Messages sent over the link are signed by their source with the key set by
+/// `with_identity`, standing in for the gossipsub signature, and relayed with it.
+#[derive(Debug, Default)]
+pub struct TransportManager {
+    link: Option<Arc<dyn FallbackLink>>,
+    identity: Option<Keypair>,
+    peers: HashMap<PeerId, PeerTransport>,
+    seen: HashSet<u64>,
+    seen_order: VecDeque<u64>,
+    next_message: u64,
+}
+
+impl TransportManager {
+    pub fn new(link: Option<Arc<dyn FallbackLink>>) -> Self {
+        Self { link, ..Default::default() }
+    }
+
+    /// Signs the messages this node sends over the fallback link with `keypair`.
+    pub fn with_identity(mut self, keypair: Keypair) -> Self {
+        self.identity = Some(keypair);
+        self
+    }
+
+    pub fn link(&self) -> Option<&Arc<dyn FallbackLink>> {
+        self.link.as_ref()
+    }
+
+    fn fallback_reaches(&self, peer: &PeerId) -> bool {
+        self.link.as_ref().is_some_and(|link| link.is_available() && link.can_reach(peer))
+    }
+
+    /// Records a fresh score for `peer` and picks its route. Degraded peers move to the
+    /// fallback link when it reaches them; otherwise they stay on TCP while connected and
+    /// are forgotten once they are not.
+    pub fn update(&mut self, peer: PeerId, score: ConnectivityScore, connected: bool) -> Option<Transition> {
+        let to = if score.total > SWITCH_THRESHOLD {
+            Some(Route::Tcp)
+        } else if self.fallback_reaches(&peer) {
+            Some(Route::Ble)
+        } else if connected {
+            Some(Route::Tcp)
+        } else {
+            None
+        };
+
+        let from = self.peers.get(&peer).map(|p| p.route);
+        match to {
+            Some(route) => {
+                let entry = self.peers.entry(peer).or_insert_with(|| PeerTransport {
+                    score,
+                    route,
+                    since_secs: 0,
+                    since: Instant::now(),
+                });
+                entry.score = score;
+                if entry.route != route {
+                    entry.route = route;
+                    entry.since = Instant::now();
+                }
+            }
+            None => {
+                self.peers.remove(&peer);
+            }
+        }
+
+        (from != to).then_some(Transition { peer_id: peer, from, to, score: score.total })
+    }
+
+    pub fn forget(&mut self, peer: &PeerId) {
+        self.peers.remove(peer);
+    }
+
+    /// Peers tracked by the manager, including ones only reachable over the fallback link.
+    pub fn known_peers(&self) -> Vec<PeerId> {
+        self.peers.keys().copied().collect()
+    }
+
+    pub fn route(&self, peer: &PeerId) -> Option<Route> {
+        self.peers.get(peer).map(|p| p.route)
+    }
+
+    pub fn fallback_peers(&self) -> Vec<PeerId> {
+        self.peers.iter().filter(|(_, p)| p.route == Route::Ble).map(|(peer, _)| *peer).collect()
+    }
+
+    /// Returns `true` the first time a message ID is seen on either transport.
+    pub fn first_seen(&mut self, id: u64) -> bool {
+        if !self.seen.insert(id) {
+            return false;
+        }
+        self.seen_order.push_back(id);
+        if self.seen_order.len() > SEEN_CAPACITY {
+            if let Some(old) = self.seen_order.pop_front() {
+                self.seen.remove(&old);
+            }
+        }
+        true
+    }
+
+    /// Signs a sealed gossipsub payload of this node, fragments it and writes it to each of
+    /// `peers` over the fallback link. Returns the peers it was written to.
+    pub fn send_fallback(&mut self, peers: &[PeerId], topic: &str, data: &[u8]) -> Vec<PeerId> {
+        let Some(identity) = &self.identity else {
+            return Vec::new();
+        };
+        let message = LinkMessage {
+            topic: topic.to_string(),
+            source: identity.public().to_peer_id(),
+            data: data.to_vec(),
+            // Ed25519 signing cannot fail
+            signature: identity.sign(&signed_bytes(topic, data)).unwrap_or_default(),
+        };
+        self.relay_fallback(peers, &message)
+    }
+
+    /// Writes a message received over the fallback link on to `peers`, with its source's
+    /// signature. Returns the peers it was written to.
+    pub fn relay_fallback(&mut self, peers: &[PeerId], message: &LinkMessage) -> Vec<PeerId> {
+        let Some(link) = self.link.clone() else {
+            return Vec::new();
+        };
+        self.next_message += 1;
+        let Ok(fragments) = fragment(self.next_message, message, link.mtu()) else {
+            return Vec::new();
+        };
+
+        peers
+            .iter()
+            .filter(|peer| fragments.iter().all(|f| link.send(peer, f.clone()).is_ok()))
+            .copied()
+            .collect()
+    }
+
+    pub fn report(&self) -> TransportReport {
+        TransportReport {
+            threshold: SWITCH_THRESHOLD,
+            fallback: self.link.as_ref().map(|link| LinkReport {
+                name: link.name().to_string(),
+                available: link.is_available(),
+                mtu: link.mtu(),
+            }),
+            peers: self
+                .peers
+                .iter()
+                .map(|(peer, state)| {
+                    let mut state = state.clone();
+                    state.since_secs = state.since.elapsed().as_secs();
+                    (peer.to_string(), state)
+                })
+                .collect(),
+        }
+    }
+}
+
+/// A whole message carried over the fallback link, signed by its source.
+#[derive(Debug)]
+pub struct LinkMessage {
+    pub topic: String,
+    pub source: PeerId,
+    pub data: Vec<u8>,
+    pub signature: Vec<u8>,
+}
+
+/// What the source of a `LinkMessage` signs: topic len (u8) | topic | data.
+fn signed_bytes(topic: &str, data: &[u8]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(1 + topic.len() + data.len());
+    bytes.push(topic.len() as u8);
+    bytes.extend_from_slice(topic.as_bytes());
+    bytes.extend_from_slice(data);
+    bytes
+}
+
+/// Fragment layout: message (u64) | index (u16) | count (u16) | topic len (u8) | topic |
+/// source len (u8) | source peer ID bytes | payload. All integers are big-endian. The
+/// payloads of the fragments joined are: signature len (u8) | signature | data.
+fn fragment(message: u64, link_message: &LinkMessage, mtu: usize) -> Result<Vec<Vec<u8>>> {
+    let topic = &link_message.topic;
+    let source = link_message.source.to_bytes();
+    let header = 8 + 2 + 2 + 1 + topic.len() + 1 + source.len();
+    if topic.len() > u8::MAX as usize || mtu <= header {
+        return Err(anyhow!("fallback link MTU {} too small for a {} byte header", mtu, header));
+    }
+    let signature = &link_message.signature;
+    let signature_len = u8::try_from(signature.len()).map_err(|_| anyhow!("signature too long for the fallback link"))?;
+    let mut data = Vec::with_capacity(1 + signature.len() + link_message.data.len());
+    data.push(signature_len);
+    data.extend_from_slice(signature);
+    data.extend_from_slice(&link_message.data);
+    let chunks: Vec<&[u8]> = data.chunks(mtu - header).collect();
+    let count = u16::try_from(chunks.len()).map_err(|_| anyhow!("message too large for the fallback link"))?;
+
+    Ok(chunks
+        .into_iter()
+        .enumerate()
+        .map(|(index, chunk)| {
+            let mut frame = Vec::with_capacity(header + chunk.len());
+            frame.extend_from_slice(&message.to_be_bytes());
+            frame.extend_from_slice(&(index as u16).to_be_bytes());
+            frame.extend_from_slice(&count.to_be_bytes());
+            frame.push(topic.len() as u8);
+            frame.extend_from_slice(topic.as_bytes());
+            frame.push(source.len() as u8);
+            frame.extend_from_slice(&source);
+            frame.extend_from_slice(chunk);
+            frame
+        })
+        .collect())
+}
+
+struct Partial {
+    topic: String,
+    source: PeerId,
+    parts: Vec<Option<Vec<u8>>>,
+    started: Instant,
+}
+
+/// Reassembles fallback link frames into whole messages, dropping the ones whose
+/// signature does not verify against their source.
+#[derive(Default)]
+pub struct Reassembler {
+    partial: HashMap<(PeerId, u64), Partial>,
+}
+
+impl Reassembler {
+    pub fn push(&mut self, frame: LinkFrame) -> Option<LinkMessage> {
+        self.partial.retain(|_, p| p.started.elapsed() < REASSEMBLY_TIMEOUT);
+
+        let data = frame.data;
+        let read_u8 = |at: usize| data.get(at).copied();
+        let message = u64::from_be_bytes(data.get(0..8)?.try_into().ok()?);
+        let index = u16::from_be_bytes(data.get(8..10)?.try_into().ok()?) as usize;
+        let count = u16::from_be_bytes(data.get(10..12)?.try_into().ok()?) as usize;
+        let topic_len = read_u8(12)? as usize;
+        let topic = String::from_utf8(data.get(13..13 + topic_len)?.to_vec()).ok()?;
+        let source_at = 13 + topic_len;
+        let source_len = read_u8(source_at)? as usize;
+        let source = PeerId::from_bytes(data.get(source_at + 1..source_at + 1 + source_len)?).ok()?;
+        let payload = data.get(source_at + 1 + source_len..)?.to_vec();
+        if index >= count {
+            return None;
+        }
+
+        let partial = self.partial.entry((frame.from, message)).or_insert_with(|| Partial {
+            topic,
+            source,
+            parts: vec![None; count],
+            started: Instant::now(),
+        });
+        if partial.parts.len() != count {
+            return None;
+        }
+        partial.parts[index] = Some(payload);
+        if partial.parts.iter().any(Option::is_none) {
+            return None;
+        }
+
+        let partial = self.partial.remove(&(frame.from, message))?;
+        let payload: Vec<u8> = partial.parts.into_iter().flatten().flatten().collect();
+        let signature_len = *payload.first()? as usize;
+        let signature = payload.get(1..1 + signature_len)?.to_vec();
+        let data = payload.get(1 + signature_len..)?.to_vec();
+        let public_key = entry::public_key_of(&partial.source)?;
+        if !public_key.verify(&signed_bytes(&partial.topic, &data), &signature) {
+            return None;
+        }
+        Some(LinkMessage { topic: partial.topic, source: partial.source, data, signature })
+    }
+}
+
+/// In-process stand-in for a BLE mesh: every `MockLink` attached to the same hub can reach
+/// the others, at a limited byte rate.
+#[derive(Clone, Debug, Default)]
+pub struct MockHub {
+    nodes: Arc<Mutex<HashMap<PeerId, mpsc::UnboundedSender<LinkFrame>>>>,
+}
+
+/// Frames queued for delivery, with their destination.
+type Outbox = mpsc::UnboundedSender<(PeerId, Vec<u8>)>;
+
+#[derive(Debug)]
+pub struct MockLink {
+    hub: MockHub,
+    mtu: usize,
+    bytes_per_sec: u64,
+    available: AtomicBool,
+    outbox: Mutex<Option<Outbox>>,
+}
+
+impl MockLink {
+    pub fn new(hub: &MockHub, mtu: usize, bytes_per_sec: u64) -> Self {
+        Self {
+            hub: hub.clone(),
+            mtu,
+            bytes_per_sec: bytes_per_sec.max(1),
+            available: AtomicBool::new(true),
+            outbox: Mutex::new(None),
+        }
+    }
+
+    pub fn set_available(&self, available: bool) {
+        self.available.store(available, Ordering::Relaxed);
+    }
+}
+
+impl FallbackLink for MockLink {
+    fn name(&self) -> &str {
+        "mock-ble"
+    }
+
+    fn mtu(&self) -> usize {
+        self.mtu
+    }
+
+    fn is_available(&self) -> bool {
+        self.available.load(Ordering::Relaxed)
+    }
+
+    fn can_reach(&self, peer: &PeerId) -> bool {
+        self.hub.nodes.lock().unwrap().contains_key(peer)
+    }
+
+    fn attach(&self, local: PeerId) -> mpsc::UnboundedReceiver<LinkFrame> {
+        let (inbound_tx, inbound_rx) = mpsc::unbounded_channel();
+        self.hub.nodes.lock().unwrap().insert(local, inbound_tx);
+
+        // Frames leave one at a time, paced to the link's byte rate
+        let (outbox_tx, mut outbox_rx) = mpsc::unbounded_channel::<(PeerId, Vec<u8>)>();
+        *self.outbox.lock().unwrap() = Some(outbox_tx);
+        let hub = self.hub.clone();
+        let bytes_per_sec = self.bytes_per_sec;
+        tokio::spawn(async move {
+            while let Some((to, data)) = outbox_rx.recv().await {
+                tokio::time::sleep(Duration::from_secs_f64(data.len() as f64 / bytes_per_sec as f64)).await;
+                let target = hub.nodes.lock().unwrap().get(&to).cloned();
+                if let Some(target) = target {
+                    let _ = target.send(LinkFrame { from: local, data });
+                }
+            }
+        });
+
+        inbound_rx
+    }
+
+    fn send(&self, to: &PeerId, frame: Vec<u8>) -> Result<()> {
+        if !self.is_available() {
+            return Err(anyhow!("mock link is down"));
+        }
+        if frame.len() > self.mtu {
+            return Err(anyhow!("frame of {} bytes exceeds MTU {}", frame.len(), self.mtu));
+        }
+        let outbox = self.outbox.lock().unwrap();
+        let outbox = outbox.as_ref().ok_or_else(|| anyhow!("mock link not attached"))?;
+        outbox.send((*to, frame)).map_err(|_| anyhow!("mock link closed"))
+    }
+}
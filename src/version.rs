@@ -0,0 +1,236 @@
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use libp2p::PeerId;
+use crate::namespace::DEFAULT_NAMESPACE;
+
+pub const PROTOCOL_NAME: &str = "ghostmesh";
+
+/// Wire protocol version announced through identify. Peers must share the major version.
+/// 2.0.0 replaced bare-string log entries with signed `entry::LogEntry`s.
+pub const PROTOCOL_VERSION: Version = Version { major: 2, minor: 9, patch: 0 };
+
+/// Chat lines on the global topic are JSON `ChatMessage`s rather than bare text.
+pub const FEATURE_CHAT_JSON: &str = "chat-json";
+
+/// The CRDT topic carries `sync::CrdtMessage`s (deltas and digests) rather than whole states.
+pub const FEATURE_CRDT_DELTA: &str = "crdt-delta";
+
+/// Peers run an anti-entropy session (`sync::AntiEntropy`) over `sync::SYNC_PROTOCOL`
+/// when they connect. Nodes of 2.7 and earlier ran it on the CRDT topic as "anti-entropy";
+/// they still catch up with newer ones through digests and full states.
+pub const FEATURE_SYNC_PROTOCOL: &str = "sync-protocol";
+
+/// CRDT messages larger than `sync::CHUNK_SIZE` travel as resumable `Chunk`s.
+pub const FEATURE_CHUNKED: &str = "chunked-transfer";
+
+/// Log entries can be removed with `entry::Tombstone`s, collected once every peer acked.
+pub const FEATURE_LOG_REMOVE: &str = "log-remove";
+
+/// Device attributes replicate on the KV topic as `kv::KvMessage`s.
+pub const FEATURE_KV: &str = "kv-store";
+
+/// Named counters replicate on the counters topic as `counter::CounterMessage`s.
+pub const FEATURE_COUNTERS: &str = "counters";
+
+/// Named collections replicate on their own `ghostmesh-col-<name>` topics as
+/// `collection::CollectionMessage`s.
+pub const FEATURE_COLLECTIONS: &str = "collections";
+
+/// Collections can hold a JSON document (`document::Document`) edited with patches.
+pub const FEATURE_DOCUMENT: &str = "json-document";
+
+/// Logs are compacted by retention, and the horizon travels as `sync::CrdtMessage::Compact`.
+pub const FEATURE_RETENTION: &str = "log-retention";
+
+/// Digests and anti-entropy offers carry a `replication::VersionVector` of the sender's log.
+pub const FEATURE_REPLICATION_STATUS: &str = "replication-status";
+
+/// Full replica states are asked for and sent over `sync::SYNC_PROTOCOL`, to the
+/// requester only, rather than published on the replica's topic.
+pub const FEATURE_STATE_SYNC: &str = "state-sync";
+
+/// Features this node understands, advertised in the identify agent string.
+pub const LOCAL_FEATURES: &[&str] = &[FEATURE_CHAT_JSON, FEATURE_CRDT_DELTA, FEATURE_SYNC_PROTOCOL, FEATURE_CHUNKED, FEATURE_LOG_REMOVE, FEATURE_KV, FEATURE_COUNTERS, FEATURE_COLLECTIONS, FEATURE_DOCUMENT, FEATURE_RETENTION, FEATURE_REPLICATION_STATUS, FEATURE_STATE_SYNC];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+pub struct Version {
+    pub major: u64,
+    pub minor: u64,
+    pub patch: u64,
+}
+
+impl Version {
+    pub fn parse(s: &str) -> Option<Self> {
+        let mut parts = s.trim().split('.');
+        let major = parts.next()?.parse().ok()?;
+        let minor = parts.next().unwrap_or("0").parse().ok()?;
+        let patch = parts.next().unwrap_or("0").parse().ok()?;
+        if parts.next().is_some() {
+            return None;
+        }
+        Some(Self { major, minor, patch })
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+/// Identify protocol version string, e.g. `ghostmesh/2.0.0`.
+pub fn protocol_string() -> String {
+    format!("{}/{}", PROTOCOL_NAME, PROTOCOL_VERSION)
+}
+
+/// Identify agent string carrying the build version, namespace and supported features,
+/// e.g. `ghostmesh/0.1.0 ns=default features=chat-json`.
+pub fn agent_string(namespace: &str) -> String {
+    format!(
+        "{}/{} ns={} features={}",
+        PROTOCOL_NAME,
+        env!("CARGO_PKG_VERSION"),
+        namespace,
+        LOCAL_FEATURES.join(",")
+    )
+}
+
+/// Extracts the namespace from an agent string. Peers that predate namespaces are
+/// members of the default one.
+pub fn parse_namespace(agent_version: &str) -> String {
+    agent_version
+        .split_whitespace()
+        .find_map(|part| part.strip_prefix("ns="))
+        .unwrap_or(DEFAULT_NAMESPACE)
+        .to_string()
+}
+
+/// Parses the `ghostmesh/x.y.z` protocol string announced by a remote peer.
+pub fn parse_protocol(protocol_version: &str) -> Option<Version> {
+    let (name, version) = protocol_version.split_once('/')?;
+    if name != PROTOCOL_NAME {
+        return None;
+    }
+    Version::parse(version)
+}
+
+/// Extracts the advertised feature list from an agent string. Peers older than
+/// 1.1.0 advertise nothing and are treated as supporting no optional features.
+pub fn parse_features(agent_version: &str) -> Vec<String> {
+    agent_version
+        .split_whitespace()
+        .find_map(|part| part.strip_prefix("features="))
+        .map(|list| list.split(',').filter(|f| !f.is_empty()).map(str::to_string).collect())
+        .unwrap_or_default()
+}
+
+/// Checks whether a remote protocol string can talk to this node.
+pub fn check_compatibility(protocol_version: &str) -> Result<Version, String> {
+    let remote = parse_protocol(protocol_version)
+        .ok_or_else(|| format!("unrecognized protocol '{}'", protocol_version))?;
+
+    if remote.major != PROTOCOL_VERSION.major {
+        return Err(format!(
+            "incompatible major version {} (local {})",
+            remote, PROTOCOL_VERSION
+        ));
+    }
+    Ok(remote)
+}
+
+/// What a peer announced about itself through identify.
+#[derive(Clone, Serialize, Debug)]
+pub struct PeerVersion {
+    pub protocol_version: String,
+    pub agent_version: String,
+    pub namespace: String,
+    pub features: Vec<String>,
+    pub compatible: bool,
+    pub reason: Option<String>,
+}
+
+impl PeerVersion {
+    pub fn from_identify(protocol_version: &str, agent_version: &str, local_namespace: &str) -> Self {
+        let namespace = parse_namespace(agent_version);
+        let (compatible, reason) = match check_compatibility(protocol_version) {
+            Ok(_) if namespace != local_namespace => {
+                (false, Some(format!("namespace '{}' does not match local '{}'", namespace, local_namespace)))
+            }
+            Ok(_) => (true, None),
+            Err(reason) => (false, Some(reason)),
+        };
+
+        Self {
+            protocol_version: protocol_version.to_string(),
+            agent_version: agent_version.to_string(),
+            namespace,
+            features: parse_features(agent_version),
+            compatible,
+            reason,
+        }
+    }
+
+    pub fn supports(&self, feature: &str) -> bool {
+        self.features.iter().any(|f| f == feature)
+    }
+}
+
+#[derive(Clone, Serialize, Debug)]
+pub struct PeerVersionReport {
+    pub peer_id: String,
+    pub connected: bool,
+    #[serde(flatten)]
+    pub version: PeerVersion,
+}
+
+/// Version skew across every peer this node has identified.
+#[derive(Clone, Serialize, Debug)]
+pub struct VersionReport {
+    pub local_version: String,
+    pub local_features: Vec<String>,
+    /// Number of identified peers per announced protocol version.
+    pub versions: BTreeMap<String, usize>,
+    pub incompatible: usize,
+    /// Features some connected peer lacks; the node falls back to older wire formats for these.
+    pub degraded_features: Vec<String>,
+    pub peers: Vec<PeerVersionReport>,
+}
+
+pub fn skew_report(versions: &HashMap<PeerId, PeerVersion>, connected: &HashSet<PeerId>) -> VersionReport {
+    let mut counts = BTreeMap::new();
+    let mut peers = Vec::new();
+    let mut incompatible = 0;
+
+    for (peer_id, version) in versions {
+        *counts.entry(version.protocol_version.clone()).or_insert(0) += 1;
+        if !version.compatible {
+            incompatible += 1;
+        }
+        peers.push(PeerVersionReport {
+            peer_id: peer_id.to_string(),
+            connected: connected.contains(peer_id),
+            version: version.clone(),
+        });
+    }
+    peers.sort_by(|a, b| a.peer_id.cmp(&b.peer_id));
+
+    let degraded_features = LOCAL_FEATURES
+        .iter()
+        .filter(|f| {
+            versions
+                .iter()
+                .any(|(p, v)| connected.contains(p) && v.compatible && !v.supports(f))
+        })
+        .map(|f| f.to_string())
+        .collect();
+
+    VersionReport {
+        local_version: protocol_string(),
+        local_features: LOCAL_FEATURES.iter().map(|f| f.to_string()).collect(),
+        versions: counts,
+        incompatible,
+        degraded_features,
+        peers,
+    }
+}
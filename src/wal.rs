@@ -0,0 +1,277 @@
+use crate::entry::{HorizonSignature, LogEntry, Tombstone};
+use crate::hlc::Hlc;
+use crate::logset::LogSet;
+use crate::namespace::Namespace;
+use crate::storage;
+use libp2p::identity::Keypair;
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs::{self, File, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::Path;
+use std::time::{Duration, Instant};
+use tracing::{error, info};
+
+/// Records appended to the WAL before the log is snapshotted and the WAL emptied.
+pub const SNAPSHOT_EVERY: usize = 1000;
+
+/// When writes to the WAL are forced to disk with fsync. Every write reaches the
+/// operating system right away, so a crash of the process alone loses nothing; the
+/// policy decides what a power cut or kernel crash may lose.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SyncPolicy {
+    /// After every write, before it is published to peers.
+    #[default]
+    Always,
+    /// At most this long after a write.
+    Interval(Duration),
+    /// Whenever the operating system flushes its cache.
+    Never,
+}
+
+impl SyncPolicy {
+    /// Parses a `--wal-sync` argument: `always`, `never` or an interval such as `200ms` or `1s`.
+    pub fn parse(spec: &str) -> Result<Self> {
+        let invalid = || anyhow!("invalid WAL sync policy '{}', expected always, never or an interval like 200ms", spec);
+        let interval = |number: &str, factor: u64| number.parse::<u64>().ok().filter(|n| *n > 0).map(|n| Duration::from_millis(n * factor));
+        match spec {
+            "always" => Ok(Self::Always),
+            "never" => Ok(Self::Never),
+            _ => match spec.strip_suffix("ms") {
+                Some(number) => interval(number, 1),
+                None => spec.strip_suffix('s').and_then(|number| interval(number, 1000)),
+            }
+            .map(Self::Interval)
+            .ok_or_else(invalid),
+        }
+    }
+}
+
+/// A change to the log, as appended to the WAL and replayed on startup. Replaying a record
+/// the snapshot already holds changes nothing.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(tag = "op", rename_all = "lowercase")]
+pub enum WalRecord {
+    Insert { entry: LogEntry },
+    Remove { tombstone: Tombstone },
+    Compact {
+        horizon: Hlc,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        signature: Option<HorizonSignature>,
+    },
+}
+
+impl WalRecord {
+    pub fn apply(self, log: &mut LogSet) {
+        match self {
+            WalRecord::Insert { entry } => {
+                log.insert(entry);
+            }
+            WalRecord::Remove { tombstone } => {
+                log.remove(tombstone);
+            }
+            WalRecord::Compact { horizon, signature } => {
+                log.compact_signed(horizon, signature);
+            }
+        }
+    }
+}
+
+/// A write to the log's storage, encoded by `LogStore::prepare` or `LogStore::snapshot`
+/// while the log is at hand, and done by `LogStore::write` once the log may be released.
+pub enum PendingWrite {
+    /// WAL lines for this many records.
+    Append { lines: String, records: usize },
+    /// The whole log, replacing the snapshot and emptying the WAL.
+    Snapshot(Vec<u8>),
+}
+
+/// The log on disk: a snapshot of the whole `LogSet` plus an append-only write-ahead log
+/// of the changes made since.
+///
+/// A change costs one appended line, `<checksum> <record>`, instead of rewriting the
+/// log. Every `SNAPSHOT_EVERY` records, on tombstone collection and on shutdown, the
+/// snapshot is rewritten atomically and the WAL emptied. On startup the snapshot is loaded
+/// and the WAL replayed; a record torn by a crash fails its checksum, and it and
+/// anything after it are cut off.
+///
+/// Writing takes two steps so the node can release the log's lock before the I/O:
+/// `prepare` encodes the records (or a whole snapshot) from the log, and `write` appends
+/// and syncs them.
+pub struct LogStore {
+    snapshot_path: String,
+    wal: File,
+    policy: SyncPolicy,
+    /// Records in the WAL, i.e. since the snapshot.
+    records: usize,
+    /// Length of the WAL up to its last complete record, where a failed append is cut back to.
+    len: u64,
+    /// Set when an append failed: records applied to the log are missing from the WAL, so
+    /// the next write is a snapshot.
+    missing: bool,
+    /// When the oldest write not yet synced was made.
+    unsynced_since: Option<Instant>,
+}
+
+impl LogStore {
+    /// Opens the log of the node on `port` and recovers it. A log kept in the JSON file of
+    /// earlier versions is migrated once into a snapshot, and the file is kept as
+    /// `storage_<port>.json.bak`; lines of the unsigned log are signed with `keypair`.
+    pub fn open(ns: &Namespace, port: u16, policy: SyncPolicy, keypair: &Keypair) -> Result<(Self, LogSet)> {
+        storage::ensure_data_dir(ns)?;
+        let snapshot_path = storage::get_log_snapshot_path(ns, port);
+        let wal_path = storage::get_wal_path(ns, port);
+        let legacy_path = storage::get_storage_path(ns, port);
+        if !Path::new(&snapshot_path).exists() && Path::new(&legacy_path).exists() {
+            let log = storage::load_json_log(ns, port, keypair)?;
+            write_snapshot(&snapshot_path, &log)?;
+            fs::rename(&legacy_path, format!("{}.bak", legacy_path))?;
+            info!("Migrated {} log entries from {} to {}", log.len(), legacy_path, snapshot_path);
+        }
+
+        let mut log = match fs::read(&snapshot_path) {
+            Ok(bytes) => serde_json::from_slice(&bytes)?,
+            Err(e) if e.kind() == ErrorKind::NotFound => LogSet::new(),
+            Err(e) => return Err(e.into()),
+        };
+        let (records, valid_len) = match fs::read(&wal_path) {
+            Ok(bytes) => replay(&bytes, &mut log),
+            Err(e) if e.kind() == ErrorKind::NotFound => (0, 0),
+            Err(e) => return Err(e.into()),
+        };
+        let wal = OpenOptions::new().create(true).append(true).open(&wal_path)?;
+        let len = wal.metadata()?.len();
+        if len > valid_len {
+            error!("Dropping {} bytes of torn or corrupt records at the end of {}", len - valid_len, wal_path);
+            wal.set_len(valid_len)?;
+            wal.sync_all()?;
+        }
+        if records > 0 {
+            info!("Replayed {} WAL records from {}", records, wal_path);
+        }
+        let store = Self { snapshot_path, wal, policy, records, len: valid_len.min(len), missing: false, unsynced_since: None };
+        Ok((store, log))
+    }
+
+    /// Appends `records` (already applied to `log`) to the WAL and syncs it as the policy
+    /// asks, snapshotting `log` once the WAL is long enough.
+    pub fn append(&mut self, records: &[WalRecord], log: &LogSet) -> Result<()> {
+        if records.is_empty() {
+            return Ok(());
+        }
+        let pending = self.prepare(records, log)?;
+        self.write(pending)
+    }
+
+    /// Encodes `records` (already applied to `log`) as WAL lines, or `log` as a new snapshot
+    /// once they would make the WAL `SNAPSHOT_EVERY` records long.
+    pub fn prepare(&self, records: &[WalRecord], log: &LogSet) -> Result<PendingWrite> {
+        if self.missing || self.records + records.len() >= SNAPSHOT_EVERY {
+            return Self::snapshot(log);
+        }
+        let mut lines = String::new();
+        for record in records {
+            let json = serde_json::to_string(record)?;
+            lines.push_str(&format!("{} {}\n", checksum(&json), json));
+        }
+        Ok(PendingWrite::Append { lines, records: records.len() })
+    }
+
+    /// Encodes `log` as a new snapshot.
+    pub fn snapshot(log: &LogSet) -> Result<PendingWrite> {
+        Ok(PendingWrite::Snapshot(serde_json::to_vec(log)?))
+    }
+
+    /// Appends prepared lines to the WAL, syncing it as the policy asks, or writes a
+    /// prepared snapshot and empties the WAL. An append that fails part way is cut off, so
+    /// later records are not written after a torn line, and the next write is a snapshot.
+    pub fn write(&mut self, pending: PendingWrite) -> Result<()> {
+        let (lines, records) = match pending {
+            PendingWrite::Append { lines, records } => (lines, records),
+            PendingWrite::Snapshot(bytes) => {
+                storage::write_atomic(&self.snapshot_path, &bytes)?;
+                // A crash before the WAL is emptied replays records the snapshot already holds
+                self.wal.set_len(0)?;
+                self.wal.sync_all()?;
+                self.records = 0;
+                self.len = 0;
+                self.missing = false;
+                self.unsynced_since = None;
+                return Ok(());
+            }
+        };
+        if let Err(e) = self.wal.write_all(lines.as_bytes()) {
+            if let Err(e) = self.wal.set_len(self.len) {
+                error!("Failed to cut a torn record off the WAL: {:?}", e);
+            }
+            self.missing = true;
+            return Err(e.into());
+        }
+        self.len += lines.len() as u64;
+        self.records += records;
+        match self.policy {
+            SyncPolicy::Always => self.wal.sync_data()?,
+            SyncPolicy::Interval(interval) => {
+                let since = *self.unsynced_since.get_or_insert_with(Instant::now);
+                if since.elapsed() >= interval {
+                    self.sync()?;
+                }
+            }
+            SyncPolicy::Never => {}
+        }
+        Ok(())
+    }
+
+    /// Syncs the writes not synced yet, if any.
+    pub fn sync(&mut self) -> Result<()> {
+        if self.unsynced_since.take().is_some() {
+            self.wal.sync_data()?;
+        }
+        Ok(())
+    }
+
+    /// Writes `log` as the new snapshot and empties the WAL.
+    pub fn checkpoint(&mut self, log: &LogSet) -> Result<()> {
+        let snapshot = Self::snapshot(log)?;
+        self.write(snapshot)
+    }
+
+    /// Records in the WAL since the last snapshot.
+    pub fn records(&self) -> usize {
+        self.records
+    }
+}
+
+fn write_snapshot(path: &str, log: &LogSet) -> Result<()> {
+    storage::write_atomic(path, &serde_json::to_vec(log)?)
+}
+
+/// First 8 bytes of the SHA-256 of a record, in hex.
+fn checksum(json: &str) -> String {
+    Sha256::digest(json.as_bytes())[..8].iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Applies the WAL's records to `log` up to the first one that is incomplete or fails its
+/// checksum. Returns the number applied and the length of the WAL they span.
+fn replay(bytes: &[u8], log: &mut LogSet) -> (usize, u64) {
+    let mut records = 0;
+    let mut valid_len = 0;
+    for line in bytes.split_inclusive(|b| *b == b'\n') {
+        let Some(line) = line.strip_suffix(b"\n") else {
+            break;
+        };
+        let record = std::str::from_utf8(line)
+            .ok()
+            .and_then(|line| line.split_once(' '))
+            .filter(|(sum, json)| checksum(json) == *sum)
+            .and_then(|(_, json)| serde_json::from_str::<WalRecord>(json).ok());
+        let Some(record) = record else {
+            break;
+        };
+        record.apply(log);
+        records += 1;
+        valid_len += line.len() as u64 + 1;
+    }
+    (records, valid_len)
+}
@@ -0,0 +1,291 @@
+use crate::collection::CollectionKind;
+use crate::entry::{EntryKey, LogEntry};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::broadcast;
+
+/// Changes remembered for resuming; older resume tokens get a `Reset`.
+const JOURNAL_SIZE: usize = 10_000;
+
+/// Changes buffered per watcher before it falls back to the journal.
+const CHANNEL_SIZE: usize = 1024;
+
+/// Keys under a root (`kv`, `counters`, `collections/<name>`) with their values.
+pub type View = BTreeMap<String, Value>;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ChangeOp {
+    Insert,
+    Update,
+    Remove,
+}
+
+/// A change to one key of the node's state, such as `log/<author>/<id>`, `kv/<device>/<attribute>`,
+/// `counters/<name>` or `collections/<name>/<member or key>`.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Change {
+    /// Position in the node's change sequence, and the token to resume after this change.
+    pub seq: u64,
+    pub op: ChangeOp,
+    pub key: String,
+    /// The new value, `None` once removed.
+    pub value: Option<Value>,
+}
+
+/// What a watcher receives.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum WatchItem {
+    Change(Change),
+    /// The resume token was too old or from an earlier run: changes were missed, and the
+    /// state should be read again. Changes follow from `seq`.
+    Reset { seq: u64 },
+    /// Every change up to `seq` was delivered; the token to resume from if nothing follows.
+    Ready { seq: u64 },
+}
+
+impl WatchItem {
+    /// Sequence number the item stands for, its event id on `GET /api/watch`.
+    pub fn seq(&self) -> u64 {
+        match self {
+            WatchItem::Change(change) => change.seq,
+            WatchItem::Reset { seq } | WatchItem::Ready { seq } => *seq,
+        }
+    }
+
+    /// The `type` it is tagged with.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            WatchItem::Change(_) => "change",
+            WatchItem::Reset { .. } => "reset",
+            WatchItem::Ready { .. } => "ready",
+        }
+    }
+}
+
+/// Ordered feed of changes to the node's state, with a journal to resume from.
+///
+/// The merge paths in `p2p::run_node` report what they changed: log entries by id, and
+/// the other replicas as views of their keys, which are diffed against the last view to
+/// tell inserts, updates and removes apart. Each change gets the next number of a
+/// sequence that starts at the wall clock in microseconds, so tokens of an earlier run
+/// are recognized.
+#[derive(Clone)]
+pub struct Watch {
+    journal: Arc<Mutex<Journal>>,
+    tx: broadcast::Sender<Change>,
+}
+
+struct Journal {
+    /// Tokens below this may have missed changes.
+    floor: u64,
+    seq: u64,
+    changes: VecDeque<Change>,
+    views: HashMap<String, View>,
+}
+
+impl Default for Watch {
+    fn default() -> Self {
+        let start = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_micros() as u64);
+        let journal = Journal { floor: start, seq: start, changes: VecDeque::new(), views: HashMap::new() };
+        Self { journal: Arc::new(Mutex::new(journal)), tx: broadcast::channel(CHANNEL_SIZE).0 }
+    }
+}
+
+impl Watch {
+    /// Sets the view of `root` without reporting changes, for the state loaded at startup.
+    pub fn seed(&self, root: &str, view: View) {
+        self.journal.lock().unwrap().views.insert(root.to_string(), view);
+    }
+
+    /// Reports the keys of `root` that differ from its last view, and keeps `view` as the
+    /// new one. Keys missing from `view` are removed.
+    pub fn update_view(&self, root: &str, view: View) {
+        let mut journal = self.journal.lock().unwrap();
+        let old = journal.views.remove(root).unwrap_or_default();
+        for (key, value) in &view {
+            match old.get(key) {
+                None => journal.record(&self.tx, ChangeOp::Insert, key_in(root, key), Some(value.clone())),
+                Some(previous) if previous != value => journal.record(&self.tx, ChangeOp::Update, key_in(root, key), Some(value.clone())),
+                Some(_) => {}
+            }
+        }
+        for key in old.keys().filter(|key| !view.contains_key(*key)) {
+            journal.record(&self.tx, ChangeOp::Remove, key_in(root, key), None);
+        }
+        journal.views.insert(root.to_string(), view);
+    }
+
+    /// Reports a single key of `root`, `None` once removed.
+    pub fn update_key(&self, root: &str, key: &str, value: Option<Value>) {
+        let mut journal = self.journal.lock().unwrap();
+        let view = journal.views.entry(root.to_string()).or_default();
+        let op = match (&value, view.get(key)) {
+            (Some(value), Some(previous)) if value == previous => return,
+            (Some(_), Some(_)) => ChangeOp::Update,
+            (Some(_), None) => ChangeOp::Insert,
+            (None, Some(_)) => ChangeOp::Remove,
+            (None, None) => return,
+        };
+        match &value {
+            Some(value) => view.insert(key.to_string(), value.clone()),
+            None => view.remove(key),
+        };
+        journal.record(&self.tx, op, key_in(root, key), value);
+    }
+
+    /// Reports log entries added to the log.
+    pub fn log_inserted<'a>(&self, entries: impl IntoIterator<Item = &'a LogEntry>) {
+        let mut journal = self.journal.lock().unwrap();
+        for entry in entries {
+            let value = serde_json::to_value(entry).ok();
+            journal.record(&self.tx, ChangeOp::Insert, log_key(&entry.key()), value);
+        }
+    }
+
+    /// Reports log entries removed or compacted.
+    pub fn log_removed(&self, keys: impl IntoIterator<Item = EntryKey>) {
+        let mut journal = self.journal.lock().unwrap();
+        for key in keys {
+            journal.record(&self.tx, ChangeOp::Remove, log_key(&key), None);
+        }
+    }
+
+    /// Starts watching the keys starting with `prefix`, after the `resume` token if given.
+    pub fn subscribe(&self, prefix: &str, resume: Option<u64>) -> Subscription {
+        let journal = self.journal.lock().unwrap();
+        let mut pending = VecDeque::new();
+        let last = match resume {
+            Some(token) if token >= journal.floor && token <= journal.seq => {
+                pending.extend(journal.since(token).filter(|change| change.key.starts_with(prefix)).cloned().map(WatchItem::Change));
+                journal.seq
+            }
+            Some(_) => {
+                pending.push_back(WatchItem::Reset { seq: journal.seq });
+                journal.seq
+            }
+            None => journal.seq,
+        };
+        pending.push_back(WatchItem::Ready { seq: last });
+        // Subscribed under the lock, so no change falls between the journal and the channel
+        let rx = self.tx.subscribe();
+        Subscription { journal: self.journal.clone(), prefix: prefix.to_string(), last, pending, rx }
+    }
+}
+
+impl Journal {
+    fn record(&mut self, tx: &broadcast::Sender<Change>, op: ChangeOp, key: String, value: Option<Value>) {
+        self.seq += 1;
+        let change = Change { seq: self.seq, op, key, value };
+        let _ = tx.send(change.clone());
+        self.changes.push_back(change);
+        if self.changes.len() > JOURNAL_SIZE {
+            if let Some(oldest) = self.changes.pop_front() {
+                self.floor = oldest.seq;
+            }
+        }
+    }
+
+    fn since(&self, token: u64) -> impl Iterator<Item = &Change> {
+        self.changes.iter().skip_while(move |change| change.seq <= token)
+    }
+}
+
+/// A watcher's feed: pending items first, then live changes. A watcher that falls behind
+/// the channel catches up from the journal, or gets a `Reset` once it cannot.
+pub struct Subscription {
+    journal: Arc<Mutex<Journal>>,
+    prefix: String,
+    last: u64,
+    pending: VecDeque<WatchItem>,
+    rx: broadcast::Receiver<Change>,
+}
+
+impl Subscription {
+    /// The next item, or `None` once the node shuts down.
+    pub async fn next(&mut self) -> Option<WatchItem> {
+        loop {
+            if let Some(item) = self.pending.pop_front() {
+                return Some(item);
+            }
+            match self.rx.recv().await {
+                Ok(change) if change.seq <= self.last => {}
+                Ok(change) => {
+                    self.last = change.seq;
+                    if change.key.starts_with(&self.prefix) {
+                        return Some(WatchItem::Change(change));
+                    }
+                }
+                Err(broadcast::error::RecvError::Lagged(_)) => self.catch_up(),
+                Err(broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    }
+
+    fn catch_up(&mut self) {
+        let journal = self.journal.lock().unwrap();
+        if self.last < journal.floor {
+            self.pending.push_back(WatchItem::Reset { seq: journal.seq });
+        } else {
+            let prefix = &self.prefix;
+            self.pending.extend(journal.since(self.last).filter(|change| change.key.starts_with(prefix)).cloned().map(WatchItem::Change));
+        }
+        self.last = journal.seq;
+        // Everything up to here is in `pending`, so start the channel afresh
+        self.rx = self.rx.resubscribe();
+    }
+}
+
+fn key_in(root: &str, key: &str) -> String {
+    if key.is_empty() {
+        root.to_string()
+    } else {
+        format!("{}/{}", root, key)
+    }
+}
+
+/// Key of a log entry, `log/<author>/<id>`: ids are only unique per author. Peer IDs have
+/// no `/`, so everything after the author is the id.
+fn log_key(key: &EntryKey) -> String {
+    format!("log/{}/{}", key.author, key.id)
+}
+
+/// View of a collection's value: members of sets, keys of maps and the leaves of a
+/// document's objects (`a/b`). Counters and registers are a single key, the root itself.
+pub fn collection_view(kind: CollectionKind, value: &Value) -> View {
+    let mut view = View::new();
+    match (kind, value) {
+        (CollectionKind::Set | CollectionKind::OrSet, Value::Array(members)) => {
+            for member in members {
+                let key = member.as_str().map_or_else(|| member.to_string(), str::to_string);
+                view.insert(key, member.clone());
+            }
+        }
+        (CollectionKind::Map, Value::Object(map)) => {
+            view.extend(map.iter().map(|(key, value)| (key.clone(), value.clone())));
+        }
+        (CollectionKind::Document, Value::Object(_)) => flatten("", value, &mut view),
+        _ => {
+            view.insert(String::new(), value.clone());
+        }
+    }
+    view
+}
+
+fn flatten(path: &str, value: &Value, view: &mut View) {
+    match value {
+        Value::Object(fields) if !fields.is_empty() => {
+            for (field, value) in fields {
+                let path = if path.is_empty() { field.clone() } else { key_in(path, field) };
+                flatten(&path, value, view);
+            }
+        }
+        _ => {
+            view.insert(path.to_string(), value.clone());
+        }
+    }
+}
@@ -0,0 +1,85 @@
+mod common;
+
+use std::time::Duration;
+
+use common::{next_event, wait_for, Mesh};
+use ghostmesh::chaos::ChaosConfig;
+use ghostmesh::p2p::NodeCommand;
+use ghostmesh::telemetry::NetworkEvent;
+
+fn partition(mesh: &Mesh, groups: &[&[usize]]) -> ChaosConfig {
+    ChaosConfig {
+        partitions: groups
+            .iter()
+            .map(|group| group.iter().map(|&i| mesh[i].peer_id.to_string()).collect())
+            .collect(),
+        ..Default::default()
+    }
+}
+
+#[tokio::test]
+async fn partition_cuts_links_until_healed() {
+    let mesh = Mesh::spawn(3).await;
+    mesh.connect_all().await;
+
+    let split = partition(&mesh, &[&[0, 1], &[2]]);
+    for node in &mesh.nodes {
+        node.send(NodeCommand::SetChaos(split.clone()));
+    }
+    wait_for("partition", || !mesh[0].is_connected_to(&mesh[2]) && !mesh[1].is_connected_to(&mesh[2])).await;
+    assert!(mesh[0].is_connected_to(&mesh[1]));
+
+    // Redials across the partition are refused
+    mesh[2].send(NodeCommand::Dial(mesh[0].addr.clone()));
+    mesh[0].send(NodeCommand::Log("during split".into()));
+    wait_for("replication inside the partition", || mesh[1].log() == ["during split"]).await;
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    assert!(mesh[2].log().is_empty());
+
+    for node in &mesh.nodes {
+        node.send(NodeCommand::SetChaos(ChaosConfig::default()));
+    }
+    mesh.connect(2, 0).await;
+    wait_for("anti-entropy after heal", || mesh[2].log() == ["during split"]).await;
+    mesh[0].send(NodeCommand::Log("after heal".into()));
+    wait_for("replication after heal", || mesh[2].log() == ["after heal", "during split"]).await;
+    mesh.shutdown().await;
+}
+
+#[tokio::test]
+async fn dropped_messages_never_reach_the_node() {
+    let mesh = Mesh::spawn(2).await;
+    mesh.connect(0, 1).await;
+    let mut events = mesh[1].events();
+
+    mesh[1].send(NodeCommand::SetChaos(ChaosConfig { drop_percent: 100.0, ..Default::default() }));
+    mesh[0].send(NodeCommand::Chat("lost".into()));
+
+    let event = next_event(&mut events, |e| matches!(e, NetworkEvent::FaultInjected { .. })).await;
+    let NetworkEvent::FaultInjected { kind, peer_id } = event else { unreachable!() };
+    assert_eq!(kind, "drop");
+    assert_eq!(peer_id, Some(mesh[0].peer_id.to_string()));
+    assert!(mesh[1].state.chat.read().unwrap().is_empty());
+    mesh.shutdown().await;
+}
+
+#[tokio::test]
+async fn reset_closes_connections() {
+    let mesh = Mesh::spawn(2).await;
+    mesh.connect(0, 1).await;
+
+    mesh[0].send(NodeCommand::ResetConnections(Some(mesh[1].peer_id)));
+
+    wait_for("reset", || !mesh[0].is_connected_to(&mesh[1]) && !mesh[1].is_connected_to(&mesh[0])).await;
+    mesh.connect(1, 0).await;
+    mesh.shutdown().await;
+}
+
+#[test]
+fn config_validation_rejects_bad_values() {
+    let config = ChaosConfig { drop_percent: 150.0, ..Default::default() };
+    assert!(config.validate().is_err());
+    let config = ChaosConfig { partitions: vec![vec!["not-a-peer".into()]], ..Default::default() };
+    assert!(config.validate().is_err());
+    assert!(ChaosConfig::default().validate().is_ok());
+}
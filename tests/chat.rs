@@ -0,0 +1,69 @@
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::sync::atomic::Ordering;
+
+use ghostmesh::namespace::Namespace;
+use ghostmesh::state::{AppState, ChatMessage, MAX_CHAT_HISTORY};
+use ghostmesh::storage;
+
+fn namespace(dir: &tempfile::TempDir) -> Namespace {
+    Namespace::default().with_data_root(dir.path().to_str().unwrap())
+}
+
+fn message(i: usize) -> ChatMessage {
+    ChatMessage { from: "peer".into(), content: i.to_string(), timestamp: i as u64, topic: "ghostmesh-global".into() }
+}
+
+#[test]
+fn the_chat_history_is_appended_and_loaded_up_to_a_torn_line() {
+    let dir = tempfile::tempdir().unwrap();
+    let ns = namespace(&dir);
+    for i in 0..3 {
+        storage::append_chat(&ns, 9000, &message(i)).unwrap();
+    }
+    // A crash mid-append leaves half a line
+    let mut file = OpenOptions::new().append(true).open(storage::get_chat_path(&ns, 9000)).unwrap();
+    file.write_all(b"{\"from\":\"pe").unwrap();
+
+    let chat = storage::load_chat(&ns, 9000).unwrap();
+    assert_eq!(chat.iter().map(|m| m.content.as_str()).collect::<Vec<_>>(), ["0", "1", "2"]);
+}
+
+#[test]
+fn a_legacy_chat_file_is_converted_and_capped() {
+    let dir = tempfile::tempdir().unwrap();
+    let ns = namespace(&dir);
+    let legacy: Vec<ChatMessage> = (0..MAX_CHAT_HISTORY + 5).map(message).collect();
+    fs::create_dir_all(ns.data_dir()).unwrap();
+    let legacy_path = format!("{}/chat_9000.json", ns.data_dir());
+    fs::write(&legacy_path, serde_json::to_string_pretty(&legacy).unwrap()).unwrap();
+
+    let chat = storage::load_chat(&ns, 9000).unwrap();
+    assert_eq!(chat.len(), MAX_CHAT_HISTORY);
+    assert_eq!(chat[0].content, "5");
+    assert!(!Path::new(&legacy_path).exists());
+
+    // Reloaded from the converted JSON Lines file
+    assert_eq!(storage::load_chat(&ns, 9000).unwrap().len(), MAX_CHAT_HISTORY);
+}
+
+#[test]
+fn chat_cursors_stay_valid_after_a_trim() {
+    let dir = tempfile::tempdir().unwrap();
+    let state = AppState::new("local".into(), namespace(&dir));
+    *state.chat.write().unwrap() = (0..10).map(message).collect();
+
+    let page = state.chat_page(None, 4);
+    assert_eq!(page.total, 10);
+    assert_eq!(page.next_before, Some(6));
+
+    // The 3 oldest messages are trimmed, as the node does past the cap
+    state.chat.write().unwrap().drain(..3);
+    state.chat_dropped.fetch_add(3, Ordering::SeqCst);
+
+    let page = state.chat_page(page.next_before, 4);
+    assert_eq!(page.total, 10);
+    assert_eq!(page.messages.iter().map(|m| m.content.as_str()).collect::<Vec<_>>(), ["3", "4", "5"]);
+    assert_eq!(page.next_before, None);
+}
@@ -0,0 +1,167 @@
+mod common;
+
+use common::{next_event, wait_for, Mesh};
+use ghostmesh::chaos::ChaosConfig;
+use ghostmesh::entry::LogEntry;
+use ghostmesh::hlc::Hlc;
+use ghostmesh::p2p::NodeCommand;
+use ghostmesh::sync::{ChunkedTransfers, CrdtMessage, CHUNK_SIZE, MAX_INCOMING_PER_PEER};
+use ghostmesh::telemetry::NetworkEvent;
+use libp2p::identity::Keypair;
+use libp2p::PeerId;
+
+/// Entries of about 300 bytes once encoded, signed by a third peer.
+fn entries(count: usize) -> Vec<LogEntry> {
+    let keypair = Keypair::generate_ed25519();
+    (0..count).map(|i| LogEntry::text(&keypair, Hlc::new(1, i as u32), &format!("{:06} {}", i, "x".repeat(90)))).collect()
+}
+
+/// Encoded size of roughly `CHUNK_SIZE * chunks` bytes.
+fn large_delta(chunks: usize) -> CrdtMessage {
+    CrdtMessage::Delta { entries: entries(chunks * CHUNK_SIZE / 300) }
+}
+
+fn fill_log(node: &common::TestNode, count: usize) {
+    let mut log = node.state.log.write().unwrap();
+    for entry in entries(count) {
+        log.insert(entry);
+    }
+}
+
+fn receive_all(
+    transfers: &mut ChunkedTransfers,
+    from: PeerId,
+    chunks: impl IntoIterator<Item = CrdtMessage>,
+) -> Option<CrdtMessage> {
+    let mut whole = None;
+    for chunk in chunks {
+        let CrdtMessage::Chunk { transfer, index, total, hash, data, .. } = chunk else { panic!("not a chunk") };
+        if let Some(msg) = transfers.receive(from, transfer, index, total, &hash, &data).unwrap() {
+            whole = Some(msg);
+        }
+    }
+    whole
+}
+
+#[test]
+fn chunks_reassemble_in_any_order() {
+    let msg = large_delta(3);
+    let payload = serde_json::to_vec(&msg).unwrap();
+    let mut chunks = ChunkedTransfers::default().split(payload);
+    assert!(chunks.len() > 3);
+    chunks.reverse();
+
+    let whole = receive_all(&mut ChunkedTransfers::default(), PeerId::random(), chunks).expect("reassembled");
+    let (CrdtMessage::Delta { entries: got }, CrdtMessage::Delta { entries: sent }) = (whole, msg) else { panic!() };
+    assert_eq!(got, sent);
+}
+
+#[tokio::test]
+async fn stalled_transfers_request_only_missing_chunks() {
+    let (sender, mut receiver, from) = (&mut ChunkedTransfers::default(), ChunkedTransfers::default(), PeerId::random());
+    let mut chunks = sender.split(serde_json::to_vec(&large_delta(3)).unwrap());
+    let lost = chunks.remove(1);
+    assert!(receive_all(&mut receiver, from, chunks).is_none());
+
+    tokio::time::sleep(ghostmesh::sync::CHUNK_RETRY_INTERVAL).await;
+    let requests = receiver.stalled();
+    let [CrdtMessage::ChunkRequest { to, transfer, missing }] = requests.as_slice() else { panic!("{:?}", requests) };
+    assert_eq!(to, &from.to_string());
+    assert_eq!(missing, &[1]);
+
+    // The resent chunk is a distinct gossipsub message, but carries the same data
+    let resent = sender.resend(*transfer, missing);
+    let (CrdtMessage::Chunk { data, attempt, .. }, CrdtMessage::Chunk { data: lost_data, .. }) = (&resent[0], &lost) else {
+        panic!()
+    };
+    assert_eq!((data, *attempt), (lost_data, 1));
+    assert!(receive_all(&mut receiver, from, resent).is_some());
+    assert!(receiver.stalled().is_empty());
+}
+
+#[test]
+fn corrupted_transfers_are_rejected() {
+    let chunks = ChunkedTransfers::default().split(serde_json::to_vec(&large_delta(2)).unwrap());
+    let mut receiver = ChunkedTransfers::default();
+    let from = PeerId::random();
+    let last = chunks.len() - 1;
+    let mut result = Ok(None);
+    for (i, chunk) in chunks.into_iter().enumerate() {
+        let CrdtMessage::Chunk { transfer, index, total, hash, mut data, .. } = chunk else { panic!() };
+        if i == 0 {
+            data = data.replacen('A', "B", 1);
+        }
+        result = receiver.receive(from, transfer, index, total, &hash, &data);
+        if i < last {
+            assert!(matches!(result, Ok(None)));
+        }
+    }
+    assert!(result.unwrap_err().to_string().contains("integrity"));
+}
+
+#[test]
+fn incoming_transfers_are_kept_apart_and_capped_per_peer() {
+    let (mut receiver, alice, bob) = (ChunkedTransfers::default(), PeerId::random(), PeerId::random());
+    let first_chunk = |msg: &CrdtMessage| {
+        let mut chunks = ChunkedTransfers::default().split(serde_json::to_vec(msg).unwrap());
+        let CrdtMessage::Chunk { transfer, index, total, hash, data, .. } = chunks.swap_remove(0) else { panic!() };
+        (transfer, index, total, hash, data)
+    };
+    let mut pending = Vec::new();
+    for _ in 0..MAX_INCOMING_PER_PEER {
+        let (transfer, index, total, hash, data) = first_chunk(&large_delta(2));
+        assert!(matches!(receiver.receive(alice, transfer, index, total, &hash, &data), Ok(None)));
+        pending.push(transfer);
+    }
+    let (transfer, index, total, hash, data) = first_chunk(&large_delta(2));
+    let refused = receiver.receive(alice, transfer, index, total, &hash, &data).unwrap_err();
+    assert!(refused.to_string().contains("in progress"));
+
+    // Another peer is not held back, even reusing a transfer id of the first one
+    let chunks = ChunkedTransfers::default().split(serde_json::to_vec(&large_delta(2)).unwrap());
+    let chunks = chunks.into_iter().map(|chunk| match chunk {
+        CrdtMessage::Chunk { index, total, hash, data, attempt, .. } => CrdtMessage::Chunk { transfer: pending[0], index, total, hash, data, attempt },
+        other => other,
+    });
+    assert!(receive_all(&mut receiver, bob, chunks).is_some());
+}
+
+#[tokio::test]
+async fn large_logs_converge_through_chunks() {
+    let mesh = Mesh::spawn(2).await;
+    fill_log(&mesh[0], 1000);
+    let mut events = mesh[0].events();
+
+    mesh.connect(0, 1).await;
+    wait_for("large log", || mesh[1].state.log.read().unwrap().read().len() == 1000).await;
+    let event = next_event(&mut events, |e| matches!(e, NetworkEvent::SyncProgress { stage, .. } if stage == "complete")).await;
+    assert!(matches!(event, NetworkEvent::SyncProgress { .. }));
+    assert!(std::iter::from_fn(|| events.try_recv().ok()).all(|e| !matches!(e, NetworkEvent::PublishFailed { .. })));
+    mesh.shutdown().await;
+}
+
+#[tokio::test]
+async fn lost_chunks_are_requested_again() {
+    let mesh = Mesh::spawn(2).await;
+    mesh.connect(0, 1).await;
+    fill_log(&mesh[0], 1000);
+
+    mesh[1].send(NodeCommand::SetChaos(ChaosConfig { drop_percent: 20.0, ..Default::default() }));
+    mesh[1].lines.send("/sync".into()).unwrap();
+    wait_for("state despite dropped chunks", || mesh[1].state.log.read().unwrap().read().len() == 1000).await;
+    mesh.shutdown().await;
+}
+
+#[tokio::test]
+async fn oversized_publishes_are_reported() {
+    let mesh = Mesh::spawn(2).await;
+    mesh.connect(0, 1).await;
+    let mut events = mesh[0].events();
+
+    mesh[0].send(NodeCommand::Chat("z".repeat(100 * 1024)));
+    let event = next_event(&mut events, |e| matches!(e, NetworkEvent::PublishFailed { .. })).await;
+    let NetworkEvent::PublishFailed { topic, bytes, .. } = event else { unreachable!() };
+    assert_eq!(topic, "ghostmesh-global");
+    assert!(bytes > 100 * 1024);
+    mesh.shutdown().await;
+}
@@ -0,0 +1,242 @@
+mod common;
+
+use common::{next_event, wait_for, Mesh, TestNode};
+use ghostmesh::collection::{Collection, CollectionKind, CollectionUpdate};
+use ghostmesh::hlc::{self, Hlc, HybridClock};
+use ghostmesh::namespace::Namespace;
+use ghostmesh::p2p::NodeCommand;
+use ghostmesh::retention::CollectionRetention;
+use ghostmesh::storage;
+use ghostmesh::telemetry::NetworkEvent;
+use serde_json::{json, Value};
+
+fn add(member: &str) -> CollectionUpdate {
+    CollectionUpdate::Add { member: member.into() }
+}
+
+fn create(name: &str, kind: CollectionKind) -> NodeCommand {
+    NodeCommand::CreateCollection { name: name.into(), kind }
+}
+
+fn update(name: &str, update: CollectionUpdate) -> NodeCommand {
+    NodeCommand::UpdateCollection { name: name.into(), update }
+}
+
+fn value_of(node: &TestNode, name: &str) -> Option<Value> {
+    node.state.collections.read().unwrap().get(name).map(|c| c.read().unwrap().value())
+}
+
+#[test]
+fn or_set_keeps_concurrent_adds() {
+    let mut a = Collection::new(CollectionKind::OrSet);
+    let first = a.update("A", add("sala"), Hlc::new(1, 0)).unwrap().unwrap();
+    let mut b = Collection::new(CollectionKind::OrSet);
+    b.apply(first).unwrap();
+
+    let remove = a.update("A", CollectionUpdate::Remove { member: "sala".into() }, Hlc::new(2, 0)).unwrap().unwrap();
+    let readd = b.update("B", add("sala"), Hlc::new(3, 0)).unwrap().unwrap();
+    assert!(a.apply(readd).unwrap());
+    assert!(!b.apply(remove).unwrap());
+    assert_eq!(a.value(), json!(["sala"]));
+    assert_eq!(a.value(), b.value());
+    assert_eq!(a.digest(), b.digest());
+}
+
+#[test]
+fn every_kind_converges() {
+    let updates = [
+        (CollectionKind::Set, add("x"), add("y")),
+        (CollectionKind::Map, CollectionUpdate::Put { key: "k".into(), value: json!(1) }, CollectionUpdate::Put { key: "k".into(), value: json!(2) }),
+        (CollectionKind::Counter, CollectionUpdate::Increment { by: 5 }, CollectionUpdate::Increment { by: -2 }),
+        (CollectionKind::Register, CollectionUpdate::Set { value: json!("a") }, CollectionUpdate::Set { value: json!({"b": true}) }),
+    ];
+    let expected = [json!(["x", "y"]), json!({"k": 2}), json!(3), json!({"b": true})];
+    for ((kind, first, second), expected) in updates.into_iter().zip(expected) {
+        let mut a = Collection::new(kind);
+        let mut b = Collection::new(kind);
+        let op_a = a.update("A", first, Hlc::new(1, 0)).unwrap().unwrap();
+        let op_b = b.update("B", second, Hlc::new(2, 0)).unwrap().unwrap();
+        a.apply(op_b).unwrap();
+        b.apply(op_a).unwrap();
+        assert_eq!(a.value(), expected, "{:?}", kind);
+        assert_eq!(a.digest(), b.digest(), "{:?}", kind);
+    }
+}
+
+#[test]
+fn updates_must_fit_the_kind() {
+    let mut counter = Collection::new(CollectionKind::Counter);
+    assert!(counter.update("A", add("x"), Hlc::new(1, 0)).is_err());
+    assert!(!add("x").fits(CollectionKind::Counter));
+    assert!(CollectionUpdate::Remove { member: "x".into() }.fits(CollectionKind::OrSet));
+    assert!(!CollectionUpdate::Remove { member: "x".into() }.fits(CollectionKind::Set));
+    assert_eq!(CollectionKind::parse("or-set"), Some(CollectionKind::OrSet));
+    assert_eq!(CollectionKind::parse("list"), None);
+
+    // States of another kind are ignored
+    let mut set = Collection::new(CollectionKind::Set);
+    let mut other = Collection::new(CollectionKind::Register);
+    other.update("A", CollectionUpdate::Set { value: json!(1) }, Hlc::new(1, 0)).unwrap();
+    assert!(!set.merge(other));
+    assert_eq!(set.kind(), CollectionKind::Set);
+}
+
+#[test]
+fn registers_and_documents_refuse_writes_from_too_far_in_the_future() {
+    let future = Hlc::new(HybridClock::default().now().wall_ms + hlc::MAX_DRIFT_MS + 60_000, 0);
+    let set = CollectionUpdate::Set { value: json!("noite") };
+    let patch = CollectionUpdate::MergePatch { patch: json!({"modo": "noite"}) };
+    for (kind, update) in [(CollectionKind::Register, set), (CollectionKind::Document, patch)] {
+        let mut a = Collection::new(kind);
+        let op = a.update("A", update, future).unwrap().unwrap();
+        let mut b = Collection::new(kind);
+        assert!(!b.apply(op).unwrap(), "{:?}", kind);
+        assert!(!b.merge(a), "{:?}", kind);
+        assert_eq!(b, Collection::new(kind));
+    }
+}
+
+#[test]
+fn or_set_ops_after_a_gap_are_refused() {
+    let mut a = Collection::new(CollectionKind::OrSet);
+    let _missed = a.update("A", add("x"), Hlc::new(1, 0)).unwrap();
+    let next = a.update("A", add("y"), Hlc::new(2, 0)).unwrap().unwrap();
+
+    let mut b = Collection::new(CollectionKind::OrSet);
+    assert!(b.apply(next).is_err());
+    assert!(b.merge(a.clone()));
+    assert_eq!(b, a);
+}
+
+#[test]
+fn retention_deletes_the_oldest_keys_and_fields() {
+    let retention = CollectionRetention::parse("max-entries=2").unwrap();
+    let mut map = Collection::new(CollectionKind::Map);
+    for (i, key) in ["b", "a", "c"].into_iter().enumerate() {
+        map.update("A", CollectionUpdate::Put { key: key.into(), value: json!(i) }, Hlc::new(i as u64 + 1, 0)).unwrap();
+    }
+    // Rewriting "b" makes "a" the oldest
+    map.update("A", CollectionUpdate::Put { key: "b".into(), value: json!(9) }, Hlc::new(4, 0)).unwrap();
+    let mut other = map.clone();
+    let ops = map.trim("A", &retention, Hlc::new(5, 0)).unwrap();
+    assert_eq!(ops.len(), 1);
+    assert_eq!(map.value(), json!({"b": 9, "c": 2}));
+
+    // The deletions replicate like any other op
+    for op in ops {
+        other.apply(op).unwrap();
+    }
+    assert_eq!(other.value(), map.value());
+    assert!(map.trim("A", &retention, Hlc::new(6, 0)).unwrap().is_empty());
+
+    let mut document = Collection::new(CollectionKind::Document);
+    document.update("A", CollectionUpdate::MergePatch { patch: json!({"x/y": "long", "z": 1}) }, Hlc::new(1, 0)).unwrap();
+    document.update("A", CollectionUpdate::MergePatch { patch: json!({"w": 2}) }, Hlc::new(10, 0)).unwrap();
+    let within = CollectionRetention { max_entries: None, max_bytes: Some(r#"{"w":2,"z":1}"#.len()) };
+    document.trim("A", &within, Hlc::new(11, 0)).unwrap();
+    assert_eq!(document.value(), json!({"w": 2, "z": 1}));
+}
+
+#[test]
+fn full_sets_refuse_new_members() {
+    let retention = CollectionRetention { max_entries: Some(2), max_bytes: None };
+    for kind in [CollectionKind::Set, CollectionKind::OrSet] {
+        let mut set = Collection::new(kind);
+        set.update("A", add("x"), Hlc::new(1, 0)).unwrap();
+        set.update("A", add("y"), Hlc::new(2, 0)).unwrap();
+        assert!(set.admits(&add("y"), &retention).is_ok(), "{:?}", kind);
+        assert!(set.admits(&add("z"), &retention).is_err(), "{:?}", kind);
+        assert!(set.trim("A", &retention, Hlc::new(3, 0)).unwrap().is_empty());
+    }
+    let bytes = CollectionRetention { max_entries: None, max_bytes: Some(r#"["x","y"]"#.len()) };
+    let mut set = Collection::new(CollectionKind::Set);
+    set.update("A", add("x"), Hlc::new(1, 0)).unwrap();
+    assert!(set.admits(&add("y"), &bytes).is_ok());
+    assert!(set.admits(&add("yz"), &bytes).is_err());
+
+    assert_eq!(CollectionRetention::parse("max-entries=10,max-bytes=2k").unwrap(), CollectionRetention { max_entries: Some(10), max_bytes: Some(2048) });
+    assert!(CollectionRetention::parse("max-entries=0").is_err());
+    assert!(CollectionRetention::parse("max-age=1d").is_err());
+}
+
+#[test]
+fn collections_persist_individually() {
+    let dir = tempfile::tempdir().unwrap();
+    let ns = Namespace::default().with_data_root(dir.path().to_str().unwrap());
+    assert!(storage::load_collections(&ns, 9000).unwrap().is_empty());
+
+    let mut rooms = Collection::new(CollectionKind::OrSet);
+    rooms.update("A", add("sala"), Hlc::new(1, 0)).unwrap();
+    let mut mode = Collection::new(CollectionKind::Register);
+    mode.update("A", CollectionUpdate::Set { value: json!("noite") }, Hlc::new(2, 0)).unwrap();
+    storage::save_collection(&ns, 9000, "rooms", &rooms).unwrap();
+    storage::save_collection(&ns, 9000, "mode", &mode).unwrap();
+
+    let loaded = storage::load_collections(&ns, 9000).unwrap();
+    assert_eq!(loaded.get("rooms"), Some(&rooms));
+    assert_eq!(loaded.get("mode"), Some(&mode));
+
+    storage::delete_collection(&ns, 9000, "rooms").unwrap();
+    assert_eq!(storage::load_collections(&ns, 9000).unwrap().into_keys().collect::<Vec<_>>(), ["mode"]);
+}
+
+#[tokio::test]
+async fn collections_replicate_between_nodes_that_open_them() {
+    let mesh = Mesh::spawn(3).await;
+    mesh.connect_all().await;
+    mesh[0].send(create("rooms", CollectionKind::OrSet));
+    mesh[1].send(create("rooms", CollectionKind::OrSet));
+    wait_for("created", || value_of(&mesh[1], "rooms").is_some()).await;
+
+    let mut events = mesh[1].events();
+    mesh[0].send(update("rooms", add("sala")));
+    let event = next_event(&mut events, |e| matches!(e, NetworkEvent::CollectionChanged { .. })).await;
+    assert!(matches!(event, NetworkEvent::CollectionChanged { ref name, kind: CollectionKind::OrSet, value: Some(ref v) }
+        if name == "rooms" && *v == json!(["sala"])));
+
+    // Listed in the state of the nodes that opened it only
+    let snapshot = mesh[1].state.snapshot();
+    assert_eq!(snapshot.collections.get("rooms"), Some(&CollectionKind::OrSet));
+    assert!(mesh[2].state.snapshot().collections.is_empty());
+
+    // Dropping leaves the topic: later updates no longer arrive
+    mesh[1].send(NodeCommand::DropCollection("rooms".into()));
+    wait_for("dropped", || value_of(&mesh[1], "rooms").is_none()).await;
+    mesh[0].send(update("rooms", add("cozinha")));
+    wait_for("local update", || value_of(&mesh[0], "rooms") == Some(json!(["cozinha", "sala"]))).await;
+    assert!(value_of(&mesh[1], "rooms").is_none());
+    mesh.shutdown().await;
+}
+
+#[tokio::test]
+async fn nodes_opening_a_collection_later_catch_up() {
+    let mesh = Mesh::spawn(2).await;
+    mesh.connect(0, 1).await;
+    mesh[0].send(create("visitas", CollectionKind::Counter));
+    mesh[0].send(update("visitas", CollectionUpdate::Increment { by: 7 }));
+    wait_for("local update", || value_of(&mesh[0], "visitas") == Some(json!(7))).await;
+
+    mesh[1].send(create("visitas", CollectionKind::Counter));
+    wait_for("catch up", || value_of(&mesh[1], "visitas") == Some(json!(7))).await;
+    mesh.shutdown().await;
+}
+
+#[tokio::test]
+async fn trimmed_keys_are_deleted_on_every_node() {
+    let mesh = Mesh::spawn(2).await;
+    mesh.connect(0, 1).await;
+    mesh[0].send(create("leituras", CollectionKind::Map));
+    mesh[1].send(create("leituras", CollectionKind::Map));
+    wait_for("created", || value_of(&mesh[1], "leituras").is_some()).await;
+    let retention = CollectionRetention { max_entries: Some(2), max_bytes: None };
+    mesh[0].send(NodeCommand::SetCollectionRetention { name: "leituras".into(), retention });
+
+    // Node 1 has no limits, node 0 trims what it receives and the deletions come back
+    for (i, key) in ["t1", "t2", "t3"].into_iter().enumerate() {
+        mesh[1].send(update("leituras", CollectionUpdate::Put { key: key.into(), value: json!(i) }));
+    }
+    let expected = Some(json!({"t2": 1, "t3": 2}));
+    wait_for("trimmed", || value_of(&mesh[0], "leituras") == expected && value_of(&mesh[1], "leituras") == expected).await;
+    assert_eq!(mesh[0].state.collection_retention.read().unwrap().get("leituras"), Some(&retention));
+    mesh.shutdown().await;
+}
@@ -0,0 +1,177 @@
+//! In-process mesh harness: nodes run on the libp2p memory transport with discovery
+//! injected through `NodeCommand::Dial`, so tests never touch the network.
+
+#![allow(dead_code)]
+
+use std::future::Future;
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::time::Duration;
+
+use ghostmesh::namespace::Namespace;
+use ghostmesh::p2p::{self, NodeCommand, NodeIo, NodeOptions, TransportKind};
+use ghostmesh::state::AppState;
+use ghostmesh::telemetry::NetworkEvent;
+use libp2p::{identity, Multiaddr, PeerId};
+use tokio::sync::{broadcast, mpsc, watch};
+use tokio::task::JoinHandle;
+
+pub const TIMEOUT: Duration = Duration::from_secs(20);
+
+/// Memory transport ports are process-wide, so every node of every test gets its own.
+static NEXT_PORT: AtomicU16 = AtomicU16::new(1);
+
+pub struct TestNode {
+    pub peer_id: PeerId,
+    pub addr: Multiaddr,
+    pub state: AppState,
+    pub commands: mpsc::UnboundedSender<NodeCommand>,
+    pub lines: mpsc::UnboundedSender<String>,
+    task: JoinHandle<anyhow::Result<()>>,
+}
+
+impl TestNode {
+    pub fn send(&self, cmd: NodeCommand) {
+        self.commands.send(cmd).expect("node stopped");
+    }
+
+    pub fn is_connected_to(&self, other: &TestNode) -> bool {
+        self.state.peers.read().unwrap().contains(&other.peer_id)
+    }
+
+    /// Text of the log entries, sorted.
+    pub fn log(&self) -> Vec<String> {
+        let mut texts: Vec<String> = self.state.log.read().unwrap().read().iter().map(|e| e.text_body()).collect();
+        texts.sort();
+        texts
+    }
+
+    pub fn events(&self) -> broadcast::Receiver<NetworkEvent> {
+        self.state.telemetry_tx.subscribe()
+    }
+}
+
+pub struct Mesh {
+    pub nodes: Vec<TestNode>,
+    shutdown: watch::Sender<bool>,
+    _data: tempfile::TempDir,
+}
+
+impl Mesh {
+    /// Starts `n` unconnected nodes in the default namespace.
+    pub async fn spawn(n: usize) -> Self {
+        Self::spawn_in(vec![Namespace::default(); n]).await
+    }
+
+    /// Starts one unconnected node per namespace, all storing under a fresh temp dir.
+    pub async fn spawn_in(namespaces: Vec<Namespace>) -> Self {
+        Self::spawn_with(namespaces.into_iter().map(|namespace| NodeOptions { namespace, ..Default::default() }).collect())
+            .await
+    }
+
+    /// Starts one unconnected node per set of options on the memory transport, all storing
+    /// under a fresh temp dir.
+    pub async fn spawn_with(options: Vec<NodeOptions>) -> Self {
+        let data = tempfile::tempdir().expect("temp dir");
+        let (shutdown, shutdown_rx) = watch::channel(false);
+        let mut nodes = Vec::new();
+
+        for options in options {
+            let namespace = options.namespace.clone().with_data_root(data.path().to_string_lossy());
+            let port = NEXT_PORT.fetch_add(1, Ordering::Relaxed);
+            let id_keys = identity::Keypair::generate_ed25519();
+            let peer_id = id_keys.public().to_peer_id();
+            let state = AppState::new(peer_id.to_string(), namespace.clone());
+
+            let (lines, line_rx) = mpsc::unbounded_channel();
+            let io = NodeIo::new(line_rx, shutdown_rx.clone());
+            let commands = io.command_tx.clone();
+            let options = NodeOptions {
+                namespace,
+                transport: TransportKind::Memory,
+                web_port: None,
+                ..options
+            };
+            let task = tokio::spawn(p2p::run_node(port, id_keys, options, state.clone(), io));
+
+            wait_for("listen address", || !state.listen_addrs.read().unwrap().is_empty()).await;
+            let addr: Multiaddr = state.listen_addrs.read().unwrap()[0].parse().unwrap();
+            let addr = addr.with(libp2p::multiaddr::Protocol::P2p(peer_id));
+
+            nodes.push(TestNode { peer_id, addr, state, commands, lines, task });
+        }
+
+        Self { nodes, shutdown, _data: data }
+    }
+
+    /// Has node `a` dial node `b` and waits until both have identified each other,
+    /// by which point gossipsub subscriptions have been exchanged.
+    pub async fn connect(&self, a: usize, b: usize) {
+        let (na, nb) = (&self.nodes[a], &self.nodes[b]);
+        na.send(NodeCommand::Dial(nb.addr.clone()));
+        wait_for("identify exchange", || {
+            na.state.versions.read().unwrap().contains_key(&nb.peer_id)
+                && nb.state.versions.read().unwrap().contains_key(&na.peer_id)
+        })
+        .await;
+        // Subscriptions travel on their own gossipsub stream; give them a moment to land
+        tokio::time::sleep(Duration::from_millis(200)).await;
+    }
+
+    pub async fn connect_all(&self) {
+        for a in 0..self.nodes.len() {
+            for b in a + 1..self.nodes.len() {
+                self.connect(a, b).await;
+            }
+        }
+    }
+
+    /// Gracefully stops every node and checks that none of them failed.
+    pub async fn shutdown(self) {
+        let _ = self.shutdown.send(true);
+        for node in self.nodes {
+            node.task.await.expect("node panicked").expect("node failed");
+        }
+    }
+}
+
+impl std::ops::Index<usize> for Mesh {
+    type Output = TestNode;
+
+    fn index(&self, i: usize) -> &TestNode {
+        &self.nodes[i]
+    }
+}
+
+/// Polls `condition` until it holds, panicking after `TIMEOUT`.
+pub async fn wait_for(what: &str, mut condition: impl FnMut() -> bool) {
+    let deadline = tokio::time::Instant::now() + TIMEOUT;
+    while !condition() {
+        if tokio::time::Instant::now() > deadline {
+            panic!("timed out waiting for {}", what);
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+}
+
+/// Waits for the first telemetry event matching `pred`, skipping the others.
+pub async fn next_event(
+    events: &mut broadcast::Receiver<NetworkEvent>,
+    mut pred: impl FnMut(&NetworkEvent) -> bool,
+) -> NetworkEvent {
+    with_timeout("telemetry event", async {
+        loop {
+            match events.recv().await {
+                Ok(event) if pred(&event) => return event,
+                Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => panic!("telemetry channel closed"),
+            }
+        }
+    })
+    .await
+}
+
+pub async fn with_timeout<T>(what: &str, fut: impl Future<Output = T>) -> T {
+    tokio::time::timeout(TIMEOUT, fut)
+        .await
+        .unwrap_or_else(|_| panic!("timed out waiting for {}", what))
+}
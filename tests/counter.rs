@@ -0,0 +1,87 @@
+mod common;
+
+use common::{next_event, wait_for, Mesh};
+use ghostmesh::counter::{CounterChange, Counters};
+use ghostmesh::namespace::Namespace;
+use ghostmesh::p2p::NodeCommand;
+use ghostmesh::storage;
+use ghostmesh::telemetry::NetworkEvent;
+
+fn add(name: &str, amount: i64) -> NodeCommand {
+    NodeCommand::CounterAdd { name: name.into(), amount }
+}
+
+#[test]
+fn concurrent_updates_add_up() {
+    let mut a = Counters::new();
+    let mut b = Counters::new();
+    let op_a = a.add("A", "visitas", 3).unwrap();
+    let op_b = b.add("B", "visitas", -1).unwrap();
+
+    assert_eq!(a.apply(op_b), [CounterChange { name: "visitas".into(), value: 2 }]);
+    b.apply(op_a);
+    assert_eq!(a, b);
+    assert_eq!(a.get("visitas"), Some(2));
+    assert_eq!(a.digest(), b.digest());
+    assert!(a.add("A", "visitas", 0).is_none());
+    assert_eq!(a.get("outro"), None);
+}
+
+#[test]
+fn ops_are_idempotent_and_order_free() {
+    let mut a = Counters::new();
+    let first = a.add("A", "pessoas", 5).unwrap();
+    let second = a.add("A", "pessoas", -2).unwrap();
+    let third = a.add("A", "pessoas", 4).unwrap();
+
+    let mut b = Counters::new();
+    b.apply(third.clone());
+    b.apply(first);
+    b.apply(third);
+    b.apply(second);
+    assert_eq!(b.get("pessoas"), Some(7));
+    assert_eq!(a, b);
+}
+
+#[test]
+fn counters_persist() {
+    let dir = tempfile::tempdir().unwrap();
+    let ns = Namespace::default().with_data_root(dir.path().to_str().unwrap());
+    assert_eq!(storage::load_counters(&ns, 9000).unwrap(), Counters::new());
+
+    let mut counters = Counters::new();
+    counters.add("A", "entradas", 10);
+    counters.add("B", "entradas", -3);
+    storage::save_counters(&ns, 9000, &counters).unwrap();
+    let loaded = storage::load_counters(&ns, 9000).unwrap();
+    assert_eq!(loaded, counters);
+    assert_eq!(loaded.all().get("entradas"), Some(&7));
+}
+
+#[tokio::test]
+async fn counters_replicate() {
+    let mesh = Mesh::spawn(2).await;
+    mesh.connect(0, 1).await;
+    let mut events = mesh[1].events();
+    mesh[0].send(add("alertas", 2));
+    let event = next_event(&mut events, |e| matches!(e, NetworkEvent::CounterChanged { .. })).await;
+    assert!(matches!(event, NetworkEvent::CounterChanged { ref name, value: 2 } if name == "alertas"));
+
+    mesh[1].send(add("alertas", -5));
+    wait_for("reply", || mesh[0].state.counters.read().unwrap().get("alertas") == Some(-3)).await;
+    mesh.shutdown().await;
+}
+
+#[tokio::test]
+async fn late_joiners_catch_up_through_digests() {
+    let mesh = Mesh::spawn(2).await;
+    mesh[0].send(add("leituras", 4));
+    mesh[1].send(add("leituras", 6));
+    wait_for("local updates", || mesh[1].state.counters.read().unwrap().get("leituras") == Some(6)).await;
+
+    mesh.connect(0, 1).await;
+    for node in [0, 1] {
+        wait_for("catch up", || mesh[node].state.counters.read().unwrap().get("leituras") == Some(10)).await;
+    }
+    mesh.shutdown().await;
+}
@@ -0,0 +1,154 @@
+mod common;
+
+use common::{wait_for, Mesh, TestNode};
+use ghostmesh::collection::{CollectionKind, CollectionUpdate};
+use ghostmesh::document::{DocOp, Document};
+use ghostmesh::hlc::Hlc;
+use ghostmesh::p2p::NodeCommand;
+use serde_json::{json, Value};
+
+fn apply_all(doc: &mut Document, ops: Vec<DocOp>) {
+    for op in ops {
+        doc.apply(op).unwrap();
+    }
+}
+
+/// Two replicas of `base`, as written by peer A.
+fn replicas(base: Value) -> (Document, Document) {
+    let mut a = Document::new();
+    let ops = a.merge_patch("A", &base, Hlc::new(1, 0)).unwrap();
+    let mut b = Document::new();
+    apply_all(&mut b, ops);
+    (a, b)
+}
+
+fn document_of(node: &TestNode, name: &str) -> Option<Value> {
+    node.state.collections.read().unwrap().get(name).map(|c| c.read().unwrap().value())
+}
+
+#[test]
+fn concurrent_field_edits_merge() {
+    let (mut a, mut b) = replicas(json!({"sala": {"luz": "OFF", "temp": 20}}));
+    let ops_a = a.merge_patch("A", &json!({"sala": {"luz": "ON"}}), Hlc::new(2, 0)).unwrap();
+    let ops_b = b.json_patch("B", &json!([{"op": "replace", "path": "/sala/temp", "value": 22}]), Hlc::new(2, 0)).unwrap();
+    apply_all(&mut a, ops_b);
+    apply_all(&mut b, ops_a);
+    assert_eq!(a.value(), json!({"sala": {"luz": "ON", "temp": 22}}));
+    assert_eq!(a, b);
+
+    // The same field keeps the latest write
+    let ops_a = a.merge_patch("A", &json!({"modo": "dia"}), Hlc::new(3, 0)).unwrap();
+    let ops_b = b.merge_patch("B", &json!({"modo": "noite"}), Hlc::new(4, 0)).unwrap();
+    apply_all(&mut a, ops_b);
+    apply_all(&mut b, ops_a);
+    assert_eq!(a.get("/modo"), Some(json!("noite")));
+    assert_eq!(a, b);
+}
+
+#[test]
+fn concurrent_list_inserts_keep_both() {
+    let (mut a, mut b) = replicas(json!({"fila": ["x", "z"]}));
+    let ops_a = a.json_patch("A", &json!([{"op": "add", "path": "/fila/1", "value": "a"}]), Hlc::new(2, 0)).unwrap();
+    let ops_b = b.json_patch("B", &json!([{"op": "add", "path": "/fila/1", "value": "b"}, {"op": "add", "path": "/fila/-", "value": "fim"}]), Hlc::new(2, 0)).unwrap();
+    apply_all(&mut a, ops_b);
+    apply_all(&mut b, ops_a);
+    assert_eq!(a.get("/fila"), Some(json!(["x", "a", "b", "z", "fim"])));
+    assert_eq!(a, b);
+}
+
+#[test]
+fn json_patch_operations() {
+    let mut doc = Document::new();
+    doc.merge_patch("A", &json!({"a": {"b": 1}, "lista": [1, 2, 3]}), Hlc::new(1, 0)).unwrap();
+    let patch = json!([
+        {"op": "test", "path": "/a/b", "value": 1},
+        {"op": "add", "path": "/a/c", "value": [true]},
+        {"op": "remove", "path": "/lista/0"},
+        {"op": "move", "from": "/a/b", "path": "/b"},
+        {"op": "copy", "from": "/a/c", "path": "/lista/0"},
+        {"op": "replace", "path": "/lista/2", "value": {"x": null}},
+    ]);
+    doc.json_patch("A", &patch, Hlc::new(2, 0)).unwrap();
+    assert_eq!(doc.value(), json!({"a": {"c": [true]}, "b": 1, "lista": [[true], 2, {"x": null}]}));
+
+    // A failing patch changes nothing
+    let before = doc.clone();
+    let failing = json!([{"op": "remove", "path": "/b"}, {"op": "test", "path": "/a/c/0", "value": false}]);
+    assert!(doc.json_patch("A", &failing, Hlc::new(3, 0)).is_err());
+    assert!(doc.json_patch("A", &json!([{"op": "replace", "path": "/nada", "value": 1}]), Hlc::new(3, 0)).is_err());
+    assert!(doc.json_patch("A", &json!([{"op": "add", "path": "/lista/9", "value": 1}]), Hlc::new(3, 0)).is_err());
+    assert_eq!(doc, before);
+}
+
+#[test]
+fn each_op_of_a_patch_takes_its_own_tick() {
+    let mut doc = Document::new();
+    let patch = json!([
+        {"op": "add", "path": "/fila", "value": []},
+        {"op": "add", "path": "/fila/-", "value": "a"},
+        {"op": "add", "path": "/fila/-", "value": "b"},
+    ]);
+    let ops = doc.json_patch("A", &patch, Hlc::new(5, u32::MAX)).unwrap();
+    let markers: Vec<Hlc> = ops.iter().map(DocOp::marker).collect();
+    assert_eq!(markers, [Hlc::new(5, u32::MAX), Hlc::new(6, 0), Hlc::new(6, 1)]);
+    assert_eq!(doc.get("/fila"), Some(json!(["a", "b"])));
+}
+
+#[test]
+fn merge_patch_follows_rfc_7396() {
+    let mut doc = Document::new();
+    doc.merge_patch("A", &json!({"title": "Olá", "author": {"given": "Ana", "family": "Silva"}, "tags": ["a"]}), Hlc::new(1, 0))
+        .unwrap();
+    let patch = json!({"title": "Oi", "author": {"family": null}, "tags": ["b"], "novo": {"x": 1, "y": null}});
+    doc.merge_patch("A", &patch, Hlc::new(2, 0)).unwrap();
+    assert_eq!(doc.value(), json!({"title": "Oi", "author": {"given": "Ana"}, "tags": ["b"], "novo": {"x": 1}}));
+    assert!(doc.merge_patch("A", &json!([1]), Hlc::new(3, 0)).is_err());
+}
+
+#[test]
+fn replacing_a_subtree_wins_over_edits_inside_it() {
+    let (mut a, mut b) = replicas(json!({"config": {"nivel": 1}}));
+    let edit = a.merge_patch("A", &json!({"config": {"nivel": 2}}), Hlc::new(2, 0)).unwrap();
+    let replace = b.json_patch("B", &json!([{"op": "replace", "path": "/config", "value": {"outro": true}}]), Hlc::new(3, 0)).unwrap();
+    apply_all(&mut a, replace);
+    apply_all(&mut b, edit);
+    assert_eq!(a.get("/config"), Some(json!({"outro": true})));
+    assert_eq!(a, b);
+}
+
+#[test]
+fn ops_inside_unseen_objects_are_refused() {
+    let mut a = Document::new();
+    let _missed = a.merge_patch("A", &json!({"sala": {}}), Hlc::new(1, 0)).unwrap();
+    let next = a.merge_patch("A", &json!({"sala": {"luz": "ON"}}), Hlc::new(2, 0)).unwrap();
+
+    let mut b = Document::new();
+    assert!(b.apply(next[0].clone()).is_err());
+    assert!(b.merge(a.clone()));
+    assert_eq!(b, a);
+}
+
+#[tokio::test]
+async fn documents_converge_across_nodes() {
+    let mesh = Mesh::spawn(2).await;
+    mesh.connect(0, 1).await;
+    for node in [0, 1] {
+        mesh[node].send(NodeCommand::CreateCollection { name: "casa".into(), kind: CollectionKind::Document });
+    }
+    wait_for("created", || document_of(&mesh[0], "casa").is_some() && document_of(&mesh[1], "casa").is_some()).await;
+
+    let patch = |patch: Value| NodeCommand::UpdateCollection { name: "casa".into(), update: CollectionUpdate::MergePatch { patch } };
+    mesh[0].send(patch(json!({"sala": {"luz": "ON"}, "alarmes": []})));
+    wait_for("first patch", || document_of(&mesh[1], "casa") == Some(json!({"sala": {"luz": "ON"}, "alarmes": []}))).await;
+    mesh[0].send(patch(json!({"sala": {"temp": 21}})));
+    mesh[1].send(NodeCommand::UpdateCollection {
+        name: "casa".into(),
+        update: CollectionUpdate::JsonPatch { patch: json!([{"op": "add", "path": "/alarmes/-", "value": "porta"}]) },
+    });
+
+    let expected = json!({"sala": {"luz": "ON", "temp": 21}, "alarmes": ["porta"]});
+    for node in [0, 1] {
+        wait_for("converged", || document_of(&mesh[node], "casa") == Some(expected.clone())).await;
+    }
+    mesh.shutdown().await;
+}
@@ -0,0 +1,176 @@
+mod common;
+
+use std::collections::HashSet;
+
+use common::{wait_for, Mesh};
+use ghostmesh::entry::{self, LogEntry, Tombstone};
+use ghostmesh::hlc::{HybridClock, Hlc, MAX_DRIFT_MS};
+use ghostmesh::logset::LogSet;
+use ghostmesh::namespace::Namespace;
+use ghostmesh::p2p::NodeCommand;
+use ghostmesh::storage;
+use ghostmesh::wal::{LogStore, SyncPolicy};
+use libp2p::identity::Keypair;
+use serde_json::json;
+
+#[test]
+fn entries_verify_against_the_author_id() {
+    let keypair = Keypair::generate_ed25519();
+    let entry = LogEntry::new(&keypair, Hlc::new(5, 0), vec!["alarm".into()], json!({"zone": 3, "armed": true}));
+    assert_eq!(entry.author, keypair.public().to_peer_id().to_string());
+    assert!(entry.verify().is_ok());
+
+    let mut tampered = entry.clone();
+    tampered.body = json!({"zone": 4, "armed": true});
+    assert!(tampered.verify().is_err());
+
+    let mut impersonated = entry;
+    impersonated.author = Keypair::generate_ed25519().public().to_peer_id().to_string();
+    assert!(impersonated.verify().is_err());
+}
+
+#[test]
+fn identical_texts_stay_distinct() {
+    let keypair = Keypair::generate_ed25519();
+    let mut clock = HybridClock::default();
+    let first = LogEntry::text(&keypair, clock.now(), "ok");
+    let second = LogEntry::text(&keypair, clock.now(), "ok");
+    assert_ne!(first.id, second.id);
+    assert!(first < second);
+}
+
+#[test]
+fn clock_moves_past_observed_timestamps() {
+    let mut clock = HybridClock::default();
+    let local = clock.now();
+    let ahead = Hlc::new(local.wall_ms + 60_000, 7);
+    clock.observe(ahead);
+    let next = clock.now();
+    assert!(next > ahead && next > local);
+}
+
+#[test]
+fn clock_ignores_timestamps_beyond_the_drift() {
+    let mut clock = HybridClock::default();
+    let local = clock.now();
+    let far = Hlc::new(local.wall_ms + MAX_DRIFT_MS + 10_000, 0);
+    assert!(!clock.observe(far));
+    assert!(clock.now().wall_ms < far.wall_ms);
+}
+
+#[test]
+fn exhausted_counters_move_to_the_next_millisecond() {
+    let mut clock = HybridClock::default();
+    let local = clock.now();
+    let last = Hlc::new(local.wall_ms + 30_000, u32::MAX);
+    assert!(clock.observe(last));
+    let next = clock.now();
+    assert!(next > last);
+    assert_eq!(next, Hlc::new(last.wall_ms + 1, 1));
+}
+
+#[test]
+fn legacy_storage_is_migrated_once() {
+    let dir = tempfile::tempdir().unwrap();
+    let ns = Namespace::default().with_data_root(dir.path().to_str().unwrap());
+    let path = storage::get_storage_path(&ns, 9000);
+    std::fs::write(&path, r#"{"value": ["/dm 12D3KooWFF4nGH hi", "Jads", "ok"]}"#).unwrap();
+
+    let keypair = Keypair::generate_ed25519();
+    let (_, log) = LogStore::open(&ns, 9000, SyncPolicy::default(), &keypair).unwrap();
+    let entries = log.read();
+    let mut texts: Vec<String> = entries.iter().map(LogEntry::text_body).collect();
+    texts.sort();
+    assert_eq!(texts, ["Jads", "ok"]);
+    let author = keypair.public().to_peer_id().to_string();
+    assert!(entries.iter().all(|e| e.verify().is_ok() && e.tags == ["migrated"] && e.author == author));
+    assert!(std::path::Path::new(&format!("{}.v1", path)).exists());
+    assert!(std::path::Path::new(&format!("{}.bak", path)).exists() && !std::path::Path::new(&path).exists());
+
+    // The migrated log loads as is, and another node migrates a line under the same id
+    let (_, reloaded) = LogStore::open(&ns, 9000, SyncPolicy::default(), &Keypair::generate_ed25519()).unwrap();
+    assert_eq!(reloaded.read(), entries);
+    let other = storage::migrate_legacy_log(&serde_json::from_str(r#"{"value": ["ok"]}"#).unwrap(), &Keypair::generate_ed25519());
+    let copy = other.iter().next().unwrap();
+    assert!(entries.iter().any(|e| e.id == copy.id && e.timestamp == copy.timestamp));
+}
+
+#[test]
+fn migrated_entries_have_one_canonical_form() {
+    let keypair = Keypair::generate_ed25519();
+    let migrated = LogEntry::legacy(&keypair, "ok");
+    assert!(migrated.verify().is_ok());
+
+    // The id and timestamp must be the ones derived from the text
+    let restamped = LogEntry::with_id(&keypair, migrated.id.clone(), Hlc::new(1_000, 0), migrated.tags.clone(), "ok".into());
+    assert!(restamped.verify().is_err());
+    let reworded = LogEntry::with_id(&keypair, migrated.id.clone(), migrated.timestamp, migrated.tags.clone(), "no".into());
+    assert!(reworded.verify().is_err());
+
+    // Anyone can sign with the legacy key of earlier versions, so nothing it signed is accepted
+    assert!(LogEntry::legacy(&entry::legacy_keypair(), "ok").verify().is_err());
+    assert!(LogEntry::text(&entry::legacy_keypair(), Hlc::new(1, 0), "ok").verify().is_err());
+
+    // The migrating node may remove its copy, like any entry it wrote
+    assert!(Tombstone::new(&keypair, &migrated, Hlc::new(2, 0)).permitted(&HashSet::new()));
+    assert!(!Tombstone::new(&Keypair::generate_ed25519(), &migrated, Hlc::new(2, 0)).permitted(&HashSet::new()));
+}
+
+#[test]
+fn copies_of_a_migrated_line_are_kept_once() {
+    let copies = [LogEntry::legacy(&Keypair::generate_ed25519(), "ok"), LogEntry::legacy(&Keypair::generate_ed25519(), "ok")];
+    let lowest = copies.iter().min_by(|a, b| a.author.cmp(&b.author)).unwrap().clone();
+    for order in [[0, 1], [1, 0]] {
+        let mut log = LogSet::new();
+        for i in order {
+            log.insert(copies[i].clone());
+        }
+        assert_eq!(log.iter().collect::<Vec<_>>(), [&lowest]);
+    }
+}
+
+#[tokio::test]
+async fn nodes_migrating_the_same_lines_merge_them_once() {
+    let mesh = Mesh::spawn(2).await;
+    let files = [r#"{"value": ["porta aberta", "luz acesa"]}"#, r#"{"value": ["luz acesa", "alarme"]}"#];
+    for (node, file) in mesh.nodes.iter().zip(files) {
+        let migrated = storage::migrate_legacy_log(&serde_json::from_str(file).unwrap(), &Keypair::generate_ed25519());
+        *node.state.log.write().unwrap() = migrated;
+    }
+    // An entry signed with the public legacy key does not spread
+    let forged = LogEntry::legacy(&entry::legacy_keypair(), "intruso");
+    mesh[1].state.log.write().unwrap().insert(forged.clone());
+
+    mesh.connect(0, 1).await;
+    for node in &mesh.nodes {
+        node.lines.send("/sync".into()).unwrap();
+    }
+    wait_for("merged legacy lines", || mesh[0].log().len() == 3).await;
+    wait_for("merged legacy lines", || mesh[1].log().len() == 4).await;
+    tokio::time::sleep(std::time::Duration::from_millis(300)).await;
+    let mut texts = mesh[0].log();
+    texts.sort();
+    assert_eq!(texts, ["alarme", "luz acesa", "porta aberta"]);
+    let mut merged = mesh[1].state.log.read().unwrap().read();
+    merged.remove(&forged);
+    assert_eq!(mesh[0].state.log.read().unwrap().read(), merged);
+    mesh.shutdown().await;
+}
+
+#[tokio::test]
+async fn peers_reject_forged_entries() {
+    let mesh = Mesh::spawn(2).await;
+    let mut forged = LogEntry::text(&Keypair::generate_ed25519(), Hlc::new(1, 0), "genuine");
+    forged.body = "forged".into();
+    mesh[0].state.log.write().unwrap().insert(forged);
+
+    mesh.connect(0, 1).await;
+    mesh[0].send(NodeCommand::LogJson { body: json!({"temp": 21.5}), tags: vec!["sensor".into()] });
+    wait_for("signed entry", || mesh[1].log() == [r#"{"temp":21.5}"#]).await;
+
+    let entries = mesh[1].state.log.read().unwrap().read();
+    let entry = entries.iter().next().unwrap();
+    assert_eq!(entry.author, mesh[0].peer_id.to_string());
+    assert_eq!(entry.tags, ["sensor"]);
+    mesh.shutdown().await;
+}
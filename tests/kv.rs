@@ -0,0 +1,143 @@
+mod common;
+
+use common::{next_event, wait_for, Mesh};
+use ghostmesh::hlc::{self, Hlc, HybridClock};
+use ghostmesh::kv::{DeviceStore, KvChange};
+use ghostmesh::namespace::Namespace;
+use ghostmesh::p2p::NodeCommand;
+use ghostmesh::storage;
+use ghostmesh::telemetry::NetworkEvent;
+use serde_json::json;
+
+fn put(device: &str, attribute: &str, value: serde_json::Value) -> NodeCommand {
+    NodeCommand::KvPut { device: device.into(), attribute: attribute.into(), value }
+}
+
+#[test]
+fn concurrent_puts_converge_on_the_latest() {
+    let mut a = DeviceStore::new();
+    let mut b = DeviceStore::new();
+    let op_a = a.put("A", "luz", "estado", json!("ON"), Hlc::new(10, 0));
+    let op_b = b.put("B", "luz", "estado", json!("OFF"), Hlc::new(20, 0));
+
+    let changes = a.apply(op_b).unwrap();
+    assert_eq!(changes, [KvChange { device: "luz".into(), attribute: "estado".into(), value: Some(json!("OFF")) }]);
+    assert!(b.apply(op_a).unwrap().is_empty());
+    assert_eq!(a, b);
+    assert_eq!(a.get("luz", "estado"), Some(json!("OFF")));
+    assert_eq!(a.digest(), b.digest());
+}
+
+#[test]
+fn deletes_keep_concurrent_puts() {
+    let mut a = DeviceStore::new();
+    let first = a.put("A", "valvula", "abertura", json!(50), Hlc::new(1, 0));
+    let mut b = DeviceStore::new();
+    b.apply(first).unwrap();
+
+    let delete = a.delete("valvula", "abertura").unwrap();
+    let update = b.put("B", "valvula", "abertura", json!(75), Hlc::new(2, 0));
+    a.apply(update).unwrap();
+    b.apply(delete).unwrap();
+    assert_eq!(a.get("valvula", "abertura"), Some(json!(75)));
+    assert_eq!(a.all(), b.all());
+    assert!(a.delete("valvula", "nada").is_none());
+}
+
+#[test]
+fn ops_after_a_gap_are_refused() {
+    let mut a = DeviceStore::new();
+    let _missed = a.put("A", "sensor", "temp", json!(20), Hlc::new(1, 0));
+    let next = a.put("A", "sensor", "umidade", json!(40), Hlc::new(2, 0));
+
+    let mut b = DeviceStore::new();
+    assert!(b.apply(next).is_err());
+    b.merge(a.clone());
+    assert_eq!(b.device("sensor"), a.device("sensor"));
+}
+
+#[test]
+fn writes_from_too_far_in_the_future_are_refused() {
+    let now = HybridClock::default().now().wall_ms;
+    let future = Hlc::new(now + hlc::MAX_DRIFT_MS + 60_000, 0);
+    let mut a = DeviceStore::new();
+    let op = a.put("A", "luz", "estado", json!("ON"), future);
+
+    let mut b = DeviceStore::new();
+    assert!(b.apply(op).unwrap().is_empty());
+    assert!(b.merge(a.clone()).is_empty());
+    assert_eq!(b.get("luz", "estado"), None);
+
+    // Within the drift allowed, the same write goes through
+    let mut a = DeviceStore::new();
+    let op = a.put("A", "luz", "estado", json!("ON"), Hlc::new(now + 1000, 0));
+    assert_eq!(b.apply(op).unwrap().len(), 1);
+}
+
+#[test]
+fn stores_persist() {
+    let dir = tempfile::tempdir().unwrap();
+    let ns = Namespace::default().with_data_root(dir.path().to_str().unwrap());
+    assert_eq!(storage::load_kv(&ns, 9000).unwrap(), DeviceStore::new());
+
+    let mut store = DeviceStore::new();
+    store.put("A", "luz", "sala", json!("ON"), Hlc::new(1, 0));
+    let earlier = store.put("A", "luz", "cozinha", json!("OFF"), Hlc::new(2, 0));
+    store.delete("luz", "cozinha");
+    storage::save_kv(&ns, 9000, &store).unwrap();
+    let loaded = storage::load_kv(&ns, 9000).unwrap();
+    assert_eq!(loaded, store);
+
+    // The reloaded clock still knows the ops it has seen
+    let mut reloaded = loaded;
+    assert!(reloaded.apply(earlier).unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn device_state_replicates() {
+    let mesh = Mesh::spawn(2).await;
+    mesh.connect(0, 1).await;
+    let mut events = mesh[1].events();
+    mesh[0].send(put("luz", "sala", json!("ON")));
+    let event = next_event(&mut events, |e| matches!(e, NetworkEvent::KvChanged { .. })).await;
+    assert!(matches!(event, NetworkEvent::KvChanged { ref device, ref attribute, value: Some(ref v) }
+        if device == "luz" && attribute == "sala" && *v == json!("ON")));
+
+    mesh[1].send(put("valvula", "abertura", json!(50)));
+    wait_for("reply", || mesh[0].state.kv.read().unwrap().get("valvula", "abertura") == Some(json!(50))).await;
+    mesh[1].send(NodeCommand::KvDelete { device: "luz".into(), attribute: "sala".into() });
+    wait_for("delete", || mesh[0].state.kv.read().unwrap().get("luz", "sala").is_none()).await;
+
+    mesh.shutdown().await;
+}
+
+#[tokio::test]
+async fn late_joiners_catch_up_through_digests() {
+    let mesh = Mesh::spawn(2).await;
+    mesh[0].send(put("porta", "estado", json!("fechada")));
+    mesh[0].send(put("porta", "bateria", json!(87)));
+    wait_for("local puts", || mesh[0].state.kv.read().unwrap().device("porta").len() == 2).await;
+
+    mesh.connect(0, 1).await;
+    wait_for("catch up", || mesh[1].state.kv.read().unwrap().all() == mesh[0].state.kv.read().unwrap().all()).await;
+
+    // Ops continue in causal order after the state transfer
+    mesh[0].send(put("porta", "estado", json!("aberta")));
+    wait_for("next op", || mesh[1].state.kv.read().unwrap().get("porta", "estado") == Some(json!("aberta"))).await;
+    mesh.shutdown().await;
+}
+
+#[tokio::test]
+async fn states_larger_than_a_gossip_message_catch_up() {
+    let mesh = Mesh::spawn(2).await;
+    let long = "x".repeat(1024);
+    for i in 0..100 {
+        mesh[0].send(put("sensor", &format!("leitura{}", i), json!(long)));
+    }
+    wait_for("local puts", || mesh[0].state.kv.read().unwrap().device("sensor").len() == 100).await;
+    assert!(serde_json::to_vec(&*mesh[0].state.kv.read().unwrap()).unwrap().len() > 64 * 1024);
+
+    mesh.connect(0, 1).await;
+    wait_for("catch up", || mesh[1].state.kv.read().unwrap().all() == mesh[0].state.kv.read().unwrap().all()).await;
+    mesh.shutdown().await;
+}
@@ -0,0 +1,204 @@
+mod common;
+
+use std::collections::HashSet;
+
+use common::{next_event, wait_for, Mesh};
+use ghostmesh::entry::{EntryKey, LogEntry, Tombstone};
+use ghostmesh::hlc::Hlc;
+use ghostmesh::logset::LogSet;
+use ghostmesh::p2p::NodeCommand;
+use ghostmesh::telemetry::NetworkEvent;
+use libp2p::identity::Keypair;
+
+fn peer(keypair: &Keypair) -> String {
+    keypair.public().to_peer_id().to_string()
+}
+
+#[test]
+fn removals_win_in_any_order() {
+    let author = Keypair::generate_ed25519();
+    let entry = LogEntry::text(&author, Hlc::new(1, 0), "gone");
+    let kept = LogEntry::text(&author, Hlc::new(2, 0), "gone");
+    let tombstone = Tombstone::new(&author, &entry, Hlc::new(3, 0));
+
+    let mut a = LogSet::new();
+    a.insert(entry.clone());
+    a.insert(kept.clone());
+    assert!(a.remove(tombstone.clone()));
+
+    // The tombstone arrives before the entry it removes
+    let mut b = LogSet::new();
+    assert!(b.remove(tombstone.clone()));
+    assert!(!b.insert(entry.clone()));
+    b.insert(kept.clone());
+
+    assert_eq!(a, b);
+    assert_eq!(a.read().into_iter().collect::<Vec<_>>(), [kept]);
+    assert!(a.is_removed(&entry.id, &entry.author));
+
+    let mut merged = LogSet::new();
+    merged.insert(entry);
+    merged.merge(b);
+    assert_eq!(merged, a);
+}
+
+#[test]
+fn tombstones_must_name_the_entry_author() {
+    let author = Keypair::generate_ed25519();
+    let entry = LogEntry::text(&author, Hlc::new(1, 0), "mine");
+    let mut log = LogSet::new();
+    log.insert(entry.clone());
+
+    let mut misattributed = entry.clone();
+    misattributed.author = peer(&Keypair::generate_ed25519());
+    log.remove(Tombstone::new(&Keypair::generate_ed25519(), &misattributed, Hlc::new(2, 0)));
+    assert!(log.contains(&entry));
+
+    let stranger = Tombstone::new(&Keypair::generate_ed25519(), &entry, Hlc::new(2, 0));
+    assert!(stranger.verify().is_ok());
+    assert!(!stranger.permitted(&HashSet::new()));
+    assert!(stranger.permitted(&HashSet::from([stranger.removed_by.clone()])));
+    assert!(Tombstone::new(&author, &entry, Hlc::new(2, 0)).permitted(&HashSet::new()));
+
+    let mut forged = stranger;
+    forged.removed_by = entry.author.clone();
+    assert!(forged.verify().is_err());
+}
+
+#[test]
+fn authors_sharing_an_id_are_removed_separately() {
+    let (victim, forger) = (Keypair::generate_ed25519(), Keypair::generate_ed25519());
+    let original = LogEntry::text(&victim, Hlc::new(1, 0), "original");
+    let forged = LogEntry::with_id(&forger, original.id.clone(), Hlc::new(2, 0), Vec::new(), "forged".into());
+    let mut log = LogSet::new();
+    log.insert(original.clone());
+    assert!(log.insert(forged.clone()));
+    assert_eq!(log.with_id(&original.id).count(), 2);
+
+    // The forger removes its own entry, which leaves the victim's in place
+    let since = log.seq();
+    assert!(log.remove(Tombstone::new(&forger, &forged, Hlc::new(3, 0))));
+    assert_eq!(log.iter().collect::<Vec<_>>(), [&original]);
+    assert!(log.is_removed(&forged.id, &forged.author) && !log.is_removed(&original.id, &original.author));
+    assert_eq!(log.get(&original.id, &original.author), Some(&original));
+    let removed: Vec<_> = log.changes_since(since).unwrap().removed.into_iter().map(|(_, key)| key.clone()).collect();
+    assert_eq!(removed, [forged.key()]);
+
+    // Acks for the forged entry's tombstone do not collect another author's
+    log.remove(Tombstone::new(&victim, &original, Hlc::new(4, 0)));
+    let b = peer(&Keypair::generate_ed25519());
+    log.ack(&b, &[forged.key()]);
+    let known = HashSet::from([peer(&victim), peer(&forger), b]);
+    assert_eq!(log.collect_garbage(&known), Vec::<EntryKey>::new());
+    assert_eq!(log.tombstones().len(), 2);
+}
+
+#[test]
+fn tombstones_are_collected_once_every_known_peer_acked() {
+    let author = Keypair::generate_ed25519();
+    let entry = LogEntry::text(&author, Hlc::new(1, 0), "old");
+    let mut log = LogSet::new();
+    log.insert(entry.clone());
+    log.remove(Tombstone::new(&author, &entry, Hlc::new(2, 0)));
+
+    assert!(log.collect_garbage(&HashSet::new()).is_empty());
+    let keys = vec![entry.key()];
+    let (b, c) = (peer(&Keypair::generate_ed25519()), peer(&Keypair::generate_ed25519()));
+    let known = HashSet::from([peer(&author), b.clone(), c.clone()]);
+    log.ack(&b, &keys);
+    assert!(log.collect_garbage(&known).is_empty());
+    log.ack(&c, &keys);
+    assert_eq!(log.collect_garbage(&known), keys);
+    assert!(log.tombstones().is_empty() && log.is_empty());
+}
+
+#[test]
+fn tombstones_survive_storage_and_old_logs_still_load() {
+    let author = Keypair::generate_ed25519();
+    let entry = LogEntry::text(&author, Hlc::new(1, 0), "x");
+    let mut log = LogSet::new();
+    log.insert(entry.clone());
+    let plain = serde_json::to_value(&log).unwrap();
+    assert_eq!(plain.as_object().unwrap().keys().collect::<Vec<_>>(), ["value"]);
+
+    log.remove(Tombstone::new(&author, &entry, Hlc::new(2, 0)));
+    let reloaded: LogSet = serde_json::from_str(&serde_json::to_string(&log).unwrap()).unwrap();
+    assert_eq!(reloaded, log);
+    assert!(reloaded.is_removed(&entry.id, &entry.author));
+    let old = serde_json::from_value::<LogSet>(plain).unwrap();
+    assert_eq!(old.get(&entry.id, &entry.author), Some(&entry));
+}
+
+#[test]
+fn an_author_reusing_an_id_keeps_one_entry() {
+    let author = Keypair::generate_ed25519();
+    let first = LogEntry::with_id(&author, "dup".into(), Hlc::new(1, 0), Vec::new(), "a".into());
+    let second = LogEntry::with_id(&author, "dup".into(), Hlc::new(2, 0), Vec::new(), "b".into());
+    let other = LogEntry::with_id(&Keypair::generate_ed25519(), "dup".into(), Hlc::new(3, 0), Vec::new(), "c".into());
+
+    let mut a = LogSet::new();
+    assert!(a.insert(second.clone()));
+    assert!(a.insert(first.clone()));
+    let mut b = LogSet::new();
+    assert!(b.insert(first.clone()));
+    assert!(!b.insert(second));
+    assert_eq!(a, b);
+    assert_eq!(a.get("dup", &first.author), Some(&first));
+
+    // Other authors' entries with the id are separate
+    a.insert(other.clone());
+    assert_eq!(a.with_id("dup").count(), 2);
+    assert_eq!(a.get("dup", &other.author), Some(&other));
+    assert!(a.with_id("du").next().is_none());
+}
+
+#[tokio::test]
+async fn removals_replicate_and_are_collected() {
+    let mesh = Mesh::spawn(2).await;
+    mesh.connect(0, 1).await;
+    mesh[0].send(NodeCommand::Log("typo".into()));
+    wait_for("entry", || mesh[1].log() == ["typo"]).await;
+    let entry = mesh[0].state.log.read().unwrap().read().into_iter().next().unwrap();
+    let (id, author) = (entry.id, entry.author);
+
+    // Only the author may remove it
+    mesh[1].send(NodeCommand::RemoveLog { id: id.clone(), author: author.clone() });
+    let mut events = mesh[1].events();
+    mesh[0].send(NodeCommand::RemoveLog { id: id.clone(), author });
+    let removed = next_event(&mut events, |e| matches!(e, NetworkEvent::LogEntryRemoved { .. })).await;
+    assert!(matches!(removed, NetworkEvent::LogEntryRemoved { id: ref removed_id, ref removed_by }
+        if *removed_id == id && *removed_by == mesh[0].peer_id.to_string()));
+    assert!(mesh[0].log().is_empty() && mesh[1].log().is_empty());
+
+    for node in &mesh.nodes {
+        wait_for("tombstone collection", || node.state.log.read().unwrap().tombstones().is_empty()).await;
+    }
+    mesh.shutdown().await;
+}
+
+#[tokio::test]
+async fn admins_remove_any_entry_and_others_are_refused() {
+    let mesh = Mesh::spawn(2).await;
+    mesh[0].send(NodeCommand::Log("keep".into()));
+    wait_for("entry", || mesh[0].log() == ["keep"]).await;
+    let entry = mesh[0].state.log.read().unwrap().read().into_iter().next().unwrap();
+
+    // A tombstone by a peer that is neither the author nor an admin is not accepted
+    mesh[1].state.log.write().unwrap().remove(Tombstone::new(&Keypair::generate_ed25519(), &entry, Hlc::new(1, 0)));
+    let mut events = mesh[0].events();
+    mesh.connect(0, 1).await;
+    next_event(&mut events, |e| matches!(e, NetworkEvent::SyncProgress { stage, .. } if stage == "complete")).await;
+    assert_eq!(mesh[0].log(), ["keep"]);
+    assert!(mesh[0].state.log.read().unwrap().tombstones().is_empty());
+
+    let admin = mesh[1].peer_id.to_string();
+    for node in &mesh.nodes {
+        node.state.log_admins.write().unwrap().insert(admin.clone());
+    }
+    mesh[0].send(NodeCommand::Log("spam".into()));
+    wait_for("entry", || mesh[1].log() == ["spam"]).await;
+    let spam = mesh[1].state.log.read().unwrap().read().into_iter().next().unwrap();
+    mesh[1].send(NodeCommand::RemoveLog { id: spam.id, author: spam.author });
+    wait_for("admin removal", || mesh[0].log() == ["keep"]).await;
+    mesh.shutdown().await;
+}
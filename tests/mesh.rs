@@ -0,0 +1,67 @@
+mod common;
+
+use common::{next_event, wait_for, Mesh};
+use ghostmesh::namespace::Namespace;
+use ghostmesh::p2p::NodeCommand;
+use ghostmesh::telemetry::NetworkEvent;
+
+#[tokio::test]
+async fn log_entries_replicate_across_the_mesh() {
+    let mesh = Mesh::spawn(3).await;
+    mesh.connect_all().await;
+
+    mesh[0].send(NodeCommand::Log("from a".into()));
+    mesh[2].send(NodeCommand::Log("from c".into()));
+
+    for node in &mesh.nodes {
+        wait_for("log replication", || node.log() == ["from a", "from c"]).await;
+    }
+    mesh.shutdown().await;
+}
+
+#[tokio::test]
+async fn chat_reaches_peers_and_telemetry() {
+    let mesh = Mesh::spawn(2).await;
+    mesh.connect(0, 1).await;
+    let mut events = mesh[1].events();
+
+    mesh[0].send(NodeCommand::Chat("hello mesh".into()));
+
+    let event = next_event(&mut events, |e| matches!(e, NetworkEvent::ChatMessage { .. })).await;
+    let NetworkEvent::ChatMessage { from, content, .. } = event else { unreachable!() };
+    assert_eq!(from, mesh[0].peer_id.to_string());
+    assert_eq!(content, "hello mesh");
+    assert_eq!(mesh[1].state.chat.read().unwrap().len(), 1);
+    mesh.shutdown().await;
+}
+
+#[tokio::test]
+async fn direct_message_is_delivered_to_recipient() {
+    let mesh = Mesh::spawn(2).await;
+    mesh.connect(0, 1).await;
+
+    mesh[0].send(NodeCommand::SendDm { to: mesh[1].peer_id.to_string(), content: "psst".into() });
+
+    wait_for("dm delivery", || !mesh[1].state.dms.read().unwrap().is_empty()).await;
+    let dm = mesh[1].state.dms.read().unwrap()[0].clone();
+    assert_eq!(dm.from, mesh[0].peer_id.to_string());
+    assert_eq!(dm.content, "psst");
+    assert!(mesh[0].state.dms.read().unwrap().is_empty());
+    mesh.shutdown().await;
+}
+
+#[tokio::test]
+async fn peers_in_other_namespaces_are_disconnected() {
+    let lab = Namespace::new("lab", None).unwrap();
+    let mesh = Mesh::spawn_in(vec![lab, Namespace::default()]).await;
+    let mut events = mesh[0].events();
+
+    mesh[0].send(NodeCommand::Dial(mesh[1].addr.clone()));
+
+    let event = next_event(&mut events, |e| matches!(e, NetworkEvent::PeerIncompatible { .. })).await;
+    let NetworkEvent::PeerIncompatible { peer_id, reason, .. } = event else { unreachable!() };
+    assert_eq!(peer_id, mesh[1].peer_id.to_string());
+    assert!(reason.contains("namespace"), "unexpected reason: {}", reason);
+    wait_for("disconnect", || !mesh[0].is_connected_to(&mesh[1])).await;
+    mesh.shutdown().await;
+}
@@ -0,0 +1,60 @@
+mod common;
+
+use common::{wait_for, Mesh};
+use ghostmesh::entry::LogEntry;
+use ghostmesh::hlc::Hlc;
+use ghostmesh::logset::LogSet;
+use ghostmesh::p2p::NodeCommand;
+use libp2p::identity::Keypair;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+fn ids(entries: impl IntoIterator<Item = LogEntry>) -> Vec<String> {
+    entries.into_iter().map(|entry| entry.id).collect()
+}
+
+#[test]
+fn range_selects_by_wall_clock() {
+    let keypair = Keypair::generate_ed25519();
+    let mut log = LogSet::new();
+    for (wall_ms, counter) in [(1_000, 0), (2_000, 0), (2_000, 1), (3_000, 0)] {
+        log.insert(LogEntry::text(&keypair, Hlc::new(wall_ms, counter), &format!("{}.{}", wall_ms, counter)));
+    }
+    let texts = |entries: Vec<LogEntry>| entries.iter().map(LogEntry::text_body).collect::<Vec<_>>();
+
+    assert_eq!(texts(log.range(Some(2_000), Some(3_000))), ["2000.0", "2000.1"]);
+    assert_eq!(texts(log.range(None, Some(2_000))), ["1000.0"]);
+    assert_eq!(texts(log.range(Some(2_500), None)), ["3000.0"]);
+    assert_eq!(log.range(None, None).len(), 4);
+}
+
+#[tokio::test]
+async fn nodes_agree_on_a_causal_order() {
+    let mesh = Mesh::spawn(2).await;
+
+    // A peer whose clock runs half a minute ahead, and one an hour ahead
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64;
+    let ahead = LogEntry::text(&Keypair::generate_ed25519(), Hlc::new(now + 30_000, 0), "Movimento detectado");
+    let far_ahead = LogEntry::text(&Keypair::generate_ed25519(), Hlc::new(now + 3_600_000, 0), "Relógio adiantado");
+    mesh[0].state.log.write().unwrap().insert(ahead.clone());
+    mesh[0].state.log.write().unwrap().insert(far_ahead.clone());
+    mesh[0].send(NodeCommand::Log("Porta aberta".into()));
+    mesh[1].send(NodeCommand::Log("Luz acesa".into()));
+    wait_for("local entries", || mesh[0].log().len() == 3 && mesh[1].log().len() == 1).await;
+
+    mesh.connect(0, 1).await;
+    wait_for("anti-entropy", || mesh[1].log().len() == 3).await;
+
+    // Written after seeing the entry from the future, so stamped after it
+    mesh[1].send(NodeCommand::Log("Alarme desligado".into()));
+    wait_for("reply", || mesh[0].log().len() == 5).await;
+
+    // The entry beyond the allowed drift is refused, and does not drag the clock along
+    assert!(!mesh[1].log().contains(&"Relógio adiantado".to_string()));
+    let order_0: Vec<String> = ids(mesh[0].state.log.read().unwrap().read()).into_iter().filter(|id| *id != far_ahead.id).collect();
+    let order_1 = ids(mesh[1].state.log.read().unwrap().read());
+    assert_eq!(order_0, order_1);
+    let last = mesh[1].state.log.read().unwrap().read().into_iter().next_back().unwrap();
+    assert_eq!(last.text_body(), "Alarme desligado");
+    assert!(last.timestamp > ahead.timestamp && last.timestamp < far_ahead.timestamp);
+    mesh.shutdown().await;
+}
@@ -0,0 +1,132 @@
+mod common;
+
+use common::{wait_for, Mesh};
+use ghostmesh::entry::{LogEntry, Tombstone};
+use ghostmesh::hlc::Hlc;
+use ghostmesh::logset::LogSet;
+use ghostmesh::p2p::NodeCommand;
+use ghostmesh::query::{LogPage, LogQuery};
+use libp2p::identity::Keypair;
+use serde_json::json;
+
+fn texts(page: &LogPage) -> Vec<String> {
+    page.entries.iter().map(LogEntry::text_body).collect()
+}
+
+fn run(log: &LogSet, query: LogQuery) -> LogPage {
+    query.run(log).unwrap()
+}
+
+#[test]
+fn filters_combine() {
+    let alice = Keypair::generate_ed25519();
+    let bob = Keypair::generate_ed25519();
+    let mut log = LogSet::new();
+    log.insert(LogEntry::new(&alice, Hlc::new(1_000, 0), vec!["porta".into()], json!("Porta da frente aberta")));
+    log.insert(LogEntry::new(&bob, Hlc::new(2_000, 0), vec!["porta".into()], json!({"estado": "fechada"})));
+    log.insert(LogEntry::new(&alice, Hlc::new(3_000, 0), vec!["luz".into()], json!("Luz da SALA acesa")));
+    log.insert(LogEntry::text(&bob, Hlc::new(4_000, 0), "nada"));
+
+    let alice_id = alice.public().to_peer_id().to_string();
+    let by_author = run(&log, LogQuery { author: Some(alice_id.clone()), ..Default::default() });
+    assert_eq!(texts(&by_author), ["Porta da frente aberta", "Luz da SALA acesa"]);
+    assert_eq!(by_author.total, 2);
+
+    let by_tag = run(&log, LogQuery { tag: Some("porta".into()), ..Default::default() });
+    assert_eq!(texts(&by_tag), ["Porta da frente aberta", r#"{"estado":"fechada"}"#]);
+
+    // Text search ignores case and covers JSON bodies and tags
+    assert_eq!(texts(&run(&log, LogQuery { q: Some("sala".into()), ..Default::default() })), ["Luz da SALA acesa"]);
+    assert_eq!(texts(&run(&log, LogQuery { q: Some("FECHADA".into()), ..Default::default() })), [r#"{"estado":"fechada"}"#]);
+    assert_eq!(run(&log, LogQuery { q: Some("lu".into()), ..Default::default() }).total, 1);
+
+    let combined = LogQuery { author: Some(alice_id), from: Some(2_000), to: Some(4_000), q: Some("a".into()), ..Default::default() };
+    assert_eq!(texts(&run(&log, combined)), ["Luz da SALA acesa"]);
+}
+
+#[test]
+fn pages_go_back_from_the_newest_entries() {
+    let author = Keypair::generate_ed25519();
+    let mut log = LogSet::new();
+    for i in 0..7 {
+        log.insert(LogEntry::text(&author, Hlc::new(1_000, i), &i.to_string()));
+    }
+
+    let first = run(&log, LogQuery { limit: Some(3), ..Default::default() });
+    assert_eq!(texts(&first), ["4", "5", "6"]);
+    assert_eq!(first.total, 7);
+    let second = run(&log, LogQuery { limit: Some(3), before: first.next_before.clone(), ..Default::default() });
+    assert_eq!(texts(&second), ["1", "2", "3"]);
+    let last = run(&log, LogQuery { limit: Some(3), before: second.next_before.clone(), ..Default::default() });
+    assert_eq!(texts(&last), ["0"]);
+    assert_eq!(last.next_before, None);
+
+    // A cursor stays valid after its entry is gone, and a malformed one is refused
+    let oldest_of_first = first.entries[0].clone();
+    log.remove(Tombstone::new(&author, &oldest_of_first, Hlc::new(2_000, 0)));
+    let again = run(&log, LogQuery { limit: Some(3), before: first.next_before, ..Default::default() });
+    assert_eq!(texts(&again), ["1", "2", "3"]);
+    assert!(LogQuery { before: Some("yesterday".into()), ..Default::default() }.run(&log).is_err());
+}
+
+#[test]
+fn since_returns_only_what_changed() {
+    let author = Keypair::generate_ed25519();
+    let mut log = LogSet::new();
+    log.insert(LogEntry::text(&author, Hlc::new(1_000, 0), "a"));
+    log.insert(LogEntry::text(&author, Hlc::new(2_000, 0), "b"));
+    let start = run(&log, LogQuery::default());
+    assert_eq!(texts(&start), ["a", "b"]);
+
+    let unchanged = run(&log, LogQuery { since: Some(start.cursor), ..Default::default() });
+    assert!(unchanged.entries.is_empty() && unchanged.removed.is_empty() && !unchanged.reset);
+    assert_eq!(unchanged.cursor, start.cursor);
+
+    // An entry stamped in the past still shows up as a change
+    log.insert(LogEntry::text(&author, Hlc::new(500, 0), "late"));
+    let b = log.iter().find(|entry| entry.text_body() == "b").unwrap().clone();
+    log.remove(Tombstone::new(&author, &b, Hlc::new(3_000, 0)));
+    log.insert(LogEntry::text(&author, Hlc::new(4_000, 0), "c"));
+    let changes = run(&log, LogQuery { since: Some(start.cursor), ..Default::default() });
+    assert_eq!(texts(&changes), ["late", "c"]);
+    assert_eq!(changes.removed, [b.key()]);
+    assert_eq!(changes.total, 3);
+
+    // Changes are handed out oldest first, `limit` at a time
+    let partial = run(&log, LogQuery { since: Some(start.cursor), limit: Some(2), ..Default::default() });
+    assert_eq!(texts(&partial), ["late"]);
+    assert_eq!(partial.removed.len(), 1);
+    let rest = run(&log, LogQuery { since: Some(partial.cursor), limit: Some(2), ..Default::default() });
+    assert_eq!(texts(&rest), ["c"]);
+    assert_eq!(rest.cursor, changes.cursor);
+
+    // Filters apply to the changes too
+    let filtered = run(&log, LogQuery { since: Some(start.cursor), q: Some("c".into()), ..Default::default() });
+    assert_eq!(texts(&filtered), ["c"]);
+
+    // Compaction is reported through the horizon
+    log.compact(Hlc::new(1_500, 0));
+    let compacted = run(&log, LogQuery { since: Some(changes.cursor), ..Default::default() });
+    assert_eq!(compacted.horizon, Some(Hlc::new(1_500, 0)));
+    assert!(compacted.entries.is_empty());
+
+    // A cursor of an earlier run gets the whole log again
+    let restarted: LogSet = serde_json::from_str(&serde_json::to_string(&log).unwrap()).unwrap();
+    let reset = run(&restarted, LogQuery { since: Some(start.cursor), ..Default::default() });
+    assert!(reset.reset);
+    assert_eq!(texts(&reset), ["c"]);
+}
+
+#[tokio::test]
+async fn entries_from_peers_are_changes() {
+    let mesh = Mesh::spawn(2).await;
+    mesh.connect(0, 1).await;
+    let cursor = run(&mesh[1].state.log.read().unwrap(), LogQuery::default()).cursor;
+
+    mesh[0].send(NodeCommand::Log("remota".into()));
+    wait_for("replicated", || mesh[1].log() == ["remota"]).await;
+    let changes = run(&mesh[1].state.log.read().unwrap(), LogQuery { since: Some(cursor), ..Default::default() });
+    assert_eq!(texts(&changes), ["remota"]);
+    assert!(!changes.reset);
+    mesh.shutdown().await;
+}
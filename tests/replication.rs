@@ -0,0 +1,150 @@
+mod common;
+
+use std::time::Duration;
+
+use common::{next_event, wait_for, Mesh, TestNode};
+use ghostmesh::chaos::ChaosConfig;
+use ghostmesh::entry::LogEntry;
+use ghostmesh::hlc::Hlc;
+use ghostmesh::logset::LogSet;
+use ghostmesh::p2p::NodeCommand;
+use ghostmesh::replication::{PeerReplication, PeerReport, VersionVector};
+use ghostmesh::telemetry::NetworkEvent;
+use libp2p::identity::Keypair;
+
+fn report_of(node: &TestNode, peer: &TestNode) -> Option<PeerReport> {
+    node.state.peer_report().into_iter().find(|report| report.peer_id == peer.peer_id.to_string())
+}
+
+fn missing(node: &TestNode, peer: &TestNode) -> Option<usize> {
+    report_of(node, peer).and_then(|report| report.replication).map(|status| status.missing)
+}
+
+#[test]
+fn the_vector_tells_what_a_peer_lacks() {
+    let alice = Keypair::generate_ed25519();
+    let bob = Keypair::generate_ed25519();
+    let mut log = LogSet::new();
+    for wall_ms in [1_000, 2_000, 3_000] {
+        log.insert(LogEntry::text(&alice, Hlc::new(wall_ms, 0), "a"));
+    }
+    log.insert(LogEntry::text(&bob, Hlc::new(2_500, 0), "b"));
+
+    let mut peer = PeerReplication::default();
+    let status = peer.status(&log);
+    assert_eq!((status.missing, status.oldest_missing_ms, status.ahead), (4, Some(1_000), false));
+
+    // The peer holds Alice's entries up to the second one, and none of Bob's
+    let mut partial = LogSet::new();
+    partial.insert(LogEntry::text(&alice, Hlc::new(2_000, 0), "a"));
+    peer.vector = VersionVector::of(&partial);
+    let status = peer.status(&log);
+    assert_eq!((status.missing, status.oldest_missing_ms), (2, Some(2_500)));
+    assert_eq!(status.lag_ms(4_000), 1_500);
+    assert!(!status.in_sync());
+
+    // Entries of authors this node has not seen put the peer ahead
+    peer.vector = VersionVector::of(&log);
+    peer.vector.observe([&LogEntry::text(&Keypair::generate_ed25519(), Hlc::new(5_000, 0), "c")]);
+    let status = peer.status(&log);
+    assert_eq!(status.missing, 0);
+    assert!(status.ahead);
+
+    peer.vector = VersionVector::of(&log);
+    assert!(peer.status(&log).in_sync());
+}
+
+#[test]
+fn an_entry_dropped_in_the_middle_leaves_the_status_unknown() {
+    let alice = Keypair::generate_ed25519();
+    let entries = [1_000, 2_000, 3_000].map(|wall_ms| LogEntry::text(&alice, Hlc::new(wall_ms, 0), "a"));
+    let log_of = |held: &[&LogEntry]| {
+        let mut log = LogSet::new();
+        for entry in held {
+            log.insert((*entry).clone());
+        }
+        log
+    };
+    let log = log_of(&[&entries[0], &entries[1], &entries[2]]);
+
+    // The peer got the first and last entries, not the second
+    let mut peer = PeerReplication { vector: VersionVector::of(&log_of(&[&entries[0], &entries[2]])), ..Default::default() };
+    let status = peer.status(&log);
+    assert_eq!((status.missing, status.unknown, status.ahead), (0, 3, false));
+    assert!(!status.in_sync());
+
+    // Likewise when the last one arrived after its vector, published by the peer
+    peer.vector = VersionVector::of(&log_of(&[&entries[0]]));
+    assert_eq!((peer.status(&log).missing, peer.status(&log).unknown), (2, 0));
+    peer.vector.observe([&entries[2]]);
+    assert_eq!((peer.status(&log).missing, peer.status(&log).unknown), (0, 3));
+
+    // Only a vector with every entry counted shows it caught up
+    peer.vector = VersionVector::of(&log);
+    assert!(peer.status(&log).in_sync());
+}
+
+#[tokio::test]
+async fn peers_that_miss_entries_show_as_behind() {
+    let mesh = Mesh::spawn(3).await;
+    mesh.connect_all().await;
+    // The anti-entropy sessions on connect exchange the vectors
+    for peer in [1, 2] {
+        wait_for("vector exchanged", || missing(&mesh[0], &mesh[peer]) == Some(0)).await;
+    }
+
+    mesh[2].send(NodeCommand::SetChaos(ChaosConfig { drop_percent: 100.0, ..Default::default() }));
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    for text in ["a", "b", "c"] {
+        mesh[0].send(NodeCommand::Log(text.into()));
+    }
+    wait_for("replicated", || mesh[1].log().len() == 3).await;
+    assert_eq!(missing(&mesh[0], &mesh[2]), Some(3));
+    assert!(report_of(&mesh[0], &mesh[2]).unwrap().lag_ms > 0);
+
+    // An entry node 1 publishes shows what it holds, but not yet that it has node 0's
+    let mut events = mesh[0].events();
+    mesh[1].send(NodeCommand::Log("d".into()));
+    let event = next_event(&mut events, |e| matches!(e, NetworkEvent::ReplicationLag { .. })).await;
+    assert!(matches!(event, NetworkEvent::ReplicationLag { missing: 3, ahead: false, .. }));
+
+    // The most behind peer is listed first
+    let report = mesh[0].state.peer_report();
+    assert_eq!(report[0].peer_id, mesh[2].peer_id.to_string());
+    assert_eq!(report[0].replication.as_ref().unwrap().missing, 4);
+
+    // A sync session shows it
+    let peer_1 = mesh[1].peer_id.to_string();
+    mesh[0].lines.send("/sync".into()).unwrap();
+    let event = next_event(&mut events, |e| matches!(e, NetworkEvent::ReplicationLag { peer_id, .. } if *peer_id == peer_1)).await;
+    assert!(matches!(event, NetworkEvent::ReplicationLag { missing: 0, lag_ms: 0, .. }));
+    assert!(report_of(&mesh[0], &mesh[1]).unwrap().in_sync);
+    mesh.shutdown().await;
+}
+
+#[tokio::test]
+async fn a_gap_in_a_peers_log_is_not_taken_as_in_sync() {
+    let mesh = Mesh::spawn(2).await;
+    mesh.connect(0, 1).await;
+    mesh[0].send(NodeCommand::Log("a".into()));
+    wait_for("first entry", || mesh[1].log() == ["a"]).await;
+
+    // Node 1 misses the second entry, then gets the third
+    mesh[1].send(NodeCommand::SetChaos(ChaosConfig { drop_percent: 100.0, ..Default::default() }));
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    mesh[0].send(NodeCommand::Log("b".into()));
+    wait_for("local write", || mesh[0].log().len() == 2).await;
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    mesh[1].send(NodeCommand::SetChaos(ChaosConfig::default()));
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    mesh[0].send(NodeCommand::Log("c".into()));
+    wait_for("third entry", || mesh[1].log() == ["a", "c"]).await;
+
+    // Its full state holds node 0's newest entry, but one entry fewer
+    let mut events = mesh[0].events();
+    mesh[0].lines.send("/sync".into()).unwrap();
+    let event = next_event(&mut events, |e| matches!(e, NetworkEvent::ReplicationLag { .. })).await;
+    assert!(matches!(event, NetworkEvent::ReplicationLag { missing: 0, unknown: 3, .. }));
+    assert!(!report_of(&mesh[0], &mesh[1]).unwrap().in_sync);
+    mesh.shutdown().await;
+}
@@ -0,0 +1,202 @@
+mod common;
+
+use std::collections::HashSet;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use common::{next_event, wait_for, Mesh};
+use ghostmesh::chaos::ChaosConfig;
+use ghostmesh::entry::{HorizonSignature, LogEntry, Tombstone};
+use ghostmesh::hlc::Hlc;
+use ghostmesh::logset::LogSet;
+use ghostmesh::namespace::Namespace;
+use ghostmesh::p2p::NodeCommand;
+use ghostmesh::retention::RetentionPolicy;
+use ghostmesh::storage;
+use ghostmesh::wal::{LogStore, SyncPolicy, WalRecord};
+use ghostmesh::telemetry::NetworkEvent;
+use libp2p::identity::Keypair;
+
+/// A log with one entry per second, `text` stamped at `wall_ms`.
+fn log_of(author: &Keypair, entries: &[(u64, &str)]) -> LogSet {
+    let mut log = LogSet::new();
+    for (wall_ms, text) in entries {
+        log.insert(LogEntry::text(author, Hlc::new(*wall_ms, 0), text));
+    }
+    log
+}
+
+fn texts(log: &LogSet) -> Vec<String> {
+    log.iter().map(|entry| entry.text_body()).collect()
+}
+
+#[test]
+fn policies_parse_from_the_command_line() {
+    let policy = RetentionPolicy::parse("max-age=7d,max-entries=1000,max-bytes=2M,archive").unwrap();
+    assert_eq!(
+        policy,
+        RetentionPolicy { max_age_secs: Some(7 * 86400), max_entries: Some(1000), max_bytes: Some(2 << 20), archive: true }
+    );
+    assert_eq!(RetentionPolicy::parse("max-age=90").unwrap().max_age_secs, Some(90));
+    assert_eq!(RetentionPolicy::parse("").unwrap(), RetentionPolicy::default());
+    assert!(RetentionPolicy::parse("max-entries=0").is_err());
+    assert!(RetentionPolicy::parse("max-age=1w").is_err());
+    assert!(RetentionPolicy::parse("keep=all").is_err());
+}
+
+#[test]
+fn the_strictest_limit_sets_the_horizon() {
+    let author = Keypair::generate_ed25519();
+    let log = log_of(&author, &[(1_000, "a"), (2_000, "b"), (3_000, "c"), (4_000, "d")]);
+    let entry_bytes = serde_json::to_vec(log.iter().next().unwrap()).unwrap().len();
+
+    assert_eq!(RetentionPolicy::default().horizon(&log, 10_000), None);
+    let by_count = RetentionPolicy { max_entries: Some(3), ..Default::default() };
+    assert_eq!(by_count.horizon(&log, 10_000), Some(Hlc::new(2_000, 0)));
+    let by_age = RetentionPolicy { max_age_secs: Some(8), ..Default::default() };
+    assert_eq!(by_age.horizon(&log, 10_500), Some(Hlc::new(2_500, 0)));
+    assert_eq!(by_age.horizon(&log, 8_500), None);
+    let by_size = RetentionPolicy { max_bytes: Some(2 * entry_bytes + 1), ..Default::default() };
+    assert_eq!(by_size.horizon(&log, 10_000), Some(Hlc::new(3_000, 0)));
+    // The newest entry stays even when it alone is over the limit
+    let tiny = RetentionPolicy { max_bytes: Some(1), ..Default::default() };
+    assert_eq!(tiny.horizon(&log, 10_000), Some(Hlc::new(4_000, 0)));
+
+    let all = RetentionPolicy { max_entries: Some(3), max_age_secs: Some(8), max_bytes: Some(2 * entry_bytes + 1), archive: false };
+    assert_eq!(all.horizon(&log, 10_500), Some(Hlc::new(3_000, 0)));
+}
+
+#[test]
+fn only_admitted_horizons_are_adopted() {
+    let admin = Keypair::generate_ed25519();
+    let admins = HashSet::from([admin.public().to_peer_id().to_string()]);
+    let now = 100_000_000;
+    let past = Hlc::new(now - 3_600_000, 0);
+    let signed = HorizonSignature::new(&admin, past);
+    let keep_all = RetentionPolicy::default();
+    assert!(keep_all.admits(past, Some(&signed), &admins, now).is_ok());
+
+    // Unsigned, signed by someone else, or signed for another horizon
+    assert!(keep_all.admits(past, None, &admins, now).is_err());
+    let stranger = HorizonSignature::new(&Keypair::generate_ed25519(), past);
+    assert!(keep_all.admits(past, Some(&stranger), &admins, now).is_err());
+    assert!(keep_all.admits(Hlc::new(now - 1, 0), Some(&signed), &admins, now).is_err());
+
+    // A horizon ahead of the clock is refused even from an admin
+    let future = Hlc::new(now + 3_600_000, 0);
+    assert!(keep_all.admits(future, Some(&HorizonSignature::new(&admin, future)), &admins, now).is_err());
+
+    // Without a signature, only as far as the local age limit reaches
+    let by_age = RetentionPolicy { max_age_secs: Some(1800), ..Default::default() };
+    assert!(by_age.admits(past, None, &admins, now).is_ok());
+    assert!(by_age.admits(Hlc::new(now - 600_000, 0), None, &admins, now).is_err());
+}
+
+#[test]
+fn compacted_entries_are_not_taken_back() {
+    let author = Keypair::generate_ed25519();
+    let mut log = log_of(&author, &[(1_000, "a"), (2_000, "b"), (3_000, "c")]);
+    let stale = log.clone();
+    let old = log.iter().next().unwrap().clone();
+    let removal = Tombstone::new(&author, log.iter().nth(1).unwrap(), Hlc::new(2_500, 0));
+
+    let dropped = log.compact(Hlc::new(3_000, 0)).unwrap();
+    assert_eq!(dropped.iter().map(|e| e.text_body()).collect::<Vec<_>>(), ["a", "b"]);
+    assert_eq!(log.horizon(), Some(Hlc::new(3_000, 0)));
+    assert!(log.compact(Hlc::new(2_000, 0)).is_none());
+
+    // Neither the entries nor removals stamped before the horizon come back
+    assert!(!log.insert(old));
+    assert!(!log.remove(removal));
+    log.merge(stale.clone());
+    assert_eq!(texts(&log), ["c"]);
+
+    // A replica merging the compacted one adopts its horizon
+    let mut other = stale;
+    other.insert(LogEntry::text(&author, Hlc::new(4_000, 0), "d"));
+    other.merge(log);
+    assert_eq!(texts(&other), ["c", "d"]);
+    assert_eq!(other.horizon(), Some(Hlc::new(3_000, 0)));
+}
+
+#[test]
+fn horizon_and_archive_are_persisted() {
+    let dir = tempfile::tempdir().unwrap();
+    let ns = Namespace::default().with_data_root(dir.path().to_str().unwrap());
+    let author = Keypair::generate_ed25519();
+    let mut log = log_of(&author, &[(1_000, "a"), (2_000, "b")]);
+    let (mut store, _) = LogStore::open(&ns, 9000, SyncPolicy::default(), &Keypair::generate_ed25519()).unwrap();
+    store.checkpoint(&log).unwrap();
+    let dropped = log.compact(Hlc::new(2_000, 0)).unwrap();
+
+    store.append(&[WalRecord::Compact { horizon: Hlc::new(2_000, 0), signature: None }], &log).unwrap();
+    storage::archive_entries(&ns, 9000, &dropped).unwrap();
+    storage::archive_entries(&ns, 9000, &[]).unwrap();
+    let (_, loaded) = LogStore::open(&ns, 9000, SyncPolicy::default(), &Keypair::generate_ed25519()).unwrap();
+    assert_eq!(loaded.horizon(), Some(Hlc::new(2_000, 0)));
+    assert_eq!(loaded, log);
+    assert_eq!(storage::load_archive(&ns, 9000).unwrap(), dropped);
+}
+
+#[tokio::test]
+async fn compaction_is_shared_and_not_undone_by_sync() {
+    let mesh = Mesh::spawn(3).await;
+    mesh.connect_all().await;
+    for text in ["a", "b", "c", "d"] {
+        mesh[1].send(NodeCommand::Log(text.into()));
+        wait_for("local write", || mesh[1].log().contains(&text.to_string())).await;
+    }
+    for node in [0, 2] {
+        wait_for("replicated", || mesh[node].log().len() == 4).await;
+    }
+
+    // Node 0 is the log admin, so the others adopt its count-based horizon
+    let admin = mesh[0].peer_id.to_string();
+    for node in &mesh.nodes {
+        node.state.log_admins.write().unwrap().insert(admin.clone());
+    }
+
+    // Node 2 misses the compaction
+    mesh[2].send(NodeCommand::SetChaos(ChaosConfig { drop_percent: 100.0, ..Default::default() }));
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let mut events = mesh[1].events();
+    mesh[0].send(NodeCommand::SetRetention(RetentionPolicy { max_entries: Some(2), ..Default::default() }));
+    let event = next_event(&mut events, |e| matches!(e, NetworkEvent::LogCompacted { .. })).await;
+    assert!(matches!(event, NetworkEvent::LogCompacted { removed: 2, archived: false, .. }));
+    for node in [0, 1] {
+        wait_for("compacted", || mesh[node].log() == ["c", "d"]).await;
+    }
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    assert_eq!(mesh[2].log().len(), 4);
+
+    // Syncing with it does not bring the compacted entries back
+    mesh[2].send(NodeCommand::SetChaos(ChaosConfig::default()));
+    mesh[0].lines.send("/sync".into()).unwrap();
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    assert_eq!(mesh[0].log(), ["c", "d"]);
+
+    // And it compacts once it hears of the horizon
+    mesh[2].lines.send("/sync".into()).unwrap();
+    wait_for("late compaction", || mesh[2].log() == ["c", "d"]).await;
+    let horizon = mesh[0].state.log.read().unwrap().horizon();
+    assert!(horizon.is_some());
+    assert_eq!(mesh[2].state.log.read().unwrap().horizon(), horizon);
+    mesh.shutdown().await;
+}
+
+#[tokio::test]
+async fn forged_horizons_are_refused() {
+    let mesh = Mesh::spawn(2).await;
+    mesh[0].send(NodeCommand::Log("kept".into()));
+    wait_for("entry", || mesh[0].log() == ["kept"]).await;
+
+    // Node 1 claims a horizon a day ahead, which would drop every entry, and announces it
+    // on connecting and in its state
+    let now_ms = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64;
+    mesh[1].state.log.write().unwrap().compact(Hlc::new(now_ms + 86_400_000, 0));
+    mesh.connect(0, 1).await;
+    mesh[0].lines.send("/sync".into()).unwrap();
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    assert_eq!(mesh[0].log(), ["kept"]);
+    assert_eq!(mesh[0].state.log.read().unwrap().horizon(), None);
+    mesh.shutdown().await;
+}
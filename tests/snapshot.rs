@@ -0,0 +1,101 @@
+mod common;
+
+use common::{next_event, wait_for, Mesh, TestNode};
+use ghostmesh::collection::{CollectionKind, CollectionUpdate};
+use ghostmesh::entry::LogEntry;
+use ghostmesh::hlc::Hlc;
+use ghostmesh::namespace::Namespace;
+use ghostmesh::p2p::NodeCommand;
+use ghostmesh::snapshot::{Snapshot, SnapshotData, SNAPSHOT_FORMAT};
+use ghostmesh::state::{AppState, DmEntry};
+use ghostmesh::storage;
+use ghostmesh::wal::SyncPolicy;
+use ghostmesh::telemetry::NetworkEvent;
+use libp2p::identity::Keypair;
+use serde_json::{json, Value};
+
+fn snapshot_with(data: SnapshotData) -> Snapshot {
+    let mut snapshot = Snapshot::capture(&AppState::new("12D3KooW".into(), Namespace::default()));
+    snapshot.data = data;
+    snapshot
+}
+
+fn value_of(node: &TestNode, name: &str) -> Option<Value> {
+    node.state.collections.read().unwrap().get(name).map(|c| c.read().unwrap().value())
+}
+
+#[test]
+fn archives_are_checked_on_import() {
+    let author = Keypair::generate_ed25519();
+    let mut data = SnapshotData::default();
+    data.log.insert(LogEntry::text(&author, Hlc::new(1, 0), "a"));
+    data.dms.push(DmEntry { from: "12D3KooW".into(), content: "oi".into(), timestamp: 1 });
+    let bytes = snapshot_with(data).encode().unwrap();
+
+    let decoded = Snapshot::decode(&bytes).unwrap();
+    assert_eq!(decoded.format, SNAPSHOT_FORMAT);
+    assert_eq!(decoded.data.log.len(), 1);
+    assert_eq!(decoded.data.dms[0].content, "oi");
+    assert!(Snapshot::decode_for(&bytes, "lab").is_err());
+
+    let mut archive: Value = serde_json::from_slice(&bytes).unwrap();
+    archive["data"]["dms"][0]["content"] = json!("tchau");
+    assert!(Snapshot::decode(&serde_json::to_vec(&archive).unwrap()).unwrap_err().to_string().contains("checksum"));
+
+    for (field, value) in [("format", json!(SNAPSHOT_FORMAT + 1)), ("protocol", json!("1.4.0"))] {
+        let mut archive: Value = serde_json::from_slice(&bytes).unwrap();
+        archive[field] = value;
+        assert!(Snapshot::decode(&serde_json::to_vec(&archive).unwrap()).is_err(), "{}", field);
+    }
+    assert!(Snapshot::decode(&bytes[..bytes.len() / 2]).is_err());
+}
+
+#[test]
+fn offline_state_is_restored_from_storage() {
+    let dir = tempfile::tempdir().unwrap();
+    let ns = Namespace::default().with_data_root(dir.path().to_str().unwrap());
+    let keypair = Keypair::generate_ed25519();
+    let dms = vec![DmEntry { from: "12D3KooW".into(), content: "oi".into(), timestamp: 1 }];
+    let book = [("12D3KooW".to_string(), ["/ip4/10.0.0.2/tcp/8080".to_string()].into())].into();
+    storage::save_dms(&ns, 9000, &dms).unwrap();
+    storage::save_address_book(&ns, 9000, &book).unwrap();
+
+    let state = AppState::new(keypair.public().to_peer_id().to_string(), ns);
+    storage::restore_state(&state, 9000, SyncPolicy::default(), &keypair);
+    let snapshot = Snapshot::capture(&state);
+    assert_eq!(snapshot.data.dms, dms);
+    assert_eq!(snapshot.data.address_book, book);
+}
+
+#[tokio::test]
+async fn a_new_node_is_cloned_from_a_snapshot() {
+    let mesh = Mesh::spawn(3).await;
+    mesh.connect(0, 2).await;
+    mesh[0].send(NodeCommand::Log("antes".into()));
+    mesh[0].send(NodeCommand::KvPut { device: "luz".into(), attribute: "sala".into(), value: json!("ON") });
+    mesh[0].send(NodeCommand::CounterAdd { name: "visitas".into(), amount: 3 });
+    mesh[0].send(NodeCommand::CreateCollection { name: "comodos".into(), kind: CollectionKind::OrSet });
+    mesh[0].send(NodeCommand::UpdateCollection { name: "comodos".into(), update: CollectionUpdate::Add { member: "sala".into() } });
+    wait_for("local writes", || value_of(&mesh[0], "comodos") == Some(json!(["sala"])) && mesh[0].log().len() == 1).await;
+
+    // Node 1 has a write of its own, which the import keeps
+    mesh[1].send(NodeCommand::Log("local".into()));
+    wait_for("local write", || mesh[1].log() == ["local"]).await;
+
+    let snapshot = Snapshot::decode(&Snapshot::capture(&mesh[0].state).encode().unwrap()).unwrap();
+    assert!(snapshot.data.address_book.contains_key(&mesh[2].peer_id.to_string()));
+    let mut events = mesh[1].events();
+    mesh[1].send(NodeCommand::ImportSnapshot(Box::new(snapshot)));
+    let event = next_event(&mut events, |e| matches!(e, NetworkEvent::SnapshotImported { .. })).await;
+    assert!(matches!(event, NetworkEvent::SnapshotImported { entries: 1, collections: 1, peers: 1, .. }));
+
+    assert_eq!(mesh[1].log(), ["antes", "local"]);
+    assert_eq!(mesh[1].state.kv.read().unwrap().get("luz", "sala"), Some(json!("ON")));
+    assert_eq!(mesh[1].state.counters.read().unwrap().get("visitas"), Some(3));
+    assert_eq!(value_of(&mesh[1], "comodos"), Some(json!(["sala"])));
+
+    // The address book brings it into the mesh, where its own entry spreads
+    wait_for("dialed from the address book", || mesh[1].is_connected_to(&mesh[2])).await;
+    wait_for("synced", || mesh[2].log() == ["antes", "local"]).await;
+    mesh.shutdown().await;
+}
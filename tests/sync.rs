@@ -0,0 +1,153 @@
+mod common;
+
+use common::{next_event, wait_for, Mesh};
+use ghostmesh::chaos::ChaosConfig;
+use ghostmesh::entry::LogEntry;
+use ghostmesh::hlc::Hlc;
+use ghostmesh::logset::LogSet;
+use ghostmesh::p2p::NodeCommand;
+use ghostmesh::sync::{AntiEntropy, CrdtMessage, LogDigest, LogTree, SyncMessage};
+use ghostmesh::telemetry::NetworkEvent;
+use libp2p::identity::Keypair;
+use libp2p::PeerId;
+
+/// The same text always yields the same entry, as if written once and replicated.
+fn entry(text: &str) -> LogEntry {
+    let keypair = Keypair::ed25519_from_bytes([7; 32]).unwrap();
+    LogEntry::with_id(&keypair, text.to_string(), Hlc::new(1, 0), Vec::new(), text.into())
+}
+
+fn log_of(entries: &[&str]) -> LogSet {
+    let mut set = LogSet::new();
+    for text in entries {
+        set.insert(entry(text));
+    }
+    set
+}
+
+#[test]
+fn digest_depends_on_content_only() {
+    assert_eq!(LogDigest::of(&log_of(&["a", "b"])), LogDigest::of(&log_of(&["b", "a"])));
+    assert_ne!(LogDigest::of(&log_of(&["a", "b"])), LogDigest::of(&log_of(&["ab"])));
+    assert_eq!(LogDigest::of(&log_of(&[])).count, 0);
+}
+
+#[test]
+fn legacy_full_state_decodes_as_state() {
+    let legacy = serde_json::to_vec(&log_of(&["old"])).unwrap();
+    match CrdtMessage::decode(&legacy) {
+        Some(CrdtMessage::State { state }) => assert!(state.contains(&entry("old"))),
+        other => panic!("unexpected {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn appends_publish_only_the_new_entry() {
+    let mesh = Mesh::spawn(2).await;
+    mesh.connect(0, 1).await;
+
+    for i in 0..50 {
+        mesh[0].send(NodeCommand::Log(format!("entry number {:03} with some padding", i)));
+    }
+    wait_for("initial replication", || mesh[1].log().len() == 50).await;
+
+    let crdt_outbound = || mesh[0].state.bandwidth.report().by_topic["ghostmesh-crdt"].outbound;
+    let before = crdt_outbound();
+    mesh[0].send(NodeCommand::Log("one more".into()));
+    wait_for("delta replication", || mesh[1].log().len() == 51).await;
+    let delta = crdt_outbound() - before;
+    assert!(delta < 500, "published {} bytes for a single entry", delta);
+    mesh.shutdown().await;
+}
+
+#[test]
+fn anti_entropy_transfers_only_missing_entries() {
+    let a: Vec<String> = (0..40).map(|i| format!("shared {}", i)).chain(["only a".to_string()]).collect();
+    let b: Vec<String> = (0..40).map(|i| format!("shared {}", i)).chain(["only b".to_string()]).collect();
+    let log_a = log_of(&a.iter().map(String::as_str).collect::<Vec<_>>());
+    let log_b = log_of(&b.iter().map(String::as_str).collect::<Vec<_>>());
+    let (peer_a, peer_b) = (PeerId::random(), PeerId::random());
+    let (mut ae_a, mut ae_b) = (AntiEntropy::default(), AntiEntropy::default());
+
+    // Every message goes through the wire encoding, as it would over the sync protocol
+    let wire = |msg: SyncMessage| serde_json::from_slice::<SyncMessage>(&serde_json::to_vec(&msg).unwrap()).expect("decodable");
+    let (offer, _) = ae_a.start(peer_b, &log_a);
+    let diff = ae_b.handle(peer_a, wire(offer), &log_b).reply.expect("diff");
+    let step = ae_a.handle(peer_b, wire(diff), &log_a);
+    let Some(SyncMessage::Entries { entries, want, .. }) = step.reply.clone() else { panic!("entries") };
+    assert_eq!(entries, vec![entry("only a")]);
+    assert_eq!(want.len(), 1);
+
+    let step = ae_b.handle(peer_a, wire(step.reply.unwrap()), &log_b);
+    assert_eq!(step.merge, vec![entry("only a")]);
+    let last = ae_a.handle(peer_b, wire(step.reply.expect("wanted entries")), &log_a);
+    assert_eq!(last.merge, vec![entry("only b")]);
+    assert_eq!(last.progress.unwrap().stage, "complete");
+}
+
+#[test]
+fn equal_logs_have_equal_trees() {
+    assert_eq!(LogTree::of(&log_of(&["x", "y"])), LogTree::of(&log_of(&["y", "x"])));
+    assert_ne!(LogTree::of(&log_of(&["x"])).root, LogTree::of(&log_of(&["x", "y"])).root);
+}
+
+#[tokio::test]
+async fn peers_reconcile_their_logs_on_connect() {
+    let mesh = Mesh::spawn(2).await;
+    mesh[0].send(NodeCommand::Log("written alone on a".into()));
+    mesh[1].send(NodeCommand::Log("written alone on b".into()));
+    wait_for("local writes", || mesh[0].log().len() == 1 && mesh[1].log().len() == 1).await;
+    let (mut events_0, mut events_1) = (mesh[0].events(), mesh[1].events());
+
+    mesh.connect(0, 1).await;
+
+    let expected = ["written alone on a", "written alone on b"];
+    for node in &mesh.nodes {
+        wait_for("anti-entropy", || node.log() == expected).await;
+    }
+    for events in [&mut events_0, &mut events_1] {
+        let event = next_event(events, |e| matches!(e, NetworkEvent::SyncProgress { stage, .. } if stage == "complete")).await;
+        let NetworkEvent::SyncProgress { entries_sent, entries_received, .. } = event else { unreachable!() };
+        assert_eq!((entries_sent, entries_received), (1, 1));
+    }
+    mesh.shutdown().await;
+}
+
+#[tokio::test]
+async fn sessions_reach_only_the_peer_involved() {
+    let mesh = Mesh::spawn(3).await;
+    for i in 0..100 {
+        mesh[0].send(NodeCommand::Log(format!("entry number {:03} with some padding", i)));
+    }
+    wait_for("local writes", || mesh[0].log().len() == 100).await;
+    mesh.connect(1, 2).await;
+    tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+
+    // Node 1 relays what it gets on the CRDT topic to node 2, but not its session with node 0
+    let crdt_inbound = || mesh[2].state.bandwidth.report().by_topic.get("ghostmesh-crdt").map_or(0, |usage| usage.inbound);
+    let before = crdt_inbound();
+    let mut events = mesh[1].events();
+    mesh.connect(0, 1).await;
+    wait_for("anti-entropy", || mesh[1].log().len() == 100).await;
+    next_event(&mut events, |e| matches!(e, NetworkEvent::SyncProgress { stage, .. } if stage == "complete")).await;
+    assert!(crdt_inbound() - before < 1_000, "node 2 received {} bytes", crdt_inbound() - before);
+    assert!(mesh[2].log().is_empty());
+    mesh.shutdown().await;
+}
+
+#[tokio::test]
+async fn full_state_is_sent_on_demand() {
+    let mesh = Mesh::spawn(2).await;
+    mesh.connect(0, 1).await;
+
+    mesh[1].send(NodeCommand::SetChaos(ChaosConfig { drop_percent: 100.0, ..Default::default() }));
+    mesh[0].send(NodeCommand::Log("missed".into()));
+    wait_for("local write", || mesh[0].log().len() == 1).await;
+    tokio::time::sleep(std::time::Duration::from_millis(300)).await;
+    assert!(mesh[1].log().is_empty());
+
+    mesh[1].send(NodeCommand::SetChaos(ChaosConfig::default()));
+    mesh[1].lines.send("/sync".into()).unwrap();
+    wait_for("on-demand state", || mesh[1].log() == ["missed"]).await;
+    mesh.shutdown().await;
+}
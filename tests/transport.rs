@@ -0,0 +1,116 @@
+mod common;
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use common::{next_event, wait_for, Mesh};
+use ghostmesh::chaos::ChaosConfig;
+use ghostmesh::health::{PeerHealth, PeerStatus, SUSPECT_AFTER_FAILURES};
+use ghostmesh::p2p::{NodeCommand, NodeOptions};
+use ghostmesh::telemetry::NetworkEvent;
+use ghostmesh::transport::{ConnectivityScore, FallbackLink, MockHub, MockLink, Route, TransportManager};
+use libp2p::PeerId;
+
+/// Small enough that a log state needs several fragments.
+const MOCK_MTU: usize = 128;
+const MOCK_RATE: u64 = 64 * 1024;
+
+#[test]
+fn score_weights_latency_loss_and_interface() {
+    assert_eq!(ConnectivityScore::compute(None, true, true).total, 100.0);
+    assert_eq!(ConnectivityScore::compute(None, false, true).total, 0.0);
+    assert_eq!(ConnectivityScore::compute(None, true, false).total, 70.0);
+
+    let mut slow = PeerHealth::new();
+    slow.record_rtt(Duration::from_millis(1000));
+    let score = ConnectivityScore::compute(Some(&slow), true, true);
+    assert_eq!(score.latency, 20.0);
+    assert_eq!(score.loss, 30.0);
+
+    let mut lossy = PeerHealth::new();
+    lossy.record_rtt(Duration::from_millis(10));
+    lossy.record_failure();
+    assert_eq!(lossy.status, PeerStatus::Alive, "one lost ping is not enough to suspect a peer");
+    assert_eq!(ConnectivityScore::compute(Some(&lossy), true, true).loss, 15.0);
+    for _ in 1..SUSPECT_AFTER_FAILURES {
+        lossy.record_failure();
+    }
+    assert_eq!(lossy.status, PeerStatus::Suspect);
+    let score = ConnectivityScore::compute(Some(&lossy), true, true);
+    assert_eq!(score.loss, 0.0, "suspect peers lose the whole loss factor");
+    assert!(score.total <= 70.0);
+
+    // A successful ping in between starts the count again
+    let mut flaky = PeerHealth::new();
+    for _ in 0..SUSPECT_AFTER_FAILURES {
+        flaky.record_failure();
+        flaky.record_rtt(Duration::from_millis(10));
+    }
+    assert_eq!(flaky.status, PeerStatus::Alive);
+}
+
+#[tokio::test]
+async fn routes_follow_score_and_link_availability() {
+    let hub = MockHub::default();
+    let link = Arc::new(MockLink::new(&hub, MOCK_MTU, MOCK_RATE));
+    let remote = PeerId::random();
+    let _remote_frames = MockLink::new(&hub, MOCK_MTU, MOCK_RATE).attach(remote);
+    let mut manager = TransportManager::new(Some(link.clone()));
+
+    let good = ConnectivityScore::compute(None, true, true);
+    let t = manager.update(remote, good, true).expect("first route");
+    assert_eq!((t.from, t.to), (None, Some(Route::Tcp)));
+    assert!(manager.update(remote, good, true).is_none());
+
+    let t = manager.update(remote, ConnectivityScore::default(), false).expect("fallback");
+    assert_eq!((t.from, t.to), (Some(Route::Tcp), Some(Route::Ble)));
+    assert_eq!(manager.fallback_peers(), vec![remote]);
+
+    link.set_available(false);
+    let t = manager.update(remote, ConnectivityScore::default(), false).expect("unreachable");
+    assert_eq!((t.from, t.to), (Some(Route::Ble), None));
+    assert!(manager.known_peers().is_empty());
+}
+
+#[tokio::test]
+async fn messages_fall_back_to_the_mock_link_when_tcp_is_cut() {
+    let hub = MockHub::default();
+    let options = (0..2)
+        .map(|_| NodeOptions {
+            fallback_link: Some(Arc::new(MockLink::new(&hub, MOCK_MTU, MOCK_RATE))),
+            ..Default::default()
+        })
+        .collect();
+    let mesh = Mesh::spawn_with(options).await;
+    let mut events = mesh[0].events();
+    mesh.connect(0, 1).await;
+    wait_for("tcp route", || mesh[0].state.transport_report().peers.len() == 1).await;
+
+    let split = ChaosConfig {
+        partitions: vec![vec![mesh[0].peer_id.to_string()], vec![mesh[1].peer_id.to_string()]],
+        ..Default::default()
+    };
+    for node in &mesh.nodes {
+        node.send(NodeCommand::SetChaos(split.clone()));
+    }
+    let event = next_event(&mut events, |e| {
+        matches!(e, NetworkEvent::TransportSwitched { to: Some(to), .. } if to == "ble")
+    })
+    .await;
+    let NetworkEvent::TransportSwitched { peer_id, from, score, .. } = event else { unreachable!() };
+    assert_eq!(peer_id, mesh[1].peer_id.to_string());
+    assert_eq!(from.as_deref(), Some("tcp"));
+    assert_eq!(score, 0.0);
+
+    mesh[0].send(NodeCommand::Log("over the fallback link with a payload long enough to fragment".into()));
+    let event = next_event(&mut events, |e| matches!(e, NetworkEvent::RouteDecision { .. })).await;
+    let NetworkEvent::RouteDecision { tcp_peers, ble_peers, .. } = event else { unreachable!() };
+    assert!(tcp_peers.is_empty());
+    assert_eq!(ble_peers, vec![mesh[1].peer_id.to_string()]);
+    wait_for("log over fallback", || mesh[1].log().len() == 1).await;
+
+    mesh[1].send(NodeCommand::Chat("reply".into()));
+    wait_for("chat over fallback", || !mesh[0].state.chat.read().unwrap().is_empty()).await;
+    assert_eq!(mesh[0].state.chat.read().unwrap()[0].from, mesh[1].peer_id.to_string());
+    mesh.shutdown().await;
+}
@@ -0,0 +1,123 @@
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::time::Duration;
+
+use ghostmesh::entry::{LogEntry, Tombstone};
+use ghostmesh::hlc::Hlc;
+use ghostmesh::logset::LogSet;
+use ghostmesh::namespace::Namespace;
+use ghostmesh::storage;
+use ghostmesh::wal::{LogStore, PendingWrite, SyncPolicy, WalRecord, SNAPSHOT_EVERY};
+use libp2p::identity::Keypair;
+
+fn namespace(dir: &tempfile::TempDir) -> Namespace {
+    Namespace::default().with_data_root(dir.path().to_str().unwrap())
+}
+
+/// Applies `record` to `log` and appends it, as the node does.
+fn write(store: &mut LogStore, log: &mut LogSet, record: WalRecord) {
+    record.clone().apply(log);
+    store.append(&[record], log).unwrap();
+}
+
+#[test]
+fn the_wal_is_replayed_up_to_a_torn_record() {
+    let dir = tempfile::tempdir().unwrap();
+    let ns = namespace(&dir);
+    let author = Keypair::generate_ed25519();
+    let (mut store, mut log) = LogStore::open(&ns, 9000, SyncPolicy::Never, &Keypair::generate_ed25519()).unwrap();
+    assert!(log.is_empty());
+
+    let entries: Vec<LogEntry> = (0..3).map(|i| LogEntry::text(&author, Hlc::new(1_000 + i, 0), &i.to_string())).collect();
+    for entry in &entries {
+        write(&mut store, &mut log, WalRecord::Insert { entry: entry.clone() });
+    }
+    write(&mut store, &mut log, WalRecord::Remove { tombstone: Tombstone::new(&author, &entries[1], Hlc::new(2_000, 0)) });
+    write(&mut store, &mut log, WalRecord::Compact { horizon: Hlc::new(1_001, 0), signature: None });
+    assert_eq!(store.records(), 5);
+    drop(store);
+
+    // A crash mid-append leaves half a record
+    let wal = storage::get_wal_path(&ns, 9000);
+    let intact = std::fs::metadata(&wal).unwrap().len();
+    OpenOptions::new().append(true).open(&wal).unwrap().write_all(b"0123abcd {\"op\":\"ins").unwrap();
+
+    let (store, recovered) = LogStore::open(&ns, 9000, SyncPolicy::Always, &Keypair::generate_ed25519()).unwrap();
+    assert_eq!(recovered, log);
+    assert_eq!(recovered.horizon(), Some(Hlc::new(1_001, 0)));
+    assert_eq!(store.records(), 5);
+    assert_eq!(std::fs::metadata(&wal).unwrap().len(), intact);
+}
+
+#[test]
+fn long_wals_are_folded_into_the_snapshot() {
+    let dir = tempfile::tempdir().unwrap();
+    let ns = namespace(&dir);
+    let author = Keypair::generate_ed25519();
+    let (mut store, mut log) = LogStore::open(&ns, 9000, SyncPolicy::Interval(Duration::from_millis(50)), &Keypair::generate_ed25519()).unwrap();
+    for i in 0..SNAPSHOT_EVERY + 2 {
+        write(&mut store, &mut log, WalRecord::Insert { entry: LogEntry::text(&author, Hlc::new(1_000, i as u32), "x") });
+    }
+    assert_eq!(store.records(), 2);
+    store.sync().unwrap();
+    drop(store);
+
+    let (store, recovered) = LogStore::open(&ns, 9000, SyncPolicy::Always, &Keypair::generate_ed25519()).unwrap();
+    assert_eq!(recovered.len(), SNAPSHOT_EVERY + 2);
+    assert_eq!(store.records(), 2);
+}
+
+#[test]
+fn writes_hold_what_was_prepared() {
+    let dir = tempfile::tempdir().unwrap();
+    let ns = namespace(&dir);
+    let author = Keypair::generate_ed25519();
+    let (mut store, mut log) = LogStore::open(&ns, 9000, SyncPolicy::Always, &Keypair::generate_ed25519()).unwrap();
+    let record = |i: u32| WalRecord::Insert { entry: LogEntry::text(&author, Hlc::new(1_000, i), "x") };
+
+    // The node releases the log between the two steps, and may change it meanwhile
+    record(0).apply(&mut log);
+    let pending = store.prepare(&[record(0)], &log).unwrap();
+    record(1).apply(&mut log);
+    store.write(pending).unwrap();
+    assert!(matches!(store.prepare(&[record(1)], &log).unwrap(), PendingWrite::Append { records: 1, .. }));
+    drop(store);
+
+    let (store, recovered) = LogStore::open(&ns, 9000, SyncPolicy::Always, &Keypair::generate_ed25519()).unwrap();
+    assert_eq!(recovered.len(), 1);
+    assert_eq!(store.records(), 1);
+}
+
+#[test]
+fn the_json_file_is_migrated_once() {
+    let dir = tempfile::tempdir().unwrap();
+    let ns = namespace(&dir);
+    let author = Keypair::generate_ed25519();
+    let mut log = LogSet::new();
+    log.insert(LogEntry::text(&author, Hlc::new(1_000, 0), "antigo"));
+    let path = storage::get_storage_path(&ns, 9000);
+    std::fs::write(&path, serde_json::to_string_pretty(&log).unwrap()).unwrap();
+
+    let (mut store, mut migrated) = LogStore::open(&ns, 9000, SyncPolicy::Always, &Keypair::generate_ed25519()).unwrap();
+    assert_eq!(migrated, log);
+    assert!(!std::path::Path::new(&path).exists());
+    write(&mut store, &mut migrated, WalRecord::Insert { entry: LogEntry::text(&author, Hlc::new(2_000, 0), "novo") });
+    drop(store);
+
+    // The backup is not migrated again
+    let (_, reopened) = LogStore::open(&ns, 9000, SyncPolicy::Always, &Keypair::generate_ed25519()).unwrap();
+    assert_eq!(reopened.len(), 2);
+    let backup: LogSet = serde_json::from_slice(&std::fs::read(format!("{}.bak", path)).unwrap()).unwrap();
+    assert_eq!(backup, log);
+}
+
+#[test]
+fn sync_policies_parse() {
+    assert_eq!(SyncPolicy::parse("always").unwrap(), SyncPolicy::Always);
+    assert_eq!(SyncPolicy::parse("never").unwrap(), SyncPolicy::Never);
+    assert_eq!(SyncPolicy::parse("200ms").unwrap(), SyncPolicy::Interval(Duration::from_millis(200)));
+    assert_eq!(SyncPolicy::parse("2s").unwrap(), SyncPolicy::Interval(Duration::from_secs(2)));
+    for invalid in ["", "0ms", "sometimes", "5"] {
+        assert!(SyncPolicy::parse(invalid).is_err(), "{}", invalid);
+    }
+}
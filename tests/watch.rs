@@ -0,0 +1,114 @@
+mod common;
+
+use common::{with_timeout, Mesh};
+use ghostmesh::collection::{CollectionKind, CollectionUpdate};
+use ghostmesh::p2p::NodeCommand;
+use ghostmesh::watch::{collection_view, Change, ChangeOp, Subscription, Watch, WatchItem};
+use serde_json::json;
+
+async fn next(subscription: &mut Subscription) -> WatchItem {
+    with_timeout("watch item", subscription.next()).await.expect("watch closed")
+}
+
+async fn next_change(subscription: &mut Subscription) -> Change {
+    loop {
+        if let WatchItem::Change(change) = next(subscription).await {
+            return change;
+        }
+    }
+}
+
+fn ops(changes: &[Change]) -> Vec<(ChangeOp, &str)> {
+    changes.iter().map(|change| (change.op, change.key.as_str())).collect()
+}
+
+#[tokio::test]
+async fn views_are_diffed_into_ordered_changes() {
+    let watch = Watch::default();
+    watch.seed("collections/docs", collection_view(CollectionKind::Document, &json!({"a": {"b": 1}})));
+    let mut all = watch.subscribe("", None);
+    let WatchItem::Ready { seq: start } = next(&mut all).await else { panic!("expected ready") };
+    let mut docs = watch.subscribe("collections/docs/", None);
+    next(&mut docs).await;
+
+    watch.update_view("collections/docs", collection_view(CollectionKind::Document, &json!({"a": {"b": 2}, "c": [1]})));
+    watch.update_key("counters", "visitas", Some(json!(1)));
+    watch.update_key("counters", "visitas", Some(json!(1)));
+    watch.update_view("collections/docs", collection_view(CollectionKind::Document, &json!({"c": [1]})));
+    watch.update_key("kv", "sala/luz", None);
+
+    let mut changes = Vec::new();
+    for _ in 0..4 {
+        changes.push(next_change(&mut all).await);
+    }
+    assert_eq!(
+        ops(&changes),
+        [
+            (ChangeOp::Update, "collections/docs/a/b"),
+            (ChangeOp::Insert, "collections/docs/c"),
+            (ChangeOp::Insert, "counters/visitas"),
+            (ChangeOp::Remove, "collections/docs/a/b"),
+        ]
+    );
+    assert_eq!(changes[0].value, Some(json!(2)));
+    assert!(changes.windows(2).all(|pair| pair[0].seq < pair[1].seq) && changes[0].seq > start);
+
+    // A prefix only sees its keys
+    let change = next_change(&mut docs).await;
+    assert_eq!(change.key, "collections/docs/a/b");
+    assert_eq!(next_change(&mut docs).await.key, "collections/docs/c");
+}
+
+#[tokio::test]
+async fn resume_tokens_pick_up_after_the_last_change() {
+    let watch = Watch::default();
+    watch.update_key("counters", "a", Some(json!(1)));
+    let mut first = watch.subscribe("counters/", None);
+    let WatchItem::Ready { seq: token } = next(&mut first).await else { panic!("expected ready") };
+    drop(first);
+
+    // Changes made while disconnected are replayed, then the live ones follow
+    watch.update_key("counters", "a", Some(json!(2)));
+    watch.update_key("kv", "sala/luz", Some(json!("acesa")));
+    let mut resumed = watch.subscribe("counters/", Some(token));
+    let missed = next_change(&mut resumed).await;
+    assert_eq!((missed.op, missed.key.as_str(), missed.value), (ChangeOp::Update, "counters/a", Some(json!(2))));
+    assert!(matches!(next(&mut resumed).await, WatchItem::Ready { .. }));
+    watch.update_key("counters", "b", Some(json!(1)));
+    assert_eq!(next_change(&mut resumed).await.key, "counters/b");
+
+    // A token of an earlier run cannot be resumed from
+    let restarted = Watch::default();
+    let mut reset = restarted.subscribe("", Some(token));
+    assert!(matches!(next(&mut reset).await, WatchItem::Reset { .. }));
+    assert!(matches!(next(&mut reset).await, WatchItem::Ready { .. }));
+}
+
+#[tokio::test]
+async fn merges_from_peers_are_watched() {
+    let mesh = Mesh::spawn(2).await;
+    mesh.connect(0, 1).await;
+    let mut log = mesh[1].state.watch.subscribe("log/", None);
+    let mut tags = mesh[1].state.watch.subscribe("collections/tags/", None);
+
+    mesh[0].send(NodeCommand::Log("remota".into()));
+    let change = next_change(&mut log).await;
+    assert_eq!(change.op, ChangeOp::Insert);
+    let entry = change.value.unwrap();
+    assert_eq!(entry["body"], json!("remota"));
+    // Ids are only unique per author
+    assert_eq!(change.key, format!("log/{}/{}", mesh[0].state.local_peer_id, entry["id"].as_str().unwrap()));
+
+    for node in [0, 1] {
+        mesh[node].send(NodeCommand::CreateCollection { name: "tags".into(), kind: CollectionKind::OrSet });
+    }
+    mesh[0].send(NodeCommand::UpdateCollection { name: "tags".into(), update: CollectionUpdate::Add { member: "urgente".into() } });
+    let change = next_change(&mut tags).await;
+    assert_eq!((change.op, change.key.as_str()), (ChangeOp::Insert, "collections/tags/urgente"));
+
+    mesh[0].send(NodeCommand::UpdateCollection { name: "tags".into(), update: CollectionUpdate::Remove { member: "urgente".into() } });
+    let change = next_change(&mut tags).await;
+    assert_eq!((change.op, change.key.as_str(), change.value), (ChangeOp::Remove, "collections/tags/urgente", None));
+    drop((log, tags));
+    mesh.shutdown().await;
+}